@@ -0,0 +1,32 @@
+use std::fs::read_dir;
+
+/// Passes the versions of every migration in the migrations directory to the server as EXPECTED_MIGRATIONS,
+/// so that /health/ready can tell if the database is missing any without needing the migrations at runtime.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+
+    let mut versions: Vec<String> = read_dir("migrations")
+        .expect("Failed to read migrations directory")
+        .map(|entry| entry.expect("Failed to read migrations directory entry"))
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| {
+            // Diesel uses the digits before the first underscore as the version, e.g. 2021-01-11-194703_users is 20210111194703
+            let name = entry.file_name().to_string_lossy().to_string();
+            let version: String = name
+                .split('_')
+                .next()
+                .unwrap_or("")
+                .chars()
+                .filter(char::is_ascii_digit)
+                .collect();
+            if version.is_empty() {
+                None
+            } else {
+                Some(version)
+            }
+        })
+        .collect();
+    versions.sort();
+
+    println!("cargo:rustc-env=EXPECTED_MIGRATIONS={}", versions.join(","));
+}
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE EXTENSION IF NOT EXISTS "uuid-ossp";
+CREATE TABLE password_reset_tokens (
+    reset_token uuid PRIMARY KEY DEFAULT uuid_generate_v4() NOT NULL UNIQUE,
+    user_id uuid NOT NULL,
+    expires_at timestamp NOT NULL,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+);
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE login_challenges;
+DROP TABLE totp_recovery_codes;
+DROP TABLE totp_secrets
\ No newline at end of file
@@ -0,0 +1,33 @@
+-- Your SQL goes here
+CREATE EXTENSION IF NOT EXISTS "uuid-ossp";
+CREATE TABLE totp_secrets (
+    user_id uuid PRIMARY KEY NOT NULL UNIQUE,
+    secret text NOT NULL,
+    enabled boolean DEFAULT false NOT NULL,
+    last_used_step bigint,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+);
+
+CREATE TABLE totp_recovery_codes (
+    recovery_code_id SERIAL PRIMARY KEY,
+    user_id uuid NOT NULL,
+    code_hash text NOT NULL,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+);
+
+CREATE TABLE login_challenges (
+    challenge uuid PRIMARY KEY DEFAULT uuid_generate_v4() NOT NULL UNIQUE,
+    user_id uuid NOT NULL,
+    expires_at timestamp NOT NULL,
+    failed_attempts smallint DEFAULT 0 NOT NULL,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+);
\ No newline at end of file
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+CREATE EXTENSION IF NOT EXISTS "uuid-ossp";
+CREATE TABLE api_keys (
+    api_key_id uuid PRIMARY KEY DEFAULT uuid_generate_v4() NOT NULL UNIQUE,
+    api_key uuid DEFAULT uuid_generate_v4() NOT NULL UNIQUE,
+    user_id uuid NOT NULL,
+    name text NOT NULL,
+    scopes text[] NOT NULL,
+    -- NULL means the key can be used for all of the user's cameras
+    camera_ids uuid[],
+    expires_at timestamp,
+    created_at timestamp DEFAULT now() NOT NULL,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+);
\ No newline at end of file
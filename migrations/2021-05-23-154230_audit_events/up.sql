@@ -0,0 +1,17 @@
+-- Your SQL goes here
+CREATE EXTENSION IF NOT EXISTS "uuid-ossp";
+-- There are no foreign keys here on purpose, events have to outlive the users and cameras they mention
+CREATE TABLE audit_events (
+    audit_event_id uuid PRIMARY KEY DEFAULT uuid_generate_v4() NOT NULL UNIQUE,
+    action text NOT NULL,
+    actor_user_id uuid,
+    actor_api_key_id uuid,
+    target_user_id uuid,
+    target_camera_id uuid,
+    ip_address text,
+    details jsonb,
+    created_at timestamp DEFAULT now() NOT NULL
+);
+CREATE INDEX audit_events_actor_user_id_idx ON audit_events (actor_user_id, created_at);
+CREATE INDEX audit_events_target_user_id_idx ON audit_events (target_user_id, created_at);
+CREATE INDEX audit_events_target_camera_id_idx ON audit_events (target_camera_id, created_at);
\ No newline at end of file
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE EXTENSION IF NOT EXISTS "uuid-ossp";
+CREATE TABLE image_metadata (
+    camera_id uuid NOT NULL,
+    -- The image's file name without the extension
+    image_id text NOT NULL,
+    -- Worked out from the image itself, not whatever the camera said it was
+    content_type text NOT NULL,
+    size_bytes bigint NOT NULL,
+    created_at timestamp DEFAULT now() NOT NULL,
+    PRIMARY KEY (camera_id, image_id),
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+);
\ No newline at end of file
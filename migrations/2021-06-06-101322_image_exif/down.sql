@@ -0,0 +1,13 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE image_metadata
+    DROP COLUMN captured_at,
+    DROP COLUMN camera_make,
+    DROP COLUMN camera_model,
+    DROP COLUMN exposure_time,
+    DROP COLUMN f_number,
+    DROP COLUMN iso,
+    DROP COLUMN focal_length,
+    DROP COLUMN orientation,
+    DROP COLUMN gps_latitude,
+    DROP COLUMN gps_longitude,
+    DROP COLUMN gps_altitude
\ No newline at end of file
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- Fields read from the image's EXIF data, NULL if the camera didn't record them
+ALTER TABLE image_metadata
+    ADD COLUMN captured_at timestamp,
+    ADD COLUMN camera_make text,
+    ADD COLUMN camera_model text,
+    ADD COLUMN exposure_time text,
+    ADD COLUMN f_number double precision,
+    ADD COLUMN iso integer,
+    ADD COLUMN focal_length double precision,
+    ADD COLUMN orientation smallint,
+    ADD COLUMN gps_latitude double precision,
+    ADD COLUMN gps_longitude double precision,
+    ADD COLUMN gps_altitude double precision;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE image_metadata DROP COLUMN content_hash;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Hex SHA-256 of the image file, used for ETags. NULL for images stored before it was added
+ALTER TABLE image_metadata ADD COLUMN content_hash text;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE trashed_images;
+ALTER TABLE users_cameras DROP COLUMN role;
\ No newline at end of file
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- viewer, editor or owner. Everyone paired with a camera so far created it, so they're all owners
+ALTER TABLE users_cameras ADD COLUMN role text NOT NULL DEFAULT 'owner';
+
+CREATE TABLE trashed_images (
+    camera_id uuid NOT NULL,
+    image_id text NOT NULL,
+    -- The image's file name in the camera's trash directory
+    file_name text NOT NULL,
+    deleted_by uuid,
+    deleted_at timestamp DEFAULT now() NOT NULL,
+    PRIMARY KEY (camera_id, image_id),
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_deleted_by
+        FOREIGN KEY (deleted_by)
+            REFERENCES users (user_id)
+            ON DELETE SET NULL
+);
\ No newline at end of file
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+-- Kept up to date as images are stored and deleted. Cameras without a row have it worked out from their directory
+CREATE TABLE camera_storage_usage (
+    camera_id uuid PRIMARY KEY,
+    image_count bigint NOT NULL,
+    total_bytes bigint NOT NULL,
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+);
\ No newline at end of file
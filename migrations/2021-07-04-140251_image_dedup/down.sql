@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE camera_dedup_savings;
+DROP INDEX image_metadata_content_hash;
\ No newline at end of file
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+-- Used to find earlier copies of an upload
+CREATE INDEX image_metadata_content_hash ON image_metadata (camera_id, content_hash);
+-- Totals of the duplicate uploads that were linked to or skipped instead of being stored again
+CREATE TABLE camera_dedup_savings (
+    camera_id uuid PRIMARY KEY,
+    duplicate_count bigint DEFAULT 0 NOT NULL,
+    bytes_saved bigint DEFAULT 0 NOT NULL,
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+);
\ No newline at end of file
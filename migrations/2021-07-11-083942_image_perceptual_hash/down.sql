@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE image_metadata DROP COLUMN perceptual_hash_3;
+ALTER TABLE image_metadata DROP COLUMN perceptual_hash_2;
+ALTER TABLE image_metadata DROP COLUMN perceptual_hash_1;
+ALTER TABLE image_metadata DROP COLUMN perceptual_hash_0;
+ALTER TABLE image_metadata DROP COLUMN perceptual_hash;
\ No newline at end of file
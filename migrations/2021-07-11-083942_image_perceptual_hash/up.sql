@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- 64 bit dHash of the image, for finding images that look alike. NULL for images stored before it was added, and for
+-- images in formats that can't be decoded
+ALTER TABLE image_metadata ADD COLUMN perceptual_hash bigint;
+-- The hash split into four 16 bit chunks. Images within a small Hamming distance of each other have at least one chunk
+-- that's close, so similar images can be found with index lookups instead of comparing against every image
+ALTER TABLE image_metadata ADD COLUMN perceptual_hash_0 integer;
+ALTER TABLE image_metadata ADD COLUMN perceptual_hash_1 integer;
+ALTER TABLE image_metadata ADD COLUMN perceptual_hash_2 integer;
+ALTER TABLE image_metadata ADD COLUMN perceptual_hash_3 integer;
+CREATE INDEX image_metadata_perceptual_hash_0 ON image_metadata (camera_id, perceptual_hash_0);
+CREATE INDEX image_metadata_perceptual_hash_1 ON image_metadata (camera_id, perceptual_hash_1);
+CREATE INDEX image_metadata_perceptual_hash_2 ON image_metadata (camera_id, perceptual_hash_2);
+CREATE INDEX image_metadata_perceptual_hash_3 ON image_metadata (camera_id, perceptual_hash_3);
\ No newline at end of file
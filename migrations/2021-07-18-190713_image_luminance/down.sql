@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE image_metadata DROP COLUMN lighting;
+ALTER TABLE image_metadata DROP COLUMN overexposed_ratio;
+ALTER TABLE image_metadata DROP COLUMN underexposed_ratio;
+ALTER TABLE image_metadata DROP COLUMN luminance_histogram;
+ALTER TABLE image_metadata DROP COLUMN mean_luminance;
\ No newline at end of file
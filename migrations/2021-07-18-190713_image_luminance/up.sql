@@ -0,0 +1,12 @@
+-- Your SQL goes here
+-- Luminance statistics, worked out from the image when it's stored. NULL for images stored before they were added, and
+-- for images in formats that can't be decoded
+-- 0 to 255
+ALTER TABLE image_metadata ADD COLUMN mean_luminance double precision;
+-- The fraction of pixels in each of 16 equal luminance ranges, darkest first
+ALTER TABLE image_metadata ADD COLUMN luminance_histogram double precision[];
+-- The fraction of pixels that are nearly black and nearly white
+ALTER TABLE image_metadata ADD COLUMN underexposed_ratio double precision;
+ALTER TABLE image_metadata ADD COLUMN overexposed_ratio double precision;
+-- "day", "dusk" or "night"
+ALTER TABLE image_metadata ADD COLUMN lighting text;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE image_metadata DROP COLUMN privacy_mask_version;
+DROP TABLE privacy_masks;
\ No newline at end of file
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- Each change to a camera's privacy masks adds a new version, so earlier ones are kept. The highest version is the one in use
+CREATE TABLE privacy_masks (
+    camera_id uuid NOT NULL,
+    version integer NOT NULL,
+    -- A JSON array of polygons, see PrivacyMask
+    masks jsonb NOT NULL,
+    created_by uuid,
+    created_at timestamp DEFAULT now() NOT NULL,
+    PRIMARY KEY (camera_id, version),
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES configs (camera_id)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_created_by
+        FOREIGN KEY (created_by)
+            REFERENCES users (user_id)
+            ON DELETE SET NULL
+);
+-- The privacy mask version applied to the image when it was stored, or NULL if the camera had no masks
+ALTER TABLE image_metadata ADD COLUMN privacy_mask_version integer;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE image_metadata DROP COLUMN watermarked;
+DROP TABLE watermarks;
\ No newline at end of file
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- A camera's timestamp and name watermark. Cameras without a row here aren't watermarked
+CREATE TABLE watermarks (
+    camera_id uuid PRIMARY KEY NOT NULL,
+    -- burn_in or on_the_fly, see WatermarkMode
+    mode text NOT NULL,
+    timezone text DEFAULT 'UTC' NOT NULL,
+    -- A strftime format string
+    timestamp_format text DEFAULT '%Y-%m-%d %H:%M:%S' NOT NULL,
+    show_camera_name boolean DEFAULT true NOT NULL,
+    -- top_left, top_right, bottom_left or bottom_right
+    position text DEFAULT 'bottom_right' NOT NULL,
+    opacity double precision DEFAULT 0.8 NOT NULL,
+    updated_at timestamp DEFAULT now() NOT NULL,
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES configs (camera_id)
+            ON DELETE CASCADE
+);
+-- Whether the watermark was burned into the image when it was stored, in which case it isn't added again when it's sent
+ALTER TABLE image_metadata ADD COLUMN watermarked boolean DEFAULT false NOT NULL;
\ No newline at end of file
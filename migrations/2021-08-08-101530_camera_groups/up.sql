@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- A user's named list of cameras, for showing together in a mosaic
+CREATE TABLE camera_groups (
+    camera_group_id uuid PRIMARY KEY DEFAULT uuid_generate_v4() NOT NULL UNIQUE,
+    user_id uuid NOT NULL,
+    name text NOT NULL,
+    -- In the order they're shown. Cameras the user loses access to are skipped rather than removed
+    camera_ids uuid[] NOT NULL,
+    created_at timestamp DEFAULT now() NOT NULL,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+);
\ No newline at end of file
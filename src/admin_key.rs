@@ -0,0 +1,31 @@
+use crate::enums::token_error::TokenError;
+
+use rocket::{
+    http::Status,
+    request::{self, FromRequest},
+    Outcome, Request,
+};
+use std::env;
+
+/// Request guard for administrative routes. Succeeds when the admin_key header matches the ADMIN_KEY environment variable.
+/// If ADMIN_KEY isn't set, administrative routes are disabled entirely.
+pub struct AdminKey;
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminKey {
+    type Error = TokenError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let admin_key = match env::var("ADMIN_KEY") {
+            Ok(admin_key) if !admin_key.is_empty() => admin_key,
+            // Admin routes are disabled if no key is configured
+            _ => return Outcome::Failure((Status::Forbidden, TokenError::NotFound)),
+        };
+
+        match request.headers().get_one("admin_key") {
+            Some(provided_key) if provided_key == admin_key => Outcome::Success(AdminKey),
+            Some(_) => Outcome::Failure((Status::Unauthorized, TokenError::NotFound)),
+            // Key does not exist
+            None => Outcome::Failure((Status::Unauthorized, TokenError::NoTokenProvided)),
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use crate::logging::request_id;
+
 use rocket::request::Request;
 use rocket::response;
 use rocket::response::{Responder, Response};
@@ -10,9 +12,11 @@ pub struct ApiError {
     pub status: Status,
 }
 
+/// The body is the error message followed by the request ID, which matches the X-Request-Id header and the server's logs.
 impl<'r> Responder<'r> for ApiError {
     fn respond_to(self, req: &Request) -> response::Result<'r> {
-        Response::build_from(self.error.respond_to(&req).unwrap())
+        let body = format!("{} (request ID: {})", self.error, request_id(req));
+        Response::build_from(body.respond_to(&req).unwrap())
             .status(self.status)
             .header(ContentType::Plain)
             .ok()
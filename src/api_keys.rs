@@ -0,0 +1,357 @@
+use crate::{
+    api_error::ApiError,
+    audit_log::{self, AuditAction, ClientIp, InsertableAuditEvent},
+    auth_token::{fail, find_token},
+    enums::token_error::TokenError,
+    logging,
+    user_tokens::UserToken,
+    users_cameras::get_users_cameras,
+    CameraServerDbConn,
+};
+
+use super::schema::api_keys;
+use chrono::{NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::{
+    http::Status,
+    request::{self, FromRequest},
+    Outcome, Request,
+};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// What an API key is allowed to do. Stored as text in the database using the serialised names.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ApiKeyScope {
+    #[serde(rename = "cameras:read")]
+    CamerasRead,
+    #[serde(rename = "images:read")]
+    ImagesRead,
+    #[serde(rename = "images:delete")]
+    ImagesDelete,
+    #[serde(rename = "config:read")]
+    ConfigRead,
+    #[serde(rename = "config:write")]
+    ConfigWrite,
+}
+
+impl ApiKeyScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiKeyScope::CamerasRead => "cameras:read",
+            ApiKeyScope::ImagesRead => "images:read",
+            ApiKeyScope::ImagesDelete => "images:delete",
+            ApiKeyScope::ConfigRead => "config:read",
+            ApiKeyScope::ConfigWrite => "config:write",
+        }
+    }
+}
+
+#[derive(Queryable, AsChangeset, Deserialize, Serialize)]
+#[table_name = "api_keys"]
+pub struct ApiKey {
+    pub api_key_id: uuid::Uuid,
+    pub api_key: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub camera_ids: Option<Vec<uuid::Uuid>>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl ApiKey {
+    pub fn has_scope(&self, scope: ApiKeyScope) -> bool {
+        self.scopes.iter().any(|x| x == scope.as_str())
+    }
+
+    /// Returns true if the key isn't restricted to certain cameras, or if camera_id is one of them.
+    pub fn allows_camera(&self, camera_id: &uuid::Uuid) -> bool {
+        self.camera_ids
+            .as_ref()
+            .map_or(true, |camera_ids| camera_ids.contains(camera_id))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= Utc::now().naive_utc())
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ApiKey {
+    type Error = TokenError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let key = find_token(request, "api_key", false);
+        match key {
+            Some(key) => {
+                let parsed_key = match uuid::Uuid::parse_str(&key) {
+                    Ok(parsed_key_ok) => parsed_key_ok,
+                    // Key cannot be parsed into a UUID
+                    Err(_) => return fail(request, Status::BadRequest, TokenError::ParseError),
+                };
+                let connection = CameraServerDbConn::from_request(&request)
+                    .expect("Failed to get DB connection on ApiKey request guard");
+                match get_by_key(parsed_key, &connection) {
+                    Ok(api_key) if api_key.is_expired() => {
+                        fail(request, Status::Unauthorized, TokenError::Expired)
+                    }
+                    Ok(api_key) => {
+                        logging::set_user_id(api_key.user_id);
+                        logging::set_api_key_id(api_key.api_key_id);
+                        Outcome::Success(api_key)
+                    }
+                    Err(_) => fail(request, Status::Unauthorized, TokenError::NotFound),
+                }
+            }
+            // Key does not exist
+            None => fail(request, Status::Unauthorized, TokenError::NoTokenProvided),
+        }
+    }
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "api_keys"]
+pub struct InsertableApiKey {
+    pub user_id: uuid::Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub camera_ids: Option<Vec<uuid::Uuid>>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/// Sent by the user to create a new API key. If camera_ids is missing, the key works for all of the user's cameras.
+#[derive(Deserialize)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    pub camera_ids: Option<Vec<uuid::Uuid>>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/// An API key without the key itself, for listing a user's keys.
+#[derive(Serialize)]
+pub struct ApiKeyInfo {
+    pub api_key_id: uuid::Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub camera_ids: Option<Vec<uuid::Uuid>>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl ApiKeyInfo {
+    pub fn from_api_key(api_key: ApiKey) -> ApiKeyInfo {
+        ApiKeyInfo {
+            api_key_id: api_key.api_key_id,
+            name: api_key.name,
+            scopes: api_key.scopes,
+            camera_ids: api_key.camera_ids,
+            expires_at: api_key.expires_at,
+            created_at: api_key.created_at,
+        }
+    }
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<ApiKey>> {
+    api_keys::table.load::<ApiKey>(&*connection)
+}
+
+pub fn get(api_key_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<ApiKey> {
+    api_keys::table
+        .find(api_key_id)
+        .get_result::<ApiKey>(connection)
+}
+
+pub fn get_by_key(api_key: uuid::Uuid, connection: &PgConnection) -> QueryResult<ApiKey> {
+    api_keys::table
+        .filter(api_keys::api_key.eq(api_key))
+        .first::<ApiKey>(connection)
+}
+
+pub fn get_users_api_keys(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<ApiKey>> {
+    api_keys::table
+        .filter(api_keys::user_id.eq(user_id))
+        .order(api_keys::created_at)
+        .load::<ApiKey>(connection)
+}
+
+pub fn insert(api_key: InsertableApiKey, connection: &PgConnection) -> QueryResult<ApiKey> {
+    diesel::insert_into(api_keys::table)
+        .values(api_key)
+        .get_result(connection)
+}
+
+pub fn update(
+    api_key_id: uuid::Uuid,
+    api_key: ApiKey,
+    connection: &PgConnection,
+) -> QueryResult<ApiKey> {
+    diesel::update(api_keys::table.find(api_key_id))
+        .set(&api_key)
+        .get_result(connection)
+}
+
+pub fn delete(api_key_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(api_keys::table.find(api_key_id)).execute(connection)
+}
+
+/// Creates a new API key for the logged in user. The response is the only time the key itself is shown.
+/// API keys can't be used to create more API keys, a user token is needed.
+#[post("/ApiKeys", format = "json", data = "<new_api_key>")]
+pub fn create_api_key(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    new_api_key: Json<NewApiKey>,
+) -> Result<Json<ApiKey>, ApiError> {
+    let new_api_key = new_api_key.into_inner();
+
+    if new_api_key.scopes.is_empty() {
+        return Err(ApiError {
+            error: "API key must have at least one scope",
+            status: Status::UnprocessableEntity,
+        });
+    }
+
+    if let Some(camera_ids) = &new_api_key.camera_ids {
+        let users_cameras = get_users_cameras(user_token.user_id, &conn).map_err(|error| {
+            error!("Failed to get list of user's cameras: {}", error);
+            ApiError {
+                error: "Failed to get list of owned cameras",
+                status: Status::InternalServerError,
+            }
+        })?;
+
+        if !camera_ids.iter().all(|camera_id| {
+            users_cameras
+                .iter()
+                .any(|users_camera| &users_camera.camera_id == camera_id)
+        }) {
+            return Err(ApiError {
+                error: "User does not have access to camera",
+                status: Status::Unauthorized,
+            });
+        }
+    }
+
+    insert(
+        InsertableApiKey {
+            user_id: user_token.user_id,
+            name: new_api_key.name,
+            scopes: new_api_key
+                .scopes
+                .iter()
+                .map(|scope| scope.as_str().to_string())
+                .collect(),
+            camera_ids: new_api_key.camera_ids,
+            expires_at: new_api_key.expires_at,
+        },
+        &conn,
+    )
+    .map(|api_key| {
+        audit_log::record(
+            InsertableAuditEvent {
+                actor_user_id: Some(user_token.user_id),
+                target_user_id: Some(user_token.user_id),
+                details: Some(json!({
+                    "api_key_id": api_key.api_key_id,
+                    "name": api_key.name,
+                    "scopes": api_key.scopes,
+                    "camera_ids": api_key.camera_ids,
+                })),
+                ..InsertableAuditEvent::new(AuditAction::ApiKeyCreated, &client_ip)
+            },
+            &conn,
+        );
+        Json(api_key)
+    })
+    .map_err(|error| {
+        error!(
+            "Failed to create API key for user {}: {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to create API key",
+            status: Status::InternalServerError,
+        }
+    })
+}
+
+/// Lists the logged in user's API keys, without the keys themselves.
+#[get("/ApiKeys")]
+pub fn list_api_keys(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<Vec<ApiKeyInfo>>, ApiError> {
+    get_users_api_keys(user_token.user_id, &conn)
+        .map(|api_keys| Json(api_keys.into_iter().map(ApiKeyInfo::from_api_key).collect()))
+        .map_err(|error| {
+            error!(
+                "Failed to get API keys for user {}: {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to get API keys",
+                status: Status::InternalServerError,
+            }
+        })
+}
+
+/// Revokes one of the logged in user's API keys.
+#[post("/ApiKeys/<api_key_id_string>/Revoke")]
+pub fn revoke_api_key(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    api_key_id_string: String,
+) -> Result<(), ApiError> {
+    let api_key_id = uuid::Uuid::parse_str(&api_key_id_string).map_err(|error| {
+        warn!(
+            "Failed to parse API key ID {}: {}",
+            api_key_id_string, error
+        );
+        ApiError {
+            error: "Failed to parse API key ID string",
+            status: Status::UnprocessableEntity,
+        }
+    })?;
+
+    // Keys belonging to other users are treated as not existing
+    let api_key = get(api_key_id, &conn)
+        .ok()
+        .filter(|api_key| api_key.user_id == user_token.user_id)
+        .ok_or(ApiError {
+            error: "API key not found",
+            status: Status::NotFound,
+        })?;
+
+    delete(api_key.api_key_id, &conn).map_err(|error| {
+        error!("Failed to delete API key {}: {}", api_key.api_key_id, error);
+        ApiError {
+            error: "Failed to revoke API key",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    audit_log::record(
+        InsertableAuditEvent {
+            actor_user_id: Some(user_token.user_id),
+            target_user_id: Some(user_token.user_id),
+            details: Some(json!({
+                "api_key_id": api_key.api_key_id,
+                "name": api_key.name,
+            })),
+            ..InsertableAuditEvent::new(AuditAction::ApiKeyRevoked, &client_ip)
+        },
+        &conn,
+    );
+
+    Ok(())
+}
@@ -0,0 +1,591 @@
+//! Routes mounted at /api/v1. These are thin wrappers around the original handlers, which are still mounted at / as
+//! deprecated aliases so that cameras already deployed with the old paths keep working.
+//! Endpoints added since then live in their own modules with /api/v1 paths, and are only mounted under /api/v1.
+
+use crate::{
+    admin_key::AdminKey,
+    api_error::ApiError,
+    api_keys::{self, ApiKey, ApiKeyInfo, NewApiKey},
+    audit_log::{self, AuditEvent, ClientIp},
+    camera::{self, Camera, InsertableCamera},
+    camera_tokens::CameraToken,
+    config::{self, Config},
+    image_metadata::ImageMetadata,
+    image_store::{CaptureTimestamp, ImageResponse, UploadConfig},
+    metrics::Metrics,
+    password_reset_tokens::PasswordResetToken,
+    rate_limiter::{Login, RateLimit, Registration, Upload},
+    totp::{self, RecoveryCodes, TotpCode, TotpDisable, TotpEnrolment},
+    user::{
+        self, AccountDeletion, AuthentiationResult, InsertableUser, LoginResult, PasswordChange,
+        PasswordReset, TotpLogin,
+    },
+    user_auth::UserAuth,
+    user_tokens::UserToken,
+    users_cameras, CameraServerDbConn,
+};
+
+use rocket::{
+    delete,
+    fairing::{Fairing, Info, Kind},
+    get,
+    http::{Accept, ContentType, Cookies, Header, Method},
+    post, put, Data, Request, Response, State,
+};
+use rocket_contrib::json::Json;
+use std::collections::HashMap;
+
+pub const BASE: &str = "/api/v1";
+
+/// A route mounted at / and the v1 route that replaces it.
+pub struct LegacyRoute {
+    pub method: Method,
+    pub path: &'static str,
+    pub successor_method: Method,
+    /// Parameters are filled in from the parameters of the same name in the legacy path
+    pub successor_path: &'static str,
+}
+
+pub const LEGACY_ROUTES: &[LegacyRoute] = &[
+    LegacyRoute {
+        method: Method::Post,
+        path: "/AddUser",
+        successor_method: Method::Post,
+        successor_path: "/api/v1/users",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/Login",
+        successor_method: Method::Post,
+        successor_path: "/api/v1/sessions",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/Login/TOTP",
+        successor_method: Method::Post,
+        successor_path: "/api/v1/sessions/totp",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/Logout",
+        successor_method: Method::Delete,
+        successor_path: "/api/v1/sessions/current",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/ChangePassword",
+        successor_method: Method::Put,
+        successor_path: "/api/v1/users/me/password",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/Users/<username>/PasswordResetToken",
+        successor_method: Method::Post,
+        successor_path: "/api/v1/users/<username>/password-reset-tokens",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/ResetPassword",
+        successor_method: Method::Post,
+        successor_path: "/api/v1/password-resets",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/DeleteAccount",
+        successor_method: Method::Delete,
+        successor_path: "/api/v1/users/me",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/TOTP/Enroll",
+        successor_method: Method::Post,
+        successor_path: "/api/v1/users/me/totp",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/TOTP/Verify",
+        successor_method: Method::Post,
+        successor_path: "/api/v1/users/me/totp/verify",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/TOTP/RecoveryCodes",
+        successor_method: Method::Post,
+        successor_path: "/api/v1/users/me/totp/recovery-codes",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/TOTP/Disable",
+        successor_method: Method::Delete,
+        successor_path: "/api/v1/users/me/totp",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/ApiKeys",
+        successor_method: Method::Post,
+        successor_path: "/api/v1/api-keys",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/ApiKeys",
+        successor_method: Method::Get,
+        successor_path: "/api/v1/api-keys",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/ApiKeys/<api_key_id_string>/Revoke",
+        successor_method: Method::Delete,
+        successor_path: "/api/v1/api-keys/<api_key_id_string>",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/AuditLog",
+        successor_method: Method::Get,
+        successor_path: "/api/v1/users/me/audit-log",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/Cameras/<camera_id_string>/AuditLog",
+        successor_method: Method::Get,
+        successor_path: "/api/v1/cameras/<camera_id_string>/audit-log",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/AddCamera",
+        successor_method: Method::Post,
+        successor_path: "/api/v1/cameras",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/ListCameras",
+        successor_method: Method::Get,
+        successor_path: "/api/v1/cameras",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/UploadImage",
+        successor_method: Method::Post,
+        successor_path: "/api/v1/cameras/me/images",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/Cameras/<camera_id_string>/LatestImage",
+        successor_method: Method::Get,
+        successor_path: "/api/v1/cameras/<camera_id_string>/images/latest",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/Cameras/<camera_id_string>/ImageList",
+        successor_method: Method::Get,
+        successor_path: "/api/v1/cameras/<camera_id_string>/images",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/Cameras/<camera_id_string>/Image/<image_id_string>",
+        successor_method: Method::Get,
+        successor_path: "/api/v1/cameras/<camera_id_string>/images/<image_id_string>",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/Cameras/<camera_id_string>/Image/<image_id_string>/Metadata",
+        successor_method: Method::Get,
+        successor_path: "/api/v1/cameras/<camera_id_string>/images/<image_id_string>/metadata",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/Cameras/<camera_id_string>/GetConfigUser",
+        successor_method: Method::Get,
+        successor_path: "/api/v1/cameras/<camera_id_string>/config",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/Cameras/GetConfigCamera",
+        successor_method: Method::Get,
+        successor_path: "/api/v1/cameras/me/config",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/Cameras/<camera_id_string>/UpdateConfig",
+        successor_method: Method::Put,
+        successor_path: "/api/v1/cameras/<camera_id_string>/config",
+    },
+];
+
+/// Returns the legacy route entry for a mounted route, if it's one of the deprecated aliases.
+pub fn find_legacy_route(method: Method, path: &str) -> Option<&'static LegacyRoute> {
+    LEGACY_ROUTES
+        .iter()
+        .find(|legacy_route| legacy_route.method == method && legacy_route.path == path)
+}
+
+/// Fills in the successor path's parameters using the values from the legacy request path.
+fn successor_uri(legacy_route: &LegacyRoute, request_path: &str) -> String {
+    let parameters: HashMap<&str, &str> = legacy_route
+        .path
+        .split('/')
+        .zip(request_path.split('/'))
+        .filter(|(template, _)| template.starts_with('<'))
+        .collect();
+
+    legacy_route
+        .successor_path
+        .split('/')
+        .map(|segment| parameters.get(segment).cloned().unwrap_or(segment))
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+/// Adds Deprecation and Link headers to responses from the legacy routes, pointing clients at the v1 route to use instead.
+pub struct DeprecationFairing;
+
+impl Fairing for DeprecationFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Legacy Route Deprecation",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let legacy_route = match request
+            .route()
+            .and_then(|route| find_legacy_route(route.method, route.uri.path()))
+        {
+            Some(legacy_route) => legacy_route,
+            None => return,
+        };
+
+        response.set_header(Header::new("Deprecation", "true"));
+        response.set_header(Header::new(
+            "Link",
+            format!(
+                "<{}>; rel=\"successor-version\"",
+                successor_uri(legacy_route, request.uri().path())
+            ),
+        ));
+    }
+}
+
+#[post("/users", format = "json", data = "<new_user>")]
+pub fn create_user(
+    conn: CameraServerDbConn,
+    rate_limit: RateLimit<Registration>,
+    client_ip: ClientIp,
+    new_user: Json<InsertableUser>,
+) -> Result<Json<AuthentiationResult>, ApiError> {
+    user::add_user(conn, rate_limit, client_ip, new_user)
+}
+
+#[post("/sessions?<session_cookie>", format = "json", data = "<user_login>")]
+pub fn create_session(
+    conn: CameraServerDbConn,
+    rate_limit: RateLimit<Login>,
+    client_ip: ClientIp,
+    cookies: Cookies,
+    session_cookie: Option<bool>,
+    user_login: Json<InsertableUser>,
+) -> Result<Json<LoginResult>, ApiError> {
+    user::login(
+        conn,
+        rate_limit,
+        client_ip,
+        cookies,
+        session_cookie,
+        user_login,
+    )
+}
+
+#[post(
+    "/sessions/totp?<session_cookie>",
+    format = "json",
+    data = "<totp_login>"
+)]
+pub fn complete_totp_session(
+    conn: CameraServerDbConn,
+    rate_limit: RateLimit<Login>,
+    client_ip: ClientIp,
+    cookies: Cookies,
+    session_cookie: Option<bool>,
+    totp_login: Json<TotpLogin>,
+) -> Result<Json<AuthentiationResult>, ApiError> {
+    user::login_totp(
+        conn,
+        rate_limit,
+        client_ip,
+        cookies,
+        session_cookie,
+        totp_login,
+    )
+}
+
+#[delete("/sessions/current")]
+pub fn delete_current_session(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    cookies: Cookies,
+) -> Result<(), ApiError> {
+    user::logout(conn, user_token, client_ip, cookies)
+}
+
+#[put("/users/me/password", format = "json", data = "<password_change>")]
+pub fn change_password(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    password_change: Json<PasswordChange>,
+) -> Result<(), ApiError> {
+    user::change_password(conn, user_token, client_ip, password_change)
+}
+
+#[post("/users/<username>/password-reset-tokens")]
+pub fn create_password_reset_token(
+    conn: CameraServerDbConn,
+    admin_key: AdminKey,
+    client_ip: ClientIp,
+    username: String,
+) -> Result<Json<PasswordResetToken>, ApiError> {
+    user::create_password_reset_token(conn, admin_key, client_ip, username)
+}
+
+#[post("/password-resets", format = "json", data = "<password_reset>")]
+pub fn reset_password(
+    conn: CameraServerDbConn,
+    client_ip: ClientIp,
+    password_reset: Json<PasswordReset>,
+) -> Result<(), ApiError> {
+    user::reset_password(conn, client_ip, password_reset)
+}
+
+#[delete("/users/me", format = "json", data = "<account_deletion>")]
+pub fn delete_account(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    account_deletion: Json<AccountDeletion>,
+) -> Result<(), ApiError> {
+    user::delete_account(conn, user_token, client_ip, account_deletion)
+}
+
+#[post("/users/me/totp")]
+pub fn enroll_totp(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<TotpEnrolment>, ApiError> {
+    totp::enroll(conn, user_token)
+}
+
+#[post("/users/me/totp/verify", format = "json", data = "<totp_code>")]
+pub fn verify_totp(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    totp_code: Json<TotpCode>,
+) -> Result<Json<RecoveryCodes>, ApiError> {
+    totp::verify(conn, user_token, client_ip, totp_code)
+}
+
+#[post("/users/me/totp/recovery-codes", format = "json", data = "<totp_code>")]
+pub fn regenerate_recovery_codes(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    totp_code: Json<TotpCode>,
+) -> Result<Json<RecoveryCodes>, ApiError> {
+    totp::regenerate_recovery_codes(conn, user_token, client_ip, totp_code)
+}
+
+#[delete("/users/me/totp", format = "json", data = "<totp_disable>")]
+pub fn disable_totp(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    totp_disable: Json<TotpDisable>,
+) -> Result<(), ApiError> {
+    totp::disable(conn, user_token, client_ip, totp_disable)
+}
+
+#[get("/users/me/audit-log?<limit>&<offset>")]
+pub fn get_account_audit_log(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    limit: Option<i64>,
+    offset: Option<i64>,
+) -> Result<Json<Vec<AuditEvent>>, ApiError> {
+    audit_log::get_account_audit_log(conn, user_token, limit, offset)
+}
+
+#[post("/api-keys", format = "json", data = "<new_api_key>")]
+pub fn create_api_key(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    new_api_key: Json<NewApiKey>,
+) -> Result<Json<ApiKey>, ApiError> {
+    api_keys::create_api_key(conn, user_token, client_ip, new_api_key)
+}
+
+#[get("/api-keys")]
+pub fn list_api_keys(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<Vec<ApiKeyInfo>>, ApiError> {
+    api_keys::list_api_keys(conn, user_token)
+}
+
+#[delete("/api-keys/<api_key_id_string>")]
+pub fn revoke_api_key(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    api_key_id_string: String,
+) -> Result<(), ApiError> {
+    api_keys::revoke_api_key(conn, user_token, client_ip, api_key_id_string)
+}
+
+#[post("/cameras", format = "json", data = "<camera_name>")]
+pub fn create_camera(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    camera_name: Json<InsertableCamera>,
+) -> Result<Json<CameraToken>, ApiError> {
+    camera::add_new_camera(conn, user_token, client_ip, camera_name)
+}
+
+#[get("/cameras")]
+pub fn list_cameras(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+) -> Result<Json<Vec<Camera>>, ApiError> {
+    users_cameras::list_cameras(conn, user_auth)
+}
+
+#[post("/cameras/me/images", data = "<image>")]
+pub fn upload_image(
+    conn: CameraServerDbConn,
+    content_type: Option<&ContentType>,
+    image: Data,
+    camera_token: CameraToken,
+    capture_timestamp: CaptureTimestamp,
+    rate_limit: RateLimit<Upload>,
+    metrics: State<Metrics>,
+    upload_config: State<UploadConfig>,
+) -> Result<String, ApiError> {
+    camera::upload_image(
+        conn,
+        content_type,
+        image,
+        camera_token,
+        capture_timestamp,
+        rate_limit,
+        metrics,
+        upload_config,
+    )
+}
+
+#[get("/cameras/me/config")]
+pub fn get_own_config(
+    conn: CameraServerDbConn,
+    camera_token: CameraToken,
+) -> Result<Json<Config>, ApiError> {
+    config::get_config_camera(conn, camera_token)
+}
+
+#[get("/cameras/<camera_id_string>/images?<lighting>&<exclude_dark>&<exclude_overexposed>")]
+pub fn list_images(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    camera_id_string: String,
+    lighting: Option<String>,
+    exclude_dark: Option<bool>,
+    exclude_overexposed: Option<bool>,
+) -> Result<Json<Vec<String>>, ApiError> {
+    camera::get_image_list(
+        conn,
+        user_auth,
+        camera_id_string,
+        lighting,
+        exclude_dark,
+        exclude_overexposed,
+    )
+}
+
+#[get("/cameras/<camera_id_string>/images/latest?<original>")]
+pub fn get_latest_image(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    accept: Option<&Accept>,
+    camera_id_string: String,
+    original: Option<bool>,
+) -> Result<ImageResponse, ApiError> {
+    camera::get_latest(conn, user_auth, accept, camera_id_string, original)
+}
+
+// Ranked below images/latest, which would otherwise collide with it
+#[get(
+    "/cameras/<camera_id_string>/images/<image_id_string>?<original>",
+    rank = 2
+)]
+pub fn get_image(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    accept: Option<&Accept>,
+    camera_id_string: String,
+    image_id_string: String,
+    original: Option<bool>,
+) -> Result<ImageResponse, ApiError> {
+    camera::get_image(
+        conn,
+        user_auth,
+        accept,
+        camera_id_string,
+        image_id_string,
+        original,
+    )
+}
+
+#[get("/cameras/<camera_id_string>/images/<image_id_string>/metadata")]
+pub fn get_image_metadata(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    camera_id_string: String,
+    image_id_string: String,
+) -> Result<Json<ImageMetadata>, ApiError> {
+    camera::get_image_metadata(conn, user_auth, camera_id_string, image_id_string)
+}
+
+#[get("/cameras/<camera_id_string>/config")]
+pub fn get_camera_config(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    camera_id_string: String,
+) -> Result<Json<Config>, ApiError> {
+    config::get_config_user(conn, camera_id_string, user_auth)
+}
+
+#[put(
+    "/cameras/<camera_id_string>/config",
+    format = "json",
+    data = "<new_config>"
+)]
+pub fn update_camera_config(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    client_ip: ClientIp,
+    camera_id_string: String,
+    new_config: Json<Config>,
+) -> Result<Json<Config>, ApiError> {
+    config::update_config(conn, user_auth, client_ip, camera_id_string, new_config)
+}
+
+#[get("/cameras/<camera_id_string>/audit-log?<limit>&<offset>")]
+pub fn get_camera_audit_log(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id_string: String,
+    limit: Option<i64>,
+    offset: Option<i64>,
+) -> Result<Json<Vec<AuditEvent>>, ApiError> {
+    audit_log::get_camera_audit_log(conn, user_token, camera_id_string, limit, offset)
+}
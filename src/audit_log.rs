@@ -0,0 +1,275 @@
+use crate::{
+    api_error::ApiError, camera::parse_camera_id, rate_limiter, user_auth::UserAuth,
+    user_tokens::UserToken, users_cameras::check_if_user_has_access_to_camera, CameraServerDbConn,
+};
+
+use super::schema::audit_events;
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::Status;
+use rocket::{
+    get,
+    request::{self, FromRequest},
+    Outcome, Request,
+};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_AUDIT_LOG_LIMIT: i64 = 100;
+const MAX_AUDIT_LOG_LIMIT: i64 = 1000;
+
+/// The kinds of event that are recorded. Stored in the action column as the string from as_str().
+#[derive(Debug, Clone, Copy)]
+pub enum AuditAction {
+    LoginSucceeded,
+    LoginFailed,
+    UserTokenCreated,
+    UserTokenRevoked,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    PasswordChanged,
+    PasswordResetTokenCreated,
+    PasswordReset,
+    TotpEnabled,
+    TotpDisabled,
+    TotpRecoveryCodesRegenerated,
+    CameraCreated,
+    CameraAccessGranted,
+    CameraConfigUpdated,
+    CameraDeleted,
+    ImagesExported,
+    ImagesDeleted,
+    ImagesRestored,
+    PrivacyMasksUpdated,
+    WatermarkUpdated,
+    WatermarkDeleted,
+    UserDeleted,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::LoginSucceeded => "login.succeeded",
+            AuditAction::LoginFailed => "login.failed",
+            AuditAction::UserTokenCreated => "user_token.created",
+            AuditAction::UserTokenRevoked => "user_token.revoked",
+            AuditAction::ApiKeyCreated => "api_key.created",
+            AuditAction::ApiKeyRevoked => "api_key.revoked",
+            AuditAction::PasswordChanged => "user.password_changed",
+            AuditAction::PasswordResetTokenCreated => "user.password_reset_token_created",
+            AuditAction::PasswordReset => "user.password_reset",
+            AuditAction::TotpEnabled => "user.totp_enabled",
+            AuditAction::TotpDisabled => "user.totp_disabled",
+            AuditAction::TotpRecoveryCodesRegenerated => "user.totp_recovery_codes_regenerated",
+            AuditAction::CameraCreated => "camera.created",
+            AuditAction::CameraAccessGranted => "camera.access_granted",
+            AuditAction::CameraConfigUpdated => "camera.config_updated",
+            AuditAction::CameraDeleted => "camera.deleted",
+            AuditAction::ImagesExported => "camera.images_exported",
+            AuditAction::ImagesDeleted => "camera.images_deleted",
+            AuditAction::ImagesRestored => "camera.images_restored",
+            AuditAction::PrivacyMasksUpdated => "camera.privacy_masks_updated",
+            AuditAction::WatermarkUpdated => "camera.watermark_updated",
+            AuditAction::WatermarkDeleted => "camera.watermark_deleted",
+            AuditAction::UserDeleted => "user.deleted",
+        }
+    }
+}
+
+/// Request guard for the client's IP address, which is recorded with audit events. Only uses X-Real-IP if the proxy
+/// config trusts it, the same as the rate limiter. Never fails.
+pub struct ClientIp(pub Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(ClientIp(
+            rate_limiter::client_ip(request).map(|ip| ip.to_string()),
+        ))
+    }
+}
+
+#[derive(Queryable, Deserialize, Serialize)]
+pub struct AuditEvent {
+    pub audit_event_id: uuid::Uuid,
+    pub action: String,
+    /// The user who did the action. None for actions without a logged in user, like failed logins.
+    pub actor_user_id: Option<uuid::Uuid>,
+    /// Set if the actor used an API key instead of a user token
+    pub actor_api_key_id: Option<uuid::Uuid>,
+    pub target_user_id: Option<uuid::Uuid>,
+    pub target_camera_id: Option<uuid::Uuid>,
+    pub ip_address: Option<String>,
+    pub details: Option<serde_json::Value>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Default)]
+#[table_name = "audit_events"]
+pub struct InsertableAuditEvent {
+    pub action: String,
+    pub actor_user_id: Option<uuid::Uuid>,
+    pub actor_api_key_id: Option<uuid::Uuid>,
+    pub target_user_id: Option<uuid::Uuid>,
+    pub target_camera_id: Option<uuid::Uuid>,
+    pub ip_address: Option<String>,
+    pub details: Option<serde_json::Value>,
+}
+
+impl InsertableAuditEvent {
+    /// Creates an event with no actor or target. Fill in the rest with struct update syntax.
+    pub fn new(action: AuditAction, client_ip: &ClientIp) -> InsertableAuditEvent {
+        InsertableAuditEvent {
+            action: action.as_str().to_string(),
+            ip_address: client_ip.0.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Creates an event done by whoever is behind user_auth, including the API key used if there was one.
+    pub fn by_user_auth(
+        action: AuditAction,
+        user_auth: &UserAuth,
+        client_ip: &ClientIp,
+    ) -> InsertableAuditEvent {
+        InsertableAuditEvent {
+            actor_user_id: Some(user_auth.user_id()),
+            actor_api_key_id: user_auth.api_key_id(),
+            ..InsertableAuditEvent::new(action, client_ip)
+        }
+    }
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<AuditEvent>> {
+    audit_events::table.load::<AuditEvent>(&*connection)
+}
+
+pub fn get(audit_event_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<AuditEvent> {
+    audit_events::table
+        .find(audit_event_id)
+        .get_result::<AuditEvent>(connection)
+}
+
+pub fn insert(
+    audit_event: InsertableAuditEvent,
+    connection: &PgConnection,
+) -> QueryResult<AuditEvent> {
+    diesel::insert_into(audit_events::table)
+        .values(audit_event)
+        .get_result(connection)
+}
+
+pub fn delete(audit_event_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(audit_events::table.find(audit_event_id)).execute(connection)
+}
+
+/// Stores an audit event. Failing to store one is logged but doesn't fail the request, since the action has already happened.
+pub fn record(audit_event: InsertableAuditEvent, connection: &PgConnection) {
+    let action = audit_event.action.clone();
+    if let Err(error) = insert(audit_event, connection) {
+        warn!("Failed to record audit event {}: {}", action, error);
+    }
+}
+
+/// Returns events done by or to the given user, newest first.
+pub fn get_users_audit_events(
+    user_id: uuid::Uuid,
+    limit: i64,
+    offset: i64,
+    connection: &PgConnection,
+) -> QueryResult<Vec<AuditEvent>> {
+    audit_events::table
+        .filter(
+            audit_events::actor_user_id
+                .eq(user_id)
+                .or(audit_events::target_user_id.eq(user_id)),
+        )
+        .order(audit_events::created_at.desc())
+        .limit(limit)
+        .offset(offset)
+        .load(connection)
+}
+
+/// Returns events about the given camera, newest first.
+pub fn get_cameras_audit_events(
+    camera_id: uuid::Uuid,
+    limit: i64,
+    offset: i64,
+    connection: &PgConnection,
+) -> QueryResult<Vec<AuditEvent>> {
+    audit_events::table
+        .filter(audit_events::target_camera_id.eq(camera_id))
+        .order(audit_events::created_at.desc())
+        .limit(limit)
+        .offset(offset)
+        .load(connection)
+}
+
+fn clamp_limit(limit: Option<i64>) -> i64 {
+    limit
+        .unwrap_or(DEFAULT_AUDIT_LOG_LIMIT)
+        .max(1)
+        .min(MAX_AUDIT_LOG_LIMIT)
+}
+
+/// Lists audit events for the logged in user's account, newest first. Use limit and offset to page through them.
+#[get("/AuditLog?<limit>&<offset>")]
+pub fn get_account_audit_log(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    limit: Option<i64>,
+    offset: Option<i64>,
+) -> Result<Json<Vec<AuditEvent>>, ApiError> {
+    get_users_audit_events(
+        user_token.user_id,
+        clamp_limit(limit),
+        offset.unwrap_or(0).max(0),
+        &conn,
+    )
+    .map(Json)
+    .map_err(|error| {
+        error!(
+            "Failed to get audit log for user {}: {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to get audit log",
+            status: Status::InternalServerError,
+        }
+    })
+}
+
+/// Lists audit events for a camera the logged in user has access to, newest first.
+#[get("/Cameras/<camera_id_string>/AuditLog?<limit>&<offset>")]
+pub fn get_camera_audit_log(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id_string: String,
+    limit: Option<i64>,
+    offset: Option<i64>,
+) -> Result<Json<Vec<AuditEvent>>, ApiError> {
+    check_if_user_has_access_to_camera(&conn, &UserAuth::UserToken(user_token), &camera_id_string)?;
+
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    get_cameras_audit_events(
+        camera_id,
+        clamp_limit(limit),
+        offset.unwrap_or(0).max(0),
+        &conn,
+    )
+    .map(Json)
+    .map_err(|error| {
+        error!(
+            "Failed to get audit log for camera {}: {}",
+            camera_id, error
+        );
+        ApiError {
+            error: "Failed to get audit log",
+            status: Status::InternalServerError,
+        }
+    })
+}
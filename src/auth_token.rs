@@ -0,0 +1,90 @@
+use crate::enums::token_error::TokenError;
+
+use rocket::{
+    http::{Cookie, Cookies, Status},
+    request, Outcome, Request,
+};
+use std::sync::Mutex;
+
+/// Name of the private cookie used for browser sessions
+pub const SESSION_COOKIE: &str = "user_token";
+
+/// The error a token guard failed with, so that the catchers can explain what went wrong
+/// Holds the last failure, as guards like UserAuth can try more than one kind of token.
+#[derive(Default)]
+struct TokenFailure(Mutex<Option<TokenError>>);
+
+/// Returns the token from an "Authorization: Bearer <token>" header, if there is one.
+/// Other authorization schemes are ignored so that they can still be used by proxies in front of the server.
+pub fn bearer_token(request: &Request) -> Option<String> {
+    let authorization = request.headers().get_one("Authorization")?;
+    let mut parts = authorization.trim().splitn(2, ' ');
+    let scheme = parts.next()?;
+    if !scheme.eq_ignore_ascii_case("Bearer") {
+        return None;
+    }
+    Some(parts.next().unwrap_or("").trim().to_string())
+}
+
+/// Finds the token for a request. In order of precedence, the token is taken from:
+/// 1. An "Authorization: Bearer <token>" header
+/// 2. The legacy header (user_token, camera_token or api_key)
+/// 3. The private session cookie, if use_session_cookie is true
+///
+/// Only the first token found is used, so a bad bearer token isn't ignored in favour of a legacy header.
+pub fn find_token(
+    request: &Request,
+    legacy_header: &str,
+    use_session_cookie: bool,
+) -> Option<String> {
+    if let Some(token) = bearer_token(request) {
+        return Some(token);
+    }
+
+    if let Some(token) = request.headers().get_one(legacy_header) {
+        return Some(token.to_string());
+    }
+
+    if use_session_cookie {
+        return request
+            .cookies()
+            .get_private(SESSION_COOKIE)
+            .map(|cookie| cookie.value().to_string());
+    }
+
+    None
+}
+
+/// Fails a token guard, remembering the error so the catchers can report it.
+pub fn fail<S>(
+    request: &Request,
+    status: Status,
+    error: TokenError,
+) -> request::Outcome<S, TokenError> {
+    *request
+        .local_cache(TokenFailure::default)
+        .0
+        .lock()
+        .expect("Token failure mutex was poisoned") = Some(error);
+    Outcome::Failure((status, error))
+}
+
+/// Returns the error message of the token guard that failed for this request, if any.
+pub fn failure_message(request: &Request) -> Option<&'static str> {
+    request
+        .local_cache(TokenFailure::default)
+        .0
+        .lock()
+        .expect("Token failure mutex was poisoned")
+        .map(|error| error.message())
+}
+
+/// Stores a user token in the private session cookie, for browsers that can't send headers with every request.
+/// Rocket marks private cookies as HttpOnly and SameSite=Strict.
+pub fn set_session_cookie(cookies: &mut Cookies, user_token: uuid::Uuid) {
+    cookies.add_private(Cookie::new(SESSION_COOKIE, user_token.to_string()));
+}
+
+pub fn remove_session_cookie(cookies: &mut Cookies) {
+    cookies.remove_private(Cookie::named(SESSION_COOKIE));
+}
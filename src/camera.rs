@@ -1,9 +1,23 @@
 use crate::{
     api_error::ApiError,
+    api_keys::ApiKeyScope,
+    audit_log::{self, AuditAction, ClientIp, InsertableAuditEvent},
     camera_tokens,
     config::{self, Config},
+    http_cache::CachePolicy,
+    image_exif::{apply_orientation, read_exif},
+    image_luminance::LuminanceFilter,
+    image_metadata::{self, ImageMetadata},
+    image_store::{
+        self, image_id, now_ms, parse_capture_timestamp, CaptureTimestamp, ImageFormat,
+        ImageResponse, StoredImageDetails, UploadConfig,
+    },
+    metrics::Metrics,
+    rate_limiter::{RateLimit, Upload},
+    user_auth::UserAuth,
     user_tokens,
-    users_cameras::{self, check_if_user_has_access_to_camera, InsertableUsersCamera},
+    users_cameras::{self, check_if_user_has_access_to_camera, CameraRole, InsertableUsersCamera},
+    watermarks::{self, WatermarkText},
     CameraServerDbConn,
 };
 
@@ -11,15 +25,26 @@ use super::schema::cameras;
 use camera_tokens::{CameraToken, InsertableCameraToken};
 use diesel::prelude::*;
 use diesel::{self};
+use multipart::server::Multipart;
 use rocket::post;
-use rocket::response::Stream;
-use rocket::{http::Status, Data};
+use rocket::{
+    http::{Accept, ContentType, Status},
+    Data, State,
+};
 use rocket_contrib::json::Json;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::path::Path;
-use std::time::SystemTime;
-use std::{env, fs::create_dir_all, fs::read_dir, fs::DirEntry};
+use serde_json::json;
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::PathBuf;
+use std::{env, fs::read_dir, fs::DirEntry};
+
+/// Batch uploads with more images than this have the extra ones rejected.
+const MAX_BATCH_IMAGES: usize = 1000;
+
+const DEFAULT_THUMBNAIL_SIZE: u32 = 320;
+const MIN_THUMBNAIL_SIZE: u32 = 16;
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
 
 #[derive(Queryable, AsChangeset, Deserialize, Serialize)]
 #[table_name = "cameras"]
@@ -78,18 +103,18 @@ pub fn list_camera_directory(
     sort: bool,
 ) -> Result<Vec<DirEntry>, ApiError> {
     let image_list = read_dir(camera_directory).map_err(|error| {
-        println!(
-            "Failed to directory {}! The error was {}",
-            camera_directory, error
-        );
+        error!("Failed to read directory {}: {}", camera_directory, error);
         ApiError {
             error: "Failed to get list of images",
             status: Status::InternalServerError,
         }
     })?;
 
+    // Only plain files count as images, anything else that's ended up in the directory is ignored
     let mut sorted_image_list: Vec<DirEntry> = image_list
         .map(|x| x.expect("Failed to map to Vec<DirEntry>"))
+        .filter(|x| x.file_type().map(|x| x.is_file()).unwrap_or(false))
+        .filter(|x| !x.file_name().to_string_lossy().starts_with('.'))
         .collect::<Vec<DirEntry>>();
 
     if sorted_image_list.len() == 0 {
@@ -100,12 +125,33 @@ pub fn list_camera_directory(
     }
 
     if sort {
-        sorted_image_list.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+        image_store::sort_images(&mut sorted_image_list);
     }
 
     Ok(sorted_image_list)
 }
 
+/// Returns the path of an image in a camera's directory, whatever its extension is.
+pub fn find_image(
+    camera_id_string: &String,
+    image_id_string: &String,
+) -> Result<PathBuf, ApiError> {
+    let images_directory_path = images_directory();
+
+    let camera_directory = camera_directory(&images_directory_path, camera_id_string);
+
+    let image_list = list_camera_directory(&camera_directory, false)?;
+
+    image_list
+        .iter()
+        .find(|x| &image_id(x) == image_id_string)
+        .map(|x| x.path())
+        .ok_or(ApiError {
+            error: "Image not found",
+            status: Status::NotFound,
+        })
+}
+
 pub fn images_directory() -> String {
     env::var("IMAGES_DIRECTORY").expect("IMAGES_DIRECTORY environment variable is not set!")
 }
@@ -114,15 +160,23 @@ pub fn camera_directory(images_directory_path: &String, camera_id_string: &Strin
     format!("{}/{}", images_directory_path, camera_id_string)
 }
 
+pub fn parse_camera_id(camera_id_string: &str) -> Result<uuid::Uuid, ApiError> {
+    uuid::Uuid::parse_str(camera_id_string).map_err(|_| ApiError {
+        error: "Invalid camera ID",
+        status: Status::BadRequest,
+    })
+}
+
 #[post("/AddCamera", format = "json", data = "<camera_name>")]
 pub fn add_new_camera(
     conn: CameraServerDbConn,
     user_token: user_tokens::UserToken,
+    client_ip: ClientIp,
     camera_name: Json<InsertableCamera>,
 ) -> Result<Json<CameraToken>, ApiError> {
     // Insert a new camera into the DB. Returns the ID for the new camera.
     let new_camera = insert(camera_name.into_inner(), &conn).map_err(|error| {
-        println!("Failed to create new camera! The error was {}", error);
+        error!("Failed to create new camera: {}", error);
         ApiError {
             error: "Failed to create new camera",
             status: Status::InternalServerError,
@@ -137,8 +191,8 @@ pub fn add_new_camera(
         &conn,
     )
     .map_err(|error| {
-        println!(
-            "Failed to add camera token for camera {}! The error was {}",
+        error!(
+            "Failed to add camera token for camera {}: {}",
             new_camera.camera_id, error
         );
         delete(new_camera.camera_id, &conn)
@@ -155,12 +209,13 @@ pub fn add_new_camera(
         InsertableUsersCamera {
             camera_id: new_camera.camera_id,
             user_id: user_token.user_id,
+            role: CameraRole::Owner.as_str().to_string(),
         },
         &conn,
     )
     .map_err(|error| {
-        println!(
-            "Failed to pair user {} to camera {}! The error was {}",
+        error!(
+            "Failed to pair user {} to camera {}: {}",
             user_token.user_id, new_camera.camera_id, error
         );
         camera_tokens::delete(new_camera.camera_id, &conn)
@@ -181,8 +236,8 @@ pub fn add_new_camera(
         &conn,
     )
     .map_err(|error| {
-        println!(
-            "Failed to add config for {}! The error was {}",
+        error!(
+            "Failed to add config for camera {}: {}",
             new_camera.camera_id, error
         );
         users_cameras::delete(users_camera.users_cameras_id, &conn)
@@ -197,45 +252,187 @@ pub fn add_new_camera(
         };
     })?;
 
+    audit_log::record(
+        InsertableAuditEvent {
+            actor_user_id: Some(user_token.user_id),
+            target_camera_id: Some(new_camera.camera_id),
+            details: Some(json!({ "name": new_camera.name })),
+            ..InsertableAuditEvent::new(AuditAction::CameraCreated, &client_ip)
+        },
+        &conn,
+    );
+    audit_log::record(
+        InsertableAuditEvent {
+            actor_user_id: Some(user_token.user_id),
+            target_user_id: Some(user_token.user_id),
+            target_camera_id: Some(new_camera.camera_id),
+            ..InsertableAuditEvent::new(AuditAction::CameraAccessGranted, &client_ip)
+        },
+        &conn,
+    );
+
     Ok(Json(new_camera_token))
 }
 
-/// Stores a new image. Returns the seconds since epoch used as the image name
-#[post("/UploadImage", format = "image/jpeg", data = "<image>")]
-pub fn upload_image(image: Data, camera_token: CameraToken) -> Result<String, ApiError> {
-    let images_directory = images_directory();
-
-    create_dir_all(format!("{}/{}", &images_directory, camera_token.camera_id))
-        .expect("Failed to create images directory");
-
-    let current_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .expect("Failed to get current time somehow?")
-        .as_secs();
-
-    image
-        .stream_to_file(format!(
-            "{}/{}/{}.jpg",
-            images_directory, camera_token.camera_id, current_time
-        ))
-        .map_err(|error| {
-            println!("Failed to stream image to file! The error was {}", error);
-            ApiError {
-                error: "Failed to save image to server",
-                status: Status::InternalServerError,
-            }
+/// Stores a new JPEG, PNG, WebP or AVIF image after checking that it's complete. Returns the image ID, which is the
+/// capture time in milliseconds since the epoch.
+/// The capture time comes from the X-Capture-Timestamp header, or the current time if it isn't sent.
+#[post("/UploadImage", data = "<image>")]
+pub fn upload_image(
+    conn: CameraServerDbConn,
+    content_type: Option<&ContentType>,
+    image: Data,
+    camera_token: CameraToken,
+    capture_timestamp: CaptureTimestamp,
+    _rate_limit: RateLimit<Upload>,
+    metrics: State<Metrics>,
+    upload_config: State<UploadConfig>,
+) -> Result<String, ApiError> {
+    image_store::check_upload_content_type(content_type)?;
+    let capture_timestamp = capture_timestamp.resolve()?;
+
+    let stored_image = image_store::store_image(
+        &camera_token.camera_id,
+        capture_timestamp,
+        &mut image.open(),
+        &upload_config,
+        &conn,
+    )?;
+
+    metrics.record_upload(&camera_token.camera_id, stored_image.bytes);
+
+    Ok(stored_image.image_id)
+}
+
+/// The outcome of storing one image from a batch upload.
+#[derive(Serialize)]
+pub struct BatchUploadResult {
+    /// Position of the image in the batch, starting from 0
+    pub index: usize,
+    pub image_id: Option<String>,
+    /// Set if the image was a copy of one the camera had already stored
+    pub duplicate_of: Option<String>,
+    pub error: Option<&'static str>,
+}
+
+/// Stores many images in one request, for cameras catching up after being offline.
+/// The body is multipart/form-data with an image part for each frame. An image can be preceded by a
+/// capture_timestamp field, which only applies to the image straight after it; images without one get the current time.
+/// Each image is stored (or not) independently, so the response has a result for every image.
+#[post(
+    "/cameras/me/images/batch",
+    format = "multipart/form-data",
+    data = "<data>"
+)]
+pub fn upload_image_batch(
+    conn: CameraServerDbConn,
+    content_type: &ContentType,
+    data: Data,
+    camera_token: CameraToken,
+    _rate_limit: RateLimit<Upload>,
+    metrics: State<Metrics>,
+    upload_config: State<UploadConfig>,
+) -> Result<Json<Vec<BatchUploadResult>>, ApiError> {
+    let boundary = content_type
+        .params()
+        .find(|(name, _)| name == &"boundary")
+        .map(|(_, value)| value.to_string())
+        .ok_or(ApiError {
+            error: "Multipart body is missing its boundary",
+            status: Status::BadRequest,
         })?;
 
-    Ok(current_time.to_string())
+    let mut multipart = Multipart::with_body(data.open(), boundary);
+    let mut results: Vec<BatchUploadResult> = Vec::new();
+    let mut next_capture_timestamp: Option<Result<u64, ApiError>> = None;
+
+    loop {
+        let mut field = match multipart.read_entry() {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(error) => {
+                warn!("Failed to read multipart body: {}", error);
+                return Err(ApiError {
+                    error: "Failed to read multipart body",
+                    status: Status::BadRequest,
+                });
+            }
+        };
+
+        match &*field.headers.name {
+            "capture_timestamp" => {
+                let mut capture_timestamp = String::new();
+                next_capture_timestamp = Some(
+                    field
+                        .data
+                        .by_ref()
+                        .take(64)
+                        .read_to_string(&mut capture_timestamp)
+                        .map_err(|_| ApiError {
+                            error: "Failed to read capture timestamp",
+                            status: Status::BadRequest,
+                        })
+                        .and_then(|_| parse_capture_timestamp(&capture_timestamp)),
+                );
+            }
+            "image" => {
+                let capture_timestamp = next_capture_timestamp.take().unwrap_or(Ok(now_ms()));
+                let result = if results.len() >= MAX_BATCH_IMAGES {
+                    Err(ApiError {
+                        error: "Too many images in one batch",
+                        status: Status::PayloadTooLarge,
+                    })
+                } else {
+                    capture_timestamp.and_then(|capture_timestamp| {
+                        image_store::store_image(
+                            &camera_token.camera_id,
+                            capture_timestamp,
+                            &mut field.data,
+                            &upload_config,
+                            &conn,
+                        )
+                    })
+                };
+
+                if let Ok(stored_image) = &result {
+                    metrics.record_upload(&camera_token.camera_id, stored_image.bytes);
+                }
+                results.push(BatchUploadResult {
+                    index: results.len(),
+                    image_id: result.as_ref().ok().map(|x| x.image_id.clone()),
+                    duplicate_of: result.as_ref().ok().and_then(|x| x.duplicate_of.clone()),
+                    error: result.err().map(|error| error.error),
+                });
+            }
+            // Anything else is skipped over, so that new fields can be added without breaking older servers
+            _ => {}
+        }
+    }
+
+    let stored_count = results.iter().filter(|x| x.image_id.is_some()).count();
+    if stored_count < results.len() {
+        warn!(
+            "Stored {} of {} images from batch upload",
+            stored_count,
+            results.len()
+        );
+    }
+
+    Ok(Json(results))
 }
 
-#[get("/Cameras/<camera_id_string>/LatestImage", format = "image/jpeg")]
+/// Returns the newest image, in the format it was uploaded in unless the Accept header asks for another one.
+/// It has the camera's watermark unless an owner asks for the original.
+#[get("/Cameras/<camera_id_string>/LatestImage?<original>")]
 pub fn get_latest(
     conn: CameraServerDbConn,
-    user_token: user_tokens::UserToken,
+    user_auth: UserAuth,
+    accept: Option<&Accept>,
     camera_id_string: String,
-) -> Result<Stream<File>, ApiError> {
-    check_if_user_has_access_to_camera(&conn, &user_token, &camera_id_string)?;
+    original: Option<bool>,
+) -> Result<ImageResponse, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ImagesRead)?;
+    check_if_user_has_access_to_camera(&conn, &user_auth, &camera_id_string)?;
 
     let images_directory_path = images_directory();
 
@@ -244,83 +441,232 @@ pub fn get_latest(
     let sorted_image_list = list_camera_directory(&camera_directory, true)?;
 
     // It should be OK to do an expect() here since sorted_directory_list() already returns an error if the dir list is empty
-    File::open(
-        sorted_image_list
-            .last()
-            .expect("Failed to get the last element of the sorted image list somehow?")
-            .path(),
-    )
-    .map(Stream::from)
-    .map_err(|error| {
-        println!("Failed to read file! The error was {}", error);
-        ApiError {
-            error: "Failed to load image",
-            status: Status::InternalServerError,
-        }
-    })
+    let latest_image = sorted_image_list
+        .last()
+        .expect("Failed to get the last element of the sorted image list somehow?");
+
+    let latest_image_id = image_id(latest_image);
+    let details = image_store::stored_image_details(
+        &camera_id_string,
+        &latest_image_id,
+        latest_image.path(),
+        &conn,
+    )?;
+    let watermark = watermarks::get_on_the_fly_text(
+        &conn,
+        &user_auth,
+        &camera_id_string,
+        &latest_image_id,
+        original.unwrap_or(false),
+    )?;
+    let details = if original.unwrap_or(false) {
+        image_store::original_image_details(details, &camera_id_string)?
+    } else {
+        details
+    };
+    // A newer image could be uploaded at any time
+    respond_with_watermark(details, accept, CachePolicy::Revalidate, watermark)
 }
 
-#[get("/Cameras/<camera_id_string>/ImageList")]
+/// Returns the IDs of a camera's images, oldest first. lighting (a comma separated list of day, dusk and night),
+/// exclude_dark and exclude_overexposed leave out images by their luminance. See LuminanceFilter.
+#[get("/Cameras/<camera_id_string>/ImageList?<lighting>&<exclude_dark>&<exclude_overexposed>")]
 pub fn get_image_list(
     conn: CameraServerDbConn,
-    user_token: user_tokens::UserToken,
+    user_auth: UserAuth,
     camera_id_string: String,
+    lighting: Option<String>,
+    exclude_dark: Option<bool>,
+    exclude_overexposed: Option<bool>,
 ) -> Result<Json<Vec<String>>, ApiError> {
     let images_directory_path = images_directory();
     let camera_directory = camera_directory(&images_directory_path, &camera_id_string);
 
-    check_if_user_has_access_to_camera(&conn, &user_token, &camera_id_string)?;
+    user_auth.require_scope(ApiKeyScope::ImagesRead)?;
+    check_if_user_has_access_to_camera(&conn, &user_auth, &camera_id_string)?;
+    let luminance_filter =
+        LuminanceFilter::parse(lighting.as_deref(), exclude_dark, exclude_overexposed)?;
 
-    let sorted_directory_list = list_camera_directory(&camera_directory, true)?
+    let sorted_directory_list: Vec<String> = list_camera_directory(&camera_directory, true)?
         .iter()
-        .map(|x| {
-            // This horrible thing removes the extension from the file name and effectively converts the DirEntrys to Strings (since returning &strs in Vecs is awkward to do)
-            Path::new(&x.file_name()).file_stem().expect("file_stem returned None! This should only happen if a file doesn't have a name somehow").to_str().expect("Failed to convert &OsStr to &str!").to_string()
-        })
+        .map(image_id)
         .collect();
+    if luminance_filter.is_unfiltered() {
+        return Ok(Json(sorted_directory_list));
+    }
 
-    Ok(Json(sorted_directory_list))
+    let camera_id = parse_camera_id(&camera_id_string)?;
+    let excluded_image_ids: HashSet<String> =
+        image_metadata::get_excluded_image_ids(camera_id, &luminance_filter, &conn)
+            .map_err(|error| {
+                error!(
+                    "Failed to get image metadata for camera {}: {}",
+                    camera_id, error
+                );
+                ApiError {
+                    error: "Failed to get list of images",
+                    status: Status::InternalServerError,
+                }
+            })?
+            .into_iter()
+            .collect();
+
+    Ok(Json(
+        sorted_directory_list
+            .into_iter()
+            .filter(|image_id| !excluded_image_ids.contains(image_id))
+            .collect(),
+    ))
 }
 
-#[get(
-    "/Cameras/<camera_id_string>/Image/<image_id_string>",
-    format = "image/jpeg"
-)]
+/// Returns an image, in the format it was uploaded in unless the Accept header asks for another one.
+/// It has the camera's watermark unless an owner asks for the original.
+#[get("/Cameras/<camera_id_string>/Image/<image_id_string>?<original>")]
 pub fn get_image(
     conn: CameraServerDbConn,
-    user_token: user_tokens::UserToken,
+    user_auth: UserAuth,
+    accept: Option<&Accept>,
     camera_id_string: String,
     image_id_string: String,
-) -> Result<Stream<File>, ApiError> {
-    check_if_user_has_access_to_camera(&conn, &user_token, &camera_id_string)?;
-
-    let images_directory_path = images_directory();
-
-    let camera_directory = camera_directory(&images_directory_path, &camera_id_string);
-
-    let image_list = list_camera_directory(&camera_directory, false)?;
-
-    let image_list_basenames: Vec<String> = image_list.iter()
-    .map(|x| {
-        Path::new(&x.file_name()).file_stem().expect("file_stem returned None! This should only happen if a file doesn't have a name somehow").to_str().expect("Failed to convert &OsStr to &str!").to_string()
-    })
-    .collect();
+    original: Option<bool>,
+) -> Result<ImageResponse, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ImagesRead)?;
+    check_if_user_has_access_to_camera(&conn, &user_auth, &camera_id_string)?;
+
+    let path = find_image(&camera_id_string, &image_id_string)?;
+    let details =
+        image_store::stored_image_details(&camera_id_string, &image_id_string, path, &conn)?;
+    let watermark = watermarks::get_on_the_fly_text(
+        &conn,
+        &user_auth,
+        &camera_id_string,
+        &image_id_string,
+        original.unwrap_or(false),
+    )?;
+    let details = if original.unwrap_or(false) {
+        image_store::original_image_details(details, &camera_id_string)?
+    } else {
+        details
+    };
+    // Images are never changed once they've been uploaded
+    respond_with_watermark(details, accept, CachePolicy::Immutable, watermark)
+}
 
-    let image_index = image_list_basenames
-        .iter()
-        .position(|x| x == &image_id_string)
-        .ok_or(ApiError {
-            error: "Image not found",
-            status: Status::NotFound,
-        })?;
+/// Sends a stored image back with the watermark drawn on it, if there is one.
+fn respond_with_watermark(
+    details: StoredImageDetails,
+    accept: Option<&Accept>,
+    cache_policy: CachePolicy,
+    watermark: Option<WatermarkText>,
+) -> Result<ImageResponse, ApiError> {
+    let watermark = match watermark {
+        Some(watermark) => watermark,
+        None => return image_store::respond_with_image(details, accept, cache_policy),
+    };
+
+    // The watermark can be changed at any time, so even images that never change have to be checked
+    image_store::respond_with_edited_image(
+        details,
+        accept,
+        CachePolicy::Revalidate,
+        &format!("watermark-{}", watermark.fingerprint()),
+        move |image| watermark.draw(&image),
+    )
+}
 
-    File::open(image_list[image_index].path())
-        .map(Stream::from)
-        .map_err(|error| {
-            println!("Failed to read file! The error was {}", error);
-            ApiError {
-                error: "Failed to load image",
+/// Returns what's known about an image: its format and size, and whatever EXIF data the camera recorded.
+#[get("/Cameras/<camera_id_string>/Image/<image_id_string>/Metadata")]
+pub fn get_image_metadata(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    camera_id_string: String,
+    image_id_string: String,
+) -> Result<Json<ImageMetadata>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ImagesRead)?;
+    check_if_user_has_access_to_camera(&conn, &user_auth, &camera_id_string)?;
+
+    let path = find_image(&camera_id_string, &image_id_string)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    match image_metadata::get(camera_id, &image_id_string, &conn) {
+        Ok(metadata) => Ok(Json(metadata)),
+        // Images from before metadata was stored have it read from the file each time instead
+        Err(diesel::NotFound) => {
+            image_store::metadata_from_file(camera_id, &image_id_string, &path).map(Json)
+        }
+        Err(error) => {
+            error!(
+                "Failed to get metadata for image {}: {}",
+                image_id_string, error
+            );
+            Err(ApiError {
+                error: "Failed to get image metadata",
                 status: Status::InternalServerError,
+            })
+        }
+    }
+}
+
+/// Returns a JPEG thumbnail of an image, turned the right way up according to its EXIF orientation.
+/// size is the longest side in pixels. Thumbnails always have the camera's watermark.
+#[get("/cameras/<camera_id_string>/images/<image_id_string>/thumbnail?<size>")]
+pub fn get_thumbnail(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    camera_id_string: String,
+    image_id_string: String,
+    size: Option<u32>,
+) -> Result<ImageResponse, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ImagesRead)?;
+    check_if_user_has_access_to_camera(&conn, &user_auth, &camera_id_string)?;
+
+    let path = find_image(&camera_id_string, &image_id_string)?;
+    let details = image_store::stored_image_details(
+        &camera_id_string,
+        &image_id_string,
+        path.clone(),
+        &conn,
+    )?;
+    let watermark = watermarks::get_on_the_fly_text(
+        &conn,
+        &user_auth,
+        &camera_id_string,
+        &image_id_string,
+        false,
+    )?;
+
+    let size = size
+        .unwrap_or(DEFAULT_THUMBNAIL_SIZE)
+        .max(MIN_THUMBNAIL_SIZE)
+        .min(MAX_THUMBNAIL_SIZE);
+    let stored_format = details.format;
+    let stored_orientation = details.stored_orientation;
+    // The watermark can be changed at any time, so thumbnails with one have to be checked like full size images
+    let (variant, cache_policy) = match &watermark {
+        Some(watermark) => (
+            format!("thumbnail-{}-watermark-{}", size, watermark.fingerprint()),
+            CachePolicy::Revalidate,
+        ),
+        None => (format!("thumbnail-{}", size), CachePolicy::Immutable),
+    };
+
+    Ok(ImageResponse::converted(
+        &details,
+        &variant,
+        ImageFormat::Jpeg,
+        cache_policy,
+        move || {
+            let image =
+                image_store::decode_stored_image(&path, stored_format)?.ok_or(ApiError {
+                    error: "Thumbnails can't be made from this image's format",
+                    status: Status::UnsupportedMediaType,
+                })?;
+            let orientation = stored_orientation.unwrap_or_else(|| read_exif(&path).orientation);
+            let mut thumbnail = apply_orientation(image.thumbnail(size, size), orientation);
+            if let Some(watermark) = watermark {
+                thumbnail = watermark.draw(&thumbnail);
             }
-        })
+            image_store::encode_image(thumbnail, ImageFormat::Jpeg)
+        },
+    ))
 }
@@ -0,0 +1,285 @@
+use crate::{
+    api_error::ApiError, api_keys::ApiKeyScope, user_auth::UserAuth, user_tokens::UserToken,
+    users_cameras::get_users_cameras, CameraServerDbConn,
+};
+
+use super::schema::camera_groups;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::{delete, get, http::Status, post, put};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Mosaics with more cameras than this would have tiles too small to be much use.
+pub const MAX_GROUP_CAMERAS: usize = max_group_cameras!();
+const MAX_NAME_LENGTH: usize = max_name_length!();
+
+/// The limits as literals, so error messages can include them with concat!().
+pub macro max_group_cameras() {
+    16
+}
+macro max_name_length() {
+    100
+}
+
+#[derive(Queryable, AsChangeset, Deserialize, Serialize)]
+#[table_name = "camera_groups"]
+pub struct CameraGroup {
+    pub camera_group_id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub name: String,
+    /// In the order they're shown
+    pub camera_ids: Vec<uuid::Uuid>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "camera_groups"]
+pub struct InsertableCameraGroup {
+    pub user_id: uuid::Uuid,
+    pub name: String,
+    pub camera_ids: Vec<uuid::Uuid>,
+}
+
+/// Sent by the user to create or replace a camera group.
+#[derive(Deserialize)]
+pub struct NewCameraGroup {
+    pub name: String,
+    pub camera_ids: Vec<uuid::Uuid>,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<CameraGroup>> {
+    camera_groups::table.load::<CameraGroup>(&*connection)
+}
+
+pub fn get(camera_group_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<CameraGroup> {
+    camera_groups::table
+        .find(camera_group_id)
+        .get_result::<CameraGroup>(connection)
+}
+
+pub fn insert(
+    camera_group: InsertableCameraGroup,
+    connection: &PgConnection,
+) -> QueryResult<CameraGroup> {
+    diesel::insert_into(camera_groups::table)
+        .values(camera_group)
+        .get_result(connection)
+}
+
+pub fn update(
+    camera_group_id: uuid::Uuid,
+    camera_group: CameraGroup,
+    connection: &PgConnection,
+) -> QueryResult<CameraGroup> {
+    diesel::update(camera_groups::table.find(camera_group_id))
+        .set(&camera_group)
+        .get_result(connection)
+}
+
+pub fn delete(camera_group_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(camera_groups::table.find(camera_group_id)).execute(connection)
+}
+
+pub fn get_users_camera_groups(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<CameraGroup>> {
+    camera_groups::table
+        .filter(camera_groups::user_id.eq(user_id))
+        .order(camera_groups::created_at)
+        .load::<CameraGroup>(connection)
+}
+
+/// Returns one of the user's camera groups. Other users' groups are treated as not existing.
+pub fn get_users_camera_group(
+    user_id: uuid::Uuid,
+    camera_group_id_string: &str,
+    connection: &PgConnection,
+) -> Result<CameraGroup, ApiError> {
+    let not_found = ApiError {
+        error: "Camera group not found",
+        status: Status::NotFound,
+    };
+    let camera_group_id = uuid::Uuid::parse_str(camera_group_id_string).map_err(|_| ApiError {
+        error: "Invalid camera group ID",
+        status: Status::BadRequest,
+    })?;
+
+    match get(camera_group_id, connection) {
+        Ok(camera_group) if camera_group.user_id == user_id => Ok(camera_group),
+        Ok(_) | Err(diesel::NotFound) => Err(not_found),
+        Err(error) => {
+            error!("Failed to get camera group {}: {}", camera_group_id, error);
+            Err(ApiError {
+                error: "Failed to get camera group",
+                status: Status::InternalServerError,
+            })
+        }
+    }
+}
+
+/// Checks the group's name and cameras. Every camera has to be one the user has access to.
+fn validate_camera_group(
+    user_id: uuid::Uuid,
+    camera_group: &NewCameraGroup,
+    connection: &PgConnection,
+) -> Result<(), ApiError> {
+    let invalid = |error: &'static str| {
+        Err(ApiError {
+            error,
+            status: Status::UnprocessableEntity,
+        })
+    };
+
+    let name_length = camera_group.name.trim().chars().count();
+    if name_length == 0 || name_length > MAX_NAME_LENGTH {
+        return invalid(concat!(
+            "Camera group name has to be between 1 and ",
+            max_name_length!(),
+            " characters"
+        ));
+    }
+    if camera_group.camera_ids.is_empty() || camera_group.camera_ids.len() > MAX_GROUP_CAMERAS {
+        return invalid(concat!(
+            "Camera groups need between 1 and ",
+            max_group_cameras!(),
+            " cameras"
+        ));
+    }
+    let unique_camera_ids: HashSet<_> = camera_group.camera_ids.iter().collect();
+    if unique_camera_ids.len() != camera_group.camera_ids.len() {
+        return invalid("Camera group has the same camera more than once");
+    }
+
+    let users_cameras = get_users_cameras(user_id, connection).map_err(|error| {
+        error!("Failed to get list of user's cameras: {}", error);
+        ApiError {
+            error: "Failed to get list of owned cameras",
+            status: Status::InternalServerError,
+        }
+    })?;
+    let has_access = camera_group.camera_ids.iter().all(|camera_id| {
+        users_cameras
+            .iter()
+            .any(|users_camera| &users_camera.camera_id == camera_id)
+    });
+    if !has_access {
+        return Err(ApiError {
+            error: "User does not have access to camera",
+            status: Status::Unauthorized,
+        });
+    }
+
+    Ok(())
+}
+
+fn save_error(user_id: uuid::Uuid, error: diesel::result::Error) -> ApiError {
+    error!(
+        "Failed to save camera group for user {}: {}",
+        user_id, error
+    );
+    ApiError {
+        error: "Failed to save camera group",
+        status: Status::InternalServerError,
+    }
+}
+
+/// Lists the user's camera groups, oldest first.
+#[get("/camera-groups")]
+pub fn list_camera_groups(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+) -> Result<Json<Vec<CameraGroup>>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::CamerasRead)?;
+
+    get_users_camera_groups(user_auth.user_id(), &conn)
+        .map(Json)
+        .map_err(|error| {
+            error!(
+                "Failed to get camera groups for user {}: {}",
+                user_auth.user_id(),
+                error
+            );
+            ApiError {
+                error: "Failed to get camera groups",
+                status: Status::InternalServerError,
+            }
+        })
+}
+
+/// Creates a camera group for the logged in user. Groups are managed with user tokens, not API keys.
+#[post("/camera-groups", format = "json", data = "<new_camera_group>")]
+pub fn create_camera_group(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    new_camera_group: Json<NewCameraGroup>,
+) -> Result<Json<CameraGroup>, ApiError> {
+    let new_camera_group = new_camera_group.into_inner();
+    validate_camera_group(user_token.user_id, &new_camera_group, &conn)?;
+
+    insert(
+        InsertableCameraGroup {
+            user_id: user_token.user_id,
+            name: new_camera_group.name.trim().to_string(),
+            camera_ids: new_camera_group.camera_ids,
+        },
+        &conn,
+    )
+    .map(Json)
+    .map_err(|error| save_error(user_token.user_id, error))
+}
+
+/// Replaces the name and cameras of one of the logged in user's camera groups.
+#[put(
+    "/camera-groups/<camera_group_id_string>",
+    format = "json",
+    data = "<new_camera_group>"
+)]
+pub fn update_camera_group(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_group_id_string: String,
+    new_camera_group: Json<NewCameraGroup>,
+) -> Result<Json<CameraGroup>, ApiError> {
+    let new_camera_group = new_camera_group.into_inner();
+    let camera_group = get_users_camera_group(user_token.user_id, &camera_group_id_string, &conn)?;
+    validate_camera_group(user_token.user_id, &new_camera_group, &conn)?;
+
+    update(
+        camera_group.camera_group_id,
+        CameraGroup {
+            name: new_camera_group.name.trim().to_string(),
+            camera_ids: new_camera_group.camera_ids,
+            ..camera_group
+        },
+        &conn,
+    )
+    .map(Json)
+    .map_err(|error| save_error(user_token.user_id, error))
+}
+
+/// Deletes one of the logged in user's camera groups. The cameras themselves aren't affected.
+#[delete("/camera-groups/<camera_group_id_string>")]
+pub fn delete_camera_group(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_group_id_string: String,
+) -> Result<(), ApiError> {
+    let camera_group = get_users_camera_group(user_token.user_id, &camera_group_id_string, &conn)?;
+
+    delete(camera_group.camera_group_id, &conn)
+        .map(|_| ())
+        .map_err(|error| {
+            error!(
+                "Failed to delete camera group {}: {}",
+                camera_group.camera_group_id, error
+            );
+            ApiError {
+                error: "Failed to delete camera group",
+                status: Status::InternalServerError,
+            }
+        })
+}
@@ -1,4 +1,8 @@
-use crate::{enums::token_error::TokenError, CameraServerDbConn};
+use crate::{
+    auth_token::{fail, find_token},
+    enums::token_error::TokenError,
+    logging, CameraServerDbConn,
+};
 
 use super::schema::camera_tokens;
 use diesel::pg::PgConnection;
@@ -18,28 +22,27 @@ impl<'a, 'r> FromRequest<'a, 'r> for CameraToken {
     type Error = TokenError;
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-        let token = request.headers().get_one("camera_token");
+        let token = find_token(request, "camera_token", false);
         match token {
             Some(token) => {
-                let parsed_token = match uuid::Uuid::parse_str(token) {
+                let parsed_token = match uuid::Uuid::parse_str(&token) {
                     Ok(parsed_token_ok) => parsed_token_ok,
                     // Token cannot be parsed into a UUID
-                    Err(_) => {
-                        return Outcome::Failure((Status::BadRequest, TokenError::ParseError))
-                    }
+                    Err(_) => return fail(request, Status::BadRequest, TokenError::ParseError),
                 };
                 let connection = CameraServerDbConn::from_request(&request)
                     .expect("Failed to get DB connection on CameraToken request guard");
                 match get(parsed_token, &connection) {
-                    Ok(camera_token) => return Outcome::Success(camera_token),
-
-                    Err(_) => {
-                        return Outcome::Failure((Status::Unauthorized, TokenError::NotFound))
+                    Ok(camera_token) => {
+                        logging::set_camera_id(camera_token.camera_id);
+                        return Outcome::Success(camera_token);
                     }
+
+                    Err(_) => return fail(request, Status::Unauthorized, TokenError::NotFound),
                 }
             }
             // Token does not exist
-            None => Outcome::Failure((Status::Unauthorized, TokenError::NoTokenProvided)),
+            None => fail(request, Status::Unauthorized, TokenError::NoTokenProvided),
         }
     }
 }
@@ -0,0 +1,53 @@
+use crate::{api_error::ApiError, auth_token::failure_message};
+
+use rocket::http::Status;
+use rocket::Request;
+
+#[catch(400)]
+pub fn bad_request(request: &Request) -> ApiError {
+    ApiError {
+        error: failure_message(request).unwrap_or("Bad request"),
+        status: Status::BadRequest,
+    }
+}
+
+#[catch(401)]
+pub fn unauthorized(request: &Request) -> ApiError {
+    ApiError {
+        error: failure_message(request).unwrap_or("Unauthorized"),
+        status: Status::Unauthorized,
+    }
+}
+
+#[catch(403)]
+pub fn forbidden(request: &Request) -> ApiError {
+    ApiError {
+        error: failure_message(request).unwrap_or("Forbidden"),
+        status: Status::Forbidden,
+    }
+}
+
+#[catch(404)]
+pub fn not_found() -> ApiError {
+    ApiError {
+        error: "Not found",
+        status: Status::NotFound,
+    }
+}
+
+/// Rocket responds with this when a JSON body is valid JSON, but not what the route expects
+#[catch(422)]
+pub fn unprocessable_entity() -> ApiError {
+    ApiError {
+        error: "The request body is missing fields or has the wrong types",
+        status: Status::UnprocessableEntity,
+    }
+}
+
+#[catch(500)]
+pub fn internal_server_error() -> ApiError {
+    ApiError {
+        error: "Internal server error",
+        status: Status::InternalServerError,
+    }
+}
@@ -1,7 +1,15 @@
+use crate::api_keys::ApiKeyScope;
+use crate::audit_log::{self, AuditAction, ClientIp, InsertableAuditEvent};
 use crate::camera_tokens::CameraToken;
-use crate::user_tokens::UserToken;
+use crate::user_auth::UserAuth;
 use crate::CameraServerDbConn;
-use crate::{api_error::ApiError, users_cameras::check_if_user_has_access_to_camera};
+use crate::{
+    api_error::ApiError,
+    camera::parse_camera_id,
+    users_cameras::{
+        check_if_user_has_access_to_camera, check_if_user_has_camera_role, CameraRole,
+    },
+};
 
 use super::schema::configs;
 use diesel::prelude::*;
@@ -9,8 +17,9 @@ use diesel::{self};
 use rocket::http::Status;
 use rocket_contrib::json::Json;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-#[derive(Queryable, AsChangeset, Insertable, Deserialize, Serialize)]
+#[derive(Queryable, AsChangeset, Insertable, Deserialize, Serialize, Clone)]
 #[table_name = "configs"]
 pub struct Config {
     pub camera_id: uuid::Uuid,
@@ -52,23 +61,15 @@ pub fn delete(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<u
 pub fn get_config_user(
     conn: CameraServerDbConn,
     camera_id_string: String,
-    user_token: UserToken,
+    user_auth: UserAuth,
 ) -> Result<Json<Config>, ApiError> {
-    check_if_user_has_access_to_camera(&conn, &user_token, &camera_id_string)?;
-
-    let camera_id = uuid::Uuid::parse_str(&camera_id_string).map_err(|error| {
-        println!(
-            "Failed to parse camera id into UUID: Input was {}, error was {}",
-            camera_id_string, error
-        );
-        ApiError {
-            error: "Failed to parse camera ID string",
-            status: Status::UnprocessableEntity,
-        }
-    })?;
+    user_auth.require_scope(ApiKeyScope::ConfigRead)?;
+    check_if_user_has_access_to_camera(&conn, &user_auth, &camera_id_string)?;
+
+    let camera_id = parse_camera_id(&camera_id_string)?;
 
     let config = get(camera_id, &conn).map_err(|error| {
-        println!("Failed to read camera config! The error was {}", error);
+        error!("Failed to read camera config: {}", error);
         return ApiError {
             error: "Failed to read config",
             status: Status::InternalServerError,
@@ -85,7 +86,7 @@ pub fn get_config_camera(
     camera_token: CameraToken,
 ) -> Result<Json<Config>, ApiError> {
     let config = get(camera_token.camera_id, &conn).map_err(|error| {
-        println!("Failed to read camera config! The error was {}", error);
+        error!("Failed to read camera config: {}", error);
         return ApiError {
             error: "Failed to read config",
             status: Status::InternalServerError,
@@ -102,31 +103,52 @@ pub fn get_config_camera(
 )]
 pub fn update_config(
     conn: CameraServerDbConn,
-    user_token: UserToken,
+    user_auth: UserAuth,
+    client_ip: ClientIp,
     camera_id_string: String,
     new_config: Json<Config>,
 ) -> Result<Json<Config>, ApiError> {
     let deserialized_new_config = new_config.into_inner();
-    check_if_user_has_access_to_camera(&conn, &user_token, &camera_id_string)?;
-
-    let camera_id = uuid::Uuid::parse_str(&camera_id_string).map_err(|error| {
-        println!(
-            "Failed to parse camera id into UUID: Input was {}, error was {}",
-            camera_id_string, error
-        );
-        ApiError {
-            error: "Failed to parse camera ID string",
-            status: Status::UnprocessableEntity,
-        }
+    user_auth.require_scope(ApiKeyScope::ConfigWrite)?;
+    check_if_user_has_camera_role(&conn, &user_auth, &camera_id_string, CameraRole::Editor)?;
+
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    // Kept so that the audit log can show what changed
+    let old_config = get(camera_id, &conn).ok();
+
+    // The camera ID in the body is ignored, otherwise a user could overwrite the config of a camera they don't have access to
+    let updated_config = update(
+        camera_id,
+        Config {
+            camera_id,
+            ..deserialized_new_config
+        },
+        &conn,
+    )
+    .map_err(|error| {
+        error!("Failed to update camera config: {}", error);
+        return ApiError {
+            error: "Failed to update config",
+            status: Status::InternalServerError,
+        };
     })?;
 
-    update(camera_id, deserialized_new_config, &conn)
-        .map(|result| Json(result))
-        .map_err(|error| {
-            println!("Failed to update camera config! The error was {}", error);
-            return ApiError {
-                error: "Failed to update config",
-                status: Status::InternalServerError,
-            };
-        })
+    audit_log::record(
+        InsertableAuditEvent {
+            target_camera_id: Some(camera_id),
+            details: Some(json!({
+                "old": old_config,
+                "new": updated_config,
+            })),
+            ..InsertableAuditEvent::by_user_auth(
+                AuditAction::CameraConfigUpdated,
+                &user_auth,
+                &client_ip,
+            )
+        },
+        &conn,
+    );
+
+    Ok(Json(updated_config))
 }
@@ -1,6 +1,21 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum TokenError {
     ParseError,
     NotFound,
     NoTokenProvided,
+    Expired,
+}
+
+impl TokenError {
+    /// Shown to the client when a token guard fails
+    pub fn message(&self) -> &'static str {
+        match self {
+            TokenError::ParseError => "Token is not a valid UUID",
+            TokenError::NotFound => "Token not found",
+            TokenError::NoTokenProvided => {
+                "No token provided. Send it with an 'Authorization: Bearer <token>' header"
+            }
+            TokenError::Expired => "Token has expired",
+        }
+    }
 }
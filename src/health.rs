@@ -0,0 +1,211 @@
+use crate::{camera::images_directory, CameraServerDbConn};
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Text;
+use rocket::{fairing::AdHoc, get, http::Status, response::status::Custom, State};
+use rocket_contrib::json::Json;
+use serde::Serialize;
+use std::fs::{remove_file, OpenOptions};
+use std::io::Write;
+
+/// Set by build.rs from the migrations directory
+const EXPECTED_MIGRATIONS: &str = env!("EXPECTED_MIGRATIONS");
+
+const DEFAULT_MIN_FREE_SPACE_MB: u64 = 1024;
+
+/// Settings for the readiness check, read from the health table in Rocket.toml.
+pub struct HealthConfig {
+    /// The server isn't ready if IMAGES_DIRECTORY has less than this much space free
+    pub min_free_space_bytes: u64,
+}
+
+/// Manages the HealthConfig.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Health Config", |rocket| {
+        let min_free_space_mb = rocket
+            .config()
+            .get_table("health")
+            .ok()
+            .and_then(|table| table.get("min_free_space_mb"))
+            .and_then(|value| value.as_integer())
+            .map(|value| value.max(0) as u64)
+            .unwrap_or(DEFAULT_MIN_FREE_SPACE_MB);
+
+        Ok(rocket.manage(HealthConfig {
+            min_free_space_bytes: min_free_space_mb * 1024 * 1024,
+        }))
+    })
+}
+
+#[derive(Serialize)]
+pub struct Liveness {
+    pub status: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct CheckResult {
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImagesDirectoryCheck {
+    pub healthy: bool,
+    pub writable: bool,
+    pub free_bytes: Option<u64>,
+    pub min_free_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct MigrationsCheck {
+    pub healthy: bool,
+    /// Migrations this build expects that haven't been run on the database
+    pub pending: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub database: CheckResult,
+    pub images_directory: ImagesDirectoryCheck,
+    pub migrations: MigrationsCheck,
+}
+
+#[derive(QueryableByName)]
+struct MigrationVersion {
+    #[sql_type = "Text"]
+    version: String,
+}
+
+fn check_database(conn: &Option<CameraServerDbConn>) -> CheckResult {
+    let result = match conn {
+        Some(conn) => sql_query("SELECT 1")
+            .execute(&**conn)
+            .map(|_| ())
+            .map_err(|error| error.to_string()),
+        None => Err(String::from("Failed to get a connection from the pool")),
+    };
+
+    CheckResult {
+        healthy: result.is_ok(),
+        error: result.err(),
+    }
+}
+
+fn check_images_directory(health_config: &HealthConfig) -> ImagesDirectoryCheck {
+    let images_directory_path = images_directory();
+    let test_file_path = format!(
+        "{}/.health-check-{}",
+        images_directory_path,
+        uuid::Uuid::new_v4()
+    );
+
+    let write_result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&test_file_path)
+        .and_then(|mut file| file.write_all(b"ok"))
+        .and_then(|_| remove_file(&test_file_path));
+
+    let free_bytes = fs2::available_space(&images_directory_path);
+
+    let mut errors = Vec::new();
+    if let Err(error) = &write_result {
+        errors.push(format!("Not writable: {}", error));
+    }
+    match &free_bytes {
+        Ok(free_bytes) if *free_bytes < health_config.min_free_space_bytes => {
+            errors.push(String::from("Not enough free space"))
+        }
+        Ok(_) => {}
+        Err(error) => errors.push(format!("Failed to get free space: {}", error)),
+    }
+
+    ImagesDirectoryCheck {
+        healthy: errors.is_empty(),
+        writable: write_result.is_ok(),
+        free_bytes: free_bytes.ok(),
+        min_free_bytes: health_config.min_free_space_bytes,
+        error: if errors.is_empty() {
+            None
+        } else {
+            Some(errors.join(", "))
+        },
+    }
+}
+
+fn check_migrations(conn: &Option<CameraServerDbConn>) -> MigrationsCheck {
+    let applied = match conn {
+        Some(conn) => sql_query("SELECT version FROM __diesel_schema_migrations")
+            .load::<MigrationVersion>(&**conn)
+            .map_err(|error| error.to_string()),
+        None => Err(String::from("Failed to get a connection from the pool")),
+    };
+
+    match applied {
+        Ok(applied) => {
+            let pending: Vec<String> = EXPECTED_MIGRATIONS
+                .split(',')
+                .filter(|version| !version.is_empty())
+                .filter(|version| !applied.iter().any(|x| &x.version == version))
+                .map(String::from)
+                .collect();
+
+            MigrationsCheck {
+                healthy: pending.is_empty(),
+                pending,
+                error: None,
+            }
+        }
+        Err(error) => MigrationsCheck {
+            healthy: false,
+            pending: Vec::new(),
+            error: Some(error),
+        },
+    }
+}
+
+/// Liveness probe. Only checks that the server is able to handle requests.
+#[get("/health/live")]
+pub fn live() -> Json<Liveness> {
+    Json(Liveness { status: "ok" })
+}
+
+/// Readiness probe. Returns 503 unless the database is reachable and migrated, and images can be stored.
+#[get("/health/ready")]
+pub fn ready(
+    conn: Option<CameraServerDbConn>,
+    health_config: State<HealthConfig>,
+) -> Custom<Json<Readiness>> {
+    let database = check_database(&conn);
+    let images_directory = check_images_directory(&health_config);
+    let migrations = check_migrations(&conn);
+
+    let healthy = database.healthy && images_directory.healthy && migrations.healthy;
+    if !healthy {
+        warn!(
+            "Readiness check failed: database {}, images directory {}, migrations {}",
+            database.healthy, images_directory.healthy, migrations.healthy
+        );
+    }
+
+    Custom(
+        if healthy {
+            Status::Ok
+        } else {
+            Status::ServiceUnavailable
+        },
+        Json(Readiness {
+            status: if healthy { "ok" } else { "unavailable" },
+            database,
+            images_directory,
+            migrations,
+        }),
+    )
+}
@@ -0,0 +1,268 @@
+use chrono::{DateTime, Utc};
+use rocket::Request;
+
+/// How long clients can cache a response for before checking whether it's changed.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CachePolicy {
+    /// For responses that can never change, like an image with a given ID. Cached for a year without checking.
+    Immutable,
+    /// For responses that can change at any time, like the latest image. Cached, but checked with an ETag every time.
+    Revalidate,
+}
+
+impl CachePolicy {
+    pub fn header_value(self) -> &'static str {
+        // Images are only available to users with access to the camera, so shared caches mustn't keep them
+        match self {
+            CachePolicy::Immutable => "private, max-age=31536000, immutable",
+            CachePolicy::Revalidate => "private, no-cache",
+        }
+    }
+}
+
+/// The part of a response that's been asked for with a Range header.
+#[derive(Debug, PartialEq)]
+pub enum RequestedRange {
+    /// No range, or one that should be ignored. The whole response is sent with 200.
+    Full,
+    /// The first and last byte to send, inclusive. Sent with 206.
+    Partial(u64, u64),
+    /// A range that starts after the end of the response. Answered with 416.
+    Unsatisfiable,
+}
+
+/// Formats a date the way HTTP headers expect, like "Sun, 06 Nov 1994 08:49:37 GMT".
+pub fn format_http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// Strips the quotes and the W/ prefix of a weak ETag, so that ETags can be compared.
+fn opaque_tag(etag: &str) -> (&str, bool) {
+    let etag = etag.trim();
+    match etag.strip_prefix("W/") {
+        Some(tag) => (tag.trim_matches('"'), true),
+        None => (etag.trim_matches('"'), false),
+    }
+}
+
+/// Whether the client's cached copy is still current, in which case the response should be 304 Not Modified.
+/// If-None-Match takes priority over If-Modified-Since, as HTTP says it should.
+pub fn is_not_modified(request: &Request, etag: &str, last_modified: DateTime<Utc>) -> bool {
+    not_modified(
+        request.headers().get_one("If-None-Match"),
+        request.headers().get_one("If-Modified-Since"),
+        etag,
+        last_modified,
+    )
+}
+
+fn not_modified(
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+    etag: &str,
+    last_modified: DateTime<Utc>,
+) -> bool {
+    if let Some(if_none_match) = if_none_match {
+        let (etag, _) = opaque_tag(etag);
+        return if_none_match
+            .split(',')
+            .any(|tag| tag.trim() == "*" || opaque_tag(tag).0 == etag);
+    }
+
+    if_modified_since
+        .and_then(parse_http_date)
+        .map(|if_modified_since| last_modified.timestamp() <= if_modified_since.timestamp())
+        .unwrap_or(false)
+}
+
+/// Whether an If-Range header still matches the response, so the range can be used. It has to match exactly: weak ETags never match.
+fn if_range_matches(if_range: &str, etag: &str, last_modified: DateTime<Utc>) -> bool {
+    if if_range.trim_start().starts_with('"') || if_range.trim_start().starts_with("W/") {
+        let (tag, weak) = opaque_tag(if_range);
+        !weak && tag == opaque_tag(etag).0
+    } else {
+        parse_http_date(if_range)
+            .map(|date| date.timestamp() == last_modified.timestamp())
+            .unwrap_or(false)
+    }
+}
+
+/// Works out which bytes of a response of the given length the Range header asks for.
+/// Only single byte ranges are supported. Anything else gets the whole response, which HTTP allows.
+pub fn requested_range(
+    request: &Request,
+    etag: &str,
+    last_modified: DateTime<Utc>,
+    length: u64,
+) -> RequestedRange {
+    let range = match request.headers().get_one("Range") {
+        Some(range) => range,
+        None => return RequestedRange::Full,
+    };
+
+    // The client's partial copy is out of date, so it needs all of it again
+    if let Some(if_range) = request.headers().get_one("If-Range") {
+        if !if_range_matches(if_range, etag, last_modified) {
+            return RequestedRange::Full;
+        }
+    }
+
+    match range.trim().strip_prefix("bytes=") {
+        Some(range) if !range.contains(',') => parse_byte_range(range, length),
+        _ => RequestedRange::Full,
+    }
+}
+
+fn parse_byte_range(range: &str, length: u64) -> RequestedRange {
+    let mut parts = range.trim().splitn(2, '-');
+    let (first, last) = match (parts.next(), parts.next()) {
+        (Some(first), Some(last)) => (first.trim(), last.trim()),
+        _ => return RequestedRange::Full,
+    };
+
+    match (first.parse::<u64>(), last.parse::<u64>()) {
+        // bytes=500-999
+        (Ok(first), Ok(last)) if first <= last => {
+            if first >= length {
+                RequestedRange::Unsatisfiable
+            } else {
+                RequestedRange::Partial(first, last.min(length - 1))
+            }
+        }
+        // bytes=500-
+        (Ok(first), Err(_)) if last.is_empty() => {
+            if first >= length {
+                RequestedRange::Unsatisfiable
+            } else {
+                RequestedRange::Partial(first, length - 1)
+            }
+        }
+        // bytes=-500, the last 500 bytes
+        (Err(_), Ok(suffix_length)) if first.is_empty() => {
+            if suffix_length == 0 || length == 0 {
+                RequestedRange::Unsatisfiable
+            } else {
+                RequestedRange::Partial(length.saturating_sub(suffix_length), length - 1)
+            }
+        }
+        _ => RequestedRange::Full,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const ETAG: &str = "\"abc123\"";
+    const LAST_MODIFIED: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    fn last_modified() -> DateTime<Utc> {
+        Utc.ymd(1994, 11, 6).and_hms(8, 49, 37)
+    }
+
+    #[test]
+    fn byte_ranges() {
+        use RequestedRange::*;
+        let cases = [
+            ("0-499", 1000, Partial(0, 499)),
+            ("500-999", 1000, Partial(500, 999)),
+            (" 10 - 20 ", 1000, Partial(10, 20)),
+            // The end is clamped to the length
+            ("500-5000", 1000, Partial(500, 999)),
+            ("500-", 1000, Partial(500, 999)),
+            ("0-0", 1, Partial(0, 0)),
+            // The last 100 bytes
+            ("-100", 1000, Partial(900, 999)),
+            ("-5000", 1000, Partial(0, 999)),
+            ("-0", 1000, Unsatisfiable),
+            ("-100", 0, Unsatisfiable),
+            ("1000-", 1000, Unsatisfiable),
+            ("1000-2000", 1000, Unsatisfiable),
+            ("0-", 0, Unsatisfiable),
+            // Invalid ranges are ignored
+            ("500-100", 1000, Full),
+            ("-", 1000, Full),
+            ("", 1000, Full),
+            ("abc-def", 1000, Full),
+            ("10", 1000, Full),
+            ("-10-20", 1000, Full),
+        ];
+
+        for (range, length, expected) in cases.iter() {
+            assert_eq!(
+                parse_byte_range(range, *length),
+                *expected,
+                "bytes={} of {}",
+                range,
+                length
+            );
+        }
+    }
+
+    #[test]
+    fn not_modified_conditions() {
+        let cases = [
+            (None, None, false),
+            (Some("\"abc123\""), None, true),
+            (Some("abc123"), None, true),
+            // If-None-Match compares weakly
+            (Some("W/\"abc123\""), None, true),
+            (Some("\"other\", \"abc123\""), None, true),
+            (Some("*"), None, true),
+            (Some("\"other\""), None, false),
+            (Some(""), None, false),
+            (None, Some(LAST_MODIFIED), true),
+            (None, Some("Mon, 07 Nov 1994 08:49:37 GMT"), true),
+            (None, Some("Sat, 05 Nov 1994 08:49:37 GMT"), false),
+            (None, Some("not a date"), false),
+            // If-None-Match takes priority
+            (Some("\"other\""), Some(LAST_MODIFIED), false),
+            (
+                Some("\"abc123\""),
+                Some("Sat, 05 Nov 1994 08:49:37 GMT"),
+                true,
+            ),
+        ];
+
+        for (if_none_match, if_modified_since, expected) in cases.iter() {
+            assert_eq!(
+                not_modified(*if_none_match, *if_modified_since, ETAG, last_modified()),
+                *expected,
+                "If-None-Match: {:?}, If-Modified-Since: {:?}",
+                if_none_match,
+                if_modified_since
+            );
+        }
+    }
+
+    #[test]
+    fn if_range_conditions() {
+        let cases = [
+            ("\"abc123\"", true),
+            ("\"other\"", false),
+            // Weak ETags never match
+            ("W/\"abc123\"", false),
+            ("*", false),
+            (LAST_MODIFIED, true),
+            ("Sat, 05 Nov 1994 08:49:37 GMT", false),
+            ("Mon, 07 Nov 1994 08:49:37 GMT", false),
+            ("not a date", false),
+        ];
+
+        for (if_range, expected) in cases.iter() {
+            assert_eq!(
+                if_range_matches(if_range, ETAG, last_modified()),
+                *expected,
+                "If-Range: {}",
+                if_range
+            );
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use crate::{
+    api_error::ApiError,
+    api_keys::ApiKeyScope,
+    camera::{camera_directory, images_directory, parse_camera_id},
+    image_metadata,
+    image_store::{ImageFormat, UploadConfig},
+    user_auth::UserAuth,
+    users_cameras::check_if_user_has_access_to_camera,
+    CameraServerDbConn,
+};
+
+use super::schema::camera_dedup_savings;
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::{get, http::Status, State};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// What happens to an upload that's byte for byte the same as one of the camera's stored images.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// Stored as a new image that shares the existing image's file
+    Link,
+    /// Not stored at all. The upload gets the existing image's ID.
+    Skip,
+}
+
+impl DuplicatePolicy {
+    /// Reads the policy from the uploads table in Rocket.toml. Defaults to linking.
+    pub fn from_config(config: &rocket::Config) -> DuplicatePolicy {
+        let policy = config
+            .get_table("uploads")
+            .ok()
+            .and_then(|table| table.get("duplicates"))
+            .and_then(|value| value.as_str());
+        match policy {
+            None | Some("link") => DuplicatePolicy::Link,
+            Some("skip") => DuplicatePolicy::Skip,
+            Some(policy) => {
+                warn!(
+                    "Unknown duplicates policy {}, linking duplicates instead",
+                    policy
+                );
+                DuplicatePolicy::Link
+            }
+        }
+    }
+}
+
+/// How much space a camera's duplicate uploads would have taken up if they'd been stored again.
+#[derive(Queryable, Insertable, AsChangeset, Deserialize, Serialize)]
+#[table_name = "camera_dedup_savings"]
+pub struct DedupSavings {
+    pub camera_id: uuid::Uuid,
+    pub duplicate_count: i64,
+    pub bytes_saved: i64,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<DedupSavings>> {
+    camera_dedup_savings::table.load::<DedupSavings>(&*connection)
+}
+
+pub fn get(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<DedupSavings> {
+    camera_dedup_savings::table
+        .find(camera_id)
+        .get_result::<DedupSavings>(connection)
+}
+
+pub fn insert(savings: DedupSavings, connection: &PgConnection) -> QueryResult<DedupSavings> {
+    diesel::insert_into(camera_dedup_savings::table)
+        .values(savings)
+        .get_result(connection)
+}
+
+pub fn update(
+    camera_id: uuid::Uuid,
+    savings: DedupSavings,
+    connection: &PgConnection,
+) -> QueryResult<DedupSavings> {
+    diesel::update(camera_dedup_savings::table.find(camera_id))
+        .set(&savings)
+        .get_result(connection)
+}
+
+pub fn delete(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(camera_dedup_savings::table.find(camera_id)).execute(connection)
+}
+
+/// An image already stored by the camera that an upload is a copy of.
+pub struct Duplicate {
+    pub image_id: String,
+    pub path: PathBuf,
+}
+
+/// Looks for a stored image of the camera's with the same content hash. Images that are in the trash or have been
+/// deleted still have metadata, so only ones that are still in the camera's directory count.
+pub fn find_duplicate(
+    camera_id: uuid::Uuid,
+    content_hash: &str,
+    connection: &PgConnection,
+) -> Option<Duplicate> {
+    let images = image_metadata::get_images_with_hash(camera_id, content_hash, connection)
+        .map_err(|error| {
+            // Not worth failing the upload over, it just gets stored again
+            warn!(
+                "Failed to look for duplicates of upload to camera {}: {}",
+                camera_id, error
+            );
+        })
+        .ok()?;
+
+    // The file name comes from the metadata, so each one is a single stat rather than a listing of the directory
+    let camera_directory = camera_directory(&images_directory(), &camera_id.to_string());
+    images.into_iter().find_map(|(image_id, content_type)| {
+        let format = ImageFormat::from_content_type(&content_type)?;
+        let path =
+            Path::new(&camera_directory).join(format!("{}.{}", image_id, format.extension()));
+        if path.is_file() {
+            Some(Duplicate { image_id, path })
+        } else {
+            None
+        }
+    })
+}
+
+/// Adds a duplicate upload to the camera's savings. Only logged if it fails, since the upload has already been handled.
+pub fn record_duplicate(camera_id: uuid::Uuid, bytes: u64, connection: &PgConnection) {
+    let result = diesel::insert_into(camera_dedup_savings::table)
+        .values(DedupSavings {
+            camera_id,
+            duplicate_count: 1,
+            bytes_saved: bytes as i64,
+        })
+        .on_conflict(camera_dedup_savings::camera_id)
+        .do_update()
+        .set((
+            camera_dedup_savings::duplicate_count.eq(camera_dedup_savings::duplicate_count + 1),
+            camera_dedup_savings::bytes_saved.eq(camera_dedup_savings::bytes_saved + bytes as i64),
+        ))
+        .execute(connection);
+    if let Err(error) = result {
+        warn!(
+            "Failed to record duplicate upload to camera {}: {}",
+            camera_id, error
+        );
+    }
+}
+
+#[derive(Serialize)]
+pub struct DedupReport {
+    #[serde(flatten)]
+    pub savings: DedupSavings,
+    pub policy: DuplicatePolicy,
+}
+
+/// Returns how many of a camera's uploads were duplicates and how much space not storing them again saved.
+#[get("/cameras/<camera_id_string>/dedup")]
+pub fn get_dedup_savings(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    upload_config: State<UploadConfig>,
+    camera_id_string: String,
+) -> Result<Json<DedupReport>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::CamerasRead)?;
+    check_if_user_has_access_to_camera(&conn, &user_auth, &camera_id_string)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    let savings = match get(camera_id, &conn) {
+        Ok(savings) => savings,
+        // No duplicates yet
+        Err(diesel::NotFound) => DedupSavings {
+            camera_id,
+            duplicate_count: 0,
+            bytes_saved: 0,
+        },
+        Err(error) => {
+            error!(
+                "Failed to get dedup savings for camera {}: {}",
+                camera_id, error
+            );
+            return Err(ApiError {
+                error: "Failed to get dedup savings",
+                status: Status::InternalServerError,
+            });
+        }
+    };
+
+    Ok(Json(DedupReport {
+        savings,
+        policy: upload_config.duplicates,
+    }))
+}
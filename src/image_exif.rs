@@ -0,0 +1,165 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use exif::{Exif, In, Tag, Value};
+use image::DynamicImage;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// The EXIF fields worth keeping from an image. Anything the camera didn't record is None.
+#[derive(Default)]
+pub struct ExifData {
+    /// When the camera says the image was taken, in the camera's local time
+    pub captured_at: Option<NaiveDateTime>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    /// Seconds, as a fraction like 1/125
+    pub exposure_time: Option<String>,
+    pub f_number: Option<f64>,
+    pub iso: Option<i32>,
+    /// Millimetres
+    pub focal_length: Option<f64>,
+    /// 1 to 8, see apply_orientation()
+    pub orientation: Option<i16>,
+    /// Degrees, negative for south
+    pub gps_latitude: Option<f64>,
+    /// Degrees, negative for west
+    pub gps_longitude: Option<f64>,
+    /// Metres, negative for below sea level
+    pub gps_altitude: Option<f64>,
+}
+
+/// Reads the EXIF data from an image file. Images without any, or with EXIF that can't be parsed, get an empty ExifData.
+pub fn read_exif(path: &Path) -> ExifData {
+    let exif = File::open(path)
+        .map_err(exif::Error::Io)
+        .and_then(|file| exif::Reader::new().read_from_container(&mut BufReader::new(file)));
+
+    match exif {
+        Ok(exif) => ExifData {
+            captured_at: read_date_time(&exif, Tag::DateTimeOriginal),
+            camera_make: read_string(&exif, Tag::Make),
+            camera_model: read_string(&exif, Tag::Model),
+            exposure_time: read_rational(&exif, Tag::ExposureTime).map(|(num, denom)| {
+                if num >= denom || num == 0 {
+                    format!("{}", num as f64 / denom as f64)
+                } else {
+                    format!("{}/{}", num, denom)
+                }
+            }),
+            f_number: read_f64(&exif, Tag::FNumber),
+            iso: read_uint(&exif, Tag::PhotographicSensitivity).map(|x| x as i32),
+            focal_length: read_f64(&exif, Tag::FocalLength),
+            orientation: read_uint(&exif, Tag::Orientation)
+                .filter(|x| (1..=8).contains(x))
+                .map(|x| x as i16),
+            gps_latitude: read_coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S'),
+            gps_longitude: read_coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W'),
+            gps_altitude: read_f64(&exif, Tag::GPSAltitude).map(|altitude| {
+                // 1 means below sea level
+                if read_uint(&exif, Tag::GPSAltitudeRef) == Some(1) {
+                    -altitude
+                } else {
+                    altitude
+                }
+            }),
+        },
+        Err(exif::Error::NotFound(_)) => ExifData::default(),
+        Err(error) => {
+            debug!("Failed to read EXIF from {}: {}", path.display(), error);
+            ExifData::default()
+        }
+    }
+}
+
+fn read_uint(exif: &Exif, tag: Tag) -> Option<u32> {
+    exif.get_field(tag, In::PRIMARY)?.value.get_uint(0)
+}
+
+/// Returns the numerator and denominator of a rational field, as long as it's a valid number.
+fn read_rational(exif: &Exif, tag: Tag) -> Option<(u32, u32)> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) => values
+            .first()
+            .filter(|x| x.denom != 0)
+            .map(|x| (x.num, x.denom)),
+        _ => None,
+    }
+}
+
+fn read_f64(exif: &Exif, tag: Tag) -> Option<f64> {
+    read_rational(exif, tag).map(|(num, denom)| num as f64 / denom as f64)
+}
+
+fn read_string(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values
+            .first()
+            .map(|x| {
+                String::from_utf8_lossy(x)
+                    .trim_end_matches('\0')
+                    .trim()
+                    .to_string()
+            })
+            .filter(|x| !x.is_empty()),
+        _ => None,
+    }
+}
+
+fn read_date_time(exif: &Exif, tag: Tag) -> Option<NaiveDateTime> {
+    let date_time = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => exif::DateTime::from_ascii(values.first()?).ok()?,
+        _ => return None,
+    };
+    NaiveDate::from_ymd_opt(
+        date_time.year as i32,
+        date_time.month as u32,
+        date_time.day as u32,
+    )?
+    .and_hms_opt(
+        date_time.hour as u32,
+        date_time.minute as u32,
+        date_time.second as u32,
+    )
+}
+
+/// GPS coordinates are stored as degrees, minutes and seconds, with a separate N/S or E/W reference.
+fn read_coordinate(
+    exif: &Exif,
+    tag: Tag,
+    reference_tag: Tag,
+    negative_reference: u8,
+) -> Option<f64> {
+    let degrees = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) if values.len() == 3 && values.iter().all(|x| x.denom != 0) => {
+            values[0].to_f64() + values[1].to_f64() / 60.0 + values[2].to_f64() / 3600.0
+        }
+        _ => return None,
+    };
+
+    let negative = match &exif.get_field(reference_tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values
+            .first()
+            .and_then(|x| x.first())
+            .map(|x| x.to_ascii_uppercase() == negative_reference)
+            .unwrap_or(false),
+        _ => false,
+    };
+
+    Some(if negative { -degrees } else { degrees })
+}
+
+/// Turns an image the right way up according to its EXIF orientation.
+pub fn apply_orientation(image: DynamicImage, orientation: Option<i16>) -> DynamicImage {
+    match orientation {
+        Some(2) => image.fliph(),
+        Some(3) => image.rotate180(),
+        Some(4) => image.flipv(),
+        // Transposed
+        Some(5) => image.rotate90().fliph(),
+        Some(6) => image.rotate90(),
+        // Transversed
+        Some(7) => image.rotate270().fliph(),
+        Some(8) => image.rotate270(),
+        _ => image,
+    }
+}
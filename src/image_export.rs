@@ -0,0 +1,493 @@
+use crate::{
+    api_error::ApiError,
+    api_keys::ApiKeyScope,
+    audit_log::{self, AuditAction, ClientIp, InsertableAuditEvent},
+    camera::{camera_directory, images_directory, list_camera_directory, parse_camera_id},
+    image_exif::{apply_orientation, read_exif},
+    image_luminance::LuminanceFilter,
+    image_metadata::{self, ImageMetadata},
+    image_store::{
+        self, image_id, image_timestamp_ms, now_ms, original_path, ImageFormat, TimeRange,
+        INCOMING_DIRECTORY,
+    },
+    user_auth::UserAuth,
+    users_cameras::check_if_user_has_access_to_camera,
+    watermarks::{self, ActiveWatermark, WatermarkText},
+    CameraServerDbConn,
+};
+
+use chrono::{TimeZone, Utc};
+use flate2::{write::GzEncoder, Compression};
+use rocket::{
+    get,
+    http::{ContentType, Status},
+    response::{self, Responder, Response},
+    Request,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, remove_file, DirEntry, File};
+use std::io::{self, BufWriter, Cursor, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+/// The archive is sent to the client in chunks of this size.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// How many chunks can be waiting to be sent before the thread writing the archive waits for the client to catch up.
+/// This is all of the archive that's ever held in memory.
+const MAX_QUEUED_CHUNKS: usize = 16;
+/// How many images' metadata is loaded at a time while the archive is written.
+const METADATA_BATCH_SIZE: usize = 500;
+
+#[derive(Serialize)]
+struct ManifestImage {
+    image_id: String,
+    /// Where the image is in the archive
+    path: String,
+    /// When the image was taken, as an RFC 3339 date
+    captured_at: Option<String>,
+    /// true if the exported copy has the camera's watermark, either burned in when it was uploaded or drawn on it for the
+    /// export. Drawn ones are encoded again, so their size and hash don't match the stored image's in metadata.
+    watermarked: bool,
+    /// None for images uploaded before metadata was stored
+    metadata: Option<ImageMetadata>,
+}
+
+/// A file to add to the archive, and the watermark to draw on it first if it needs one.
+struct ExportedImage {
+    path: PathBuf,
+    archive_path: String,
+    watermark: Option<ExportWatermark>,
+}
+
+struct ExportWatermark {
+    text: WatermarkText,
+    stored_format: ImageFormat,
+    /// None if the image has no metadata and it has to be read from the file
+    stored_orientation: Option<Option<i16>>,
+}
+
+impl ExportWatermark {
+    /// Returns the image with the watermark on it, or None if it can't be decoded.
+    fn draw(&self, path: &Path) -> Option<Vec<u8>> {
+        let image = image_store::decode_stored_image(path, self.stored_format).ok()??;
+        // Encoding it again loses its EXIF orientation, and the watermark goes on the image the right way up
+        let orientation = self
+            .stored_orientation
+            .unwrap_or_else(|| read_exif(path).orientation);
+        let image = self.text.draw(&apply_orientation(image, orientation));
+        image_store::encode_image(image, self.stored_format.edited_format()).ok()
+    }
+}
+
+/// Written to the archive as manifest.json, after all of the images. The images are listed as they're added, so the
+/// manifest is built up in a temp file rather than in memory.
+struct ManifestWriter {
+    path: String,
+    file: BufWriter<File>,
+    image_count: usize,
+}
+
+impl ManifestWriter {
+    /// camera_id, from, to and exported_at go at the top. from and to are the time range that was asked for, in
+    /// milliseconds since the epoch.
+    fn create(path: String, camera_id: uuid::Uuid, time_range: &TimeRange) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(&path)?);
+        write!(
+            file,
+            "{{\"camera_id\":{},\"from\":{},\"to\":{},\"exported_at\":{},\"images\":[",
+            json!(camera_id),
+            json!(time_range.from),
+            json!(time_range.to),
+            json!(Utc::now().to_rfc3339()),
+        )?;
+        Ok(ManifestWriter {
+            path,
+            file,
+            image_count: 0,
+        })
+    }
+
+    fn add_image(&mut self, image: &ManifestImage) -> io::Result<()> {
+        if self.image_count > 0 {
+            self.file.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut self.file, image)?;
+        self.image_count += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.file.write_all(b"]}")?;
+        self.file.flush()
+    }
+}
+
+impl Drop for ManifestWriter {
+    fn drop(&mut self) {
+        if let Err(error) = remove_file(&self.path) {
+            warn!("Failed to remove export manifest {}: {}", self.path, error);
+        }
+    }
+}
+
+/// Everything the export's thread needs to write the archive.
+struct Export {
+    conn: CameraServerDbConn,
+    camera_id: uuid::Uuid,
+    camera_id_string: String,
+    time_range: TimeRange,
+    luminance_filter: LuminanceFilter,
+    /// The on-the-fly watermark to draw on the images, if they need one
+    watermark: Option<ActiveWatermark>,
+    original: bool,
+    /// The camera's images in the time range, before they're filtered by luminance
+    entries: Vec<DirEntry>,
+}
+
+impl Export {
+    /// Works out where an image comes from and whether it needs a watermark drawn on it, and its manifest entry.
+    fn prepare_image(
+        &self,
+        entry: &DirEntry,
+        metadata: Option<ImageMetadata>,
+    ) -> (ExportedImage, ManifestImage) {
+        let image_id = image_id(entry);
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let burned_in = metadata
+            .as_ref()
+            .map_or(false, |metadata| metadata.watermarked);
+
+        let mut exported_image = ExportedImage {
+            path: entry.path(),
+            archive_path: format!("images/{}", file_name),
+            watermark: None,
+        };
+        let mut watermarked = burned_in;
+        if burned_in && self.original {
+            // Images stored before originals were kept only have the watermarked copy
+            let original_path = original_path(&self.camera_id_string, &file_name);
+            if original_path.exists() {
+                exported_image.path = original_path;
+                watermarked = false;
+            }
+        }
+        let stored_format = ImageFormat::from_path(&exported_image.path);
+        match (&self.watermark, stored_format) {
+            // Images that can't be decoded are sent without it, like they are one at a time
+            (Some(watermark), Some(stored_format)) if !burned_in && stored_format.can_decode() => {
+                exported_image.archive_path = format!(
+                    "images/{}.{}",
+                    image_id,
+                    stored_format.edited_format().extension()
+                );
+                exported_image.watermark = Some(ExportWatermark {
+                    text: watermark.text(image_timestamp_ms(&image_id)),
+                    stored_format,
+                    stored_orientation: metadata.as_ref().map(|metadata| metadata.orientation),
+                });
+                watermarked = true;
+            }
+            _ => {}
+        }
+
+        let manifest_image = ManifestImage {
+            captured_at: image_timestamp_ms(&image_id)
+                .map(|timestamp| Utc.timestamp_millis(timestamp as i64).to_rfc3339()),
+            watermarked,
+            metadata,
+            path: exported_image.archive_path.clone(),
+            image_id,
+        };
+        (exported_image, manifest_image)
+    }
+}
+
+/// Collects what the archive is written as into chunks, and hands them to an ArchiveReader on another thread.
+struct ChunkWriter {
+    sender: SyncSender<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn send_chunk(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+        // The reader is only dropped once the client has gone away
+        self.sender
+            .send(Ok(chunk))
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "Client stopped downloading"))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = buf.len().min(CHUNK_SIZE - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..length]);
+        if self.chunk.len() == CHUNK_SIZE {
+            self.send_chunk()?;
+        }
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_chunk()
+    }
+}
+
+/// The body of an export. Reads the chunks written by the export's thread until it finishes.
+struct ArchiveReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl Read for ArchiveReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.chunk.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.receiver.recv() {
+                Ok(chunk) => self.chunk = Cursor::new(chunk?),
+                // The thread has finished and dropped its sender, so that's the end of the archive
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+/// A tar.gz archive that's streamed to the client as it's written.
+pub struct ImageExport {
+    reader: ArchiveReader,
+    file_name: String,
+}
+
+impl<'r> Responder<'r> for ImageExport {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::new("application", "gzip"))
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.file_name),
+            )
+            .chunked_body(self.reader, CHUNK_SIZE as u64)
+            .ok()
+    }
+}
+
+/// Adds an image to the archive. Returns false if it was left out.
+fn append_image<W: Write>(archive: &mut tar::Builder<W>, image: ExportedImage) -> io::Result<bool> {
+    let ExportedImage {
+        path,
+        archive_path,
+        watermark,
+    } = image;
+
+    if let Some(watermark) = watermark {
+        // Leaving it out is better than sending it without the watermark
+        return match watermark.draw(&path) {
+            Some(image) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(image.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(now_ms() / 1000);
+                header.set_cksum();
+                archive.append_data(&mut header, &archive_path, image.as_slice())?;
+                Ok(true)
+            }
+            None => {
+                warn!(
+                    "Left image {} out of export, since its watermark couldn't be drawn",
+                    path.display()
+                );
+                Ok(false)
+            }
+        };
+    }
+
+    match archive.append_path_with_name(&path, &archive_path) {
+        Ok(()) => Ok(true),
+        // Deleted since the export started
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            warn!("Image {} disappeared during export", path.display());
+            Ok(false)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Writes the images to the archive a batch at a time, loading their metadata as it goes, followed by the manifest.
+fn write_archive(
+    writer: ChunkWriter,
+    export: &Export,
+    manifest: &mut ManifestWriter,
+) -> io::Result<()> {
+    let mut archive = tar::Builder::new(GzEncoder::new(writer, Compression::fast()));
+
+    for batch in export.entries.chunks(METADATA_BATCH_SIZE) {
+        let image_ids: Vec<String> = batch.iter().map(image_id).collect();
+        let mut metadata: HashMap<String, ImageMetadata> =
+            image_metadata::get_images_metadata(export.camera_id, &image_ids, &export.conn)
+                .map_err(|error| {
+                    io::Error::new(
+                        ErrorKind::Other,
+                        format!("Failed to get image metadata: {}", error),
+                    )
+                })?
+                .into_iter()
+                .map(|metadata| (metadata.image_id.clone(), metadata))
+                .collect();
+
+        for entry in batch {
+            let image_id = image_id(entry);
+            if !export.luminance_filter.matches(metadata.get(&image_id)) {
+                continue;
+            }
+            let (image, manifest_image) = export.prepare_image(entry, metadata.remove(&image_id));
+            if append_image(&mut archive, image)? {
+                manifest.add_image(&manifest_image)?;
+            }
+        }
+    }
+
+    manifest.finish()?;
+    archive.append_path_with_name(&manifest.path, "manifest.json")?;
+    archive.into_inner()?.finish()?.flush()
+}
+
+/// Returns a tar.gz archive of a camera's images taken between from (inclusive) and to (exclusive), along with a manifest.json
+/// listing each image's metadata. from and to can be milliseconds since the epoch or RFC 3339 dates, and are both optional.
+/// Images can be left out by their luminance the same way as in the image list.
+/// Images have the camera's watermark unless an owner asks for the originals, the same as when they're downloaded one
+/// at a time. Images that can't have a watermark drawn on them are left out.
+/// The archive is written as it's sent, with the manifest at the end, so exports of any size only use a fixed amount of
+/// memory. The image count in the audit log is recorded once it's finished, so it's how many were actually sent.
+#[get(
+    "/cameras/<camera_id_string>/images/export?<from>&<to>&<lighting>&<exclude_dark>&<exclude_overexposed>&<original>"
+)]
+pub fn export_images(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    client_ip: ClientIp,
+    camera_id_string: String,
+    from: Option<String>,
+    to: Option<String>,
+    lighting: Option<String>,
+    exclude_dark: Option<bool>,
+    exclude_overexposed: Option<bool>,
+    original: Option<bool>,
+) -> Result<ImageExport, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ImagesRead)?;
+    check_if_user_has_access_to_camera(&conn, &user_auth, &camera_id_string)?;
+    let original = original.unwrap_or(false);
+    let watermark =
+        watermarks::get_on_the_fly_watermark(&conn, &user_auth, &camera_id_string, original)?;
+
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    let time_range = TimeRange::parse(from.as_deref(), to.as_deref())?;
+    let luminance_filter =
+        LuminanceFilter::parse(lighting.as_deref(), exclude_dark, exclude_overexposed)?;
+
+    let images_directory_path = images_directory();
+    let camera_directory = camera_directory(&images_directory_path, &camera_id_string);
+
+    let entries: Vec<_> = list_camera_directory(&camera_directory, true)?
+        .into_iter()
+        .filter(|entry| time_range.contains(&image_id(entry)))
+        .collect();
+
+    let incoming_directory = format!("{}/{}", images_directory_path, INCOMING_DIRECTORY);
+    let manifest = create_dir_all(&incoming_directory)
+        .and_then(|_| {
+            ManifestWriter::create(
+                format!(
+                    "{}/{}.manifest.tmp",
+                    incoming_directory,
+                    uuid::Uuid::new_v4()
+                ),
+                camera_id,
+                &time_range,
+            )
+        })
+        .map_err(|error| {
+            error!("Failed to create export manifest: {}", error);
+            ApiError {
+                error: "Failed to export images",
+                status: Status::InternalServerError,
+            }
+        })?;
+
+    let audit_event = InsertableAuditEvent {
+        target_camera_id: Some(camera_id),
+        ..InsertableAuditEvent::by_user_auth(AuditAction::ImagesExported, &user_auth, &client_ip)
+    };
+    // The database connection goes with the export, which loads the metadata as it writes the archive
+    let export = Export {
+        conn,
+        camera_id,
+        camera_id_string,
+        time_range,
+        luminance_filter,
+        watermark,
+        original,
+        entries,
+    };
+
+    let (sender, receiver) = sync_channel(MAX_QUEUED_CHUNKS);
+    let writer = ChunkWriter {
+        sender: sender.clone(),
+        chunk: Vec::with_capacity(CHUNK_SIZE),
+    };
+    thread::Builder::new()
+        .name(format!("export-{}", camera_id))
+        .spawn(move || {
+            let mut manifest = manifest;
+            match write_archive(writer, &export, &mut manifest) {
+                Ok(()) => {}
+                Err(error) if error.kind() == ErrorKind::BrokenPipe => {
+                    debug!("Export of camera {} cancelled by the client", camera_id);
+                }
+                Err(error) => {
+                    error!(
+                        "Failed to export images from camera {}: {}",
+                        camera_id, error
+                    );
+                    // Makes the response end with an error instead of looking like a complete archive
+                    let _ = sender.send(Err(error));
+                }
+            }
+
+            audit_log::record(
+                InsertableAuditEvent {
+                    details: Some(json!({
+                        "from": export.time_range.from,
+                        "to": export.time_range.to,
+                        "image_count": manifest.image_count,
+                    })),
+                    ..audit_event
+                },
+                &export.conn,
+            );
+        })
+        .map_err(|error| {
+            error!("Failed to start export thread: {}", error);
+            ApiError {
+                error: "Failed to export images",
+                status: Status::InternalServerError,
+            }
+        })?;
+
+    Ok(ImageExport {
+        reader: ArchiveReader {
+            receiver,
+            chunk: Cursor::new(Vec::new()),
+        },
+        file_name: format!("{}.tar.gz", camera_id),
+    })
+}
@@ -0,0 +1,173 @@
+use crate::{api_error::ApiError, image_metadata::ImageMetadata};
+
+use image::DynamicImage;
+use rocket::http::Status;
+
+/// Images are scaled down to fit in this many pixels square before their luminance is measured.
+const SAMPLE_SIZE: u32 = 256;
+const HISTOGRAM_BINS: usize = 16;
+/// Pixels at or below this luminance count as underexposed, and at or above OVEREXPOSED_LUMINANCE as overexposed.
+const UNDEREXPOSED_LUMINANCE: u8 = 10;
+const OVEREXPOSED_LUMINANCE: u8 = 245;
+/// Mean luminances below these are night and dusk.
+const NIGHT_MAX_MEAN: f64 = 40.0;
+const DUSK_MAX_MEAN: f64 = 100.0;
+/// Frames with at least this fraction of pixels underexposed are dark, and with at least OVEREXPOSED_FRAME_RATIO
+/// overexposed are blown out.
+pub const DARK_FRAME_RATIO: f64 = 0.9;
+pub const OVEREXPOSED_FRAME_RATIO: f64 = 0.5;
+
+/// How light it was when an image was taken, judged by its mean luminance.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Lighting {
+    Day,
+    Dusk,
+    Night,
+}
+
+impl Lighting {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Lighting::Day => "day",
+            Lighting::Dusk => "dusk",
+            Lighting::Night => "night",
+        }
+    }
+
+    pub fn parse(lighting: &str) -> Option<Lighting> {
+        match lighting {
+            "day" => Some(Lighting::Day),
+            "dusk" => Some(Lighting::Dusk),
+            "night" => Some(Lighting::Night),
+            _ => None,
+        }
+    }
+
+    fn from_mean(mean_luminance: f64) -> Lighting {
+        if mean_luminance < NIGHT_MAX_MEAN {
+            Lighting::Night
+        } else if mean_luminance < DUSK_MAX_MEAN {
+            Lighting::Dusk
+        } else {
+            Lighting::Day
+        }
+    }
+}
+
+/// Luminance statistics for an image, stored in its metadata.
+pub struct LuminanceStats {
+    /// 0 to 255
+    pub mean: f64,
+    /// The fraction of pixels in each of 16 equal luminance ranges, darkest first
+    pub histogram: Vec<f64>,
+    /// The fraction of pixels that are nearly black
+    pub underexposed_ratio: f64,
+    /// The fraction of pixels that are nearly white
+    pub overexposed_ratio: f64,
+    pub lighting: Lighting,
+}
+
+impl LuminanceStats {
+    pub fn of(image: &DynamicImage) -> LuminanceStats {
+        let sample = image.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE).to_luma8();
+        let pixel_count = (sample.width() * sample.height()).max(1) as f64;
+
+        let mut histogram = vec![0_u32; HISTOGRAM_BINS];
+        let mut total = 0_u64;
+        let mut underexposed = 0_u32;
+        let mut overexposed = 0_u32;
+        for pixel in sample.pixels() {
+            let luminance = pixel[0];
+            histogram[luminance as usize * HISTOGRAM_BINS / 256] += 1;
+            total += luminance as u64;
+            if luminance <= UNDEREXPOSED_LUMINANCE {
+                underexposed += 1;
+            } else if luminance >= OVEREXPOSED_LUMINANCE {
+                overexposed += 1;
+            }
+        }
+
+        let mean = total as f64 / pixel_count;
+        LuminanceStats {
+            mean,
+            histogram: histogram
+                .into_iter()
+                .map(|count| count as f64 / pixel_count)
+                .collect(),
+            underexposed_ratio: underexposed as f64 / pixel_count,
+            overexposed_ratio: overexposed as f64 / pixel_count,
+            lighting: Lighting::from_mean(mean),
+        }
+    }
+}
+
+/// Which images to include, going by their luminance. Used by the image list and export.
+/// Images without luminance statistics (stored before they were added, or that can't be decoded) are always included,
+/// since there's no telling what they look like.
+#[derive(Default)]
+pub struct LuminanceFilter {
+    /// Only images with one of these lightings, or any lighting if None
+    pub lighting: Option<Vec<Lighting>>,
+    /// Leaves out frames that are almost entirely black
+    pub exclude_dark: bool,
+    /// Leaves out frames that are mostly blown out
+    pub exclude_overexposed: bool,
+}
+
+impl LuminanceFilter {
+    /// Parses the filter from query parameters. lighting is a comma separated list, like "day,dusk".
+    pub fn parse(
+        lighting: Option<&str>,
+        exclude_dark: Option<bool>,
+        exclude_overexposed: Option<bool>,
+    ) -> Result<LuminanceFilter, ApiError> {
+        let lighting = match lighting {
+            Some(lighting) => Some(
+                lighting
+                    .split(',')
+                    .map(|lighting| Lighting::parse(lighting.trim()))
+                    .collect::<Option<Vec<Lighting>>>()
+                    .ok_or(ApiError {
+                        error: "Lighting must be day, dusk or night",
+                        status: Status::BadRequest,
+                    })?,
+            ),
+            None => None,
+        };
+
+        Ok(LuminanceFilter {
+            lighting,
+            exclude_dark: exclude_dark.unwrap_or(false),
+            exclude_overexposed: exclude_overexposed.unwrap_or(false),
+        })
+    }
+
+    /// Whether the filter lets every image through, in which case the metadata doesn't need loading.
+    pub fn is_unfiltered(&self) -> bool {
+        self.lighting.is_none() && !self.exclude_dark && !self.exclude_overexposed
+    }
+
+    /// Whether an image with the given metadata is included. Images without metadata are.
+    pub fn matches(&self, metadata: Option<&ImageMetadata>) -> bool {
+        let metadata = match metadata {
+            Some(metadata) => metadata,
+            None => return true,
+        };
+
+        let lighting = metadata.lighting.as_deref().and_then(Lighting::parse);
+        let lighting_matches = match (&self.lighting, lighting) {
+            (Some(allowed), Some(lighting)) => allowed.contains(&lighting),
+            _ => true,
+        };
+        let is_dark = self.exclude_dark
+            && metadata
+                .underexposed_ratio
+                .map_or(false, |ratio| ratio >= DARK_FRAME_RATIO);
+        let is_overexposed = self.exclude_overexposed
+            && metadata
+                .overexposed_ratio
+                .map_or(false, |ratio| ratio >= OVEREXPOSED_FRAME_RATIO);
+
+        lighting_matches && !is_dark && !is_overexposed
+    }
+}
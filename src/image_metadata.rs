@@ -0,0 +1,298 @@
+use super::schema::{image_metadata, trashed_images};
+use crate::image_exif::ExifData;
+use crate::image_luminance::{self, Lighting, LuminanceFilter};
+use crate::image_similarity;
+use crate::image_store::ImageAnalysis;
+use chrono::NaiveDateTime;
+use diesel::dsl::sql;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Bool, Integer};
+use diesel::{self};
+use serde::{Deserialize, Serialize};
+
+/// Details about a stored image that can't be worked out from its file name.
+/// Images uploaded before this table was added don't have a row.
+#[derive(Queryable, Deserialize, Serialize)]
+pub struct ImageMetadata {
+    pub camera_id: uuid::Uuid,
+    pub image_id: String,
+    pub content_type: String,
+    pub size_bytes: i64,
+    /// When the image was uploaded
+    pub created_at: NaiveDateTime,
+    /// The rest are read from the image's EXIF data. See ExifData for what they mean.
+    pub captured_at: Option<NaiveDateTime>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub exposure_time: Option<String>,
+    pub f_number: Option<f64>,
+    pub iso: Option<i32>,
+    pub focal_length: Option<f64>,
+    pub orientation: Option<i16>,
+    pub gps_latitude: Option<f64>,
+    pub gps_longitude: Option<f64>,
+    pub gps_altitude: Option<f64>,
+    /// Hex SHA-256 of the file
+    pub content_hash: Option<String>,
+    /// The image's dHash, sent as hex. None for formats that can't be decoded.
+    #[serde(
+        serialize_with = "image_similarity::serialize_hash",
+        skip_deserializing
+    )]
+    pub perceptual_hash: Option<i64>,
+    /// The perceptual hash split up for indexing. See image_similarity.
+    #[serde(skip)]
+    pub perceptual_hash_0: Option<i32>,
+    #[serde(skip)]
+    pub perceptual_hash_1: Option<i32>,
+    #[serde(skip)]
+    pub perceptual_hash_2: Option<i32>,
+    #[serde(skip)]
+    pub perceptual_hash_3: Option<i32>,
+    /// Luminance statistics. See LuminanceStats for what they mean.
+    pub mean_luminance: Option<f64>,
+    pub luminance_histogram: Option<Vec<f64>>,
+    pub underexposed_ratio: Option<f64>,
+    pub overexposed_ratio: Option<f64>,
+    /// "day", "dusk" or "night"
+    pub lighting: Option<String>,
+    /// The version of the camera's privacy masks that was applied, or None if it had none
+    pub privacy_mask_version: Option<i32>,
+    /// Whether the camera's watermark was burned into the image when it was stored
+    pub watermarked: bool,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[table_name = "image_metadata"]
+pub struct InsertableImageMetadata {
+    pub camera_id: uuid::Uuid,
+    pub image_id: String,
+    pub content_type: String,
+    pub size_bytes: i64,
+    pub captured_at: Option<NaiveDateTime>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub exposure_time: Option<String>,
+    pub f_number: Option<f64>,
+    pub iso: Option<i32>,
+    pub focal_length: Option<f64>,
+    pub orientation: Option<i16>,
+    pub gps_latitude: Option<f64>,
+    pub gps_longitude: Option<f64>,
+    pub gps_altitude: Option<f64>,
+    pub content_hash: Option<String>,
+    pub perceptual_hash: Option<i64>,
+    pub perceptual_hash_0: Option<i32>,
+    pub perceptual_hash_1: Option<i32>,
+    pub perceptual_hash_2: Option<i32>,
+    pub perceptual_hash_3: Option<i32>,
+    pub mean_luminance: Option<f64>,
+    pub luminance_histogram: Option<Vec<f64>>,
+    pub underexposed_ratio: Option<f64>,
+    pub overexposed_ratio: Option<f64>,
+    pub lighting: Option<String>,
+    pub privacy_mask_version: Option<i32>,
+    pub watermarked: bool,
+}
+
+impl InsertableImageMetadata {
+    pub fn new(
+        camera_id: uuid::Uuid,
+        image_id: String,
+        content_type: String,
+        size_bytes: i64,
+        content_hash: String,
+        exif: ExifData,
+        analysis: Option<ImageAnalysis>,
+    ) -> InsertableImageMetadata {
+        let perceptual_hash = analysis.as_ref().map(|analysis| analysis.perceptual_hash);
+        let chunks = perceptual_hash.map(|hash| hash.chunks());
+        let luminance = analysis.map(|analysis| analysis.luminance);
+        InsertableImageMetadata {
+            camera_id,
+            image_id,
+            content_type,
+            size_bytes,
+            captured_at: exif.captured_at,
+            camera_make: exif.camera_make,
+            camera_model: exif.camera_model,
+            exposure_time: exif.exposure_time,
+            f_number: exif.f_number,
+            iso: exif.iso,
+            focal_length: exif.focal_length,
+            orientation: exif.orientation,
+            gps_latitude: exif.gps_latitude,
+            gps_longitude: exif.gps_longitude,
+            gps_altitude: exif.gps_altitude,
+            content_hash: Some(content_hash),
+            perceptual_hash: perceptual_hash.map(|hash| hash.0 as i64),
+            perceptual_hash_0: chunks.map(|chunks| chunks[0]),
+            perceptual_hash_1: chunks.map(|chunks| chunks[1]),
+            perceptual_hash_2: chunks.map(|chunks| chunks[2]),
+            perceptual_hash_3: chunks.map(|chunks| chunks[3]),
+            mean_luminance: luminance.as_ref().map(|luminance| luminance.mean),
+            underexposed_ratio: luminance
+                .as_ref()
+                .map(|luminance| luminance.underexposed_ratio),
+            overexposed_ratio: luminance
+                .as_ref()
+                .map(|luminance| luminance.overexposed_ratio),
+            lighting: luminance
+                .as_ref()
+                .map(|luminance| luminance.lighting.as_str().to_string()),
+            luminance_histogram: luminance.map(|luminance| luminance.histogram),
+            privacy_mask_version: None,
+            watermarked: false,
+        }
+    }
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<ImageMetadata>> {
+    image_metadata::table.load::<ImageMetadata>(&*connection)
+}
+
+pub fn get(
+    camera_id: uuid::Uuid,
+    image_id: &str,
+    connection: &PgConnection,
+) -> QueryResult<ImageMetadata> {
+    image_metadata::table
+        .find((camera_id, image_id))
+        .get_result::<ImageMetadata>(connection)
+}
+
+pub fn insert(
+    metadata: InsertableImageMetadata,
+    connection: &PgConnection,
+) -> QueryResult<ImageMetadata> {
+    diesel::insert_into(image_metadata::table)
+        .values(metadata)
+        .get_result(connection)
+}
+
+pub fn update(
+    camera_id: uuid::Uuid,
+    image_id: &str,
+    metadata: InsertableImageMetadata,
+    connection: &PgConnection,
+) -> QueryResult<ImageMetadata> {
+    diesel::update(image_metadata::table.find((camera_id, image_id)))
+        .set(&metadata)
+        .get_result(connection)
+}
+
+pub fn delete(
+    camera_id: uuid::Uuid,
+    image_id: &str,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::delete(image_metadata::table.find((camera_id, image_id))).execute(connection)
+}
+
+/// Returns the metadata of the given images from a camera. Images without metadata are left out.
+pub fn get_images_metadata(
+    camera_id: uuid::Uuid,
+    image_ids: &[String],
+    connection: &PgConnection,
+) -> QueryResult<Vec<ImageMetadata>> {
+    image_metadata::table
+        .filter(image_metadata::camera_id.eq(camera_id))
+        .filter(image_metadata::image_id.eq_any(image_ids))
+        .load::<ImageMetadata>(connection)
+}
+
+/// Returns the IDs and content types of a camera's images with the given content hash, oldest first.
+pub fn get_images_with_hash(
+    camera_id: uuid::Uuid,
+    content_hash: &str,
+    connection: &PgConnection,
+) -> QueryResult<Vec<(String, String)>> {
+    image_metadata::table
+        .filter(image_metadata::camera_id.eq(camera_id))
+        .filter(image_metadata::content_hash.eq(content_hash))
+        .order(image_metadata::created_at.asc())
+        .select((image_metadata::image_id, image_metadata::content_type))
+        .load(connection)
+}
+
+/// Returns the IDs of a camera's images that the filter leaves out. Images without metadata, or without the statistic
+/// a filter looks at, are never left out (see LuminanceFilter), so the list is only of images that have them.
+pub fn get_excluded_image_ids(
+    camera_id: uuid::Uuid,
+    filter: &LuminanceFilter,
+    connection: &PgConnection,
+) -> QueryResult<Vec<String>> {
+    // NULL statistics make their comparisons NULL, which doesn't count as excluded
+    let mut excluded: Box<dyn BoxableExpression<image_metadata::table, Pg, SqlType = Bool>> =
+        Box::new(false.into_sql::<Bool>());
+    if let Some(lighting) = &filter.lighting {
+        let lighting: Vec<&str> = lighting.iter().map(Lighting::as_str).collect();
+        excluded = Box::new(excluded.or(image_metadata::lighting.ne_all(lighting)));
+    }
+    if filter.exclude_dark {
+        excluded = Box::new(
+            excluded.or(image_metadata::underexposed_ratio.ge(image_luminance::DARK_FRAME_RATIO)),
+        );
+    }
+    if filter.exclude_overexposed {
+        excluded = Box::new(
+            excluded
+                .or(image_metadata::overexposed_ratio.ge(image_luminance::OVEREXPOSED_FRAME_RATIO)),
+        );
+    }
+
+    image_metadata::table
+        .filter(image_metadata::camera_id.eq(camera_id))
+        .filter(excluded)
+        .select(image_metadata::image_id)
+        .load(connection)
+}
+
+/// Returns the IDs of a camera's images whose perceptual hash is within max_distance bits of the given one, with their
+/// distances, closest first and at most limit of them. Only images with any of the given values in the matching hash
+/// chunk are looked at, so the chunk indexes narrow the search down before any distances are worked out.
+/// Images in the trash and exclude_image_id are left out.
+pub fn get_similar_images(
+    camera_id: uuid::Uuid,
+    hash: i64,
+    chunk_values: [Vec<i32>; 4],
+    max_distance: i32,
+    exclude_image_id: Option<&str>,
+    limit: i64,
+    connection: &PgConnection,
+) -> QueryResult<Vec<(String, i32)>> {
+    // bit_count() is only in Postgres 14 and later, so the differing bits are counted from the XOR's text instead
+    let distance = || {
+        sql::<Integer>("length(replace(((image_metadata.perceptual_hash # ")
+            .bind::<BigInt, _>(hash)
+            .sql(")::bit(64))::text, '0', ''))")
+    };
+    let [values_0, values_1, values_2, values_3] = chunk_values;
+    let mut query = image_metadata::table
+        .filter(image_metadata::camera_id.eq(camera_id))
+        .filter(
+            image_metadata::image_id.ne_all(
+                trashed_images::table
+                    .filter(trashed_images::camera_id.eq(camera_id))
+                    .select(trashed_images::image_id),
+            ),
+        )
+        .filter(
+            image_metadata::perceptual_hash_0
+                .eq_any(values_0)
+                .or(image_metadata::perceptual_hash_1.eq_any(values_1))
+                .or(image_metadata::perceptual_hash_2.eq_any(values_2))
+                .or(image_metadata::perceptual_hash_3.eq_any(values_3)),
+        )
+        .filter(distance().le(max_distance))
+        .select((image_metadata::image_id, distance()))
+        .into_boxed();
+    if let Some(exclude_image_id) = exclude_image_id {
+        query = query.filter(image_metadata::image_id.ne(exclude_image_id));
+    }
+    query
+        .order((distance().asc(), image_metadata::image_id.asc()))
+        .limit(limit)
+        .load(connection)
+}
@@ -0,0 +1,221 @@
+use crate::{
+    api_error::ApiError,
+    api_keys::ApiKeyScope,
+    camera::{find_image, parse_camera_id},
+    image_metadata,
+    image_store::{self, ImageFormat, UploadConfig},
+    user_auth::UserAuth,
+    users_cameras::check_if_user_has_access_to_camera,
+    CameraServerDbConn,
+};
+
+use diesel::PgConnection;
+use image::DynamicImage;
+use rocket::{get, http::ContentType, http::Status, post, Data, State};
+use rocket_contrib::json::Json;
+use serde::{Serialize, Serializer};
+
+const DEFAULT_MAX_DISTANCE: u32 = 8;
+/// Past this, the chunks have to be searched so widely that most of the camera's images would be candidates anyway.
+const MAX_MAX_DISTANCE: u32 = 15;
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+/// A 64 bit dHash. Each bit is whether a pixel is darker than the one to its right, in a 9x8 grayscale copy of the image.
+/// Images that look alike have hashes that differ in only a few bits, even after resizing, recompression or small
+/// changes in lighting.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PerceptualHash(pub u64);
+
+impl PerceptualHash {
+    pub fn of(image: &DynamicImage) -> PerceptualHash {
+        let small = image.thumbnail_exact(9, 8).to_luma8();
+        let mut hash = 0;
+        for y in 0..8 {
+            for x in 0..8 {
+                hash <<= 1;
+                if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                    hash |= 1;
+                }
+            }
+        }
+        PerceptualHash(hash)
+    }
+
+    /// The hash split into four 16 bit chunks, most significant first, for the indexed columns.
+    pub fn chunks(self) -> [i32; 4] {
+        [
+            (self.0 >> 48) as u16 as i32,
+            (self.0 >> 32) as u16 as i32,
+            (self.0 >> 16) as u16 as i32,
+            self.0 as u16 as i32,
+        ]
+    }
+}
+
+/// Sends stored perceptual hashes as 16 hex digits.
+pub fn serialize_hash<S: Serializer>(hash: &Option<i64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match hash {
+        Some(hash) => serializer.serialize_str(&format!("{:016x}", *hash as u64)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Every 16 bit value within the given Hamming distance of the chunk.
+fn chunk_neighbours(chunk: i32, distance: u32) -> Vec<i32> {
+    (0..=u16::MAX as i32)
+        .filter(|value| ((value ^ chunk) as u32).count_ones() <= distance)
+        .collect()
+}
+
+/// An image that looks like the one searched for.
+#[derive(Serialize)]
+pub struct SimilarImage {
+    pub image_id: String,
+    /// Hamming distance between the perceptual hashes. Lower is more alike.
+    pub distance: u32,
+}
+
+/// Finds a camera's images whose perceptual hash is within max_distance of the given one, closest first.
+/// If two hashes are within distance d of each other, at least one of their four chunks is within d / 4 (by the
+/// pigeonhole principle), so only images with a chunk near one of the hash's chunks need to be looked at. Those are
+/// found with the chunk indexes, which keeps searches fast however many images the camera has. The distances are
+/// worked out and sorted by the database, so the closest images are found even when lots of them are near each other.
+/// Images in the trash still have metadata, so the query leaves them out.
+fn find_similar_images(
+    camera_id: uuid::Uuid,
+    hash: PerceptualHash,
+    max_distance: u32,
+    limit: usize,
+    exclude_image_id: Option<&str>,
+    connection: &PgConnection,
+) -> Result<Vec<SimilarImage>, ApiError> {
+    let chunk_distance = max_distance / 4;
+    let [chunk_0, chunk_1, chunk_2, chunk_3] = hash.chunks();
+    let similar_images = image_metadata::get_similar_images(
+        camera_id,
+        hash.0 as i64,
+        [
+            chunk_neighbours(chunk_0, chunk_distance),
+            chunk_neighbours(chunk_1, chunk_distance),
+            chunk_neighbours(chunk_2, chunk_distance),
+            chunk_neighbours(chunk_3, chunk_distance),
+        ],
+        max_distance as i32,
+        exclude_image_id,
+        limit as i64,
+        connection,
+    )
+    .map_err(|error| {
+        error!(
+            "Failed to search for similar images from camera {}: {}",
+            camera_id, error
+        );
+        ApiError {
+            error: "Failed to search for similar images",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    Ok(similar_images
+        .into_iter()
+        .map(|(image_id, distance)| SimilarImage {
+            image_id,
+            distance: distance as u32,
+        })
+        .collect())
+}
+
+fn search_options(max_distance: Option<u32>, limit: Option<usize>) -> (u32, usize) {
+    (
+        max_distance
+            .unwrap_or(DEFAULT_MAX_DISTANCE)
+            .min(MAX_MAX_DISTANCE),
+        limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT),
+    )
+}
+
+fn unhashable_image() -> ApiError {
+    ApiError {
+        error: "Images in this format can't be compared",
+        status: Status::UnprocessableEntity,
+    }
+}
+
+/// Returns a camera's images that look like one of its other images, closest first.
+/// max_distance is how many bits of the perceptual hashes can differ (at most 15), and limit is how many images to return.
+#[get("/cameras/<camera_id_string>/images/<image_id_string>/similar?<max_distance>&<limit>")]
+pub fn get_similar_images(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    camera_id_string: String,
+    image_id_string: String,
+    max_distance: Option<u32>,
+    limit: Option<usize>,
+) -> Result<Json<Vec<SimilarImage>>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ImagesRead)?;
+    check_if_user_has_access_to_camera(&conn, &user_auth, &camera_id_string)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+    let (max_distance, limit) = search_options(max_distance, limit);
+
+    let path = find_image(&camera_id_string, &image_id_string)?;
+    let stored_hash = image_metadata::get(camera_id, &image_id_string, &conn)
+        .ok()
+        .and_then(|metadata| metadata.perceptual_hash);
+    let hash = match stored_hash {
+        Some(hash) => PerceptualHash(hash as u64),
+        // Images stored before hashes were added get hashed now
+        None => {
+            let format = ImageFormat::from_path(&path).ok_or_else(unhashable_image)?;
+            let image =
+                image_store::decode_stored_image(&path, format)?.ok_or_else(unhashable_image)?;
+            PerceptualHash::of(&image)
+        }
+    };
+
+    find_similar_images(
+        camera_id,
+        hash,
+        max_distance,
+        limit,
+        Some(&image_id_string),
+        &conn,
+    )
+    .map(Json)
+}
+
+/// Returns a camera's images that look like the image in the body, closest first. The image isn't stored.
+/// Takes the same options as get_similar_images().
+#[post(
+    "/cameras/<camera_id_string>/images/similar?<max_distance>&<limit>",
+    data = "<image>"
+)]
+pub fn search_similar_images(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    content_type: Option<&ContentType>,
+    upload_config: State<UploadConfig>,
+    image: Data,
+    camera_id_string: String,
+    max_distance: Option<u32>,
+    limit: Option<usize>,
+) -> Result<Json<Vec<SimilarImage>>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ImagesRead)?;
+    check_if_user_has_access_to_camera(&conn, &user_auth, &camera_id_string)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+    let (max_distance, limit) = search_options(max_distance, limit);
+
+    image_store::check_upload_content_type(content_type)?;
+    let image = image_store::decode_upload(&mut image.open(), &upload_config)?
+        .ok_or_else(unhashable_image)?;
+
+    find_similar_images(
+        camera_id,
+        PerceptualHash::of(&image),
+        max_distance,
+        limit,
+        None,
+        &conn,
+    )
+    .map(Json)
+}
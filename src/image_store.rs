@@ -0,0 +1,1375 @@
+use crate::{
+    api_error::ApiError,
+    camera::images_directory,
+    http_cache::{format_http_date, is_not_modified, requested_range, CachePolicy, RequestedRange},
+    image_dedup::{self, DuplicatePolicy},
+    image_exif::{apply_orientation, read_exif},
+    image_luminance::LuminanceStats,
+    image_metadata::{self, ImageMetadata, InsertableImageMetadata},
+    image_similarity::PerceptualHash,
+    image_trash,
+    privacy_masks::{self, ActiveMasks},
+    storage_quota::{self, QuotaConfig},
+    watermarks::{self, WatermarkText},
+};
+
+use chrono::{DateTime, Utc};
+use diesel::PgConnection;
+use image::{DynamicImage, ImageError};
+use rocket::{
+    fairing::AdHoc,
+    http::{Accept, ContentType, MediaType, Status},
+    request::{self, FromRequest},
+    response::{self, Body, Responder, ResponseBuilder},
+    Outcome, Request, Response,
+};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fs::{create_dir_all, hard_link, remove_file, rename, DirEntry, File};
+use std::io::{self, BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Header cameras use to say when an image was taken, either as milliseconds since the epoch or as an RFC 3339 date.
+pub const CAPTURE_TIMESTAMP_HEADER: &str = "X-Capture-Timestamp";
+
+/// Capture timestamps this far ahead of the server's clock are still accepted, to allow for a bit of clock drift.
+const MAX_CLOCK_SKEW_MS: u64 = 5 * 60 * 1000;
+/// 2000-01-01. Anything earlier is almost certainly a timestamp in seconds instead of milliseconds.
+const MIN_CAPTURE_TIMESTAMP_MS: u64 = 946_684_800_000;
+/// How many names, a millisecond apart, are tried for an image before giving up.
+const MAX_NAME_COLLISIONS: u64 = 1000;
+
+/// Uploads are written here first and only moved into the camera's directory once they've been checked.
+/// It's inside IMAGES_DIRECTORY so that the move is on the same filesystem, and it's skipped when scanning for cameras.
+pub const INCOMING_DIRECTORY: &str = ".incoming";
+
+/// Images with a burned-in watermark have a copy without it kept here, under a directory for each camera, so that owners
+/// can still get the original. Copies have the same file name as the image, stay while it's in the trash and are
+/// deleted with it. They don't count towards storage quotas.
+pub const ORIGINALS_DIRECTORY: &str = ".originals";
+
+const DEFAULT_MAX_IMAGE_SIZE_MB: u64 = 10;
+/// Images with more pixels than this are rejected before decoding, so that a small file can't use up all the memory.
+const MAX_IMAGE_PIXELS: u64 = 100_000_000;
+/// Quality used when converting images to JPEG for clients that don't accept the stored format.
+const JPEG_QUALITY: u8 = 90;
+
+/// Settings for uploads, read from the uploads table in Rocket.toml.
+pub struct UploadConfig {
+    pub max_image_bytes: u64,
+    pub duplicates: DuplicatePolicy,
+    pub quotas: QuotaConfig,
+}
+
+/// Manages the UploadConfig, including the storage quotas.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Upload Config", |rocket| {
+        let max_image_size_mb = rocket
+            .config()
+            .get_table("uploads")
+            .ok()
+            .and_then(|table| table.get("max_image_size_mb"))
+            .and_then(|value| value.as_integer())
+            .map(|value| value.max(0) as u64)
+            .unwrap_or(DEFAULT_MAX_IMAGE_SIZE_MB);
+        let duplicates = DuplicatePolicy::from_config(rocket.config());
+        let quotas = QuotaConfig::from_config(rocket.config());
+
+        Ok(rocket.manage(UploadConfig {
+            max_image_bytes: max_image_size_mb * 1024 * 1024,
+            duplicates,
+            quotas,
+        }))
+    })
+}
+
+/// The image formats that can be uploaded.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    WebP,
+    Avif,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 4] = [
+        ImageFormat::Jpeg,
+        ImageFormat::Png,
+        ImageFormat::WebP,
+        ImageFormat::Avif,
+    ];
+
+    /// Works out the format from the first few bytes of the file, ignoring whatever the client said it was.
+    pub fn sniff(header: &[u8]) -> Option<ImageFormat> {
+        if header.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(ImageFormat::Jpeg)
+        } else if header.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+            Some(ImageFormat::Png)
+        } else if header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WEBP" {
+            Some(ImageFormat::WebP)
+        } else if is_avif(header) {
+            Some(ImageFormat::Avif)
+        } else {
+            None
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<ImageFormat> {
+        ImageFormat::ALL
+            .iter()
+            .find(|format| format.content_type().to_string() == content_type)
+            .cloned()
+    }
+
+    pub fn from_media_type(media_type: &MediaType) -> Option<ImageFormat> {
+        ImageFormat::ALL
+            .iter()
+            .find(|format| format.content_type().media_type() == media_type)
+            .cloned()
+    }
+
+    pub fn from_path(path: &Path) -> Option<ImageFormat> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "jpeg" => Some(ImageFormat::Jpeg),
+            _ => ImageFormat::ALL
+                .iter()
+                .find(|format| format.extension() == extension)
+                .cloned(),
+        }
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            ImageFormat::Jpeg => ContentType::JPEG,
+            ImageFormat::Png => ContentType::PNG,
+            ImageFormat::WebP => ContentType::WEBP,
+            ImageFormat::Avif => ContentType::new("image", "avif"),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+            ImageFormat::WebP => "webp",
+            ImageFormat::Avif => "avif",
+        }
+    }
+
+    /// False for AVIF. Lossless WebP can't be decoded either, but that's only found out by trying.
+    pub fn can_decode(&self) -> bool {
+        self.decoder_format().is_some()
+    }
+
+    /// The format an edited copy of an image is encoded in. Only JPEG and PNG can be encoded, so anything else becomes PNG.
+    pub fn edited_format(&self) -> ImageFormat {
+        match self {
+            ImageFormat::Jpeg => ImageFormat::Jpeg,
+            _ => ImageFormat::Png,
+        }
+    }
+
+    /// The format to decode with. AVIF can't be decoded, so only its container is checked on upload, and it can't be converted.
+    fn decoder_format(&self) -> Option<image::ImageFormat> {
+        match self {
+            ImageFormat::Jpeg => Some(image::ImageFormat::Jpeg),
+            ImageFormat::Png => Some(image::ImageFormat::Png),
+            ImageFormat::WebP => Some(image::ImageFormat::WebP),
+            ImageFormat::Avif => None,
+        }
+    }
+
+    /// Images can be converted to these formats when the client doesn't accept the stored one.
+    fn encoder_format(&self) -> Option<image::ImageOutputFormat> {
+        match self {
+            ImageFormat::Jpeg => Some(image::ImageOutputFormat::Jpeg(JPEG_QUALITY)),
+            ImageFormat::Png => Some(image::ImageOutputFormat::Png),
+            ImageFormat::WebP | ImageFormat::Avif => None,
+        }
+    }
+}
+
+/// Uploads have to be labelled with one of the allowed image types, though the label isn't trusted beyond that.
+pub fn check_upload_content_type(content_type: Option<&ContentType>) -> Result<(), ApiError> {
+    match content_type
+        .and_then(|content_type| ImageFormat::from_media_type(content_type.media_type()))
+    {
+        Some(_) => Ok(()),
+        None => Err(ApiError {
+            error: "Content-Type must be image/jpeg, image/png, image/webp or image/avif",
+            status: Status::UnsupportedMediaType,
+        }),
+    }
+}
+
+/// AVIF files are ISO base media files with avif (still image) or avis (image sequence) in their ftyp box's brands.
+fn is_avif(header: &[u8]) -> bool {
+    if header.len() < 12 || &header[4..8] != b"ftyp" {
+        return false;
+    }
+    let box_size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let brands_end = box_size.min(header.len());
+
+    // The major brand, then the compatible brands after the minor version
+    std::iter::once(&header[8..12])
+        .chain(header.get(16..brands_end).unwrap_or(&[]).chunks_exact(4))
+        .any(|brand| brand == b"avif" || brand == b"avis")
+}
+
+/// Request guard for the X-Capture-Timestamp header. Never fails, the value is checked by resolve() in the route.
+pub struct CaptureTimestamp(pub Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for CaptureTimestamp {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(CaptureTimestamp(
+            request
+                .headers()
+                .get_one(CAPTURE_TIMESTAMP_HEADER)
+                .map(String::from),
+        ))
+    }
+}
+
+impl CaptureTimestamp {
+    /// Returns the capture time in milliseconds since the epoch, or the current time if the camera didn't send one.
+    pub fn resolve(&self) -> Result<u64, ApiError> {
+        match &self.0 {
+            Some(capture_timestamp) => parse_capture_timestamp(capture_timestamp),
+            None => Ok(now_ms()),
+        }
+    }
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Failed to get current time somehow?")
+        .as_millis() as u64
+}
+
+/// Parses milliseconds since the epoch or an RFC 3339 date into milliseconds since the epoch.
+pub fn parse_timestamp_ms(timestamp: &str) -> Option<u64> {
+    let timestamp = timestamp.trim();
+    match timestamp.parse::<u64>() {
+        Ok(timestamp_ms) => Some(timestamp_ms),
+        Err(_) => DateTime::parse_from_rfc3339(timestamp)
+            .ok()
+            .filter(|date| date.timestamp_millis() >= 0)
+            .map(|date| date.timestamp_millis() as u64),
+    }
+}
+
+/// Parses a capture timestamp sent by a camera, as milliseconds since the epoch or an RFC 3339 date.
+pub fn parse_capture_timestamp(capture_timestamp: &str) -> Result<u64, ApiError> {
+    let timestamp_ms = parse_timestamp_ms(capture_timestamp).ok_or(ApiError {
+        error: "Capture timestamp must be milliseconds since the epoch or an RFC 3339 date",
+        status: Status::BadRequest,
+    })?;
+
+    if timestamp_ms < MIN_CAPTURE_TIMESTAMP_MS {
+        return Err(ApiError {
+            error: "Capture timestamp is too old, it should be in milliseconds",
+            status: Status::BadRequest,
+        });
+    }
+    if timestamp_ms > now_ms() + MAX_CLOCK_SKEW_MS {
+        return Err(ApiError {
+            error: "Capture timestamp is in the future",
+            status: Status::BadRequest,
+        });
+    }
+
+    Ok(timestamp_ms)
+}
+
+pub fn originals_directory(images_directory_path: &String, camera_id_string: &String) -> String {
+    format!(
+        "{}/{}/{}",
+        images_directory_path, ORIGINALS_DIRECTORY, camera_id_string
+    )
+}
+
+/// Where the copy of a stored image without its burned-in watermark is kept, whether or not there is one.
+pub fn original_path(camera_id_string: &str, file_name: &str) -> PathBuf {
+    Path::new(&originals_directory(
+        &images_directory(),
+        &camera_id_string.to_string(),
+    ))
+    .join(file_name)
+}
+
+/// Deletes the copy of an image kept without its burned-in watermark, once the image itself has been deleted for good.
+pub fn remove_original(camera_id_string: &str, file_name: &str) {
+    let path = original_path(camera_id_string, file_name);
+    match remove_file(&path) {
+        Ok(()) => {}
+        // Most images don't have one
+        Err(error) if error.kind() == ErrorKind::NotFound => {}
+        Err(error) => warn!(
+            "Failed to delete original of image {}: {}",
+            path.display(),
+            error
+        ),
+    }
+}
+
+/// Returns the image ID of a file in a camera directory, which is its name without the extension.
+pub fn image_id(entry: &DirEntry) -> String {
+    Path::new(&entry.file_name())
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Images are ordered by their ID as a number, so that IDs in seconds from before capture timestamps were
+/// added still sort before the newer ones in milliseconds. Anything that isn't a number goes last.
+pub fn sort_images(images: &mut Vec<DirEntry>) {
+    images.sort_by_cached_key(|entry| {
+        let image_id = image_id(entry);
+        (image_id.parse::<u64>().unwrap_or(u64::MAX), image_id)
+    });
+}
+
+/// When an image was taken, in milliseconds since the epoch, worked out from its ID.
+pub fn image_timestamp_ms(image_id: &str) -> Option<u64> {
+    let timestamp = image_id.parse::<u64>().ok()?;
+    // IDs from before capture timestamps were added are in seconds
+    if timestamp < MIN_CAPTURE_TIMESTAMP_MS {
+        Some(timestamp * 1000)
+    } else {
+        Some(timestamp)
+    }
+}
+
+/// A range of capture times to pick images by, from from (inclusive) to to (exclusive). Either end can be left open.
+#[derive(Clone, Copy)]
+pub struct TimeRange {
+    /// Milliseconds since the epoch
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+impl TimeRange {
+    /// Parses from and to as milliseconds since the epoch or RFC 3339 dates.
+    pub fn parse(from: Option<&str>, to: Option<&str>) -> Result<TimeRange, ApiError> {
+        let parse_bound = |bound: Option<&str>| {
+            bound
+                .map(|bound| {
+                    parse_timestamp_ms(bound).ok_or(ApiError {
+                        error: "from and to must be milliseconds since the epoch or RFC 3339 dates",
+                        status: Status::BadRequest,
+                    })
+                })
+                .transpose()
+        };
+        let time_range = TimeRange {
+            from: parse_bound(from)?,
+            to: parse_bound(to)?,
+        };
+
+        if let (Some(from), Some(to)) = (time_range.from, time_range.to) {
+            if from > to {
+                return Err(ApiError {
+                    error: "from must be before to",
+                    status: Status::BadRequest,
+                });
+            }
+        }
+        Ok(time_range)
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.from.is_none() && self.to.is_none()
+    }
+
+    /// Whether the image was taken within the range. Images with IDs that aren't timestamps are only in unbounded ranges.
+    pub fn contains(&self, image_id: &str) -> bool {
+        let timestamp = image_timestamp_ms(image_id);
+        self.from
+            .map_or(true, |from| timestamp.map_or(false, |x| x >= from))
+            && self
+                .to
+                .map_or(true, |to| timestamp.map_or(false, |x| x < to))
+    }
+}
+
+/// What's worked out from an image's pixels when it's stored.
+pub struct ImageAnalysis {
+    pub perceptual_hash: PerceptualHash,
+    pub luminance: LuminanceStats,
+}
+
+impl ImageAnalysis {
+    pub fn of(image: &DynamicImage) -> ImageAnalysis {
+        ImageAnalysis {
+            perceptual_hash: PerceptualHash::of(image),
+            luminance: LuminanceStats::of(image),
+        }
+    }
+}
+
+/// An image that's been written to disk.
+pub struct StoredImage {
+    pub image_id: String,
+    pub bytes: u64,
+    /// The ID of the image this was a copy of, if it was a duplicate
+    pub duplicate_of: Option<String>,
+}
+
+fn save_error(action: &str, path: &str, error: io::Error) -> ApiError {
+    error!("Failed to {} {}: {}", action, path, error);
+    ApiError {
+        error: "Failed to save image to server",
+        status: Status::InternalServerError,
+    }
+}
+
+/// Writes the upload to the temp file, stopping as soon as it goes over the size limit. Returns the size of the image.
+fn write_temp_file(
+    image: &mut dyn Read,
+    temp_path: &str,
+    upload_config: &UploadConfig,
+) -> Result<u64, ApiError> {
+    let mut file =
+        File::create(temp_path).map_err(|error| save_error("create", temp_path, error))?;
+
+    // Reading one byte past the limit is enough to tell that it's too big
+    let bytes = io::copy(
+        &mut image.take(upload_config.max_image_bytes + 1),
+        &mut file,
+    )
+    .map_err(|error| save_error("write", temp_path, error))?;
+    if bytes > upload_config.max_image_bytes {
+        return Err(ApiError {
+            error: "Image is too large",
+            status: Status::PayloadTooLarge,
+        });
+    }
+    if bytes == 0 {
+        return Err(ApiError {
+            error: "Image is empty",
+            status: Status::BadRequest,
+        });
+    }
+
+    file.sync_all()
+        .map_err(|error| save_error("sync", temp_path, error))?;
+
+    Ok(bytes)
+}
+
+/// Checks that the file ends with the given marker, ignoring any zeros after it since some cameras pad the end.
+fn ends_with_marker(file: &mut File, length: u64, marker: &[u8]) -> io::Result<bool> {
+    let tail_length = length.min(64);
+    file.seek(SeekFrom::Start(length - tail_length))?;
+    let mut tail = Vec::new();
+    file.take(tail_length).read_to_end(&mut tail)?;
+
+    let end = tail.iter().rposition(|x| *x != 0).map_or(0, |x| x + 1);
+    Ok(tail[..end].ends_with(marker))
+}
+
+/// Checks that the top level boxes of an ISO base media file add up to the length of the file.
+fn boxes_fill_file(file: &mut File, length: u64) -> io::Result<bool> {
+    let mut position = 0;
+    while position < length {
+        file.seek(SeekFrom::Start(position))?;
+        let mut header = [0; 16];
+        if length - position < 8 {
+            return Ok(false);
+        }
+        file.read_exact(&mut header[..8])?;
+        let box_size = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            // The box carries on to the end of the file
+            0 => return Ok(true),
+            // The size is in a 64 bit field after the type
+            1 => {
+                if length - position < 16 {
+                    return Ok(false);
+                }
+                file.read_exact(&mut header[8..16])?;
+                let mut large_size = [0; 8];
+                large_size.copy_from_slice(&header[8..16]);
+                u64::from_be_bytes(large_size)
+            }
+            box_size => box_size as u64,
+        };
+        if box_size < 8 {
+            return Ok(false);
+        }
+        position += box_size;
+    }
+    Ok(position == length)
+}
+
+/// Checks whether the whole image was uploaded. Each format has its own way of saying where it ends.
+fn is_complete(format: ImageFormat, file: &mut File) -> io::Result<bool> {
+    let length = file.metadata()?.len();
+    match format {
+        ImageFormat::Jpeg => ends_with_marker(file, length, &[0xFF, 0xD9]),
+        // The IEND chunk, including its CRC
+        ImageFormat::Png => ends_with_marker(
+            file,
+            length,
+            &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82],
+        ),
+        // The RIFF header has the size of the rest of the file
+        ImageFormat::WebP => {
+            let mut riff_size = [0; 4];
+            file.seek(SeekFrom::Start(4))?;
+            file.read_exact(&mut riff_size)?;
+            Ok(length >= u32::from_le_bytes(riff_size) as u64 + 8)
+        }
+        ImageFormat::Avif => boxes_fill_file(file, length),
+    }
+}
+
+/// Checks that the file is really an image in one of the allowed formats, that it's complete and that it decodes.
+/// Images the decoder doesn't support (AVIF and lossless WebP) are only checked as far as their container.
+/// Returns the decoded image as well, or None for those.
+fn validate_image(temp_path: &str) -> Result<(ImageFormat, Option<DynamicImage>), ApiError> {
+    let mut file = File::open(temp_path).map_err(|error| save_error("open", temp_path, error))?;
+
+    let mut header = Vec::new();
+    (&mut file)
+        .take(64)
+        .read_to_end(&mut header)
+        .map_err(|error| save_error("read", temp_path, error))?;
+    let format = ImageFormat::sniff(&header).ok_or(ApiError {
+        error: "Unsupported image format",
+        status: Status::UnsupportedMediaType,
+    })?;
+
+    // Errors reading here mean the file is shorter than its headers say, so it's counted as truncated
+    if !is_complete(format, &mut file).unwrap_or(false) {
+        return Err(ApiError {
+            error: "Image is truncated",
+            status: Status::BadRequest,
+        });
+    }
+
+    let decoder_format = match format.decoder_format() {
+        Some(decoder_format) => decoder_format,
+        None => return Ok((format, None)),
+    };
+
+    let invalid_image = || ApiError {
+        error: "Image could not be decoded",
+        status: Status::BadRequest,
+    };
+
+    file.seek(SeekFrom::Start(0))
+        .map_err(|error| save_error("read", temp_path, error))?;
+    let (width, height) =
+        match image::io::Reader::with_format(BufReader::new(&file), decoder_format)
+            .into_dimensions()
+        {
+            Ok(dimensions) => dimensions,
+            Err(ImageError::Unsupported(_)) => return Ok((format, None)),
+            Err(_) => return Err(invalid_image()),
+        };
+    if width as u64 * height as u64 > MAX_IMAGE_PIXELS {
+        return Err(ApiError {
+            error: "Image dimensions are too large",
+            status: Status::PayloadTooLarge,
+        });
+    }
+
+    file.seek(SeekFrom::Start(0))
+        .map_err(|error| save_error("read", temp_path, error))?;
+    match image::io::Reader::with_format(BufReader::new(&file), decoder_format).decode() {
+        Ok(image) => Ok((format, Some(image))),
+        Err(ImageError::Unsupported(_)) => Ok((format, None)),
+        Err(error) => {
+            warn!("Rejected image that failed to decode: {}", error);
+            Err(invalid_image())
+        }
+    }
+}
+
+/// Hard links the checked temp file (or the earlier copy of a duplicate) into the camera's directory, named after the capture time.
+/// Unlike a rename, linking fails if the name is taken, so the time can be bumped a millisecond at a time until a free
+/// one is found. Frames captured in the same millisecond are all kept, in the order they were stored, up to
+/// MAX_NAME_COLLISIONS of them. Returns the new image's ID.
+fn link_into_place(
+    source_path: &Path,
+    camera_id: uuid::Uuid,
+    camera_directory: &str,
+    capture_timestamp: u64,
+    format: ImageFormat,
+    connection: &PgConnection,
+) -> Result<String, ApiError> {
+    let image_ids: Vec<String> = (capture_timestamp..capture_timestamp + MAX_NAME_COLLISIONS)
+        .map(|timestamp| timestamp.to_string())
+        .collect();
+    // The IDs of trashed images are taken, since they still have metadata and can be restored
+    let trashed_image_ids: HashSet<String> =
+        image_trash::get_trashed_image_ids(camera_id, &image_ids, connection)
+            .map_err(|error| {
+                error!(
+                    "Failed to check the trash of camera {}: {}",
+                    camera_id, error
+                );
+                ApiError {
+                    error: "Failed to save image to server",
+                    status: Status::InternalServerError,
+                }
+            })?
+            .into_iter()
+            .collect();
+
+    for image_id in image_ids {
+        // Images in other formats share the same IDs, so their names have to be checked too
+        let taken_by_other_format = ImageFormat::ALL
+            .iter()
+            .filter(|other_format| **other_format != format)
+            .any(|other_format| {
+                Path::new(&format!(
+                    "{}/{}.{}",
+                    camera_directory,
+                    image_id,
+                    other_format.extension()
+                ))
+                .exists()
+            });
+        if taken_by_other_format || trashed_image_ids.contains(&image_id) {
+            continue;
+        }
+
+        let path = format!("{}/{}.{}", camera_directory, image_id, format.extension());
+        match hard_link(source_path, &path) {
+            Ok(()) => return Ok(image_id),
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => {}
+            Err(error) => return Err(save_error("link image to", &path, error)),
+        }
+    }
+
+    warn!(
+        "Camera {} has no free names left for images captured at {}",
+        camera_id, capture_timestamp
+    );
+    Err(ApiError {
+        error: "Too many images have the same capture time",
+        status: Status::Conflict,
+    })
+}
+
+/// An upload after the camera's privacy masks and watermark have been applied.
+struct EditedUpload {
+    format: ImageFormat,
+    bytes: u64,
+    analysis: Option<ImageAnalysis>,
+    watermarked: bool,
+    /// true if the image was turned the right way up before it was edited
+    reoriented: bool,
+    /// true if the image without its burned-in watermark was written to original_temp_path
+    has_original: bool,
+}
+
+/// Applies the camera's privacy masks and burns in its watermark, rewriting the checked temp file, and analyses the
+/// image as it'll be stored. The decoded image is dropped by the time this returns, since it can be much bigger than the file.
+/// Masks and watermarks are placed on the image the right way up, and re-encoding it loses its EXIF orientation, so
+/// edited images are turned the right way up first. If a watermark is burned in, the image is also written without it
+/// (but still masked) to original_temp_path, to be kept as the original.
+fn edit_and_analyse(
+    temp_path: &str,
+    original_temp_path: &str,
+    format: ImageFormat,
+    decoded_image: Option<DynamicImage>,
+    bytes: u64,
+    orientation: Option<i16>,
+    active_masks: Option<&ActiveMasks>,
+    watermark: Option<&WatermarkText>,
+) -> Result<EditedUpload, ApiError> {
+    let unedited = |analysis| EditedUpload {
+        format,
+        bytes,
+        analysis,
+        watermarked: false,
+        reoriented: false,
+        has_original: false,
+    };
+    let decoded_image = match decoded_image {
+        Some(decoded_image) => decoded_image,
+        // Storing it unmasked isn't an option
+        None if active_masks.is_some() => {
+            return Err(ApiError {
+                error: "Privacy masks can't be applied to images in this format",
+                status: Status::UnsupportedMediaType,
+            })
+        }
+        // But storing it without its watermark is better than losing it
+        None => {
+            if watermark.is_some() {
+                warn!("Storing upload without its watermark, since it can't be decoded");
+            }
+            return Ok(unedited(None));
+        }
+    };
+    if active_masks.is_none() && watermark.is_none() {
+        return Ok(unedited(Some(ImageAnalysis::of(&decoded_image))));
+    }
+
+    let mut edited_image = apply_orientation(decoded_image, orientation);
+    if let Some(active_masks) = active_masks {
+        edited_image = privacy_masks::apply_masks(&edited_image, &active_masks.masks);
+    }
+    // Edited WebP images are stored as PNG
+    let format = format.edited_format();
+    if let Some(watermark) = watermark {
+        let watermarked_image = watermark.draw(&edited_image);
+        let encoded_original = encode_image(edited_image, format)?;
+        std::fs::write(original_temp_path, &encoded_original)
+            .map_err(|error| save_error("write", original_temp_path, error))?;
+        edited_image = watermarked_image;
+    }
+    let analysis = ImageAnalysis::of(&edited_image);
+
+    let encoded = encode_image(edited_image, format)?;
+    std::fs::write(temp_path, &encoded).map_err(|error| save_error("write", temp_path, error))?;
+
+    Ok(EditedUpload {
+        format,
+        bytes: encoded.len() as u64,
+        analysis: Some(analysis),
+        watermarked: watermark.is_some(),
+        reoriented: true,
+        has_original: watermark.is_some(),
+    })
+}
+
+/// Checks an uploaded image and stores it in the camera's directory. The image only appears there once it's been
+/// completely written and checked, so a half-written or rejected upload is never listed.
+/// Copies of an image the camera has already stored share its file, or aren't stored at all, depending on the duplicates policy.
+/// If the camera has privacy masks, they're applied before the image is stored, so the unmasked image is never kept.
+/// So is its watermark, if it's burned in, but then a copy without it is kept in ORIGINALS_DIRECTORY as well.
+pub fn store_image(
+    camera_id: &uuid::Uuid,
+    capture_timestamp: u64,
+    image: &mut dyn Read,
+    upload_config: &UploadConfig,
+    connection: &PgConnection,
+) -> Result<StoredImage, ApiError> {
+    let images_directory_path = images_directory();
+    let incoming_directory = format!("{}/{}", images_directory_path, INCOMING_DIRECTORY);
+    let camera_directory = format!("{}/{}", images_directory_path, camera_id);
+    for directory in &[&incoming_directory, &camera_directory] {
+        create_dir_all(directory)
+            .map_err(|error| save_error("create directory", directory, error))?;
+    }
+
+    let temp_path = format!("{}/{}.tmp", incoming_directory, uuid::Uuid::new_v4());
+    let original_temp_path = format!("{}.original", temp_path);
+    let result = write_temp_file(image, &temp_path, upload_config).and_then(|bytes| {
+        let (format, decoded_image) = validate_image(&temp_path)?;
+        // Read before masking, since re-encoding the image loses its EXIF data
+        let mut exif = read_exif(Path::new(&temp_path));
+        let active_masks = privacy_masks::get_active_masks(*camera_id, connection)?;
+        let watermark = watermarks::get_burn_in_watermark(*camera_id, connection)
+            .map(|watermark| watermark.text(Some(capture_timestamp)));
+        let EditedUpload {
+            format,
+            bytes,
+            analysis,
+            watermarked,
+            reoriented,
+            has_original,
+        } = edit_and_analyse(
+            &temp_path,
+            &original_temp_path,
+            format,
+            decoded_image,
+            bytes,
+            exif.orientation,
+            active_masks.as_ref(),
+            watermark.as_ref(),
+        )?;
+        if reoriented {
+            exif.orientation = Some(1);
+        }
+        let content_hash = hash_file(Path::new(&temp_path))
+            .map_err(|error| save_error("hash", &temp_path, error))?;
+
+        let duplicate = image_dedup::find_duplicate(*camera_id, &content_hash, connection);
+        if let Some(duplicate) = &duplicate {
+            if upload_config.duplicates == DuplicatePolicy::Skip {
+                debug!("Skipped duplicate of image {}", duplicate.image_id);
+                image_dedup::record_duplicate(*camera_id, bytes, connection);
+                return Ok(StoredImage {
+                    image_id: duplicate.image_id.clone(),
+                    bytes,
+                    duplicate_of: Some(duplicate.image_id.clone()),
+                });
+            }
+        }
+
+        // Quotas count every image at its full size, even ones that share a file
+        storage_quota::make_room(*camera_id, bytes, &upload_config.quotas, connection)?;
+        // If the earlier copy has been deleted (or evicted to make room) since it was found, the upload is stored as is
+        let linked = duplicate.and_then(|duplicate| {
+            link_into_place(
+                &duplicate.path,
+                *camera_id,
+                &camera_directory,
+                capture_timestamp,
+                format,
+                connection,
+            )
+            .ok()
+            .map(|image_id| (image_id, duplicate.image_id))
+        });
+        let (image_id, duplicate_of) = match linked {
+            Some((image_id, duplicate_of)) => {
+                image_dedup::record_duplicate(*camera_id, bytes, connection);
+                (image_id, Some(duplicate_of))
+            }
+            None => (
+                link_into_place(
+                    Path::new(&temp_path),
+                    *camera_id,
+                    &camera_directory,
+                    capture_timestamp,
+                    format,
+                    connection,
+                )?,
+                None,
+            ),
+        };
+        storage_quota::record_change(*camera_id, 1, bytes as i64, connection);
+
+        // The image has already been stored, so failing to keep its original is only logged
+        if has_original {
+            let originals_directory =
+                originals_directory(&images_directory_path, &camera_id.to_string());
+            let original_path = original_path(
+                &camera_id.to_string(),
+                &format!("{}.{}", image_id, format.extension()),
+            );
+            let kept = create_dir_all(&originals_directory)
+                .and_then(|_| rename(&original_temp_path, &original_path));
+            if let Err(error) = kept {
+                error!(
+                    "Failed to keep original of image {} from camera {}: {}",
+                    image_id, camera_id, error
+                );
+            }
+        }
+
+        // The image is already stored at this point, and its format can still be worked out from the extension
+        if let Err(error) = image_metadata::insert(
+            InsertableImageMetadata {
+                privacy_mask_version: active_masks.map(|active_masks| active_masks.version),
+                watermarked,
+                ..InsertableImageMetadata::new(
+                    *camera_id,
+                    image_id.clone(),
+                    format.content_type().to_string(),
+                    bytes as i64,
+                    content_hash,
+                    exif,
+                    analysis,
+                )
+            },
+            connection,
+        ) {
+            error!("Failed to add metadata for image {}: {}", image_id, error);
+        }
+
+        Ok(StoredImage {
+            image_id,
+            bytes,
+            duplicate_of,
+        })
+    });
+
+    // Once it's been linked into place the temp file is just a second name for the image
+    for path in &[&temp_path, &original_temp_path] {
+        if let Err(error) = remove_file(path) {
+            if error.kind() != ErrorKind::NotFound {
+                warn!("Failed to remove temp file {}: {}", path, error);
+            }
+        }
+    }
+
+    result
+}
+
+/// Checks an uploaded image the same way store_image() does and decodes it, without storing it.
+/// Returns None for images the decoder doesn't support.
+pub fn decode_upload(
+    image: &mut dyn Read,
+    upload_config: &UploadConfig,
+) -> Result<Option<DynamicImage>, ApiError> {
+    let incoming_directory = format!("{}/{}", images_directory(), INCOMING_DIRECTORY);
+    create_dir_all(&incoming_directory)
+        .map_err(|error| save_error("create directory", &incoming_directory, error))?;
+
+    let temp_path = format!("{}/{}.tmp", incoming_directory, uuid::Uuid::new_v4());
+    let result = write_temp_file(image, &temp_path, upload_config)
+        .and_then(|_| validate_image(&temp_path))
+        .map(|(_, decoded_image)| decoded_image);
+
+    if let Err(error) = remove_file(&temp_path) {
+        if error.kind() != ErrorKind::NotFound {
+            warn!("Failed to remove temp file {}: {}", temp_path, error);
+        }
+    }
+
+    result
+}
+
+/// Hex SHA-256 of a file.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// What's needed to send a stored image back, besides the image itself.
+pub struct StoredImageDetails {
+    pub path: PathBuf,
+    pub format: ImageFormat,
+    /// Used for the ETag
+    pub content_hash: String,
+    pub last_modified: DateTime<Utc>,
+    /// true if the image has the camera's watermark burned in
+    pub watermarked: bool,
+    /// The EXIF orientation from the image's metadata, or None if it doesn't have any and it has to be read from the file
+    pub stored_orientation: Option<Option<i16>>,
+}
+
+/// Looks up the format and hash of a stored image in its metadata.
+/// Images without metadata have their format worked out from the extension and their hash worked out from the file on each request.
+pub fn stored_image_details(
+    camera_id_string: &str,
+    image_id: &str,
+    path: PathBuf,
+    connection: &PgConnection,
+) -> Result<StoredImageDetails, ApiError> {
+    let metadata = uuid::Uuid::parse_str(camera_id_string)
+        .ok()
+        .and_then(|camera_id| image_metadata::get(camera_id, image_id, connection).ok());
+
+    let format = metadata
+        .as_ref()
+        .and_then(|metadata| ImageFormat::from_content_type(&metadata.content_type))
+        .or_else(|| ImageFormat::from_path(&path))
+        .unwrap_or(ImageFormat::Jpeg);
+
+    let watermarked = metadata
+        .as_ref()
+        .map_or(false, |metadata| metadata.watermarked);
+    let stored_orientation = metadata.as_ref().map(|metadata| metadata.orientation);
+
+    let content_hash = match metadata.and_then(|metadata| metadata.content_hash) {
+        Some(content_hash) => content_hash,
+        None => hash_file(&path).map_err(|error| load_error(&path, &error))?,
+    };
+
+    let last_modified = std::fs::metadata(&path)
+        .and_then(|file_metadata| file_metadata.modified())
+        .map(DateTime::<Utc>::from)
+        .map_err(|error| load_error(&path, &error))?;
+
+    Ok(StoredImageDetails {
+        path,
+        format,
+        content_hash,
+        last_modified,
+        watermarked,
+        stored_orientation,
+    })
+}
+
+/// Swaps an image with a burned-in watermark for the copy kept without it, for owners asking for the original.
+/// Images without one are their own original.
+pub fn original_image_details(
+    details: StoredImageDetails,
+    camera_id_string: &str,
+) -> Result<StoredImageDetails, ApiError> {
+    if !details.watermarked {
+        return Ok(details);
+    }
+
+    let file_name = details
+        .path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let path = original_path(camera_id_string, &file_name);
+    let last_modified = match std::fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => DateTime::<Utc>::from(modified),
+        // Images stored before originals were kept
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            return Err(ApiError {
+                error: "The original of this image wasn't kept",
+                status: Status::NotFound,
+            })
+        }
+        Err(error) => return Err(load_error(&path, &error)),
+    };
+
+    Ok(StoredImageDetails {
+        path,
+        content_hash: format!("{}-original", details.content_hash),
+        last_modified,
+        watermarked: false,
+        ..details
+    })
+}
+
+/// Builds the metadata for an image that doesn't have any stored, from the file itself.
+/// The upload time isn't known, so the file's modification time is used instead.
+pub fn metadata_from_file(
+    camera_id: uuid::Uuid,
+    image_id: &str,
+    path: &Path,
+) -> Result<ImageMetadata, ApiError> {
+    let file_metadata = std::fs::metadata(path).map_err(|error| load_error(path, &error))?;
+    let modified = file_metadata
+        .modified()
+        .map(|modified| DateTime::<Utc>::from(modified).naive_utc())
+        .map_err(|error| load_error(path, &error))?;
+    let format = ImageFormat::from_path(path).unwrap_or(ImageFormat::Jpeg);
+    let exif = read_exif(path);
+
+    Ok(ImageMetadata {
+        camera_id,
+        image_id: image_id.to_string(),
+        content_type: format.content_type().to_string(),
+        size_bytes: file_metadata.len() as i64,
+        created_at: modified,
+        captured_at: exif.captured_at,
+        camera_make: exif.camera_make,
+        camera_model: exif.camera_model,
+        exposure_time: exif.exposure_time,
+        f_number: exif.f_number,
+        iso: exif.iso,
+        focal_length: exif.focal_length,
+        orientation: exif.orientation,
+        gps_latitude: exif.gps_latitude,
+        gps_longitude: exif.gps_longitude,
+        gps_altitude: exif.gps_altitude,
+        content_hash: hash_file(path).ok(),
+        // Not worth decoding the whole image for, so these are left out
+        perceptual_hash: None,
+        perceptual_hash_0: None,
+        perceptual_hash_1: None,
+        perceptual_hash_2: None,
+        perceptual_hash_3: None,
+        mean_luminance: None,
+        luminance_histogram: None,
+        underexposed_ratio: None,
+        overexposed_ratio: None,
+        lighting: None,
+        privacy_mask_version: None,
+        watermarked: false,
+    })
+}
+
+/// Whether a media range from an Accept header, like image/* or image/png, covers the media type.
+fn media_range_matches(media_range: &MediaType, media_type: &MediaType) -> bool {
+    (media_range.top() == "*" || media_range.top() == media_type.top())
+        && (media_range.sub() == "*" || media_range.sub() == media_type.sub())
+}
+
+/// Picks the format to send a stored image in. The stored format is used unless the Accept header rules it out,
+/// in which case the most preferred format the image can be converted to is used instead.
+/// Returns None if the client doesn't accept anything the image can be sent as.
+pub fn negotiate_format(
+    stored_format: ImageFormat,
+    accept: Option<&Accept>,
+) -> Option<ImageFormat> {
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return Some(stored_format),
+    };
+
+    let mut preferences: Vec<_> = accept
+        .iter()
+        .filter(|media_range| media_range.weight_or(1.0) > 0.0)
+        .collect();
+    // Sorting is stable, so ranges with the same weight stay in the client's order
+    preferences.sort_by(|a, b| {
+        b.weight_or(1.0)
+            .partial_cmp(&a.weight_or(1.0))
+            .unwrap_or(Ordering::Equal)
+    });
+
+    let can_convert = stored_format.decoder_format().is_some();
+    preferences.into_iter().find_map(|media_range| {
+        if media_range_matches(
+            media_range.media_type(),
+            stored_format.content_type().media_type(),
+        ) {
+            Some(stored_format)
+        } else {
+            ImageFormat::from_media_type(media_range.media_type())
+                .filter(|format| can_convert && format.encoder_format().is_some())
+        }
+    })
+}
+
+enum ImageBody {
+    File(PathBuf),
+    /// Converting is left until the response is sent, so that it's skipped when the client's copy is still current
+    Converted(Box<dyn FnOnce() -> Result<Vec<u8>, ApiError>>),
+}
+
+/// A stored image, either as it is on disk or converted to another format.
+/// Conditional requests are answered with 304 and range requests with 206, so that clients don't download images they already have.
+pub struct ImageResponse {
+    body: ImageBody,
+    format: ImageFormat,
+    /// Quoted, as it appears in the header
+    etag: String,
+    last_modified: DateTime<Utc>,
+    cache_policy: CachePolicy,
+}
+
+impl ImageResponse {
+    /// A response made from a stored image, like a thumbnail. variant has to be different for everything made from the same image,
+    /// since it's part of the ETag.
+    pub fn converted<F>(
+        details: &StoredImageDetails,
+        variant: &str,
+        format: ImageFormat,
+        cache_policy: CachePolicy,
+        convert: F,
+    ) -> ImageResponse
+    where
+        F: FnOnce() -> Result<Vec<u8>, ApiError> + 'static,
+    {
+        ImageResponse {
+            body: ImageBody::Converted(Box::new(convert)),
+            format,
+            etag: format!("\"{}.{}\"", details.content_hash, variant),
+            last_modified: details.last_modified,
+            cache_policy,
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ImageResponse {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = Response::build();
+        response
+            // The body depends on the Accept header, so caches have to keep a copy for each one
+            .raw_header("Vary", "Accept")
+            .raw_header("ETag", self.etag.clone())
+            .raw_header("Last-Modified", format_http_date(self.last_modified))
+            .raw_header("Cache-Control", self.cache_policy.header_value())
+            .raw_header("Accept-Ranges", "bytes");
+
+        if is_not_modified(request, &self.etag, self.last_modified) {
+            return response.status(Status::NotModified).ok();
+        }
+
+        match self.body {
+            ImageBody::File(path) => match File::open(&path) {
+                Ok(file) => send_range(
+                    response,
+                    request,
+                    file,
+                    self.format,
+                    &self.etag,
+                    self.last_modified,
+                ),
+                Err(error) => load_error(&path, &error).respond_to(request),
+            },
+            ImageBody::Converted(convert) => match convert() {
+                Ok(bytes) => send_range(
+                    response,
+                    request,
+                    Cursor::new(bytes),
+                    self.format,
+                    &self.etag,
+                    self.last_modified,
+                ),
+                Err(error) => error.respond_to(request),
+            },
+        }
+    }
+}
+
+/// Sends the part of the body the Range header asks for, or all of it if there isn't one.
+fn send_range<'r, B: Read + Seek + 'r>(
+    mut response: ResponseBuilder<'r>,
+    request: &Request,
+    mut body: B,
+    format: ImageFormat,
+    etag: &str,
+    last_modified: DateTime<Utc>,
+) -> response::Result<'r> {
+    let length = match body.seek(SeekFrom::End(0)) {
+        Ok(length) => length,
+        Err(error) => {
+            error!("Failed to get the size of an image: {}", error);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    match requested_range(request, &etag, last_modified, length) {
+        RequestedRange::Full => {
+            body.seek(SeekFrom::Start(0))
+                .map_err(|_| Status::InternalServerError)?;
+            response.header(format.content_type()).sized_body(body);
+        }
+        RequestedRange::Partial(first, last) => {
+            body.seek(SeekFrom::Start(first))
+                .map_err(|_| Status::InternalServerError)?;
+            response
+                .status(Status::PartialContent)
+                .header(format.content_type())
+                .raw_header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", first, last, length),
+                )
+                .raw_body(Body::Sized(body, last - first + 1));
+        }
+        RequestedRange::Unsatisfiable => {
+            response
+                .status(Status::RangeNotSatisfiable)
+                .raw_header("Content-Range", format!("bytes */{}", length));
+        }
+    }
+    response.ok()
+}
+
+fn load_error(path: &Path, error: &dyn std::fmt::Display) -> ApiError {
+    error!("Failed to read file {}: {}", path.display(), error);
+    ApiError {
+        error: "Failed to load image",
+        status: Status::InternalServerError,
+    }
+}
+
+/// Decodes a stored image. Returns None for images that can be stored but not decoded (AVIF and lossless WebP).
+pub fn decode_stored_image(
+    path: &Path,
+    format: ImageFormat,
+) -> Result<Option<DynamicImage>, ApiError> {
+    let decoder_format = match format.decoder_format() {
+        Some(decoder_format) => decoder_format,
+        None => return Ok(None),
+    };
+
+    let file = File::open(path).map_err(|error| load_error(path, &error))?;
+    match image::io::Reader::with_format(BufReader::new(file), decoder_format).decode() {
+        Ok(image) => Ok(Some(image)),
+        Err(ImageError::Unsupported(_)) => Ok(None),
+        Err(error) => Err(load_error(path, &error)),
+    }
+}
+
+/// Encodes an image as JPEG or PNG, the formats images can be converted to.
+pub fn encode_image(image: DynamicImage, format: ImageFormat) -> Result<Vec<u8>, ApiError> {
+    let encoder_format = format
+        .encoder_format()
+        .expect("Images can only be encoded as JPEG or PNG");
+
+    // JPEG has no alpha channel
+    let image = match format {
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()),
+        _ => image,
+    };
+
+    let mut bytes = Vec::new();
+    image
+        .write_to(&mut bytes, encoder_format)
+        .map_err(|error| {
+            error!("Failed to encode image: {}", error);
+            ApiError {
+                error: "Failed to convert image",
+                status: Status::InternalServerError,
+            }
+        })?;
+    Ok(bytes)
+}
+
+/// Sends a stored image back, converting it if the client doesn't accept the format it was uploaded in.
+pub fn respond_with_image(
+    details: StoredImageDetails,
+    accept: Option<&Accept>,
+    cache_policy: CachePolicy,
+) -> Result<ImageResponse, ApiError> {
+    let not_acceptable = || ApiError {
+        error: "Image isn't available in any of the accepted formats",
+        status: Status::NotAcceptable,
+    };
+
+    let format = negotiate_format(details.format, accept).ok_or_else(not_acceptable)?;
+    if format == details.format {
+        return Ok(ImageResponse {
+            etag: format!("\"{}\"", details.content_hash),
+            last_modified: details.last_modified,
+            body: ImageBody::File(details.path),
+            format,
+            cache_policy,
+        });
+    }
+
+    let path = details.path.clone();
+    let stored_format = details.format;
+    Ok(ImageResponse::converted(
+        &details,
+        format.extension(),
+        format,
+        cache_policy,
+        move || {
+            // negotiate_format() only picks decodable formats, but lossless WebP can't be told apart until it's decoded
+            let image = decode_stored_image(&path, stored_format)?.ok_or_else(not_acceptable)?;
+            encode_image(image, format)
+        },
+    ))
+}
+
+/// Sends a stored image back with a change made to it, like a watermark. It's sent as JPEG or PNG, since the image has
+/// to be encoded again. variant has to be different for each change, since it's part of the ETag.
+/// Images that can't be decoded are sent unchanged.
+pub fn respond_with_edited_image<F>(
+    details: StoredImageDetails,
+    accept: Option<&Accept>,
+    cache_policy: CachePolicy,
+    variant: &str,
+    edit: F,
+) -> Result<ImageResponse, ApiError>
+where
+    F: FnOnce(DynamicImage) -> DynamicImage + 'static,
+{
+    if !details.format.can_decode() {
+        return respond_with_image(details, accept, cache_policy);
+    }
+
+    // WebP can't be encoded, so it's sent as PNG unless the client would rather have JPEG
+    let format = negotiate_format(details.format.edited_format(), accept).ok_or(ApiError {
+        error: "Image isn't available in any of the accepted formats",
+        status: Status::NotAcceptable,
+    })?;
+
+    let path = details.path.clone();
+    let stored_format = details.format;
+    let stored_orientation = details.stored_orientation;
+    Ok(ImageResponse::converted(
+        &details,
+        &format!("{}.{}", variant, format.extension()),
+        format,
+        cache_policy,
+        move || {
+            // Lossless WebP can't be told apart until it's decoded
+            let image = decode_stored_image(&path, stored_format)?.ok_or(ApiError {
+                error: "Images in this format can't be edited",
+                status: Status::UnsupportedMediaType,
+            })?;
+            // Encoding it again loses its EXIF orientation, and edits go on the image the right way up
+            let orientation = stored_orientation.unwrap_or_else(|| read_exif(&path).orientation);
+            encode_image(edit(apply_orientation(image, orientation)), format)
+        },
+    ))
+}
@@ -0,0 +1,622 @@
+use crate::{
+    api_error::ApiError,
+    api_keys::ApiKeyScope,
+    audit_log::{self, AuditAction, ClientIp, InsertableAuditEvent},
+    camera::{
+        camera_directory, find_image, images_directory, list_camera_directory, parse_camera_id,
+    },
+    image_metadata,
+    image_store::{image_id, remove_original, TimeRange},
+    storage_quota,
+    user_auth::UserAuth,
+    users_cameras::{check_if_user_has_camera_role, CameraRole},
+    CameraServerDbConn, CameraServerDbConnPool,
+};
+
+use super::schema::trashed_images;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::{delete, fairing::AdHoc, get, http::Status, post, State};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs::{create_dir_all, hard_link, remove_file, rename};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::thread;
+
+/// Deleted images are moved here, under a directory for each camera. It's inside IMAGES_DIRECTORY so that moving
+/// images in and out is just a rename, and it's skipped when scanning for cameras.
+pub const TRASH_DIRECTORY: &str = ".trash";
+
+const DEFAULT_HOLD_DAYS: i64 = 30;
+/// How often the trash is checked for images that have been there longer than the hold time.
+const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// Batch deletes listing more image IDs than this are rejected.
+const MAX_BATCH_DELETE_IMAGES: usize = 1000;
+
+/// Settings for the trash, read from the trash table in Rocket.toml.
+pub struct TrashConfig {
+    /// How long deleted images can be restored for before they're deleted for good
+    pub hold_time: Duration,
+}
+
+/// Manages the TrashConfig and starts the thread that empties the trash. Has to be attached after the database.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Trash", |rocket| {
+        let hold_days = rocket
+            .config()
+            .get_table("trash")
+            .ok()
+            .and_then(|table| table.get("hold_days"))
+            .and_then(|value| value.as_integer())
+            .map(|value| value.max(0))
+            .unwrap_or(DEFAULT_HOLD_DAYS);
+        let hold_time = Duration::days(hold_days);
+
+        // Request guards aren't available outside of requests, so the thread gets its own handle to the pool
+        let pool = match rocket.state::<CameraServerDbConnPool>() {
+            Some(pool) => pool.0.clone(),
+            None => {
+                error!("The trash fairing has to be attached after the database fairing");
+                return Err(rocket);
+            }
+        };
+
+        let spawned = thread::Builder::new()
+            .name("trash-purge".to_string())
+            .spawn(move || loop {
+                match pool.get() {
+                    Ok(connection) => purge_expired_images(hold_time, &connection),
+                    Err(error) => warn!(
+                        "Failed to get a database connection to empty the trash: {}",
+                        error
+                    ),
+                }
+                thread::sleep(PURGE_INTERVAL);
+            });
+        if let Err(error) = spawned {
+            error!("Failed to start the trash purge thread: {}", error);
+            return Err(rocket);
+        }
+
+        Ok(rocket.manage(TrashConfig { hold_time }))
+    })
+}
+
+#[derive(Queryable, AsChangeset, Deserialize, Serialize)]
+#[table_name = "trashed_images"]
+pub struct TrashedImage {
+    pub camera_id: uuid::Uuid,
+    pub image_id: String,
+    /// The image's file name in the camera's trash directory, which is the same as it was in the camera's directory
+    pub file_name: String,
+    /// None if the user who deleted it has since deleted their account
+    pub deleted_by: Option<uuid::Uuid>,
+    pub deleted_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "trashed_images"]
+pub struct InsertableTrashedImage {
+    pub camera_id: uuid::Uuid,
+    pub image_id: String,
+    pub file_name: String,
+    pub deleted_by: Option<uuid::Uuid>,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<TrashedImage>> {
+    trashed_images::table.load::<TrashedImage>(&*connection)
+}
+
+pub fn get(
+    camera_id: uuid::Uuid,
+    image_id: &str,
+    connection: &PgConnection,
+) -> QueryResult<TrashedImage> {
+    trashed_images::table
+        .find((camera_id, image_id))
+        .get_result::<TrashedImage>(connection)
+}
+
+pub fn insert(
+    trashed_image: InsertableTrashedImage,
+    connection: &PgConnection,
+) -> QueryResult<TrashedImage> {
+    diesel::insert_into(trashed_images::table)
+        .values(trashed_image)
+        .get_result(connection)
+}
+
+pub fn update(
+    camera_id: uuid::Uuid,
+    image_id: &str,
+    trashed_image: TrashedImage,
+    connection: &PgConnection,
+) -> QueryResult<TrashedImage> {
+    diesel::update(trashed_images::table.find((camera_id, image_id)))
+        .set(&trashed_image)
+        .get_result(connection)
+}
+
+pub fn delete(
+    camera_id: uuid::Uuid,
+    image_id: &str,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::delete(trashed_images::table.find((camera_id, image_id))).execute(connection)
+}
+
+/// Returns the images in a camera's trash, most recently deleted first.
+pub fn get_cameras_trashed_images(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<TrashedImage>> {
+    trashed_images::table
+        .filter(trashed_images::camera_id.eq(camera_id))
+        .order(trashed_images::deleted_at.desc())
+        .load::<TrashedImage>(connection)
+}
+
+pub fn get_images_deleted_before(
+    deleted_before: NaiveDateTime,
+    connection: &PgConnection,
+) -> QueryResult<Vec<TrashedImage>> {
+    trashed_images::table
+        .filter(trashed_images::deleted_at.lt(deleted_before))
+        .load::<TrashedImage>(connection)
+}
+
+/// Returns which of the given IDs belong to images in the camera's trash. The IDs stay taken until the images are
+/// purged, since their metadata is kept so that they can be restored.
+pub fn get_trashed_image_ids(
+    camera_id: uuid::Uuid,
+    image_ids: &[String],
+    connection: &PgConnection,
+) -> QueryResult<Vec<String>> {
+    trashed_images::table
+        .filter(trashed_images::camera_id.eq(camera_id))
+        .filter(trashed_images::image_id.eq_any(image_ids))
+        .select(trashed_images::image_id)
+        .load(connection)
+}
+
+pub fn trash_directory(images_directory_path: &String, camera_id_string: &String) -> String {
+    format!(
+        "{}/{}/{}",
+        images_directory_path, TRASH_DIRECTORY, camera_id_string
+    )
+}
+
+fn trash_error(
+    action: &str,
+    path: &dyn std::fmt::Display,
+    error: &dyn std::fmt::Display,
+) -> ApiError {
+    error!("Failed to {} {}: {}", action, path, error);
+    ApiError {
+        error: "Failed to move image to the trash",
+        status: Status::InternalServerError,
+    }
+}
+
+/// Moves an image from its camera's directory into the camera's trash directory.
+fn trash_image(
+    camera_id: uuid::Uuid,
+    image_id: &str,
+    path: &Path,
+    deleted_by: uuid::Uuid,
+    connection: &PgConnection,
+) -> Result<(), ApiError> {
+    let images_directory_path = images_directory();
+    let trash_directory = trash_directory(&images_directory_path, &camera_id.to_string());
+    create_dir_all(&trash_directory)
+        .map_err(|error| trash_error("create directory", &trash_directory, &error))?;
+
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let bytes = path.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+    // Worked out before the image is moved, so that it isn't left out of a fresh count and then subtracted as well
+    storage_quota::get_or_compute_usage(camera_id, connection)?;
+
+    // The row goes in first, so that an image is never in the trash directory without one
+    insert(
+        InsertableTrashedImage {
+            camera_id,
+            image_id: image_id.to_string(),
+            file_name: file_name.clone(),
+            deleted_by: Some(deleted_by),
+        },
+        connection,
+    )
+    .map_err(|error| match error {
+        diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        ) => ApiError {
+            error: "An image with this ID is already in the trash",
+            status: Status::Conflict,
+        },
+        error => trash_error("add trash entry for", &image_id, &error),
+    })?;
+
+    let trash_path = format!("{}/{}", trash_directory, file_name);
+    if let Err(error) = rename(path, &trash_path) {
+        if let Err(error) = delete(camera_id, image_id, connection) {
+            error!("Failed to remove trash entry for {}: {}", image_id, error);
+        }
+        return Err(trash_error("move", &path.display(), &error));
+    }
+    storage_quota::record_change(camera_id, -1, -(bytes as i64), connection);
+
+    Ok(())
+}
+
+/// Deletes a trashed image for good, along with its metadata and the original kept without its watermark.
+fn purge_image(trashed_image: &TrashedImage, connection: &PgConnection) {
+    let images_directory_path = images_directory();
+    let path = format!(
+        "{}/{}",
+        trash_directory(&images_directory_path, &trashed_image.camera_id.to_string()),
+        trashed_image.file_name
+    );
+    match remove_file(&path) {
+        Ok(()) => {}
+        Err(error) if error.kind() == ErrorKind::NotFound => {}
+        Err(error) => {
+            // The row is kept so that it's tried again next time
+            warn!("Failed to delete trashed image {}: {}", path, error);
+            return;
+        }
+    }
+    remove_original(
+        &trashed_image.camera_id.to_string(),
+        &trashed_image.file_name,
+    );
+
+    let result = connection.transaction::<_, diesel::result::Error, _>(|| {
+        image_metadata::delete(trashed_image.camera_id, &trashed_image.image_id, connection)?;
+        delete(trashed_image.camera_id, &trashed_image.image_id, connection)
+    });
+    if let Err(error) = result {
+        warn!(
+            "Failed to remove trash entry for {}: {}",
+            trashed_image.image_id, error
+        );
+    }
+}
+
+/// Deletes every image that's been in the trash for longer than hold_time.
+pub fn purge_expired_images(hold_time: Duration, connection: &PgConnection) {
+    let deleted_before = Utc::now().naive_utc() - hold_time;
+    let expired_images = match get_images_deleted_before(deleted_before, connection) {
+        Ok(expired_images) => expired_images,
+        Err(error) => {
+            warn!("Failed to get images to remove from the trash: {}", error);
+            return;
+        }
+    };
+
+    for trashed_image in &expired_images {
+        purge_image(trashed_image, connection);
+    }
+    if !expired_images.is_empty() {
+        debug!("Emptied {} images from the trash", expired_images.len());
+    }
+}
+
+fn record_audit_event(
+    action: AuditAction,
+    camera_id: uuid::Uuid,
+    image_ids: &[String],
+    user_auth: &UserAuth,
+    client_ip: &ClientIp,
+    connection: &PgConnection,
+) {
+    audit_log::record(
+        InsertableAuditEvent {
+            target_camera_id: Some(camera_id),
+            details: Some(json!({ "image_ids": image_ids })),
+            ..InsertableAuditEvent::by_user_auth(action, user_auth, client_ip)
+        },
+        connection,
+    );
+}
+
+/// Moves an image to the trash, where it can be restored until the hold time runs out.
+#[delete("/cameras/<camera_id_string>/images/<image_id_string>")]
+pub fn delete_image(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    client_ip: ClientIp,
+    camera_id_string: String,
+    image_id_string: String,
+) -> Result<(), ApiError> {
+    user_auth.require_scope(ApiKeyScope::ImagesDelete)?;
+    check_if_user_has_camera_role(&conn, &user_auth, &camera_id_string, CameraRole::Editor)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    let path = find_image(&camera_id_string, &image_id_string)?;
+    trash_image(
+        camera_id,
+        &image_id_string,
+        &path,
+        user_auth.user_id(),
+        &conn,
+    )?;
+
+    record_audit_event(
+        AuditAction::ImagesDeleted,
+        camera_id,
+        &[image_id_string],
+        &user_auth,
+        &client_ip,
+        &conn,
+    );
+    Ok(())
+}
+
+/// Which images to delete in a batch delete. Either image_ids, or from and/or to, but not both.
+#[derive(Deserialize)]
+pub struct ImageSelection {
+    pub image_ids: Option<Vec<String>>,
+    /// Milliseconds since the epoch or an RFC 3339 date, inclusive
+    pub from: Option<String>,
+    /// Milliseconds since the epoch or an RFC 3339 date, exclusive
+    pub to: Option<String>,
+}
+
+/// The outcome of deleting one image from a batch delete.
+#[derive(Serialize)]
+pub struct BatchDeleteResult {
+    pub image_id: String,
+    pub error: Option<&'static str>,
+}
+
+/// Moves many images to the trash at once, either by ID or by when they were taken.
+/// Every image gets a result, so that one missing image doesn't stop the rest from being deleted.
+#[post(
+    "/cameras/<camera_id_string>/images/batch-delete",
+    format = "json",
+    data = "<selection>"
+)]
+pub fn delete_images(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    client_ip: ClientIp,
+    camera_id_string: String,
+    selection: Json<ImageSelection>,
+) -> Result<Json<Vec<BatchDeleteResult>>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ImagesDelete)?;
+    check_if_user_has_camera_role(&conn, &user_auth, &camera_id_string, CameraRole::Editor)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    let selection = selection.into_inner();
+    let time_range = TimeRange::parse(selection.from.as_deref(), selection.to.as_deref())?;
+
+    let images_directory_path = images_directory();
+    let camera_directory = camera_directory(&images_directory_path, &camera_id_string);
+
+    let to_delete: Vec<(String, Option<_>)> = match selection.image_ids {
+        Some(image_ids) => {
+            if !time_range.is_unbounded() {
+                return Err(ApiError {
+                    error: "Images can be picked by image_ids or by from and to, but not both",
+                    status: Status::BadRequest,
+                });
+            }
+            if image_ids.len() > MAX_BATCH_DELETE_IMAGES {
+                return Err(ApiError {
+                    error: "Too many images in batch delete",
+                    status: Status::PayloadTooLarge,
+                });
+            }
+
+            let image_list = list_camera_directory(&camera_directory, false)?;
+            image_ids
+                .into_iter()
+                .map(|image_id_string| {
+                    let path = image_list
+                        .iter()
+                        .find(|entry| image_id(entry) == image_id_string)
+                        .map(|entry| entry.path());
+                    (image_id_string, path)
+                })
+                .collect()
+        }
+        None => {
+            // Otherwise an empty body would delete everything
+            if time_range.is_unbounded() {
+                return Err(ApiError {
+                    error: "Either image_ids or at least one of from and to is needed",
+                    status: Status::BadRequest,
+                });
+            }
+
+            list_camera_directory(&camera_directory, true)?
+                .into_iter()
+                .map(|entry| (image_id(&entry), entry))
+                .filter(|(image_id, _)| time_range.contains(image_id))
+                .map(|(image_id, entry)| (image_id, Some(entry.path())))
+                .collect()
+        }
+    };
+
+    let results: Vec<BatchDeleteResult> = to_delete
+        .into_iter()
+        .map(|(image_id, path)| {
+            let result = match path {
+                Some(path) => trash_image(camera_id, &image_id, &path, user_auth.user_id(), &conn),
+                None => Err(ApiError {
+                    error: "Image not found",
+                    status: Status::NotFound,
+                }),
+            };
+            BatchDeleteResult {
+                image_id,
+                error: result.err().map(|error| error.error),
+            }
+        })
+        .collect();
+
+    let deleted_image_ids: Vec<String> = results
+        .iter()
+        .filter(|result| result.error.is_none())
+        .map(|result| result.image_id.clone())
+        .collect();
+    if !deleted_image_ids.is_empty() {
+        record_audit_event(
+            AuditAction::ImagesDeleted,
+            camera_id,
+            &deleted_image_ids,
+            &user_auth,
+            &client_ip,
+            &conn,
+        );
+    }
+
+    Ok(Json(results))
+}
+
+/// An image in the trash, along with when it'll be deleted for good.
+#[derive(Serialize)]
+pub struct TrashListEntry {
+    #[serde(flatten)]
+    pub trashed_image: TrashedImage,
+    pub purge_at: NaiveDateTime,
+}
+
+/// Lists the images in a camera's trash, most recently deleted first.
+#[get("/cameras/<camera_id_string>/trash")]
+pub fn list_trash(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    trash_config: State<TrashConfig>,
+    camera_id_string: String,
+) -> Result<Json<Vec<TrashListEntry>>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ImagesRead)?;
+    check_if_user_has_camera_role(&conn, &user_auth, &camera_id_string, CameraRole::Viewer)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    let trashed_images = get_cameras_trashed_images(camera_id, &conn).map_err(|error| {
+        error!("Failed to get trash for camera {}: {}", camera_id, error);
+        ApiError {
+            error: "Failed to get trash",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    Ok(Json(
+        trashed_images
+            .into_iter()
+            .map(|trashed_image| TrashListEntry {
+                purge_at: trashed_image.deleted_at + trash_config.hold_time,
+                trashed_image,
+            })
+            .collect(),
+    ))
+}
+
+/// Moves an image from the trash back into its camera's directory, with the same ID it had before.
+#[post("/cameras/<camera_id_string>/trash/<image_id_string>/restore")]
+pub fn restore_image(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    client_ip: ClientIp,
+    camera_id_string: String,
+    image_id_string: String,
+) -> Result<(), ApiError> {
+    user_auth.require_scope(ApiKeyScope::ImagesDelete)?;
+    check_if_user_has_camera_role(&conn, &user_auth, &camera_id_string, CameraRole::Editor)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    let trashed_image = match get(camera_id, &image_id_string, &conn) {
+        Ok(trashed_image) => trashed_image,
+        Err(diesel::NotFound) => {
+            return Err(ApiError {
+                error: "Image isn't in the trash",
+                status: Status::NotFound,
+            })
+        }
+        Err(error) => {
+            error!("Failed to get trashed image {}: {}", image_id_string, error);
+            return Err(ApiError {
+                error: "Failed to restore image",
+                status: Status::InternalServerError,
+            });
+        }
+    };
+
+    // A new image could have been uploaded with the same ID since this one was deleted
+    if find_image(&camera_id_string, &image_id_string).is_ok() {
+        return Err(ApiError {
+            error: "Another image with this ID has been uploaded since it was deleted",
+            status: Status::Conflict,
+        });
+    }
+
+    let images_directory_path = images_directory();
+    let trash_path = format!(
+        "{}/{}",
+        trash_directory(&images_directory_path, &camera_id_string),
+        trashed_image.file_name
+    );
+    let camera_directory = camera_directory(&images_directory_path, &camera_id_string);
+    let restore_error = |action: &str, path: &str, error: &dyn std::fmt::Display| {
+        error!("Failed to {} {}: {}", action, path, error);
+        ApiError {
+            error: "Failed to restore image",
+            status: Status::InternalServerError,
+        }
+    };
+    create_dir_all(&camera_directory)
+        .map_err(|error| restore_error("create directory", &camera_directory, &error))?;
+    // Restored images count towards the quota again, but aren't refused for going over it
+    storage_quota::get_or_compute_usage(camera_id, &conn)?;
+
+    // Linked rather than renamed, so that it can't replace an image uploaded at the same moment
+    let path = format!("{}/{}", camera_directory, trashed_image.file_name);
+    hard_link(&trash_path, &path).map_err(|error| match error.kind() {
+        ErrorKind::AlreadyExists => ApiError {
+            error: "Another image with this ID has been uploaded since it was deleted",
+            status: Status::Conflict,
+        },
+        _ => restore_error("restore", &trash_path, &error),
+    })?;
+
+    // Left in place, the entry would have the image's metadata deleted when the trash is emptied
+    if let Err(error) = delete(camera_id, &image_id_string, &conn) {
+        if let Err(error) = remove_file(&path) {
+            error!("Failed to undo restoring {}: {}", path, error);
+        }
+        return Err(restore_error(
+            "remove trash entry for",
+            &image_id_string,
+            &error,
+        ));
+    }
+    let bytes = Path::new(&path)
+        .metadata()
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    storage_quota::record_change(camera_id, 1, bytes as i64, &conn);
+    if let Err(error) = remove_file(&trash_path) {
+        warn!(
+            "Failed to remove restored image {} from the trash: {}",
+            trash_path, error
+        );
+    }
+
+    record_audit_event(
+        AuditAction::ImagesRestored,
+        camera_id,
+        &[image_id_string],
+        &user_auth,
+        &client_ip,
+        &conn,
+    );
+    Ok(())
+}
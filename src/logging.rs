@@ -0,0 +1,197 @@
+use chrono::Utc;
+use env_logger::{fmt::Formatter, Env};
+use log::Record;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    Data, Request, Response,
+};
+use serde_json::json;
+use std::cell::RefCell;
+use std::env;
+use std::io::Write;
+use std::time::Instant;
+
+/// Header used to pass request IDs in both directions. Clients and proxies can send their own, otherwise one is generated.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+/// Context added to every log line written while handling a request.
+#[derive(Default, Clone)]
+struct RequestFields {
+    request_id: Option<String>,
+    user_id: Option<uuid::Uuid>,
+    camera_id: Option<uuid::Uuid>,
+    api_key_id: Option<uuid::Uuid>,
+}
+
+// Rocket 0.4 handles each request from start to finish on one worker thread, so the fields for the current
+// request can be kept in a thread local where the logger can get at them.
+thread_local! {
+    static REQUEST_FIELDS: RefCell<RequestFields> = RefCell::new(RequestFields::default());
+}
+
+/// Sets up the logger. The level is set with RUST_LOG (defaults to info), and LOG_FORMAT=json switches to one JSON object per line.
+/// This has to be called before Rocket is started, otherwise Rocket installs its own logger.
+pub fn init() {
+    let json_output = env::var("LOG_FORMAT")
+        .map(|format| format.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
+
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    if json_output {
+        builder.format(format_json);
+    } else {
+        builder.format(format_text);
+    }
+    builder.init();
+}
+
+fn format_text(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let fields = REQUEST_FIELDS.with(|fields| fields.borrow().clone());
+
+    let mut context = String::new();
+    if let Some(request_id) = &fields.request_id {
+        context.push_str(&format!(" request_id={}", request_id));
+    }
+    if let Some(user_id) = &fields.user_id {
+        context.push_str(&format!(" user_id={}", user_id));
+    }
+    if let Some(camera_id) = &fields.camera_id {
+        context.push_str(&format!(" camera_id={}", camera_id));
+    }
+    if let Some(api_key_id) = &fields.api_key_id {
+        context.push_str(&format!(" api_key_id={}", api_key_id));
+    }
+
+    writeln!(
+        buf,
+        "{} {:<5} {}:{} {}",
+        Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+        record.level(),
+        record.target(),
+        context,
+        record.args()
+    )
+}
+
+fn format_json(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let fields = REQUEST_FIELDS.with(|fields| fields.borrow().clone());
+
+    writeln!(
+        buf,
+        "{}",
+        json!({
+            "timestamp": Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+            "level": record.level().to_string(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+            "request_id": fields.request_id,
+            "user_id": fields.user_id,
+            "camera_id": fields.camera_id,
+            "api_key_id": fields.api_key_id,
+        })
+    )
+}
+
+/// Adds the user ID to log lines for the rest of the request. Called by the token guards once they've succeeded.
+pub fn set_user_id(user_id: uuid::Uuid) {
+    REQUEST_FIELDS.with(|fields| fields.borrow_mut().user_id = Some(user_id));
+}
+
+pub fn set_camera_id(camera_id: uuid::Uuid) {
+    REQUEST_FIELDS.with(|fields| fields.borrow_mut().camera_id = Some(camera_id));
+}
+
+pub fn set_api_key_id(api_key_id: uuid::Uuid) {
+    REQUEST_FIELDS.with(|fields| fields.borrow_mut().api_key_id = Some(api_key_id));
+}
+
+/// Request-local value holding the request's ID and when it started.
+struct RequestContext {
+    request_id: String,
+    start: Instant,
+}
+
+/// Returns the ID of the request, as sent back in the X-Request-Id header.
+pub fn request_id(request: &Request) -> String {
+    request.local_cache(new_request_context).request_id.clone()
+}
+
+fn new_request_context() -> RequestContext {
+    RequestContext {
+        request_id: uuid::Uuid::new_v4().to_string(),
+        start: Instant::now(),
+    }
+}
+
+/// Only IDs made of simple characters are reused, so that they can't be used to inject anything into the logs.
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LENGTH
+        && request_id
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_' || x == '.')
+}
+
+/// Gives every request an ID, adds it to the response headers and logs a summary line once the request is done.
+pub struct RequestLogFairing;
+
+impl Fairing for RequestLogFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Logger",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let incoming_request_id = request
+            .headers()
+            .get_one(REQUEST_ID_HEADER)
+            .filter(|request_id| is_valid_request_id(request_id))
+            .map(String::from);
+
+        let context = request.local_cache(|| RequestContext {
+            request_id: incoming_request_id.unwrap_or(uuid::Uuid::new_v4().to_string()),
+            start: Instant::now(),
+        });
+
+        REQUEST_FIELDS.with(|fields| {
+            *fields.borrow_mut() = RequestFields {
+                request_id: Some(context.request_id.clone()),
+                ..RequestFields::default()
+            }
+        });
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let context = request.local_cache(new_request_context);
+        response.set_header(Header::new(REQUEST_ID_HEADER, context.request_id.clone()));
+
+        let route = request
+            .route()
+            .map(|route| route.uri.path().to_string())
+            .unwrap_or(String::from("unmatched"));
+        let status = response.status();
+        let latency_ms = context.start.elapsed().as_secs_f64() * 1000.0;
+
+        let message = format!(
+            "method={} route={} status={} latency_ms={:.1}",
+            request.method(),
+            route,
+            status.code,
+            latency_ms
+        );
+        if status.code >= 500 {
+            error!(target: "request", "{}", message);
+        } else if route.starts_with("/health/") {
+            // Probes hit these every few seconds, so they'd drown out everything else at info
+            debug!(target: "request", "{}", message);
+        } else {
+            info!(target: "request", "{}", message);
+        }
+
+        REQUEST_FIELDS.with(|fields| *fields.borrow_mut() = RequestFields::default());
+    }
+}
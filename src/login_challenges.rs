@@ -0,0 +1,75 @@
+use super::schema::login_challenges;
+
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{self};
+use serde::{Deserialize, Serialize};
+
+/// A login that has passed the password check and is waiting for a TOTP code.
+#[derive(Queryable, AsChangeset, Deserialize, Serialize)]
+#[table_name = "login_challenges"]
+pub struct LoginChallenge {
+    pub challenge: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub expires_at: NaiveDateTime,
+    pub failed_attempts: i16,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "login_challenges"]
+pub struct InsertableLoginChallenge {
+    pub user_id: uuid::Uuid,
+    pub expires_at: NaiveDateTime,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<LoginChallenge>> {
+    login_challenges::table.load::<LoginChallenge>(&*connection)
+}
+
+pub fn get(challenge: uuid::Uuid, connection: &PgConnection) -> QueryResult<LoginChallenge> {
+    login_challenges::table
+        .find(challenge)
+        .get_result::<LoginChallenge>(connection)
+}
+
+pub fn insert(
+    login_challenge: InsertableLoginChallenge,
+    connection: &PgConnection,
+) -> QueryResult<LoginChallenge> {
+    diesel::insert_into(login_challenges::table)
+        .values(login_challenge)
+        .get_result(connection)
+}
+
+pub fn update(
+    challenge: uuid::Uuid,
+    login_challenge: LoginChallenge,
+    connection: &PgConnection,
+) -> QueryResult<LoginChallenge> {
+    diesel::update(login_challenges::table.find(challenge))
+        .set(&login_challenge)
+        .get_result(connection)
+}
+
+/// Counts an attempt at the challenge if it has had fewer than max_attempts, and returns the new count. Returns None if
+/// it's used them all up or doesn't exist. It's one conditional UPDATE, so attempts made at the same time can't go over.
+pub fn count_attempt(
+    challenge: uuid::Uuid,
+    max_attempts: i16,
+    connection: &PgConnection,
+) -> QueryResult<Option<i16>> {
+    diesel::update(
+        login_challenges::table
+            .find(challenge)
+            .filter(login_challenges::failed_attempts.lt(max_attempts)),
+    )
+    .set(login_challenges::failed_attempts.eq(login_challenges::failed_attempts + 1))
+    .returning(login_challenges::failed_attempts)
+    .get_result(connection)
+    .optional()
+}
+
+pub fn delete(challenge: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(login_challenges::table.find(challenge)).execute(connection)
+}
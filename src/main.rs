@@ -1,4 +1,6 @@
 #![feature(proc_macro_hygiene, decl_macro)]
+// The OpenAPI components are one big json! invocation, which goes past the default limit
+#![recursion_limit = "256"]
 
 #[macro_use]
 extern crate rocket;
@@ -6,42 +8,172 @@ extern crate rocket;
 extern crate diesel;
 #[macro_use]
 extern crate rocket_contrib;
+#[macro_use]
+extern crate log;
 
 extern crate bcrypt;
 
+mod admin_key;
+mod api_keys;
+mod audit_log;
+mod auth_token;
 mod camera;
+mod camera_groups;
 mod camera_tokens;
+mod catchers;
 mod enums {
     pub mod token_error;
 }
 mod api_error;
+mod api_v1;
 mod config;
+mod health;
+mod http_cache;
+mod image_dedup;
+mod image_exif;
+mod image_export;
+mod image_luminance;
+mod image_metadata;
+mod image_similarity;
+mod image_store;
+mod image_trash;
+mod logging;
+mod login_challenges;
+mod metrics;
+mod mosaic;
+mod openapi;
+mod password_reset_tokens;
+mod privacy_masks;
+mod rate_limiter;
 mod schema;
+mod storage_quota;
+mod totp;
+mod totp_recovery_codes;
 mod user;
+mod user_auth;
 mod user_tokens;
 mod users_cameras;
+mod watermarks;
 
 #[database("camera-server-db")]
 pub struct CameraServerDbConn(diesel::PgConnection);
 
 fn main() {
+    logging::init();
+
     rocket::ignite()
+        .attach(logging::RequestLogFairing)
         .attach(CameraServerDbConn::fairing())
+        .attach(rate_limiter::RateLimiterFairing)
+        .attach(metrics::MetricsFairing)
+        .attach(health::fairing())
+        .attach(image_store::fairing())
+        .attach(mosaic::fairing())
+        // Needs the database pool, so it's attached after CameraServerDbConn
+        .attach(image_trash::fairing())
         .mount(
             "/",
             routes![
                 user::add_user,
                 user::login,
+                user::login_totp,
+                user::logout,
+                user::change_password,
+                user::create_password_reset_token,
+                user::reset_password,
+                user::delete_account,
+                totp::enroll,
+                totp::verify,
+                totp::regenerate_recovery_codes,
+                totp::disable,
+                api_keys::create_api_key,
+                api_keys::list_api_keys,
+                api_keys::revoke_api_key,
+                audit_log::get_account_audit_log,
+                audit_log::get_camera_audit_log,
+                metrics::get_metrics,
+                health::live,
+                health::ready,
+                openapi::get_openapi,
+                openapi::get_docs,
                 camera::add_new_camera,
                 camera::upload_image,
                 camera::get_latest,
                 camera::get_image_list,
                 camera::get_image,
+                camera::get_image_metadata,
                 users_cameras::list_cameras,
                 config::get_config_user,
                 config::get_config_camera,
                 config::update_config,
             ],
         )
+        .mount(
+            api_v1::BASE,
+            routes![
+                api_v1::create_user,
+                api_v1::create_session,
+                api_v1::complete_totp_session,
+                api_v1::delete_current_session,
+                api_v1::change_password,
+                api_v1::create_password_reset_token,
+                api_v1::reset_password,
+                api_v1::delete_account,
+                api_v1::enroll_totp,
+                api_v1::verify_totp,
+                api_v1::regenerate_recovery_codes,
+                api_v1::disable_totp,
+                api_v1::get_account_audit_log,
+                api_v1::create_api_key,
+                api_v1::list_api_keys,
+                api_v1::revoke_api_key,
+                api_v1::create_camera,
+                api_v1::list_cameras,
+                api_v1::upload_image,
+                camera::upload_image_batch,
+                camera::get_thumbnail,
+                camera_groups::list_camera_groups,
+                camera_groups::create_camera_group,
+                camera_groups::update_camera_group,
+                camera_groups::delete_camera_group,
+                mosaic::get_mosaic,
+                image_export::export_images,
+                image_dedup::get_dedup_savings,
+                image_similarity::get_similar_images,
+                image_similarity::search_similar_images,
+                image_trash::delete_image,
+                image_trash::delete_images,
+                image_trash::list_trash,
+                image_trash::restore_image,
+                privacy_masks::get_privacy_masks,
+                privacy_masks::list_privacy_mask_versions,
+                privacy_masks::update_privacy_masks,
+                storage_quota::get_camera_storage,
+                storage_quota::get_user_storage,
+                users_cameras::set_camera_role,
+                watermarks::get_watermark,
+                watermarks::update_watermark,
+                watermarks::delete_watermark,
+                api_v1::get_own_config,
+                api_v1::list_images,
+                api_v1::get_latest_image,
+                api_v1::get_image,
+                api_v1::get_image_metadata,
+                api_v1::get_camera_config,
+                api_v1::update_camera_config,
+                api_v1::get_camera_audit_log,
+            ],
+        )
+        .attach(api_v1::DeprecationFairing)
+        // Attached after mounting, since it documents every mounted route
+        .attach(openapi::OpenApiFairing)
+        .register(catchers![
+            catchers::bad_request,
+            catchers::unauthorized,
+            catchers::forbidden,
+            catchers::not_found,
+            catchers::unprocessable_entity,
+            catchers::internal_server_error,
+        ])
         .launch();
 }
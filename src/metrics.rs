@@ -0,0 +1,423 @@
+use crate::{
+    api_error::ApiError, camera::images_directory, config, CameraServerDbConn,
+    CameraServerDbConnPool,
+};
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    get,
+    http::{ContentType, Status},
+    response::content::Content,
+    Data, Request, Response, Rocket, State,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_dir, Metadata};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A camera is counted as offline once it's missed this many of its configured upload intervals.
+const MISSED_INTERVALS_BEFORE_OFFLINE: u64 = 3;
+
+/// Walking the images directory stats every stored image, so scrapes in between reuse the last walk.
+const IMAGE_STORE_SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Latency buckets in seconds. Image downloads can take a while on slow connections, so these go up to 10s.
+const REQUEST_DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Holds all of the server's Prometheus metrics. Managed by Rocket, see MetricsFairing.
+pub struct Metrics {
+    registry: Registry,
+    http_requests_total: IntCounterVec,
+    http_request_duration_seconds: HistogramVec,
+    image_uploads_total: IntCounterVec,
+    image_upload_bytes_total: IntCounterVec,
+    db_pool_connections: IntGauge,
+    db_pool_idle_connections: IntGauge,
+    db_pool_max_connections: IntGauge,
+    image_store_bytes: IntGauge,
+    camera_last_image_timestamp_seconds: IntGaugeVec,
+    cameras_online: IntGauge,
+    cameras_offline: IntGauge,
+    image_store_scan: Mutex<Option<ImageStoreScan>>,
+    /// Unix time of each camera's last upload since the server started. Duplicate uploads don't write a new file, so
+    /// they can't be seen in the image store.
+    last_uploads: Mutex<HashMap<uuid::Uuid, u64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some(String::from("camera_server")), None)
+                .expect("Failed to create metrics registry"),
+            http_requests_total: IntCounterVec::new(
+                Opts::new("http_requests_total", "Number of HTTP requests handled"),
+                &["method", "route", "status"],
+            )
+            .expect("Failed to create http_requests_total metric"),
+            http_request_duration_seconds: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time taken to handle HTTP requests",
+                )
+                .buckets(REQUEST_DURATION_BUCKETS.to_vec()),
+                &["method", "route"],
+            )
+            .expect("Failed to create http_request_duration_seconds metric"),
+            image_uploads_total: IntCounterVec::new(
+                Opts::new("image_uploads_total", "Number of images uploaded"),
+                &["camera_id"],
+            )
+            .expect("Failed to create image_uploads_total metric"),
+            image_upload_bytes_total: IntCounterVec::new(
+                Opts::new("image_upload_bytes_total", "Bytes of image data uploaded"),
+                &["camera_id"],
+            )
+            .expect("Failed to create image_upload_bytes_total metric"),
+            db_pool_connections: IntGauge::new(
+                "db_pool_connections",
+                "Number of open database connections",
+            )
+            .expect("Failed to create db_pool_connections metric"),
+            db_pool_idle_connections: IntGauge::new(
+                "db_pool_idle_connections",
+                "Number of idle database connections",
+            )
+            .expect("Failed to create db_pool_idle_connections metric"),
+            db_pool_max_connections: IntGauge::new(
+                "db_pool_max_connections",
+                "Maximum size of the database connection pool",
+            )
+            .expect("Failed to create db_pool_max_connections metric"),
+            image_store_bytes: IntGauge::new("image_store_bytes", "Total size of stored images")
+                .expect("Failed to create image_store_bytes metric"),
+            camera_last_image_timestamp_seconds: IntGaugeVec::new(
+                Opts::new(
+                    "camera_last_image_timestamp_seconds",
+                    "Unix time of each camera's last upload",
+                ),
+                &["camera_id"],
+            )
+            .expect("Failed to create camera_last_image_timestamp_seconds metric"),
+            cameras_online: IntGauge::new(
+                "cameras_online",
+                "Number of cameras that have uploaded an image recently",
+            )
+            .expect("Failed to create cameras_online metric"),
+            cameras_offline: IntGauge::new(
+                "cameras_offline",
+                "Number of cameras that have missed several uploads in a row, or never uploaded",
+            )
+            .expect("Failed to create cameras_offline metric"),
+            image_store_scan: Mutex::new(None),
+            last_uploads: Mutex::new(HashMap::new()),
+        };
+
+        let registry = &metrics.registry;
+        let register = |collector: Box<dyn prometheus::core::Collector>| {
+            registry
+                .register(collector)
+                .expect("Failed to register metric")
+        };
+        register(Box::new(metrics.http_requests_total.clone()));
+        register(Box::new(metrics.http_request_duration_seconds.clone()));
+        register(Box::new(metrics.image_uploads_total.clone()));
+        register(Box::new(metrics.image_upload_bytes_total.clone()));
+        register(Box::new(metrics.db_pool_connections.clone()));
+        register(Box::new(metrics.db_pool_idle_connections.clone()));
+        register(Box::new(metrics.db_pool_max_connections.clone()));
+        register(Box::new(metrics.image_store_bytes.clone()));
+        register(Box::new(
+            metrics.camera_last_image_timestamp_seconds.clone(),
+        ));
+        register(Box::new(metrics.cameras_online.clone()));
+        register(Box::new(metrics.cameras_offline.clone()));
+
+        metrics
+    }
+
+    /// Counts an uploaded image against the camera that sent it, including duplicates that weren't stored again.
+    pub fn record_upload(&self, camera_id: &uuid::Uuid, bytes: u64) {
+        self.last_uploads
+            .lock()
+            .expect("Last uploads mutex was poisoned")
+            .insert(*camera_id, unix_time());
+
+        let camera_id = camera_id.to_string();
+        self.image_uploads_total
+            .with_label_values(&[&camera_id])
+            .inc();
+        self.image_upload_bytes_total
+            .with_label_values(&[&camera_id])
+            .inc_by(bytes);
+    }
+
+    /// Updates the gauges that are worked out when scraped rather than as things happen.
+    fn refresh(&self, conn: &CameraServerDbConn, pool: &CameraServerDbConnPool) {
+        let pool_state = pool.0.state();
+        self.db_pool_connections.set(pool_state.connections as i64);
+        self.db_pool_idle_connections
+            .set(pool_state.idle_connections as i64);
+        self.db_pool_max_connections.set(pool.0.max_size() as i64);
+
+        let mut image_store_scan = self
+            .image_store_scan
+            .lock()
+            .expect("Image store scan mutex was poisoned");
+        if image_store_scan.as_ref().map_or(false, |scan| {
+            scan.scanned_at.elapsed() >= IMAGE_STORE_SCAN_INTERVAL
+        }) {
+            *image_store_scan = None;
+        }
+        let image_store =
+            image_store_scan.get_or_insert_with(|| scan_image_store(&images_directory()));
+        self.image_store_bytes
+            .set(image_store.cameras.values().map(|x| x.bytes).sum::<u64>() as i64);
+
+        let configs = match config::all(conn) {
+            Ok(configs) => configs,
+            Err(error) => {
+                error!("Failed to get camera configs for metrics: {}", error);
+                return;
+            }
+        };
+
+        let now = unix_time();
+        let last_uploads = self
+            .last_uploads
+            .lock()
+            .expect("Last uploads mutex was poisoned");
+
+        // Cameras that have been deleted shouldn't keep reporting their last timestamp
+        self.camera_last_image_timestamp_seconds.reset();
+
+        let mut online = 0;
+        let mut offline = 0;
+        for camera_config in configs {
+            let last_upload = last_uploads.get(&camera_config.camera_id).copied();
+            // Newest file in the store, for cameras that haven't uploaded since the server started
+            let last_modified = image_store
+                .cameras
+                .get(&camera_config.camera_id.to_string())
+                .and_then(|x| x.last_modified);
+
+            if let Some(last_image) = last_upload.max(last_modified) {
+                self.camera_last_image_timestamp_seconds
+                    .with_label_values(&[&camera_config.camera_id.to_string()])
+                    .set(last_image as i64);
+            }
+
+            // Files are compared with when the directory was walked rather than now, so a reused walk doesn't make
+            // cameras look late
+            let since_last_upload = last_upload.map(|last_upload| now.saturating_sub(last_upload));
+            let since_last_modified = last_modified
+                .map(|last_modified| image_store.scanned_at_unix.saturating_sub(last_modified));
+            let since_last_image = since_last_upload
+                .into_iter()
+                .chain(since_last_modified)
+                .min();
+
+            let allowed_gap =
+                camera_config.interval.max(1) as u64 * MISSED_INTERVALS_BEFORE_OFFLINE;
+            match since_last_image {
+                Some(since_last_image) if since_last_image <= allowed_gap => online += 1,
+                _ => offline += 1,
+            }
+        }
+
+        self.cameras_online.set(online);
+        self.cameras_offline.set(offline);
+    }
+}
+
+struct ImageStoreScan {
+    scanned_at: Instant,
+    scanned_at_unix: u64,
+    cameras: HashMap<String, CameraDirectoryStats>,
+}
+
+struct CameraDirectoryStats {
+    bytes: u64,
+    /// Unix time of the most recently modified image
+    last_modified: Option<u64>,
+}
+
+/// Walks the images directory, returning the total size and newest image time for each camera directory.
+/// Duplicate images that share a file only count towards the size once.
+fn scan_image_store(images_directory_path: &String) -> ImageStoreScan {
+    let mut stats = ImageStoreScan {
+        scanned_at: Instant::now(),
+        scanned_at_unix: unix_time(),
+        cameras: HashMap::new(),
+    };
+    let mut linked_files = HashSet::new();
+
+    let camera_directories = match read_dir(images_directory_path) {
+        Ok(camera_directories) => camera_directories,
+        Err(error) => {
+            error!(
+                "Failed to read images directory {} for metrics: {}",
+                images_directory_path, error
+            );
+            return stats;
+        }
+    };
+
+    for camera_directory in camera_directories.filter_map(Result::ok) {
+        let camera_directory_path = camera_directory.path();
+        // Skips files and the directory that uploads are written to before they're checked
+        if !camera_directory_path.is_dir()
+            || file_name_string(&camera_directory_path).starts_with('.')
+        {
+            continue;
+        }
+
+        let mut camera_stats = CameraDirectoryStats {
+            bytes: 0,
+            last_modified: None,
+        };
+
+        if let Ok(images) = read_dir(&camera_directory_path) {
+            for metadata in images
+                .filter_map(Result::ok)
+                .filter_map(|image| image.metadata().ok())
+                .filter(|metadata| metadata.is_file())
+            {
+                if is_first_link(&metadata, &mut linked_files) {
+                    camera_stats.bytes += metadata.len();
+                }
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+                    .map(|modified| modified.as_secs());
+                camera_stats.last_modified = camera_stats.last_modified.max(modified);
+            }
+        }
+
+        stats
+            .cameras
+            .insert(file_name_string(&camera_directory_path), camera_stats);
+    }
+
+    stats
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Failed to get current time somehow?")
+        .as_secs()
+}
+
+/// Returns false if the file is a hard link to one that's already been counted.
+#[cfg(unix)]
+fn is_first_link(metadata: &Metadata, linked_files: &mut HashSet<(u64, u64)>) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    // Most images only have one link, so only the shared ones need remembering
+    metadata.nlink() < 2 || linked_files.insert((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn is_first_link(_: &Metadata, _: &mut HashSet<(u64, u64)>) -> bool {
+    true
+}
+
+fn file_name_string(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Metrics in the Prometheus text format.
+/// This isn't authenticated, so if the server is public it should be blocked by the reverse proxy in front of it.
+#[get("/metrics")]
+pub fn get_metrics(
+    conn: CameraServerDbConn,
+    metrics: State<Metrics>,
+    pool: State<CameraServerDbConnPool>,
+) -> Result<Content<String>, ApiError> {
+    metrics.refresh(&conn, &pool);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&metrics.registry.gather(), &mut buffer)
+        .map_err(|error| {
+            error!("Failed to encode metrics: {}", error);
+            ApiError {
+                error: "Failed to encode metrics",
+                status: Status::InternalServerError,
+            }
+        })?;
+
+    let text = String::from_utf8(buffer).map_err(|error| {
+        error!("Metrics weren't valid UTF-8: {}", error);
+        ApiError {
+            error: "Failed to encode metrics",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    Ok(Content(
+        ContentType::with_params("text", "plain", ("version", "0.0.4")),
+        text,
+    ))
+}
+
+/// Request-local value holding when the request started, used to time it.
+struct RequestStart(Instant);
+
+/// Manages the Metrics and records the count and latency of every request.
+pub struct MetricsFairing;
+
+impl Fairing for MetricsFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Metrics",
+            kind: Kind::Attach | Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        Ok(rocket.manage(Metrics::new()))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let metrics = match request.guard::<State<Metrics>>().succeeded() {
+            Some(metrics) => metrics,
+            None => return,
+        };
+
+        let elapsed = request
+            .local_cache(|| RequestStart(Instant::now()))
+            .0
+            .elapsed();
+
+        // The route's path template is used rather than the actual path, so IDs don't each get their own series
+        let route = request
+            .route()
+            .map(|route| route.uri.path().to_string())
+            .unwrap_or(String::from("unmatched"));
+        let method = request.method().as_str();
+
+        metrics
+            .http_requests_total
+            .with_label_values(&[method, &route, &response.status().code.to_string()])
+            .inc();
+        metrics
+            .http_request_duration_seconds
+            .with_label_values(&[method, &route])
+            .observe(elapsed.as_secs_f64());
+    }
+}
@@ -0,0 +1,569 @@
+use crate::{
+    api_error::ApiError,
+    api_keys::ApiKeyScope,
+    camera::{camera_directory, images_directory, list_camera_directory},
+    camera_groups::{self, max_group_cameras, MAX_GROUP_CAMERAS},
+    config,
+    http_cache::{format_http_date, is_not_modified},
+    image_store::{self, image_id, image_timestamp_ms, now_ms, ImageFormat},
+    user_auth::UserAuth,
+    users_cameras::{check_if_user_has_access_to_camera, get_users_cameras},
+    watermarks, CameraServerDbConn,
+};
+
+use chrono::{DateTime, Utc};
+use image::{imageops, imageops::FilterType, DynamicImage, Rgba, RgbaImage};
+use rocket::{
+    fairing::AdHoc,
+    get,
+    http::{ContentType, Status},
+    response::{self, Responder},
+    Request, Response, State,
+};
+use rusttype::{point, Font, Scale};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+const DEFAULT_CACHE_SECONDS: u64 = 10;
+/// Once this many mosaics are cached, expired ones are cleared out on the next new one.
+const MAX_CACHED_BEFORE_PRUNE: usize = 100;
+
+const DEFAULT_TILE_WIDTH: u32 = 480;
+const MIN_TILE_WIDTH: u32 = 160;
+const MAX_TILE_WIDTH: u32 = 960;
+/// Space between the tiles, in pixels
+const GAP: u32 = 4;
+/// How thick the border around a late or offline camera's tile is, in pixels
+const BORDER: u32 = 4;
+
+/// Used for cameras whose config can't be read.
+const DEFAULT_INTERVAL_SECONDS: u64 = 60;
+/// A camera is late once its latest frame is this many upload intervals old, and offline at OFFLINE_INTERVALS.
+const LATE_INTERVALS: u64 = 3;
+const OFFLINE_INTERVALS: u64 = 10;
+
+/// Settings for mosaics, read from the mosaic table in Rocket.toml, and the mosaics made recently.
+/// Mosaics are cached briefly, so that wall displays polling the same cameras share one instead of each making their own.
+pub struct MosaicCache {
+    max_age: Duration,
+    mosaics: Mutex<HashMap<String, CachedMosaic>>,
+    /// A lock for each mosaic being made, so requests that miss the cache at the same time wait for one of them to make
+    /// it instead of all making their own
+    builds: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+#[derive(Clone)]
+struct CachedMosaic {
+    jpeg: Vec<u8>,
+    /// Quoted, as it appears in the header
+    etag: String,
+    created_at: DateTime<Utc>,
+    expires_at: Instant,
+}
+
+impl MosaicCache {
+    fn get(&self, key: &str) -> Option<CachedMosaic> {
+        let mosaics = self
+            .mosaics
+            .lock()
+            .expect("Mosaic cache mutex was poisoned");
+        mosaics
+            .get(key)
+            .filter(|mosaic| mosaic.expires_at > Instant::now())
+            .cloned()
+    }
+
+    fn insert(&self, key: String, jpeg: Vec<u8>) -> CachedMosaic {
+        let now = Instant::now();
+        let hash = Sha256::digest(&jpeg);
+        let mosaic = CachedMosaic {
+            etag: format!(
+                "\"{}\"",
+                hash.iter()
+                    .take(8)
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>()
+            ),
+            jpeg,
+            created_at: Utc::now(),
+            expires_at: now + self.max_age,
+        };
+
+        let mut mosaics = self
+            .mosaics
+            .lock()
+            .expect("Mosaic cache mutex was poisoned");
+        if mosaics.len() > MAX_CACHED_BEFORE_PRUNE {
+            mosaics.retain(|_, mosaic| mosaic.expires_at > now);
+        }
+        mosaics.insert(key, mosaic.clone());
+        mosaic
+    }
+
+    /// Returns the lock to hold while making the mosaic for the key.
+    fn build_lock(&self, key: &str) -> Arc<Mutex<()>> {
+        let mut builds = self.builds.lock().expect("Mosaic build mutex was poisoned");
+        if builds.len() > MAX_CACHED_BEFORE_PRUNE {
+            // Nothing else has a copy of these, so no one is making those mosaics
+            builds.retain(|_, lock| Arc::strong_count(lock) > 1);
+        }
+        builds.entry(key.to_string()).or_default().clone()
+    }
+}
+
+/// Manages the MosaicCache.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Mosaic Cache", |rocket| {
+        let cache_seconds = rocket
+            .config()
+            .get_table("mosaic")
+            .ok()
+            .and_then(|table| table.get("cache_seconds"))
+            .and_then(|value| value.as_integer())
+            .map(|value| value.max(0) as u64)
+            .unwrap_or(DEFAULT_CACHE_SECONDS);
+
+        Ok(rocket.manage(MosaicCache {
+            max_age: Duration::from_secs(cache_seconds),
+            mosaics: Mutex::new(HashMap::new()),
+            builds: Mutex::new(HashMap::new()),
+        }))
+    })
+}
+
+/// How recently a camera sent its latest frame, compared to how often it's meant to.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Freshness {
+    Live,
+    Late,
+    Offline,
+}
+
+impl Freshness {
+    fn of(age_seconds: Option<u64>, interval_seconds: u64) -> Freshness {
+        match age_seconds {
+            Some(age) if age < interval_seconds * LATE_INTERVALS => Freshness::Live,
+            Some(age) if age < interval_seconds * OFFLINE_INTERVALS => Freshness::Late,
+            _ => Freshness::Offline,
+        }
+    }
+
+    fn colour(self) -> [u8; 3] {
+        match self {
+            Freshness::Live => [46, 204, 64],
+            Freshness::Late => [255, 191, 0],
+            Freshness::Offline => [230, 50, 50],
+        }
+    }
+}
+
+/// Describes how long ago a frame was taken, like "5m ago".
+fn format_age(age_seconds: u64) -> String {
+    match age_seconds {
+        0..=4 => "just now".to_string(),
+        5..=59 => format!("{}s ago", age_seconds),
+        60..=3599 => format!("{}m ago", age_seconds / 60),
+        3600..=86399 => format!("{}h ago", age_seconds / 3600),
+        _ => format!("{}d ago", age_seconds / 86400),
+    }
+}
+
+/// What goes in one of the mosaic's tiles.
+struct Tile {
+    camera_name: String,
+    /// None if the camera has no images, or its latest one can't be decoded
+    frame: Option<DynamicImage>,
+    /// How old the latest frame is, or None if the camera has no images
+    age_seconds: Option<u64>,
+    freshness: Freshness,
+}
+
+/// Returns the path and ID of the camera's latest image, or None if it doesn't have any.
+fn find_latest_image(camera_id_string: &str) -> Option<(PathBuf, String)> {
+    let camera_directory = camera_directory(&images_directory(), &camera_id_string.to_string());
+    // Cameras that have never uploaded don't have a directory yet
+    if !Path::new(&camera_directory).is_dir() {
+        return None;
+    }
+    list_camera_directory(&camera_directory, true)
+        .ok()?
+        .last()
+        .map(|latest_image| (latest_image.path(), image_id(latest_image)))
+}
+
+/// Loads the camera's latest frame, with its watermark if that's added on the fly, and works out how stale it is.
+fn load_tile(
+    conn: &CameraServerDbConn,
+    user_auth: &UserAuth,
+    camera_id: uuid::Uuid,
+    camera_name: String,
+) -> Result<Tile, ApiError> {
+    let camera_id_string = camera_id.to_string();
+    let interval_seconds = config::get(camera_id, conn)
+        .map(|config| config.interval.max(1) as u64)
+        .unwrap_or(DEFAULT_INTERVAL_SECONDS);
+
+    let (path, latest_image_id) = match find_latest_image(&camera_id_string) {
+        Some(latest_image) => latest_image,
+        None => {
+            return Ok(Tile {
+                camera_name,
+                frame: None,
+                age_seconds: None,
+                freshness: Freshness::Offline,
+            })
+        }
+    };
+
+    let age_seconds = image_timestamp_ms(&latest_image_id)
+        .map(|captured_at_ms| now_ms().saturating_sub(captured_at_ms) / 1000);
+    let watermark = watermarks::get_on_the_fly_text(
+        conn,
+        user_auth,
+        &camera_id_string,
+        &latest_image_id,
+        false,
+    )?;
+    let frame = ImageFormat::from_path(&path)
+        .and_then(|format| image_store::decode_stored_image(&path, format).ok()?)
+        .map(|frame| match &watermark {
+            Some(watermark) => watermark.draw(&frame),
+            None => frame,
+        });
+
+    Ok(Tile {
+        camera_name,
+        frame,
+        age_seconds,
+        freshness: Freshness::of(age_seconds, interval_seconds),
+    })
+}
+
+/// Shortens the text with an ellipsis until it fits in max_width pixels.
+fn fit_text(font: &Font, scale: Scale, text: &str, max_width: f32) -> String {
+    let mut text = text.to_string();
+    if watermarks::text_width(font, scale, &text) <= max_width {
+        return text;
+    }
+    while !text.is_empty() {
+        text.pop();
+        let shortened = format!("{}…", text.trim_end());
+        if watermarks::text_width(font, scale, &shortened) <= max_width {
+            return shortened;
+        }
+    }
+    text
+}
+
+/// Draws a tile into the mosaic with its top left corner at (left, top): the frame scaled to fit, and a bar along the
+/// bottom with a coloured square for how fresh it is, the camera's name and the frame's age.
+fn draw_tile(
+    mosaic: &mut RgbaImage,
+    tile: &Tile,
+    (left, top): (u32, u32),
+    (tile_width, tile_height): (u32, u32),
+    font: &Font,
+) {
+    if let Some(frame) = &tile.frame {
+        let frame = frame
+            .resize(tile_width, tile_height, FilterType::Triangle)
+            .to_rgba8();
+        let x = left + (tile_width - frame.width()) / 2;
+        let y = top + (tile_height - frame.height()) / 2;
+        imageops::overlay(mosaic, &frame, x, y);
+    }
+
+    let colour = tile.freshness.colour();
+    let bar_height = (tile_height / 10).max(18);
+    let bar_top = top + tile_height - bar_height;
+    let text_height = bar_height as f32 * 0.7;
+    let scale = Scale::uniform(text_height);
+    let padding = (bar_height - text_height as u32) / 2;
+    let baseline = bar_top as f32 + padding as f32 + font.v_metrics(scale).ascent;
+    watermarks::fill_rect(
+        mosaic,
+        (left, bar_top),
+        (tile_width, bar_height),
+        [0, 0, 0],
+        0.6,
+    );
+
+    let square_size = text_height as u32 * 2 / 3;
+    watermarks::fill_rect(
+        mosaic,
+        (left + padding * 2, bar_top + (bar_height - square_size) / 2),
+        (square_size, square_size),
+        colour,
+        1.0,
+    );
+
+    let age = match (&tile.frame, tile.age_seconds) {
+        (_, None) => "No images".to_string(),
+        (None, Some(age_seconds)) => format!("{}, no preview", format_age(age_seconds)),
+        (Some(_), Some(age_seconds)) => format_age(age_seconds),
+    };
+    let age_width = watermarks::text_width(font, scale, &age);
+    let age_left = (left + tile_width) as f32 - padding as f32 * 2.0 - age_width;
+    watermarks::draw_text(
+        mosaic,
+        font,
+        scale,
+        point(age_left, baseline),
+        &age,
+        [255, 255, 255],
+        1.0,
+    );
+
+    let name_left = (left + padding * 3 + square_size) as f32;
+    let name = fit_text(
+        font,
+        scale,
+        &tile.camera_name,
+        age_left - name_left - text_height,
+    );
+    watermarks::draw_text(
+        mosaic,
+        font,
+        scale,
+        point(name_left, baseline),
+        &name,
+        [255, 255, 255],
+        1.0,
+    );
+
+    // Drawn last so that the label bar doesn't cover it
+    if tile.freshness != Freshness::Live {
+        let (width, height) = (tile_width, tile_height);
+        watermarks::fill_rect(mosaic, (left, top), (width, BORDER), colour, 1.0);
+        watermarks::fill_rect(
+            mosaic,
+            (left, top + height - BORDER),
+            (width, BORDER),
+            colour,
+            1.0,
+        );
+        watermarks::fill_rect(mosaic, (left, top), (BORDER, height), colour, 1.0);
+        watermarks::fill_rect(
+            mosaic,
+            (left + width - BORDER, top),
+            (BORDER, height),
+            colour,
+            1.0,
+        );
+    }
+}
+
+/// Tiles the frames into a grid, left to right and then top to bottom, and encodes it as JPEG.
+fn compose(tiles: &[Tile], tile_width: u32, columns: u32) -> Result<Vec<u8>, ApiError> {
+    // 4:3, like most cameras
+    let tile_height = tile_width * 3 / 4;
+    let rows = (tiles.len() as u32 + columns - 1) / columns;
+    let mut mosaic = RgbaImage::from_pixel(
+        columns * tile_width + (columns + 1) * GAP,
+        rows * tile_height + (rows + 1) * GAP,
+        Rgba([16, 16, 16, 255]),
+    );
+    let font = watermarks::load_font();
+
+    for (index, tile) in tiles.iter().enumerate() {
+        let column = index as u32 % columns;
+        let row = index as u32 / columns;
+        let left = GAP + column * (tile_width + GAP);
+        let top = GAP + row * (tile_height + GAP);
+        watermarks::fill_rect(
+            &mut mosaic,
+            (left, top),
+            (tile_width, tile_height),
+            [32, 32, 32],
+            1.0,
+        );
+        draw_tile(
+            &mut mosaic,
+            tile,
+            (left, top),
+            (tile_width, tile_height),
+            &font,
+        );
+    }
+
+    image_store::encode_image(DynamicImage::ImageRgba8(mosaic), ImageFormat::Jpeg)
+}
+
+/// Works out which cameras to show, in order, with their names. Either camera_ids (a comma separated list) or group_id
+/// has to be given. Every camera in camera_ids has to be one the user can see, but cameras in a group that the user has
+/// since lost access to are left out.
+fn resolve_cameras(
+    conn: &CameraServerDbConn,
+    user_auth: &UserAuth,
+    camera_ids: Option<&str>,
+    group_id: Option<&str>,
+) -> Result<Vec<(uuid::Uuid, String)>, ApiError> {
+    let users_cameras: HashMap<uuid::Uuid, String> = get_users_cameras(user_auth.user_id(), conn)
+        .map_err(|error| {
+            error!("Failed to get list of user's cameras: {}", error);
+            ApiError {
+                error: "Failed to get list of owned cameras",
+                status: Status::InternalServerError,
+            }
+        })?
+        .into_iter()
+        .filter(|camera| user_auth.allows_camera(&camera.camera_id))
+        .map(|camera| (camera.camera_id, camera.name))
+        .collect();
+
+    let camera_ids = match (camera_ids, group_id) {
+        (Some(camera_ids), None) => {
+            let camera_id_strings: Vec<String> = camera_ids
+                .split(',')
+                .map(|camera_id| camera_id.trim().to_string())
+                .collect();
+            if camera_id_strings.len() > MAX_GROUP_CAMERAS {
+                return Err(ApiError {
+                    error: concat!(
+                        "Mosaics can have at most ",
+                        max_group_cameras!(),
+                        " cameras"
+                    ),
+                    status: Status::UnprocessableEntity,
+                });
+            }
+            for camera_id_string in &camera_id_strings {
+                check_if_user_has_access_to_camera(conn, user_auth, camera_id_string)?;
+            }
+            camera_id_strings
+                .iter()
+                .filter_map(|camera_id_string| uuid::Uuid::parse_str(camera_id_string).ok())
+                .collect()
+        }
+        (None, Some(group_id)) => {
+            camera_groups::get_users_camera_group(user_auth.user_id(), group_id, conn)?.camera_ids
+        }
+        _ => {
+            return Err(ApiError {
+                error: "Either camera_ids or group_id has to be given",
+                status: Status::BadRequest,
+            })
+        }
+    };
+
+    let cameras: Vec<(uuid::Uuid, String)> = camera_ids
+        .into_iter()
+        .filter_map(|camera_id| {
+            users_cameras
+                .get(&camera_id)
+                .map(|name| (camera_id, name.clone()))
+        })
+        .collect();
+    if cameras.is_empty() {
+        return Err(ApiError {
+            error: "None of the group's cameras can be shown",
+            status: Status::NotFound,
+        });
+    }
+    Ok(cameras)
+}
+
+/// A mosaic, which can be cached by the client for as long as it's cached on the server.
+pub struct MosaicResponse {
+    mosaic: CachedMosaic,
+    max_age: Duration,
+}
+
+impl<'r> Responder<'r> for MosaicResponse {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = Response::build();
+        response
+            .raw_header("ETag", self.mosaic.etag.clone())
+            .raw_header("Last-Modified", format_http_date(self.mosaic.created_at))
+            // Only users with access to every camera in it can see it, so shared caches mustn't keep it
+            .raw_header(
+                "Cache-Control",
+                format!("private, max-age={}", self.max_age.as_secs()),
+            );
+
+        if is_not_modified(request, &self.mosaic.etag, self.mosaic.created_at) {
+            return response.status(Status::NotModified).ok();
+        }
+        response
+            .header(ContentType::JPEG)
+            .sized_body(Cursor::new(self.mosaic.jpeg))
+            .ok()
+    }
+}
+
+/// Returns a JPEG of the latest frame from each of several cameras, in a grid, labelled with the camera's name and
+/// how old the frame is. Cameras whose latest frame is late or that have stopped sending are outlined in amber or red.
+/// The cameras are either camera_ids (a comma separated list) or the cameras in one of the user's groups.
+/// tile_width is each frame's width in pixels, and columns defaults to making the grid as square as possible.
+/// Mosaics are cached for a few seconds, so it's fine for lots of displays to poll the same one.
+#[get("/mosaic?<camera_ids>&<group_id>&<tile_width>&<columns>")]
+pub fn get_mosaic(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    cache: State<MosaicCache>,
+    camera_ids: Option<String>,
+    group_id: Option<String>,
+    tile_width: Option<u32>,
+    columns: Option<u32>,
+) -> Result<MosaicResponse, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ImagesRead)?;
+    let cameras = resolve_cameras(
+        &conn,
+        &user_auth,
+        camera_ids.as_deref(),
+        group_id.as_deref(),
+    )?;
+
+    let tile_width = tile_width
+        .unwrap_or(DEFAULT_TILE_WIDTH)
+        .max(MIN_TILE_WIDTH)
+        .min(MAX_TILE_WIDTH);
+    let columns = columns
+        .unwrap_or_else(|| (cameras.len() as f64).sqrt().ceil() as u32)
+        .max(1)
+        .min(cameras.len() as u32);
+
+    // Everything that goes into the mosaic is the same for every user who can see these cameras
+    let key = format!(
+        "{}|{}|{}",
+        cameras
+            .iter()
+            .map(|(camera_id, _)| camera_id.to_string())
+            .collect::<Vec<String>>()
+            .join(","),
+        tile_width,
+        columns
+    );
+    if let Some(mosaic) = cache.get(&key) {
+        return Ok(MosaicResponse {
+            mosaic,
+            max_age: cache.max_age,
+        });
+    }
+
+    let build_lock = cache.build_lock(&key);
+    // A poisoned lock only means another request panicked while making this mosaic, which doesn't stop this one
+    let _building = build_lock.lock().unwrap_or_else(PoisonError::into_inner);
+    // It may have been made while this request waited for the lock
+    if let Some(mosaic) = cache.get(&key) {
+        return Ok(MosaicResponse {
+            mosaic,
+            max_age: cache.max_age,
+        });
+    }
+
+    let tiles = cameras
+        .into_iter()
+        .map(|(camera_id, camera_name)| load_tile(&conn, &user_auth, camera_id, camera_name))
+        .collect::<Result<Vec<Tile>, ApiError>>()?;
+    let jpeg = compose(&tiles, tile_width, columns)?;
+
+    Ok(MosaicResponse {
+        mosaic: cache.insert(key, jpeg),
+        max_age: cache.max_age,
+    })
+}
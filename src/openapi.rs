@@ -0,0 +1,1195 @@
+use crate::{api_keys::ApiKeyScope, api_v1::find_legacy_route};
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    get,
+    http::Method,
+    response::content::Html,
+    Rocket, Route, State,
+};
+use rocket_contrib::json::JsonValue;
+use serde_json::{json, Map, Value};
+
+const DOCS_PAGE: &str = include_str!("../static/docs.html");
+
+/// How a route is authenticated. Used to fill in the security requirements of each operation.
+#[derive(Clone, Copy)]
+enum Auth {
+    None,
+    /// The UserToken guard: a user token as a bearer token, user_token header or session cookie
+    UserToken,
+    /// The UserAuth guard: a user token, or an API key with the given scope
+    UserAuth(ApiKeyScope),
+    CameraToken,
+    AdminKey,
+}
+
+#[derive(Clone, Copy)]
+enum Body {
+    None,
+    /// A JSON body matching one of the schemas in components()
+    Json(&'static str),
+    /// A JSON array of one of the schemas in components()
+    JsonList(&'static str),
+    /// An image in any of the formats that can be uploaded
+    Image,
+    /// A tar.gz archive
+    Archive,
+    /// A multipart/form-data body matching one of the schemas in components()
+    Multipart(&'static str),
+    Text(&'static str),
+}
+
+/// Documentation for a route that can't be worked out from the Route itself.
+struct OperationDoc {
+    method: Method,
+    path: &'static str,
+    summary: &'static str,
+    auth: Auth,
+    request_body: Body,
+    response: Body,
+}
+
+/// Every documented route. Routes that are mounted but missing from here still show up in the document, just without the extra detail.
+const OPERATIONS: &[OperationDoc] = &[
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/users",
+        summary: "Creates a new user and logs them in",
+        auth: Auth::None,
+        request_body: Body::Json("InsertableUser"),
+        response: Body::Json("AuthentiationResult"),
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/sessions",
+        summary: "Logs in. If the user has TOTP enabled, a challenge is returned that must be completed with /api/v1/sessions/totp",
+        auth: Auth::None,
+        request_body: Body::Json("InsertableUser"),
+        response: Body::Json("LoginResult"),
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/sessions/totp",
+        summary: "Completes a login challenge with a TOTP code or a recovery code",
+        auth: Auth::None,
+        request_body: Body::Json("TotpLogin"),
+        response: Body::Json("AuthentiationResult"),
+    },
+    OperationDoc {
+        method: Method::Delete,
+        path: "/api/v1/sessions/current",
+        summary: "Revokes the token used for the request and clears the session cookie",
+        auth: Auth::UserToken,
+        request_body: Body::None,
+        response: Body::None,
+    },
+    OperationDoc {
+        method: Method::Put,
+        path: "/api/v1/users/me/password",
+        summary: "Changes the user's password and logs out every other session",
+        auth: Auth::UserToken,
+        request_body: Body::Json("PasswordChange"),
+        response: Body::None,
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/users/<username>/password-reset-tokens",
+        summary: "Creates a password reset token for a user",
+        auth: Auth::AdminKey,
+        request_body: Body::None,
+        response: Body::Json("PasswordResetToken"),
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/password-resets",
+        summary: "Sets a new password using a password reset token",
+        auth: Auth::None,
+        request_body: Body::Json("PasswordReset"),
+        response: Body::None,
+    },
+    OperationDoc {
+        method: Method::Delete,
+        path: "/api/v1/users/me",
+        summary: "Deletes the user, along with any cameras only they have access to",
+        auth: Auth::UserToken,
+        request_body: Body::Json("AccountDeletion"),
+        response: Body::None,
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/users/me/totp",
+        summary: "Starts TOTP enrolment by generating a new secret",
+        auth: Auth::UserToken,
+        request_body: Body::None,
+        response: Body::Json("TotpEnrolment"),
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/users/me/totp/verify",
+        summary: "Enables TOTP if the code matches the pending secret",
+        auth: Auth::UserToken,
+        request_body: Body::Json("TotpCode"),
+        response: Body::Json("RecoveryCodes"),
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/users/me/totp/recovery-codes",
+        summary: "Replaces the user's recovery codes",
+        auth: Auth::UserToken,
+        request_body: Body::Json("TotpCode"),
+        response: Body::Json("RecoveryCodes"),
+    },
+    OperationDoc {
+        method: Method::Delete,
+        path: "/api/v1/users/me/totp",
+        summary: "Turns TOTP off",
+        auth: Auth::UserToken,
+        request_body: Body::Json("TotpDisable"),
+        response: Body::None,
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/api-keys",
+        summary: "Creates an API key. This is the only time the key itself is returned",
+        auth: Auth::UserToken,
+        request_body: Body::Json("NewApiKey"),
+        response: Body::Json("ApiKey"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/api-keys",
+        summary: "Lists the user's API keys",
+        auth: Auth::UserToken,
+        request_body: Body::None,
+        response: Body::JsonList("ApiKeyInfo"),
+    },
+    OperationDoc {
+        method: Method::Delete,
+        path: "/api/v1/api-keys/<api_key_id_string>",
+        summary: "Revokes one of the user's API keys",
+        auth: Auth::UserToken,
+        request_body: Body::None,
+        response: Body::None,
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/users/me/audit-log",
+        summary: "Lists audit events for the user's account, newest first",
+        auth: Auth::UserToken,
+        request_body: Body::None,
+        response: Body::JsonList("AuditEvent"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/audit-log",
+        summary: "Lists audit events for a camera, newest first",
+        auth: Auth::UserToken,
+        request_body: Body::None,
+        response: Body::JsonList("AuditEvent"),
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/cameras",
+        summary: "Creates a camera that the user has access to, returning the token the camera uses",
+        auth: Auth::UserToken,
+        request_body: Body::Json("InsertableCamera"),
+        response: Body::Json("CameraToken"),
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/cameras/me/images",
+        summary: "Uploads an image from a camera. The capture time can be sent in the X-Capture-Timestamp header, \
+            as milliseconds since the epoch or an RFC 3339 date. An image captured at the same time as one that's already \
+            stored or in the trash gets the next free millisecond, and once 1000 are taken it's rejected with 409. \
+            Images over the size limit are rejected with 413, \
+            and anything that isn't a complete, decodable JPEG, PNG, WebP or AVIF image with 415 or 400. \
+            Uploads that would go over the camera's or its owner's storage quota are rejected with 507, unless the \
+            quota policy is to delete the camera's oldest images to make room. Copies of an image the camera has already \
+            stored share its file, or with the skip duplicates policy aren't stored and get the earlier image's ID. \
+            If the camera has privacy masks, they're applied before the image is stored. Masked WebP images are stored as PNG, \
+            and AVIF images are rejected with 415 since they can't be masked. \
+            A watermark that's burned in is drawn on the same way, but images it can't be drawn on are stored without it. \
+            Returns the ID of the new image",
+        auth: Auth::CameraToken,
+        request_body: Body::Image,
+        response: Body::Text("The new image's ID"),
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/cameras/me/images/batch",
+        summary: "Uploads many images from a camera at once, for catching up after being offline. \
+            Each image is stored independently and checked against the storage quotas the same way as a single upload, \
+            and the response has a result for every image",
+        auth: Auth::CameraToken,
+        request_body: Body::Multipart("ImageBatch"),
+        response: Body::JsonList("BatchUploadResult"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/images/latest",
+        summary: "Returns the newest image from a camera. It's sent in the format it was uploaded in, unless the Accept \
+            header rules that out, in which case it's converted to JPEG or PNG if they're accepted. Otherwise returns 406. \
+            If the camera's watermark is added on the fly, it's drawn on and the image is sent as JPEG or PNG. \
+            Owners can set original to get the image without it, including the copy kept from before a burned-in one. \
+            Has an ETag and Last-Modified to revalidate with, and supports Range requests",
+        auth: Auth::UserAuth(ApiKeyScope::ImagesRead),
+        request_body: Body::None,
+        response: Body::Image,
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/images",
+        summary: "Lists the IDs of a camera's images, oldest first. lighting (a comma separated list of day, dusk and night) \
+            only includes images with that lighting, and exclude_dark and exclude_overexposed leave out frames that are \
+            almost entirely black or mostly blown out. Images without luminance statistics are always included",
+        auth: Auth::UserAuth(ApiKeyScope::ImagesRead),
+        request_body: Body::None,
+        response: Body::JsonList("ImageId"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/images/<image_id_string>",
+        summary: "Returns an image. Formats are negotiated with the Accept header the same way as for the latest image. \
+            Images never change, so they can be cached indefinitely, unless the camera's watermark is added on the fly. \
+            Then it's drawn on the same way as for the latest image, and has to be revalidated since the watermark can change. \
+            Supports If-None-Match, If-Modified-Since and Range requests",
+        auth: Auth::UserAuth(ApiKeyScope::ImagesRead),
+        request_body: Body::None,
+        response: Body::Image,
+    },
+    OperationDoc {
+        method: Method::Delete,
+        path: "/api/v1/cameras/<camera_id_string>/images/<image_id_string>",
+        summary: "Moves an image to the camera's trash, where it can be restored until the trash's hold time runs out. \
+            Needs the editor or owner role on the camera",
+        auth: Auth::UserAuth(ApiKeyScope::ImagesDelete),
+        request_body: Body::None,
+        response: Body::None,
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/cameras/<camera_id_string>/images/batch-delete",
+        summary: "Moves images to the trash, either by ID or by when they were taken. Returns a result for each image. \
+            Needs the editor or owner role on the camera",
+        auth: Auth::UserAuth(ApiKeyScope::ImagesDelete),
+        request_body: Body::Json("ImageSelection"),
+        response: Body::JsonList("BatchDeleteResult"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/trash",
+        summary: "Lists the images in a camera's trash, most recently deleted first",
+        auth: Auth::UserAuth(ApiKeyScope::ImagesRead),
+        request_body: Body::None,
+        response: Body::JsonList("TrashedImage"),
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/cameras/<camera_id_string>/trash/<image_id_string>/restore",
+        summary: "Moves an image out of the trash with the ID it had before. Returns 409 if another image has taken its ID \
+            since. Needs the editor or owner role on the camera",
+        auth: Auth::UserAuth(ApiKeyScope::ImagesDelete),
+        request_body: Body::None,
+        response: Body::None,
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/storage",
+        summary: "Returns how many images a camera has and how much space they take up, along with its quota. \
+            Images in the trash don't count. Needs the owner role on the camera",
+        auth: Auth::UserAuth(ApiKeyScope::CamerasRead),
+        request_body: Body::None,
+        response: Body::Json("CameraStorageReport"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/images/<image_id_string>/similar",
+        summary: "Finds the camera's images that look like this one, closest first, by comparing perceptual hashes. \
+            max_distance is how many of the hashes' 64 bits can differ (default 8, at most 15) and limit is how many \
+            images to return (default 20, at most 100)",
+        auth: Auth::UserAuth(ApiKeyScope::ImagesRead),
+        request_body: Body::None,
+        response: Body::JsonList("SimilarImage"),
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/cameras/<camera_id_string>/images/similar",
+        summary: "Finds the camera's images that look like the image sent, which isn't stored. \
+            Takes the same max_distance and limit as searching by image ID. Returns 422 for AVIF and lossless WebP images, \
+            which can't be compared",
+        auth: Auth::UserAuth(ApiKeyScope::ImagesRead),
+        request_body: Body::Image,
+        response: Body::JsonList("SimilarImage"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/dedup",
+        summary: "Returns how many of a camera's uploads were copies of images it had already stored, \
+            and how much space not storing them again has saved",
+        auth: Auth::UserAuth(ApiKeyScope::CamerasRead),
+        request_body: Body::None,
+        response: Body::Json("DedupReport"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/users/me/storage",
+        summary: "Returns the total storage used by the cameras the user owns and the user's quota, along with each camera's usage",
+        auth: Auth::UserAuth(ApiKeyScope::CamerasRead),
+        request_body: Body::None,
+        response: Body::Json("UserStorageReport"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/images/export",
+        summary: "Downloads a tar.gz archive of the images taken from from (inclusive) to to (exclusive), with a manifest.json \
+            after them listing each image's timestamp and metadata. from and to are milliseconds since the epoch or RFC 3339 dates, \
+            and leaving either out leaves that end of the range open. lighting, exclude_dark and exclude_overexposed filter \
+            the images the same way as the image list. Images have the camera's watermark, drawn on as JPEG or PNG if it's \
+            added on the fly, unless an owner sets original. The archive is streamed as it's made",
+        auth: Auth::UserAuth(ApiKeyScope::ImagesRead),
+        request_body: Body::None,
+        response: Body::Archive,
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/images/<image_id_string>/metadata",
+        summary: "Returns an image's format, size and the EXIF data the camera recorded with it",
+        auth: Auth::UserAuth(ApiKeyScope::ImagesRead),
+        request_body: Body::None,
+        response: Body::Json("ImageMetadata"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/images/<image_id_string>/thumbnail",
+        summary: "Returns a JPEG thumbnail of an image, rotated according to its EXIF orientation. size is the longest side in pixels, \
+            320 by default and at most 1024. Has the camera's watermark if it's added on the fly. \
+            Responds with 415 if the image's format can't be decoded",
+        auth: Auth::UserAuth(ApiKeyScope::ImagesRead),
+        request_body: Body::None,
+        response: Body::Image,
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras",
+        summary: "Lists the cameras the user has access to",
+        auth: Auth::UserAuth(ApiKeyScope::CamerasRead),
+        request_body: Body::None,
+        response: Body::JsonList("Camera"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/config",
+        summary: "Returns a camera's config",
+        auth: Auth::UserAuth(ApiKeyScope::ConfigRead),
+        request_body: Body::None,
+        response: Body::Json("Config"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/me/config",
+        summary: "Returns the config of the camera making the request",
+        auth: Auth::CameraToken,
+        request_body: Body::None,
+        response: Body::Json("Config"),
+    },
+    OperationDoc {
+        method: Method::Put,
+        path: "/api/v1/cameras/<camera_id_string>/config",
+        summary: "Replaces a camera's config. The camera_id in the body is ignored. \
+            Needs the editor or owner role on the camera",
+        auth: Auth::UserAuth(ApiKeyScope::ConfigWrite),
+        request_body: Body::Json("Config"),
+        response: Body::Json("Config"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/privacy-masks",
+        summary: "Returns the current version of a camera's privacy masks, or 404 if they've never been set",
+        auth: Auth::UserAuth(ApiKeyScope::ConfigRead),
+        request_body: Body::None,
+        response: Body::Json("PrivacyMaskVersion"),
+    },
+    OperationDoc {
+        method: Method::Put,
+        path: "/api/v1/cameras/<camera_id_string>/privacy-masks",
+        summary: "Replaces a camera's privacy masks, adding a new version. Uploads have the regions inside the masks blacked \
+            out or blurred before they're stored, so the unmasked pixels are never kept. Images stored before the change \
+            aren't affected. An empty list turns masking off. Needs the owner role on the camera",
+        auth: Auth::UserAuth(ApiKeyScope::ConfigWrite),
+        request_body: Body::Json("PrivacyMaskUpdate"),
+        response: Body::Json("PrivacyMaskVersion"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/privacy-masks/versions",
+        summary: "Lists every version of a camera's privacy masks, newest first",
+        auth: Auth::UserAuth(ApiKeyScope::ConfigRead),
+        request_body: Body::None,
+        response: Body::JsonList("PrivacyMaskVersion"),
+    },
+    OperationDoc {
+        method: Method::Put,
+        path: "/api/v1/cameras/<camera_id_string>/users/<username>",
+        summary: "Gives a user a role on a camera, giving them access to it if they didn't have it. Viewers can see the \
+            camera's images and config, editors can also delete and restore images and change the config, and owners can \
+            also change privacy masks, watermarks and who has access. Responds with 409 when demoting the camera's last \
+            owner. Needs the owner role on the camera",
+        auth: Auth::UserAuth(ApiKeyScope::ConfigWrite),
+        request_body: Body::Json("CameraRoleUpdate"),
+        response: Body::Json("CameraAccess"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/cameras/<camera_id_string>/watermark",
+        summary: "Returns a camera's watermark settings, or 404 if it doesn't have a watermark",
+        auth: Auth::UserAuth(ApiKeyScope::ConfigRead),
+        request_body: Body::None,
+        response: Body::Json("Watermark"),
+    },
+    OperationDoc {
+        method: Method::Put,
+        path: "/api/v1/cameras/<camera_id_string>/watermark",
+        summary: "Sets a camera's watermark: its name and the capture time in a chosen time zone and format, in a corner of \
+            the image. burn_in draws it on uploads before they're stored, so only images uploaded after the change have it, \
+            and keeps their originals for owners. on_the_fly draws it on images as they're sent, leaving the stored originals as \
+            they are. Needs the owner role on the camera",
+        auth: Auth::UserAuth(ApiKeyScope::ConfigWrite),
+        request_body: Body::Json("WatermarkUpdate"),
+        response: Body::Json("Watermark"),
+    },
+    OperationDoc {
+        method: Method::Delete,
+        path: "/api/v1/cameras/<camera_id_string>/watermark",
+        summary: "Turns a camera's watermark off and returns its old settings. Images it was burned into keep it. \
+            Needs the owner role on the camera",
+        auth: Auth::UserAuth(ApiKeyScope::ConfigWrite),
+        request_body: Body::None,
+        response: Body::Json("Watermark"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/camera-groups",
+        summary: "Lists the user's camera groups, oldest first",
+        auth: Auth::UserAuth(ApiKeyScope::CamerasRead),
+        request_body: Body::None,
+        response: Body::JsonList("CameraGroup"),
+    },
+    OperationDoc {
+        method: Method::Post,
+        path: "/api/v1/camera-groups",
+        summary: "Creates a named group of up to 16 of the user's cameras, for showing together in a mosaic",
+        auth: Auth::UserToken,
+        request_body: Body::Json("NewCameraGroup"),
+        response: Body::Json("CameraGroup"),
+    },
+    OperationDoc {
+        method: Method::Put,
+        path: "/api/v1/camera-groups/<camera_group_id_string>",
+        summary: "Replaces the name and cameras of one of the user's camera groups",
+        auth: Auth::UserToken,
+        request_body: Body::Json("NewCameraGroup"),
+        response: Body::Json("CameraGroup"),
+    },
+    OperationDoc {
+        method: Method::Delete,
+        path: "/api/v1/camera-groups/<camera_group_id_string>",
+        summary: "Deletes one of the user's camera groups",
+        auth: Auth::UserToken,
+        request_body: Body::None,
+        response: Body::None,
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/api/v1/mosaic",
+        summary: "Returns a JPEG grid of the latest frame from each of several cameras, given either as camera_ids \
+            (a comma separated list of up to 16) or as group_id. Each tile is labelled with the camera's name and how old \
+            the frame is, and cameras that are late by a few upload intervals or have stopped sending are outlined in \
+            amber or red. Cameras in a group that the user has lost access to are left out. tile_width is each tile's \
+            width in pixels, and columns defaults to making the grid as square as possible. Mosaics are cached on the \
+            server for a few seconds and can be cached by the client for as long",
+        auth: Auth::UserAuth(ApiKeyScope::ImagesRead),
+        request_body: Body::None,
+        response: Body::Image,
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/metrics",
+        summary: "Prometheus metrics",
+        auth: Auth::None,
+        request_body: Body::None,
+        response: Body::Text("Metrics in the Prometheus text format"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/health/live",
+        summary: "Liveness probe",
+        auth: Auth::None,
+        request_body: Body::None,
+        response: Body::Json("Liveness"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/health/ready",
+        summary: "Readiness probe. Returns 503 if any check fails",
+        auth: Auth::None,
+        request_body: Body::None,
+        response: Body::Json("Readiness"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/openapi.json",
+        summary: "This document",
+        auth: Auth::None,
+        request_body: Body::None,
+        response: Body::Text("OpenAPI 3 document"),
+    },
+    OperationDoc {
+        method: Method::Get,
+        path: "/docs",
+        summary: "Interactive documentation for this API",
+        auth: Auth::None,
+        request_body: Body::None,
+        response: Body::Text("HTML page"),
+    },
+];
+
+/// The generated document, managed by Rocket.
+pub struct OpenApiDocument(Value);
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn content(body: Body) -> Option<Value> {
+    match body {
+        Body::None => None,
+        Body::Json(name) => Some(json!({ "application/json": { "schema": schema_ref(name) } })),
+        Body::JsonList(name) => Some(json!({
+            "application/json": { "schema": { "type": "array", "items": schema_ref(name) } }
+        })),
+        Body::Image => {
+            let binary = json!({ "schema": { "type": "string", "format": "binary" } });
+            Some(json!({
+                "image/jpeg": binary,
+                "image/png": binary,
+                "image/webp": binary,
+                "image/avif": binary,
+            }))
+        }
+        Body::Archive => Some(json!({
+            "application/gzip": { "schema": { "type": "string", "format": "binary" } }
+        })),
+        Body::Multipart(name) => {
+            Some(json!({ "multipart/form-data": { "schema": schema_ref(name) } }))
+        }
+        Body::Text(description) => Some(json!({
+            "text/plain": { "schema": { "type": "string", "description": description } }
+        })),
+    }
+}
+
+fn security(auth: Auth) -> Value {
+    let user_token = vec![
+        json!({ "bearerAuth": [] }),
+        json!({ "userToken": [] }),
+        json!({ "sessionCookie": [] }),
+    ];
+    match auth {
+        Auth::None => json!([]),
+        Auth::UserToken => Value::Array(user_token),
+        Auth::UserAuth(_) => Value::Array(
+            user_token
+                .into_iter()
+                .chain(vec![json!({ "apiKey": [] })])
+                .collect(),
+        ),
+        Auth::CameraToken => json!([{ "bearerAuth": [] }, { "cameraToken": [] }]),
+        Auth::AdminKey => json!([{ "adminKey": [] }]),
+    }
+}
+
+/// Turns a Rocket path like /Cameras/<camera_id_string>/Image into an OpenAPI one like /Cameras/{camera_id_string}/Image.
+/// Returns the path along with the names of its parameters.
+fn openapi_path(rocket_path: &str) -> (String, Vec<String>) {
+    let mut parameters = Vec::new();
+    let segments: Vec<String> = rocket_path
+        .split('/')
+        .map(|segment| {
+            if segment.starts_with('<') && segment.ends_with('>') {
+                let name = segment
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .trim_end_matches("..")
+                    .to_string();
+                parameters.push(name.clone());
+                format!("{{{}}}", name)
+            } else {
+                segment.to_string()
+            }
+        })
+        .collect();
+    (segments.join("/"), parameters)
+}
+
+fn query_parameter_names(query: Option<&str>) -> Vec<String> {
+    query
+        .unwrap_or("")
+        .split('&')
+        .filter(|x| x.starts_with('<') && x.ends_with('>'))
+        .map(|x| {
+            x.trim_start_matches('<')
+                .trim_end_matches('>')
+                .trim_end_matches("..")
+                .to_string()
+        })
+        .collect()
+}
+
+fn parameter_schema(name: &str) -> Value {
+    match name {
+        "session_cookie" | "exclude_dark" | "exclude_overexposed" | "original" => {
+            json!({ "type": "boolean" })
+        }
+        "max_distance" => json!({ "type": "integer", "minimum": 0, "maximum": 15 }),
+        "limit" | "offset" => json!({ "type": "integer", "format": "int64", "minimum": 0 }),
+        "size" => json!({ "type": "integer", "minimum": 16, "maximum": 1024 }),
+        "tile_width" => json!({ "type": "integer", "minimum": 160, "maximum": 960 }),
+        "columns" => json!({ "type": "integer", "minimum": 1, "maximum": 16 }),
+        "camera_ids" => json!({ "type": "string", "description": "Comma separated camera IDs" }),
+        "image_id_string" => json!({ "type": "string" }),
+        name if name.ends_with("_id_string") || name.ends_with("_id") => {
+            json!({ "type": "string", "format": "uuid" })
+        }
+        _ => json!({ "type": "string" }),
+    }
+}
+
+fn operation(route: &Route) -> Value {
+    let path = route.uri.path();
+    // Legacy routes are documented by their v1 successors
+    let legacy_route = find_legacy_route(route.method, path);
+    let (doc_method, doc_path) = match legacy_route {
+        Some(legacy_route) => (legacy_route.successor_method, legacy_route.successor_path),
+        None => (route.method, path),
+    };
+    let doc = OPERATIONS
+        .iter()
+        .find(|doc| doc.method == doc_method && doc.path == doc_path);
+
+    let (_, path_parameters) = openapi_path(path);
+    let mut parameters: Vec<Value> = path_parameters
+        .iter()
+        .map(|name| {
+            json!({ "name": name, "in": "path", "required": true, "schema": parameter_schema(name) })
+        })
+        .collect();
+    parameters.extend(query_parameter_names(route.uri.query()).iter().map(|name| {
+        json!({ "name": name, "in": "query", "required": false, "schema": parameter_schema(name) })
+    }));
+
+    let mut operation = Map::new();
+    operation.insert(
+        String::from("operationId"),
+        json!(route.name.unwrap_or("unnamed")),
+    );
+    operation.insert(String::from("parameters"), Value::Array(parameters));
+
+    let mut responses = Map::new();
+    let mut success = json!({ "description": "Success" });
+    let mut description = String::new();
+
+    match doc {
+        Some(doc) => {
+            operation.insert(String::from("summary"), json!(doc.summary));
+            operation.insert(String::from("security"), security(doc.auth));
+            if let Auth::UserAuth(scope) = doc.auth {
+                description = format!("API keys need the {} scope.", scope.as_str());
+            }
+            if let Some(request_content) = content(doc.request_body) {
+                operation.insert(
+                    String::from("requestBody"),
+                    json!({ "required": true, "content": request_content }),
+                );
+            }
+            if let Some(response_content) = content(doc.response) {
+                success["content"] = response_content;
+            }
+            if !matches!(doc.auth, Auth::None) {
+                responses.insert(String::from("401"), schema_ref_response("Unauthorized"));
+                responses.insert(String::from("403"), schema_ref_response("Forbidden"));
+            }
+        }
+        None => {
+            description = String::from("This route hasn't been documented yet.");
+        }
+    }
+
+    if let Some(legacy_route) = legacy_route {
+        operation.insert(String::from("deprecated"), json!(true));
+        description = format!(
+            "Deprecated, use {} {} instead. {}",
+            legacy_route.successor_method, legacy_route.successor_path, description
+        )
+        .trim_end()
+        .to_string();
+    }
+
+    if !description.is_empty() {
+        operation.insert(String::from("description"), json!(description));
+    }
+
+    responses.insert(String::from("200"), success);
+    responses.insert(String::from("default"), schema_ref_response("Error"));
+    operation.insert(String::from("responses"), Value::Object(responses));
+
+    Value::Object(operation)
+}
+
+fn schema_ref_response(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/responses/{}", name) })
+}
+
+/// Builds the document from the mounted routes.
+fn build_document<'a>(routes: impl Iterator<Item = &'a Route>) -> Value {
+    let mut paths = Map::new();
+    for route in routes {
+        let (path, _) = openapi_path(route.uri.path());
+        let path_item = paths.entry(path).or_insert(json!({}));
+        path_item[route.method.as_str().to_lowercase()] = operation(route);
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Camera Server",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Tokens can be sent as \"Authorization: Bearer <token>\" or in the legacy user_token, camera_token and api_key headers. \
+                If both are sent, the bearer token is used. The routes outside /api/v1 are deprecated aliases kept for deployed cameras. \
+                Errors are returned as plain text followed by the request ID, which is also sent in the X-Request-Id header.",
+        },
+        "paths": paths,
+        "components": components(),
+    })
+}
+
+fn components() -> Value {
+    let timestamp = json!({ "type": "string", "format": "date-time", "description": "UTC, without a timezone" });
+    let uuid = json!({ "type": "string", "format": "uuid" });
+    let error_response = |description: &str| {
+        json!({
+            "description": description,
+            "headers": { "X-Request-Id": { "schema": { "type": "string" } } },
+            "content": { "text/plain": { "schema": { "$ref": "#/components/schemas/Error" } } },
+        })
+    };
+
+    let image_metadata = json!({
+        "type": "object",
+        "required": ["camera_id", "image_id", "content_type", "size_bytes", "created_at"],
+        "description": "EXIF fields are null when the camera didn't record them",
+        "properties": {
+            "camera_id": uuid,
+            "image_id": schema_ref("ImageId"),
+            "content_type": { "type": "string" },
+            "size_bytes": { "type": "integer", "format": "int64" },
+            "created_at": timestamp,
+            "captured_at": { "type": "string", "format": "date-time", "nullable": true, "description": "From EXIF, in the camera's local time" },
+            "camera_make": { "type": "string", "nullable": true },
+            "camera_model": { "type": "string", "nullable": true },
+            "exposure_time": { "type": "string", "nullable": true, "description": "Seconds, as a fraction like 1/125" },
+            "f_number": { "type": "number", "nullable": true },
+            "iso": { "type": "integer", "nullable": true },
+            "focal_length": { "type": "number", "nullable": true, "description": "Millimetres" },
+            "orientation": { "type": "integer", "minimum": 1, "maximum": 8, "nullable": true },
+            "gps_latitude": { "type": "number", "nullable": true },
+            "gps_longitude": { "type": "number", "nullable": true },
+            "gps_altitude": { "type": "number", "nullable": true, "description": "Metres" },
+            "content_hash": { "type": "string", "nullable": true, "description": "Hex SHA-256 of the file, which is also its ETag" },
+            "perceptual_hash": { "type": "string", "nullable": true, "description": "Hex 64 bit dHash, for finding images that look alike" },
+            "mean_luminance": { "type": "number", "nullable": true, "minimum": 0, "maximum": 255 },
+            "luminance_histogram": { "type": "array", "items": { "type": "number" }, "nullable": true, "description": "The fraction of pixels in each of 16 equal luminance ranges, darkest first" },
+            "underexposed_ratio": { "type": "number", "nullable": true, "description": "The fraction of pixels that are nearly black" },
+            "overexposed_ratio": { "type": "number", "nullable": true, "description": "The fraction of pixels that are nearly white" },
+            "lighting": { "type": "string", "enum": ["day", "dusk", "night"], "nullable": true },
+            "privacy_mask_version": { "type": "integer", "nullable": true, "description": "The version of the camera's privacy masks applied when it was stored" },
+            "watermarked": { "type": "boolean", "description": "Whether the camera's watermark was burned in when it was stored" },
+        },
+    });
+    let limits = json!({
+        "type": "object",
+        "description": "Null means no limit",
+        "properties": {
+            "max_bytes": { "type": "integer", "format": "int64", "nullable": true },
+            "max_images": { "type": "integer", "format": "int64", "nullable": true },
+        },
+    });
+    let quota_policy = json!({
+        "type": "string",
+        "enum": ["reject", "evict_oldest"],
+        "description": "What happens to uploads over quota",
+    });
+    json!({
+        "securitySchemes": {
+            "bearerAuth": { "type": "http", "scheme": "bearer", "description": "A user token, camera token or API key" },
+            "userToken": { "type": "apiKey", "in": "header", "name": "user_token" },
+            "sessionCookie": { "type": "apiKey", "in": "cookie", "name": "user_token", "description": "Set by /api/v1/sessions?session_cookie=true" },
+            "cameraToken": { "type": "apiKey", "in": "header", "name": "camera_token" },
+            "apiKey": { "type": "apiKey", "in": "header", "name": "api_key" },
+            "adminKey": { "type": "apiKey", "in": "header", "name": "admin_key", "description": "The server's ADMIN_KEY environment variable" },
+        },
+        "responses": {
+            "Error": error_response("An error"),
+            "Unauthorized": error_response("The token is missing, invalid or expired"),
+            "Forbidden": error_response("The token isn't allowed to do this"),
+        },
+        "schemas": {
+            "Error": { "type": "string", "example": "Camera has no images (or doesn't exist) (request ID: 3f2c7b1e-5d4a-4f6e-9b8a-1c2d3e4f5a6b)" },
+            "InsertableUser": {
+                "type": "object",
+                "required": ["username", "password"],
+                "properties": { "username": { "type": "string" }, "password": { "type": "string", "format": "password" } },
+            },
+            "UserInfo": {
+                "type": "object",
+                "properties": { "username": { "type": "string" }, "user_id": uuid },
+            },
+            "AuthentiationResult": {
+                "type": "object",
+                "properties": { "user_info": schema_ref("UserInfo"), "user_token": uuid },
+            },
+            "TotpChallenge": {
+                "type": "object",
+                "properties": { "totp_required": { "type": "boolean" }, "challenge": uuid, "expires_at": timestamp },
+            },
+            "LoginResult": { "oneOf": [schema_ref("AuthentiationResult"), schema_ref("TotpChallenge")] },
+            "TotpLogin": {
+                "type": "object",
+                "required": ["challenge"],
+                "description": "Either code or recovery_code is required",
+                "properties": { "challenge": uuid, "code": { "type": "string" }, "recovery_code": { "type": "string" } },
+            },
+            "PasswordChange": {
+                "type": "object",
+                "required": ["current_password", "new_password"],
+                "properties": { "current_password": { "type": "string", "format": "password" }, "new_password": { "type": "string", "format": "password", "minLength": 8 } },
+            },
+            "PasswordReset": {
+                "type": "object",
+                "required": ["reset_token", "new_password"],
+                "properties": { "reset_token": uuid, "new_password": { "type": "string", "format": "password", "minLength": 8 } },
+            },
+            "PasswordResetToken": {
+                "type": "object",
+                "properties": { "reset_token": uuid, "user_id": uuid, "expires_at": timestamp },
+            },
+            "AccountDeletion": {
+                "type": "object",
+                "required": ["password"],
+                "properties": { "password": { "type": "string", "format": "password" } },
+            },
+            "TotpEnrolment": {
+                "type": "object",
+                "properties": { "secret": { "type": "string" }, "provisioning_uri": { "type": "string" } },
+            },
+            "TotpCode": {
+                "type": "object",
+                "required": ["code"],
+                "properties": { "code": { "type": "string" } },
+            },
+            "TotpDisable": {
+                "type": "object",
+                "required": ["password", "code"],
+                "properties": { "password": { "type": "string", "format": "password" }, "code": { "type": "string" } },
+            },
+            "RecoveryCodes": {
+                "type": "object",
+                "properties": { "recovery_codes": { "type": "array", "items": { "type": "string" } } },
+            },
+            "ApiKeyScope": { "type": "string", "enum": ["cameras:read", "images:read", "images:delete", "config:read", "config:write"] },
+            "NewApiKey": {
+                "type": "object",
+                "required": ["name", "scopes"],
+                "properties": {
+                    "name": { "type": "string" },
+                    "scopes": { "type": "array", "items": schema_ref("ApiKeyScope") },
+                    "camera_ids": { "type": "array", "items": uuid, "nullable": true, "description": "Leave out to allow all of the user's cameras" },
+                    "expires_at": { "type": "string", "format": "date-time", "nullable": true },
+                },
+            },
+            "ApiKey": {
+                "type": "object",
+                "properties": {
+                    "api_key_id": uuid,
+                    "api_key": uuid,
+                    "user_id": uuid,
+                    "name": { "type": "string" },
+                    "scopes": { "type": "array", "items": schema_ref("ApiKeyScope") },
+                    "camera_ids": { "type": "array", "items": uuid, "nullable": true },
+                    "expires_at": { "type": "string", "format": "date-time", "nullable": true },
+                    "created_at": timestamp,
+                },
+            },
+            "ApiKeyInfo": {
+                "type": "object",
+                "properties": {
+                    "api_key_id": uuid,
+                    "name": { "type": "string" },
+                    "scopes": { "type": "array", "items": schema_ref("ApiKeyScope") },
+                    "camera_ids": { "type": "array", "items": uuid, "nullable": true },
+                    "expires_at": { "type": "string", "format": "date-time", "nullable": true },
+                    "created_at": timestamp,
+                },
+            },
+            "AuditEvent": {
+                "type": "object",
+                "properties": {
+                    "audit_event_id": uuid,
+                    "action": { "type": "string", "example": "camera.config_updated" },
+                    "actor_user_id": { "type": "string", "format": "uuid", "nullable": true },
+                    "actor_api_key_id": { "type": "string", "format": "uuid", "nullable": true },
+                    "target_user_id": { "type": "string", "format": "uuid", "nullable": true },
+                    "target_camera_id": { "type": "string", "format": "uuid", "nullable": true },
+                    "ip_address": { "type": "string", "nullable": true },
+                    "details": { "type": "object", "nullable": true },
+                    "created_at": timestamp,
+                },
+            },
+            "InsertableCamera": {
+                "type": "object",
+                "required": ["name"],
+                "properties": { "name": { "type": "string" } },
+            },
+            "Camera": {
+                "type": "object",
+                "properties": { "camera_id": uuid, "name": { "type": "string" } },
+            },
+            "CameraToken": {
+                "type": "object",
+                "properties": { "camera_token": uuid, "camera_id": uuid },
+            },
+            "Config": {
+                "type": "object",
+                "required": ["camera_id", "interval"],
+                "properties": { "camera_id": uuid, "interval": { "type": "integer", "format": "int16", "description": "Seconds between uploads" } },
+            },
+            "PrivacyMask": {
+                "type": "object",
+                "required": ["points"],
+                "description": "A polygon in the image the right way up, after EXIF orientation is applied. Earlier versions of the server \
+                    placed masks before it was, so on cameras that send rotated images, masks saved with them now cover a different area \
+                    and need saving again",
+                "properties": {
+                    "points": {
+                        "type": "array",
+                        "minItems": 3,
+                        "maxItems": 100,
+                        "description": "[x, y] pairs as fractions of the image's width and height, with [0, 0] the top left corner",
+                        "items": { "type": "array", "items": { "type": "number", "minimum": 0, "maximum": 1 }, "minItems": 2, "maxItems": 2 },
+                    },
+                    "effect": { "type": "string", "enum": ["black", "blur"], "default": "black" },
+                },
+            },
+            "PrivacyMaskUpdate": {
+                "type": "object",
+                "required": ["masks"],
+                "properties": { "masks": { "type": "array", "items": schema_ref("PrivacyMask"), "maxItems": 32 } },
+            },
+            "PrivacyMaskVersion": {
+                "type": "object",
+                "properties": {
+                    "camera_id": uuid,
+                    "version": { "type": "integer" },
+                    "masks": { "type": "array", "items": schema_ref("PrivacyMask") },
+                    "created_by": { "type": "string", "format": "uuid", "nullable": true },
+                    "created_at": timestamp,
+                },
+            },
+            "CameraGroup": {
+                "type": "object",
+                "properties": {
+                    "camera_group_id": uuid,
+                    "user_id": uuid,
+                    "name": { "type": "string" },
+                    "camera_ids": { "type": "array", "items": uuid, "description": "In the order they're shown" },
+                    "created_at": timestamp,
+                },
+            },
+            "NewCameraGroup": {
+                "type": "object",
+                "required": ["name", "camera_ids"],
+                "properties": {
+                    "name": { "type": "string", "minLength": 1, "maxLength": 100 },
+                    "camera_ids": { "type": "array", "items": uuid, "minItems": 1, "maxItems": 16 },
+                },
+            },
+            "Watermark": {
+                "type": "object",
+                "properties": {
+                    "camera_id": uuid,
+                    "mode": { "type": "string", "enum": ["burn_in", "on_the_fly"] },
+                    "timezone": { "type": "string", "description": "An IANA time zone, like Europe/London" },
+                    "timestamp_format": { "type": "string", "description": "A strftime format for the capture time. Empty leaves it out" },
+                    "show_camera_name": { "type": "boolean" },
+                    "position": { "type": "string", "enum": ["top_left", "top_right", "bottom_left", "bottom_right"] },
+                    "opacity": { "type": "number", "minimum": 0, "maximum": 1 },
+                    "updated_at": timestamp,
+                },
+            },
+            "CameraRoleUpdate": {
+                "type": "object",
+                "required": ["role"],
+                "properties": { "role": schema_ref("CameraRole") },
+            },
+            "CameraAccess": {
+                "type": "object",
+                "properties": {
+                    "camera_id": uuid,
+                    "user_id": uuid,
+                    "username": { "type": "string" },
+                    "role": schema_ref("CameraRole"),
+                },
+            },
+            "CameraRole": { "type": "string", "enum": ["viewer", "editor", "owner"] },
+            "WatermarkUpdate": {
+                "type": "object",
+                "required": ["mode"],
+                "properties": {
+                    "mode": { "type": "string", "enum": ["burn_in", "on_the_fly"] },
+                    "timezone": { "type": "string", "default": "UTC" },
+                    "timestamp_format": { "type": "string", "maxLength": 64, "default": "%Y-%m-%d %H:%M:%S" },
+                    "show_camera_name": { "type": "boolean", "default": true },
+                    "position": { "type": "string", "enum": ["top_left", "top_right", "bottom_left", "bottom_right"], "default": "bottom_right" },
+                    "opacity": { "type": "number", "minimum": 0, "maximum": 1, "default": 0.8 },
+                },
+            },
+            "ImageId": {
+                "type": "string",
+                "description": "Milliseconds since the Unix epoch when the image was captured, bumped by a millisecond at a time if \
+                    another image already has that ID. Images uploaded before capture times were supported use seconds",
+            },
+            "ImageBatch": {
+                "type": "object",
+                "description": "Repeat capture_timestamp and image for each image. A capture_timestamp only applies to the image straight after it",
+                "properties": {
+                    "capture_timestamp": { "type": "string", "description": "Milliseconds since the epoch or an RFC 3339 date" },
+                    "image": { "type": "string", "format": "binary" },
+                },
+            },
+            "ImageMetadata": image_metadata,
+            "ImageSelection": {
+                "type": "object",
+                "description": "Either image_ids, or from and/or to. from is inclusive and to is exclusive",
+                "properties": {
+                    "image_ids": { "type": "array", "items": schema_ref("ImageId"), "maxItems": 1000 },
+                    "from": { "type": "string", "description": "Milliseconds since the epoch or an RFC 3339 date" },
+                    "to": { "type": "string", "description": "Milliseconds since the epoch or an RFC 3339 date" },
+                },
+            },
+            "BatchDeleteResult": {
+                "type": "object",
+                "properties": {
+                    "image_id": { "type": "string" },
+                    "error": { "type": "string", "nullable": true },
+                },
+            },
+            "TrashedImage": {
+                "type": "object",
+                "properties": {
+                    "camera_id": uuid,
+                    "image_id": schema_ref("ImageId"),
+                    "file_name": { "type": "string" },
+                    "deleted_by": { "type": "string", "format": "uuid", "nullable": true },
+                    "deleted_at": timestamp,
+                    "purge_at": timestamp,
+                },
+            },
+            "CameraStorageReport": {
+                "type": "object",
+                "properties": {
+                    "camera_id": uuid,
+                    "image_count": { "type": "integer", "format": "int64" },
+                    "total_bytes": { "type": "integer", "format": "int64" },
+                    "quota": limits,
+                    "policy": quota_policy,
+                },
+            },
+            "UserStorageReport": {
+                "type": "object",
+                "properties": {
+                    "image_count": { "type": "integer", "format": "int64" },
+                    "total_bytes": { "type": "integer", "format": "int64" },
+                    "quota": limits,
+                    "policy": quota_policy,
+                    "cameras": { "type": "array", "items": schema_ref("CameraStorageReport") },
+                },
+            },
+            "BatchUploadResult": {
+                "type": "object",
+                "properties": {
+                    "index": { "type": "integer", "description": "Position of the image in the batch, starting from 0" },
+                    "image_id": { "type": "string", "nullable": true },
+                    "duplicate_of": { "type": "string", "nullable": true, "description": "The image this was a copy of" },
+                    "error": { "type": "string", "nullable": true },
+                },
+            },
+            "SimilarImage": {
+                "type": "object",
+                "properties": {
+                    "image_id": schema_ref("ImageId"),
+                    "distance": { "type": "integer", "description": "How many bits of the perceptual hashes differ. Lower is more alike" },
+                },
+            },
+            "DedupReport": {
+                "type": "object",
+                "properties": {
+                    "camera_id": uuid,
+                    "duplicate_count": { "type": "integer", "format": "int64" },
+                    "bytes_saved": { "type": "integer", "format": "int64", "description": "What the duplicates would have taken up if they'd been stored again" },
+                    "policy": { "type": "string", "enum": ["link", "skip"] },
+                },
+            },
+            "Liveness": {
+                "type": "object",
+                "properties": { "status": { "type": "string" } },
+            },
+            "Readiness": {
+                "type": "object",
+                "properties": {
+                    "status": { "type": "string", "enum": ["ok", "unavailable"] },
+                    "database": { "type": "object" },
+                    "images_directory": { "type": "object" },
+                    "migrations": { "type": "object" },
+                },
+            },
+        },
+    })
+}
+
+/// Builds the OpenAPI document from every mounted route. Has to be attached after all the routes are mounted.
+pub struct OpenApiFairing;
+
+impl Fairing for OpenApiFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "OpenAPI Document",
+            kind: Kind::Attach,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let document = build_document(rocket.routes());
+        Ok(rocket.manage(OpenApiDocument(document)))
+    }
+}
+
+#[get("/openapi.json")]
+pub fn get_openapi(document: State<OpenApiDocument>) -> JsonValue {
+    JsonValue(document.0.clone())
+}
+
+/// A small page that renders /openapi.json. Everything it needs is in the page, so it works without internet access.
+#[get("/docs")]
+pub fn get_docs() -> Html<&'static str> {
+    Html(DOCS_PAGE)
+}
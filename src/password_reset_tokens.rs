@@ -0,0 +1,74 @@
+use super::schema::password_reset_tokens;
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{self};
+use serde::{Deserialize, Serialize};
+
+#[derive(Queryable, AsChangeset, Deserialize, Serialize)]
+#[table_name = "password_reset_tokens"]
+pub struct PasswordResetToken {
+    pub reset_token: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "password_reset_tokens"]
+pub struct InsertablePasswordResetToken {
+    pub user_id: uuid::Uuid,
+    pub expires_at: NaiveDateTime,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<PasswordResetToken>> {
+    password_reset_tokens::table.load::<PasswordResetToken>(&*connection)
+}
+
+pub fn get(reset_token: uuid::Uuid, connection: &PgConnection) -> QueryResult<PasswordResetToken> {
+    password_reset_tokens::table
+        .find(reset_token)
+        .get_result::<PasswordResetToken>(connection)
+}
+
+pub fn insert(
+    reset_token: InsertablePasswordResetToken,
+    connection: &PgConnection,
+) -> QueryResult<PasswordResetToken> {
+    diesel::insert_into(password_reset_tokens::table)
+        .values(reset_token)
+        .get_result(connection)
+}
+
+pub fn update(
+    reset_token_id: uuid::Uuid,
+    reset_token: PasswordResetToken,
+    connection: &PgConnection,
+) -> QueryResult<PasswordResetToken> {
+    diesel::update(password_reset_tokens::table.find(reset_token_id))
+        .set(&reset_token)
+        .get_result(connection)
+}
+
+pub fn delete(reset_token: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(password_reset_tokens::table.find(reset_token)).execute(connection)
+}
+
+/// Deletes a reset token if it hasn't expired. Returns false if there was no such token, including when another
+/// request redeemed it first.
+pub fn redeem(reset_token: uuid::Uuid, connection: &PgConnection) -> QueryResult<bool> {
+    let deleted = diesel::delete(
+        password_reset_tokens::table
+            .find(reset_token)
+            .filter(password_reset_tokens::expires_at.gt(Utc::now().naive_utc())),
+    )
+    .execute(connection)?;
+
+    Ok(deleted > 0)
+}
+
+/// Deletes every reset token belonging to a user, used once one of them has been redeemed.
+pub fn delete_all_for_user(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(password_reset_tokens::table.filter(password_reset_tokens::user_id.eq(user_id)))
+        .execute(connection)
+}
@@ -0,0 +1,495 @@
+use crate::{
+    api_error::ApiError,
+    api_keys::ApiKeyScope,
+    audit_log::{self, AuditAction, ClientIp, InsertableAuditEvent},
+    camera::parse_camera_id,
+    user_auth::UserAuth,
+    users_cameras::{
+        check_if_user_has_access_to_camera, check_if_user_has_camera_role, CameraRole,
+    },
+    CameraServerDbConn,
+};
+
+use super::schema::privacy_masks;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::{self};
+use image::{imageops, imageops::FilterType, DynamicImage, Rgba, RgbaImage};
+use rocket::{get, http::Status, put};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const MAX_MASKS: usize = 32;
+const MAX_POINTS: usize = 100;
+/// Blurred regions are scaled down by this much and back up again, which leaves nothing recognisable.
+const BLUR_FACTOR: u32 = 16;
+
+/// What's done to the pixels inside a mask.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaskEffect {
+    Black,
+    Blur,
+}
+
+impl Default for MaskEffect {
+    fn default() -> MaskEffect {
+        MaskEffect::Black
+    }
+}
+
+/// A polygon covering part of a camera's view that mustn't be stored.
+/// Points are fractions of the image's width and height, with (0, 0) the top left corner and (1, 1) the bottom right, so
+/// masks still fit if the camera's resolution changes. They're in the image the right way up, after any EXIF orientation
+/// is applied. Earlier versions of the server placed masks before it was, so on cameras that send rotated images, masks
+/// saved with them now cover a different area and need saving again.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PrivacyMask {
+    pub points: Vec<[f64; 2]>,
+    #[serde(default)]
+    pub effect: MaskEffect,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct PrivacyMaskVersion {
+    pub camera_id: uuid::Uuid,
+    pub version: i32,
+    /// A JSON array of PrivacyMasks
+    pub masks: serde_json::Value,
+    /// None if the user who made the change has since deleted their account
+    pub created_by: Option<uuid::Uuid>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "privacy_masks"]
+pub struct InsertablePrivacyMaskVersion {
+    pub camera_id: uuid::Uuid,
+    pub version: i32,
+    pub masks: serde_json::Value,
+    pub created_by: Option<uuid::Uuid>,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<PrivacyMaskVersion>> {
+    privacy_masks::table.load::<PrivacyMaskVersion>(&*connection)
+}
+
+pub fn get(
+    camera_id: uuid::Uuid,
+    version: i32,
+    connection: &PgConnection,
+) -> QueryResult<PrivacyMaskVersion> {
+    privacy_masks::table
+        .find((camera_id, version))
+        .get_result::<PrivacyMaskVersion>(connection)
+}
+
+pub fn insert(
+    privacy_mask_version: InsertablePrivacyMaskVersion,
+    connection: &PgConnection,
+) -> QueryResult<PrivacyMaskVersion> {
+    diesel::insert_into(privacy_masks::table)
+        .values(privacy_mask_version)
+        .get_result(connection)
+}
+
+pub fn delete(
+    camera_id: uuid::Uuid,
+    version: i32,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::delete(privacy_masks::table.find((camera_id, version))).execute(connection)
+}
+
+/// Returns the camera's current privacy masks, which are the highest version, or None if they've never been set.
+pub fn get_current_version(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Option<PrivacyMaskVersion>> {
+    privacy_masks::table
+        .filter(privacy_masks::camera_id.eq(camera_id))
+        .order(privacy_masks::version.desc())
+        .first::<PrivacyMaskVersion>(connection)
+        .optional()
+}
+
+/// Returns every version of the camera's privacy masks, newest first.
+pub fn get_cameras_versions(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<PrivacyMaskVersion>> {
+    privacy_masks::table
+        .filter(privacy_masks::camera_id.eq(camera_id))
+        .order(privacy_masks::version.desc())
+        .load::<PrivacyMaskVersion>(connection)
+}
+
+/// The privacy masks to apply to an upload.
+pub struct ActiveMasks {
+    pub version: i32,
+    pub masks: Vec<PrivacyMask>,
+}
+
+/// Returns the masks to apply to the camera's uploads, or None if it doesn't have any.
+/// Any error here has to stop the upload, since storing it without its masks would defeat the point.
+pub fn get_active_masks(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> Result<Option<ActiveMasks>, ApiError> {
+    let mask_error = |error: &dyn std::fmt::Display| {
+        error!(
+            "Failed to get privacy masks for camera {}: {}",
+            camera_id, error
+        );
+        ApiError {
+            error: "Failed to apply privacy masks",
+            status: Status::InternalServerError,
+        }
+    };
+
+    let current_version = match get_current_version(camera_id, connection) {
+        Ok(Some(current_version)) => current_version,
+        Ok(None) => return Ok(None),
+        Err(error) => return Err(mask_error(&error)),
+    };
+    let masks: Vec<PrivacyMask> =
+        serde_json::from_value(current_version.masks).map_err(|error| mask_error(&error))?;
+
+    if masks.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(ActiveMasks {
+            version: current_version.version,
+            masks,
+        }))
+    }
+}
+
+/// Returns the pixel spans inside the polygon on each row, as (y, first x, last x exclusive).
+/// A pixel is inside if its centre is, using the even-odd rule.
+fn polygon_spans(points: &[(f64, f64)], width: u32, height: u32) -> Vec<(u32, u32, u32)> {
+    let min_y = points
+        .iter()
+        .map(|point| point.1)
+        .fold(f64::INFINITY, f64::min);
+    let max_y = points
+        .iter()
+        .map(|point| point.1)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let first_row = min_y.floor().max(0.0) as u32;
+    let last_row = (max_y.ceil().max(0.0) as u32).min(height);
+
+    let mut spans = Vec::new();
+    let mut crossings = Vec::new();
+    for y in first_row..last_row {
+        let centre_y = y as f64 + 0.5;
+        crossings.clear();
+        for (index, start) in points.iter().enumerate() {
+            let end = points[(index + 1) % points.len()];
+            if (start.1 <= centre_y) != (end.1 <= centre_y) {
+                crossings
+                    .push(start.0 + (centre_y - start.1) * (end.0 - start.0) / (end.1 - start.1));
+            }
+        }
+        crossings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        for pair in crossings.chunks_exact(2) {
+            // Pixels whose centres are between the two crossings
+            let first_x = (pair[0] - 0.5).ceil().max(0.0) as u32;
+            let last_x = ((pair[1] - 0.5).ceil().max(0.0) as u32).min(width);
+            if first_x < last_x {
+                spans.push((y, first_x, last_x));
+            }
+        }
+    }
+    spans
+}
+
+/// Blacks out or blurs the parts of the image inside the masks.
+pub fn apply_masks(image: &DynamicImage, masks: &[PrivacyMask]) -> DynamicImage {
+    let mut image = image.to_rgba8();
+    let (width, height) = image.dimensions();
+
+    for mask in masks {
+        let points: Vec<(f64, f64)> = mask
+            .points
+            .iter()
+            .map(|[x, y]| (x * width as f64, y * height as f64))
+            .collect();
+        let spans = polygon_spans(&points, width, height);
+        if spans.is_empty() {
+            continue;
+        }
+
+        match mask.effect {
+            MaskEffect::Black => {
+                for (y, first_x, last_x) in spans {
+                    for x in first_x..last_x {
+                        let alpha = image.get_pixel(x, y)[3];
+                        image.put_pixel(x, y, Rgba([0, 0, 0, alpha]));
+                    }
+                }
+            }
+            MaskEffect::Blur => {
+                let blurred = blur_region(&image, &spans);
+                let (left, top) = spans_origin(&spans);
+                for (y, first_x, last_x) in spans {
+                    for x in first_x..last_x {
+                        image.put_pixel(x, y, *blurred.get_pixel(x - left, y - top));
+                    }
+                }
+            }
+        }
+    }
+
+    DynamicImage::ImageRgba8(image)
+}
+
+fn spans_origin(spans: &[(u32, u32, u32)]) -> (u32, u32) {
+    let left = spans.iter().map(|span| span.1).min().unwrap_or(0);
+    let top = spans.iter().map(|span| span.0).min().unwrap_or(0);
+    (left, top)
+}
+
+/// Returns a heavily blurred copy of the rectangle around the spans, by scaling it down and back up again.
+fn blur_region(image: &RgbaImage, spans: &[(u32, u32, u32)]) -> RgbaImage {
+    let (left, top) = spans_origin(spans);
+    let right = spans.iter().map(|span| span.2).max().unwrap_or(left + 1);
+    let bottom = spans.iter().map(|span| span.0 + 1).max().unwrap_or(top + 1);
+    let (region_width, region_height) = (right - left, bottom - top);
+
+    let region = imageops::crop_imm(image, left, top, region_width, region_height).to_image();
+    let small = imageops::resize(
+        &region,
+        (region_width / BLUR_FACTOR).max(1),
+        (region_height / BLUR_FACTOR).max(1),
+        FilterType::Triangle,
+    );
+    imageops::resize(&small, region_width, region_height, FilterType::Triangle)
+}
+
+fn validate_masks(masks: &[PrivacyMask]) -> Result<(), ApiError> {
+    let invalid = |error: &'static str| {
+        Err(ApiError {
+            error,
+            status: Status::UnprocessableEntity,
+        })
+    };
+
+    if masks.len() > MAX_MASKS {
+        return invalid("Too many privacy masks");
+    }
+    for mask in masks {
+        if mask.points.len() < 3 || mask.points.len() > MAX_POINTS {
+            return invalid("Privacy masks need between 3 and 100 points");
+        }
+        let in_bounds = mask
+            .points
+            .iter()
+            .flatten()
+            .all(|coordinate| (0.0..=1.0).contains(coordinate));
+        if !in_bounds {
+            return invalid("Privacy mask points have to be between 0 and 1");
+        }
+    }
+    Ok(())
+}
+
+fn read_error(camera_id: uuid::Uuid, error: diesel::result::Error) -> ApiError {
+    error!(
+        "Failed to get privacy masks for camera {}: {}",
+        camera_id, error
+    );
+    ApiError {
+        error: "Failed to get privacy masks",
+        status: Status::InternalServerError,
+    }
+}
+
+/// Returns the camera's current privacy masks.
+#[get("/cameras/<camera_id_string>/privacy-masks")]
+pub fn get_privacy_masks(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    camera_id_string: String,
+) -> Result<Json<PrivacyMaskVersion>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ConfigRead)?;
+    check_if_user_has_access_to_camera(&conn, &user_auth, &camera_id_string)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    match get_current_version(camera_id, &conn) {
+        Ok(Some(current_version)) => Ok(Json(current_version)),
+        Ok(None) => Err(ApiError {
+            error: "Camera has no privacy masks",
+            status: Status::NotFound,
+        }),
+        Err(error) => Err(read_error(camera_id, error)),
+    }
+}
+
+/// Returns every version of the camera's privacy masks, newest first.
+#[get("/cameras/<camera_id_string>/privacy-masks/versions")]
+pub fn list_privacy_mask_versions(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    camera_id_string: String,
+) -> Result<Json<Vec<PrivacyMaskVersion>>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ConfigRead)?;
+    check_if_user_has_access_to_camera(&conn, &user_auth, &camera_id_string)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    get_cameras_versions(camera_id, &conn)
+        .map(Json)
+        .map_err(|error| read_error(camera_id, error))
+}
+
+#[derive(Deserialize)]
+pub struct PrivacyMaskUpdate {
+    pub masks: Vec<PrivacyMask>,
+}
+
+/// Replaces the camera's privacy masks, adding a new version. An empty list turns masking off.
+/// Masks are applied to images as they're uploaded, so images stored before the change aren't affected.
+/// Only owners can change them.
+#[put(
+    "/cameras/<camera_id_string>/privacy-masks",
+    format = "json",
+    data = "<update>"
+)]
+pub fn update_privacy_masks(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    client_ip: ClientIp,
+    camera_id_string: String,
+    update: Json<PrivacyMaskUpdate>,
+) -> Result<Json<PrivacyMaskVersion>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ConfigWrite)?;
+    check_if_user_has_camera_role(&conn, &user_auth, &camera_id_string, CameraRole::Owner)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+    let masks = update.into_inner().masks;
+    validate_masks(&masks)?;
+
+    let version = get_current_version(camera_id, &conn)
+        .map_err(|error| read_error(camera_id, error))?
+        .map_or(1, |current_version| current_version.version + 1);
+
+    let new_version = insert(
+        InsertablePrivacyMaskVersion {
+            camera_id,
+            version,
+            masks: serde_json::to_value(&masks)
+                .expect("Failed to serialise privacy masks somehow?"),
+            created_by: Some(user_auth.user_id()),
+        },
+        &conn,
+    )
+    .map_err(|error| match error {
+        // Someone else changed them at the same time
+        diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        ) => ApiError {
+            error: "Privacy masks were changed by someone else, try again",
+            status: Status::Conflict,
+        },
+        error => {
+            error!(
+                "Failed to update privacy masks for camera {}: {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to update privacy masks",
+                status: Status::InternalServerError,
+            }
+        }
+    })?;
+
+    audit_log::record(
+        InsertableAuditEvent {
+            target_camera_id: Some(camera_id),
+            details: Some(json!({ "version": version, "mask_count": masks.len() })),
+            ..InsertableAuditEvent::by_user_auth(
+                AuditAction::PrivacyMasksUpdated,
+                &user_auth,
+                &client_ip,
+            )
+        },
+        &conn,
+    );
+
+    Ok(Json(new_version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::polygon_spans;
+
+    #[test]
+    fn triangle() {
+        let points = [(0.0, 0.0), (4.0, 0.0), (0.0, 4.0)];
+        // Pixels whose centres are on the hypotenuse are left out
+        assert_eq!(
+            polygon_spans(&points, 10, 10),
+            vec![(0, 0, 3), (1, 0, 2), (2, 0, 1)]
+        );
+    }
+
+    #[test]
+    fn concave_polygon() {
+        // A U shape, open at the bottom
+        let points = [
+            (0.0, 0.0),
+            (6.0, 0.0),
+            (6.0, 4.0),
+            (4.0, 4.0),
+            (4.0, 2.0),
+            (2.0, 2.0),
+            (2.0, 4.0),
+            (0.0, 4.0),
+        ];
+        assert_eq!(
+            polygon_spans(&points, 10, 10),
+            vec![
+                (0, 0, 6),
+                (1, 0, 6),
+                (2, 0, 2),
+                (2, 4, 6),
+                (3, 0, 2),
+                (3, 4, 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn edges_on_the_image_border() {
+        let points = [(0.0, 0.0), (4.0, 0.0), (4.0, 3.0), (0.0, 3.0)];
+        assert_eq!(
+            polygon_spans(&points, 4, 3),
+            vec![(0, 0, 4), (1, 0, 4), (2, 0, 4)]
+        );
+    }
+
+    #[test]
+    fn edges_past_the_image_border() {
+        let points = [(-5.0, -5.0), (15.0, -5.0), (15.0, 15.0), (-5.0, 15.0)];
+        assert_eq!(
+            polygon_spans(&points, 4, 3),
+            vec![(0, 0, 4), (1, 0, 4), (2, 0, 4)]
+        );
+    }
+
+    #[test]
+    fn horizontal_edges_through_pixel_centres() {
+        // The top and bottom edges are on the centres of rows 1 and 3. Horizontal edges never cross a row's centre
+        // line, so they're skipped instead of dividing by zero.
+        let points = [(1.0, 1.5), (5.0, 1.5), (5.0, 3.5), (1.0, 3.5)];
+        assert_eq!(polygon_spans(&points, 10, 10), vec![(1, 1, 5), (2, 1, 5)]);
+    }
+
+    #[test]
+    fn polygon_with_no_area() {
+        let points = [(0.0, 2.5), (5.0, 2.5), (3.0, 2.5)];
+        assert_eq!(polygon_spans(&points, 10, 10), vec![]);
+    }
+}
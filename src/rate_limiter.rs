@@ -0,0 +1,527 @@
+use crate::api_error::ApiError;
+
+use rocket::{
+    config::{Table, Value},
+    fairing::{Fairing, Info, Kind},
+    http::{Header, Status},
+    request::{self, FromRequest},
+    Outcome, Request, Response, Rocket, State,
+};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Once this many buckets exist, expired ones are cleared out, at most once every PRUNE_INTERVAL.
+const MAX_BUCKETS_BEFORE_PRUNE: usize = 10_000;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+/// Once this many buckets are in use, new keys share one overflow bucket per scope until some expire. That keeps
+/// memory bounded without letting anyone past the limits.
+const MAX_BUCKETS: usize = 100_000;
+
+/// How many requests are allowed in a window, and how long a key is locked out for after going over.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitRule {
+    pub max_requests: u32,
+    pub window: Duration,
+    pub lockout: Duration,
+}
+
+impl RateLimitRule {
+    /// Reads a rule from a table in the rate_limits config, falling back to the given defaults for missing keys.
+    fn from_config(table: Option<&Table>, name: &str, default: RateLimitRule) -> RateLimitRule {
+        let rule_table = table
+            .and_then(|table| table.get(name))
+            .and_then(Value::as_table);
+        let get = |key: &str, default_value: u64| {
+            rule_table
+                .and_then(|rule_table| rule_table.get(key))
+                .and_then(Value::as_integer)
+                .map(|value| value.max(0) as u64)
+                .unwrap_or(default_value)
+        };
+
+        RateLimitRule {
+            max_requests: get("max_requests", default.max_requests as u64) as u32,
+            window: Duration::from_secs(get("window_seconds", default.window.as_secs())),
+            lockout: Duration::from_secs(get("lockout_seconds", default.lockout.as_secs())),
+        }
+    }
+}
+
+struct Bucket {
+    window_start: Instant,
+    /// The window of the rule the bucket counts against, so pruning doesn't cut long windows short
+    window: Duration,
+    requests: u32,
+    locked_until: Option<Instant>,
+}
+
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    last_pruned: Option<Instant>,
+}
+
+/// Keeps track of how many requests each IP/username has made. Managed by Rocket, configured by the rate_limits table in Rocket.toml.
+pub struct RateLimiter {
+    login_ip: RateLimitRule,
+    login_username: RateLimitRule,
+    registration_ip: RateLimitRule,
+    upload_ip: RateLimitRule,
+    /// Whether to believe the X-Real-IP header, set by the proxy table in Rocket.toml. Only safe behind a proxy that
+    /// always sets it, since otherwise clients can send anything in it.
+    trust_real_ip: bool,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn from_config(table: Option<&Table>, trust_real_ip: bool) -> RateLimiter {
+        RateLimiter {
+            login_ip: RateLimitRule::from_config(
+                table,
+                "login_ip",
+                RateLimitRule {
+                    max_requests: 20,
+                    window: Duration::from_secs(60),
+                    lockout: Duration::from_secs(300),
+                },
+            ),
+            login_username: RateLimitRule::from_config(
+                table,
+                "login_username",
+                RateLimitRule {
+                    max_requests: 5,
+                    window: Duration::from_secs(900),
+                    lockout: Duration::from_secs(900),
+                },
+            ),
+            registration_ip: RateLimitRule::from_config(
+                table,
+                "registration_ip",
+                RateLimitRule {
+                    max_requests: 5,
+                    window: Duration::from_secs(3600),
+                    lockout: Duration::from_secs(3600),
+                },
+            ),
+            upload_ip: RateLimitRule::from_config(
+                table,
+                "upload_ip",
+                RateLimitRule {
+                    max_requests: 600,
+                    window: Duration::from_secs(60),
+                    lockout: Duration::from_secs(60),
+                },
+            ),
+            trust_real_ip,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_pruned: None,
+            }),
+        }
+    }
+
+    /// Counts a request against id in scope. Returns the number of seconds until it can be used again if it's over
+    /// the limit.
+    fn hit(&self, scope: &str, id: &str, rule: &RateLimitRule) -> Result<(), u64> {
+        self.hit_at(scope, id, rule, Instant::now())
+    }
+
+    fn hit_at(&self, scope: &str, id: &str, rule: &RateLimitRule, now: Instant) -> Result<(), u64> {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .expect("Rate limiter bucket mutex was poisoned");
+        let Buckets {
+            buckets,
+            last_pruned,
+        } = &mut *buckets;
+
+        let prune_due = last_pruned.map_or(true, |last_pruned| {
+            now.saturating_duration_since(last_pruned) >= PRUNE_INTERVAL
+        });
+        if buckets.len() > MAX_BUCKETS_BEFORE_PRUNE && prune_due {
+            buckets.retain(|_, bucket| {
+                bucket.locked_until.map_or(false, |until| until > now)
+                    || now.saturating_duration_since(bucket.window_start) < bucket.window
+            });
+            *last_pruned = Some(now);
+        }
+
+        let mut key = bucket_key(scope, id);
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+            key = overflow_key(scope);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            window_start: now,
+            window: rule.window,
+            requests: 0,
+            locked_until: None,
+        });
+        bucket.window = rule.window;
+
+        if let Some(locked_until) = bucket.locked_until {
+            if locked_until > now {
+                return Err(seconds_until(now, locked_until));
+            }
+            bucket.locked_until = None;
+            bucket.window_start = now;
+            bucket.requests = 0;
+        }
+
+        if now.saturating_duration_since(bucket.window_start) >= rule.window {
+            bucket.window_start = now;
+            bucket.requests = 0;
+        }
+
+        bucket.requests += 1;
+
+        if bucket.requests > rule.max_requests {
+            let locked_until = now + rule.lockout;
+            bucket.locked_until = Some(locked_until);
+            return Err(seconds_until(now, locked_until));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of seconds until id in scope is unlocked, without counting a request against it.
+    fn check(&self, scope: &str, id: &str) -> Result<(), u64> {
+        self.check_at(scope, id, Instant::now())
+    }
+
+    fn check_at(&self, scope: &str, id: &str, now: Instant) -> Result<(), u64> {
+        let buckets = self
+            .buckets
+            .lock()
+            .expect("Rate limiter bucket mutex was poisoned");
+
+        // Keys that didn't get a bucket of their own are counted in the overflow one
+        let bucket = buckets
+            .buckets
+            .get(&bucket_key(scope, id))
+            .or_else(|| buckets.buckets.get(&overflow_key(scope)));
+        match bucket.and_then(|bucket| bucket.locked_until) {
+            Some(locked_until) if locked_until > now => Err(seconds_until(now, locked_until)),
+            _ => Ok(()),
+        }
+    }
+
+    fn reset(&self, scope: &str, id: &str) {
+        self.buckets
+            .lock()
+            .expect("Rate limiter bucket mutex was poisoned")
+            .buckets
+            .remove(&bucket_key(scope, id));
+    }
+}
+
+fn bucket_key(scope: &str, id: &str) -> String {
+    format!("{}:{}", scope, id)
+}
+
+/// Can't clash with a real key, since those always have an ID after the scope
+fn overflow_key(scope: &str) -> String {
+    format!("{}#overflow", scope)
+}
+
+/// Returns the client's IP address. The X-Real-IP header is only used if the proxy config trusts it, since
+/// otherwise a client could get around its limits by sending a different one with each request.
+pub fn client_ip(request: &Request) -> Option<IpAddr> {
+    let trust_real_ip = request
+        .guard::<State<RateLimiter>>()
+        .succeeded()
+        .map_or(false, |rate_limiter| rate_limiter.trust_real_ip);
+
+    if trust_real_ip {
+        request.client_ip()
+    } else {
+        request.remote().map(|address| address.ip())
+    }
+}
+
+fn seconds_until(now: Instant, until: Instant) -> u64 {
+    // Round up so that clients never retry a second too early
+    let remaining = until.duration_since(now);
+    remaining.as_secs() + if remaining.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+/// Request-local value holding the Retry-After (in seconds) to send back, or 0 if the request wasn't limited.
+#[derive(Default)]
+struct RetryAfter(AtomicU64);
+
+impl RetryAfter {
+    fn set(&self, seconds: u64) {
+        self.0.store(seconds, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The rate limited routes. Each one has its own limits.
+pub trait LimitedRoute {
+    const NAME: &'static str;
+
+    fn ip_rule(rate_limiter: &RateLimiter) -> &RateLimitRule;
+}
+
+pub struct Login;
+pub struct Registration;
+pub struct Upload;
+
+impl LimitedRoute for Login {
+    const NAME: &'static str = "login";
+
+    fn ip_rule(rate_limiter: &RateLimiter) -> &RateLimitRule {
+        &rate_limiter.login_ip
+    }
+}
+
+impl LimitedRoute for Registration {
+    const NAME: &'static str = "registration";
+
+    fn ip_rule(rate_limiter: &RateLimiter) -> &RateLimitRule {
+        &rate_limiter.registration_ip
+    }
+}
+
+impl LimitedRoute for Upload {
+    const NAME: &'static str = "upload";
+
+    fn ip_rule(rate_limiter: &RateLimiter) -> &RateLimitRule {
+        &rate_limiter.upload_ip
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: u64,
+}
+
+/// Request guard that counts the request against the client's IP for route R.
+/// Fails with 429 Too Many Requests (and a Retry-After header) if the IP is over the limit.
+pub struct RateLimit<'a, R: LimitedRoute> {
+    rate_limiter: &'a RateLimiter,
+    retry_after: &'a RetryAfter,
+    route: PhantomData<R>,
+}
+
+impl<'a, 'r, R: LimitedRoute> FromRequest<'a, 'r> for RateLimit<'a, R> {
+    type Error = RateLimited;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let rate_limiter = request
+            .guard::<State<RateLimiter>>()
+            .expect("Rate limiter isn't managed, is the fairing attached?")
+            .inner();
+        let retry_after = request.local_cache(RetryAfter::default);
+
+        let client_ip = client_ip(request)
+            .map(|ip| ip.to_string())
+            .unwrap_or(String::from("unknown"));
+
+        match rate_limiter.hit(
+            &format!("{}:ip", R::NAME),
+            &client_ip,
+            R::ip_rule(rate_limiter),
+        ) {
+            Ok(_) => Outcome::Success(RateLimit {
+                rate_limiter,
+                retry_after,
+                route: PhantomData,
+            }),
+            Err(seconds) => {
+                retry_after.set(seconds);
+                Outcome::Failure((
+                    Status::TooManyRequests,
+                    RateLimited {
+                        retry_after: seconds,
+                    },
+                ))
+            }
+        }
+    }
+}
+
+const LOGIN_USERNAME_SCOPE: &str = "login:username";
+
+impl<'a> RateLimit<'a, Login> {
+    /// Returns an ApiError if the username is locked out because of too many failed logins.
+    pub fn check_username(&self, username: &String) -> Result<(), ApiError> {
+        self.rate_limiter
+            .check(LOGIN_USERNAME_SCOPE, &username.to_lowercase())
+            .map_err(|seconds| {
+                self.retry_after.set(seconds);
+                ApiError {
+                    error: "Too many failed login attempts, try again later",
+                    status: Status::TooManyRequests,
+                }
+            })
+    }
+
+    /// Counts a failed login against the username. Once there are too many, the username is locked out.
+    pub fn record_failed_login(&self, username: &String) {
+        // The lockout is reported by check_username() on the next attempt
+        let _ = self.rate_limiter.hit(
+            LOGIN_USERNAME_SCOPE,
+            &username.to_lowercase(),
+            &self.rate_limiter.login_username,
+        );
+    }
+
+    /// Clears failed logins for the username after a successful login.
+    pub fn reset_username(&self, username: &String) {
+        self.rate_limiter
+            .reset(LOGIN_USERNAME_SCOPE, &username.to_lowercase());
+    }
+}
+
+/// Manages the RateLimiter and adds Retry-After headers to rate limited responses.
+pub struct RateLimiterFairing;
+
+impl Fairing for RateLimiterFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate Limiter",
+            kind: Kind::Attach | Kind::Response,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let trust_real_ip = rocket
+            .config()
+            .get_table("proxy")
+            .ok()
+            .and_then(|table| table.get("trust_real_ip"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let rate_limiter =
+            RateLimiter::from_config(rocket.config().get_table("rate_limits").ok(), trust_real_ip);
+        Ok(rocket.manage(rate_limiter))
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let retry_after = request.local_cache(RetryAfter::default).get();
+        if retry_after > 0 {
+            response.set_header(Header::new("Retry-After", retry_after.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULE: RateLimitRule = RateLimitRule {
+        max_requests: 2,
+        window: Duration::from_secs(60),
+        lockout: Duration::from_secs(300),
+    };
+
+    fn rate_limiter() -> RateLimiter {
+        RateLimiter::from_config(None, false)
+    }
+
+    #[test]
+    fn locks_out_after_max_requests() {
+        let rate_limiter = rate_limiter();
+        let now = Instant::now();
+
+        assert_eq!(rate_limiter.hit_at("test", "a", &RULE, now), Ok(()));
+        assert_eq!(rate_limiter.hit_at("test", "a", &RULE, now), Ok(()));
+        assert_eq!(rate_limiter.hit_at("test", "a", &RULE, now), Err(300));
+        assert_eq!(
+            rate_limiter.check_at("test", "a", now + Duration::from_millis(1500)),
+            Err(299)
+        );
+        // Other keys and scopes have their own buckets
+        assert_eq!(rate_limiter.check_at("test", "b", now), Ok(()));
+        assert_eq!(rate_limiter.hit_at("other", "a", &RULE, now), Ok(()));
+    }
+
+    #[test]
+    fn lockout_expires() {
+        let rate_limiter = rate_limiter();
+        let now = Instant::now();
+        for _ in 0..3 {
+            let _ = rate_limiter.hit_at("test", "a", &RULE, now);
+        }
+
+        let unlocked = now + RULE.lockout;
+        assert_eq!(rate_limiter.check_at("test", "a", unlocked), Ok(()));
+        // The count starts again once the lockout is over
+        assert_eq!(rate_limiter.hit_at("test", "a", &RULE, unlocked), Ok(()));
+        assert_eq!(rate_limiter.hit_at("test", "a", &RULE, unlocked), Ok(()));
+        assert_eq!(rate_limiter.hit_at("test", "a", &RULE, unlocked), Err(300));
+    }
+
+    #[test]
+    fn window_resets_count() {
+        let rate_limiter = rate_limiter();
+        let now = Instant::now();
+
+        assert_eq!(rate_limiter.hit_at("test", "a", &RULE, now), Ok(()));
+        assert_eq!(rate_limiter.hit_at("test", "a", &RULE, now), Ok(()));
+        let next_window = now + RULE.window;
+        assert_eq!(rate_limiter.hit_at("test", "a", &RULE, next_window), Ok(()));
+        assert_eq!(rate_limiter.hit_at("test", "a", &RULE, next_window), Ok(()));
+    }
+
+    #[test]
+    fn reset_clears_count() {
+        let rate_limiter = rate_limiter();
+        let now = Instant::now();
+        for _ in 0..3 {
+            let _ = rate_limiter.hit_at("test", "a", &RULE, now);
+        }
+
+        rate_limiter.reset("test", "a");
+        assert_eq!(rate_limiter.check_at("test", "a", now), Ok(()));
+    }
+
+    #[test]
+    fn pruning_keeps_buckets_in_their_own_window() {
+        let rate_limiter = rate_limiter();
+        let now = Instant::now();
+        let long_rule = RateLimitRule {
+            window: Duration::from_secs(3600),
+            ..RULE
+        };
+        assert_eq!(rate_limiter.hit_at("long", "a", &long_rule, now), Ok(()));
+        assert_eq!(rate_limiter.hit_at("long", "a", &long_rule, now), Ok(()));
+        for i in 0..MAX_BUCKETS_BEFORE_PRUNE {
+            let _ = rate_limiter.hit_at("short", &i.to_string(), &RULE, now);
+        }
+
+        // The short buckets' window is over, but the long one's isn't, so it still counts
+        let later = now + Duration::from_secs(120);
+        assert_eq!(
+            rate_limiter.hit_at("long", "a", &long_rule, later),
+            Err(300)
+        );
+        assert_eq!(rate_limiter.buckets.lock().unwrap().buckets.len(), 1);
+    }
+
+    #[test]
+    fn new_keys_share_an_overflow_bucket_once_full() {
+        let rate_limiter = rate_limiter();
+        let now = Instant::now();
+        for i in 0..MAX_BUCKETS {
+            let _ = rate_limiter.hit_at("full", &i.to_string(), &RULE, now);
+        }
+
+        assert_eq!(rate_limiter.hit_at("test", "a", &RULE, now), Ok(()));
+        assert_eq!(rate_limiter.hit_at("test", "b", &RULE, now), Ok(()));
+        assert_eq!(rate_limiter.hit_at("test", "c", &RULE, now), Err(300));
+        assert_eq!(rate_limiter.check_at("test", "d", now), Err(300));
+        assert_eq!(
+            rate_limiter.buckets.lock().unwrap().buckets.len(),
+            MAX_BUCKETS + 1
+        );
+    }
+}
@@ -1,3 +1,56 @@
+table! {
+    api_keys (api_key_id) {
+        api_key_id -> Uuid,
+        api_key -> Uuid,
+        user_id -> Uuid,
+        name -> Text,
+        scopes -> Array<Text>,
+        camera_ids -> Nullable<Array<Uuid>>,
+        expires_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    audit_events (audit_event_id) {
+        audit_event_id -> Uuid,
+        action -> Text,
+        actor_user_id -> Nullable<Uuid>,
+        actor_api_key_id -> Nullable<Uuid>,
+        target_user_id -> Nullable<Uuid>,
+        target_camera_id -> Nullable<Uuid>,
+        ip_address -> Nullable<Text>,
+        details -> Nullable<Jsonb>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    camera_dedup_savings (camera_id) {
+        camera_id -> Uuid,
+        duplicate_count -> Int8,
+        bytes_saved -> Int8,
+    }
+}
+
+table! {
+    camera_groups (camera_group_id) {
+        camera_group_id -> Uuid,
+        user_id -> Uuid,
+        name -> Text,
+        camera_ids -> Array<Uuid>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    camera_storage_usage (camera_id) {
+        camera_id -> Uuid,
+        image_count -> Int8,
+        total_bytes -> Int8,
+    }
+}
+
 table! {
     camera_tokens (camera_token) {
         camera_token -> Uuid,
@@ -19,6 +72,94 @@ table! {
     }
 }
 
+table! {
+    image_metadata (camera_id, image_id) {
+        camera_id -> Uuid,
+        image_id -> Text,
+        content_type -> Text,
+        size_bytes -> Int8,
+        created_at -> Timestamp,
+        captured_at -> Nullable<Timestamp>,
+        camera_make -> Nullable<Text>,
+        camera_model -> Nullable<Text>,
+        exposure_time -> Nullable<Text>,
+        f_number -> Nullable<Float8>,
+        iso -> Nullable<Int4>,
+        focal_length -> Nullable<Float8>,
+        orientation -> Nullable<Int2>,
+        gps_latitude -> Nullable<Float8>,
+        gps_longitude -> Nullable<Float8>,
+        gps_altitude -> Nullable<Float8>,
+        content_hash -> Nullable<Text>,
+        perceptual_hash -> Nullable<Int8>,
+        perceptual_hash_0 -> Nullable<Int4>,
+        perceptual_hash_1 -> Nullable<Int4>,
+        perceptual_hash_2 -> Nullable<Int4>,
+        perceptual_hash_3 -> Nullable<Int4>,
+        mean_luminance -> Nullable<Float8>,
+        luminance_histogram -> Nullable<Array<Float8>>,
+        underexposed_ratio -> Nullable<Float8>,
+        overexposed_ratio -> Nullable<Float8>,
+        lighting -> Nullable<Text>,
+        privacy_mask_version -> Nullable<Int4>,
+        watermarked -> Bool,
+    }
+}
+
+table! {
+    login_challenges (challenge) {
+        challenge -> Uuid,
+        user_id -> Uuid,
+        expires_at -> Timestamp,
+        failed_attempts -> Int2,
+    }
+}
+
+table! {
+    password_reset_tokens (reset_token) {
+        reset_token -> Uuid,
+        user_id -> Uuid,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    privacy_masks (camera_id, version) {
+        camera_id -> Uuid,
+        version -> Int4,
+        masks -> Jsonb,
+        created_by -> Nullable<Uuid>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    totp_recovery_codes (recovery_code_id) {
+        recovery_code_id -> Int4,
+        user_id -> Uuid,
+        code_hash -> Text,
+    }
+}
+
+table! {
+    totp_secrets (user_id) {
+        user_id -> Uuid,
+        secret -> Text,
+        enabled -> Bool,
+        last_used_step -> Nullable<Int8>,
+    }
+}
+
+table! {
+    trashed_images (camera_id, image_id) {
+        camera_id -> Uuid,
+        image_id -> Text,
+        file_name -> Text,
+        deleted_by -> Nullable<Uuid>,
+        deleted_at -> Timestamp,
+    }
+}
+
 table! {
     user_tokens (user_token) {
         user_token -> Uuid,
@@ -39,14 +180,41 @@ table! {
         users_cameras_id -> Int4,
         camera_id -> Uuid,
         user_id -> Uuid,
+        role -> Text,
+    }
+}
+
+table! {
+    watermarks (camera_id) {
+        camera_id -> Uuid,
+        mode -> Text,
+        timezone -> Text,
+        timestamp_format -> Text,
+        show_camera_name -> Bool,
+        position -> Text,
+        opacity -> Float8,
+        updated_at -> Timestamp,
     }
 }
 
 allow_tables_to_appear_in_same_query!(
+    api_keys,
+    audit_events,
+    camera_dedup_savings,
+    camera_groups,
+    camera_storage_usage,
     camera_tokens,
     cameras,
     configs,
+    image_metadata,
+    login_challenges,
+    password_reset_tokens,
+    privacy_masks,
+    totp_recovery_codes,
+    totp_secrets,
+    trashed_images,
     user_tokens,
     users,
     users_cameras,
+    watermarks,
 );
@@ -0,0 +1,450 @@
+use crate::{
+    api_error::ApiError,
+    api_keys::ApiKeyScope,
+    camera::{camera_directory, images_directory, list_camera_directory, parse_camera_id},
+    image_metadata,
+    image_store::{image_id, remove_original, UploadConfig},
+    user_auth::UserAuth,
+    users_cameras::{self, check_if_user_has_camera_role, CameraRole},
+    CameraServerDbConn,
+};
+
+use super::schema::camera_storage_usage;
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::{get, http::Status, State};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use std::fs::{read_dir, remove_file, DirEntry};
+use std::io::ErrorKind;
+use std::vec;
+
+/// What happens to an upload that would take a camera or its owner over quota.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaPolicy {
+    /// The upload fails with 507
+    Reject,
+    /// The camera's oldest images are deleted until the upload fits
+    EvictOldest,
+}
+
+/// A storage limit. None means no limit.
+#[derive(Clone, Copy, Serialize)]
+pub struct Limits {
+    pub max_bytes: Option<u64>,
+    pub max_images: Option<u64>,
+}
+
+impl Limits {
+    fn allows(&self, total_bytes: i64, image_count: i64) -> bool {
+        self.max_bytes
+            .map_or(true, |max_bytes| total_bytes <= max_bytes as i64)
+            && self
+                .max_images
+                .map_or(true, |max_images| image_count <= max_images as i64)
+    }
+}
+
+/// Settings for quotas, read from the quotas table in Rocket.toml.
+pub struct QuotaConfig {
+    pub policy: QuotaPolicy,
+    /// Applies to each camera
+    pub camera: Limits,
+    /// Applies to the total of all the cameras a user owns
+    pub user: Limits,
+}
+
+impl QuotaConfig {
+    pub fn from_config(config: &rocket::Config) -> QuotaConfig {
+        let table = config.get_table("quotas").ok();
+        let get_limit = |key: &str| {
+            table
+                .and_then(|table| table.get(key))
+                .and_then(|value| value.as_integer())
+                .map(|value| value.max(0) as u64)
+        };
+
+        let policy = match table
+            .and_then(|table| table.get("policy"))
+            .and_then(|value| value.as_str())
+        {
+            None | Some("reject") => QuotaPolicy::Reject,
+            Some("evict_oldest") => QuotaPolicy::EvictOldest,
+            Some(policy) => {
+                warn!(
+                    "Unknown quota policy {}, rejecting uploads over quota instead",
+                    policy
+                );
+                QuotaPolicy::Reject
+            }
+        };
+
+        QuotaConfig {
+            policy,
+            camera: Limits {
+                max_bytes: get_limit("camera_max_mb").map(|mb| mb * 1024 * 1024),
+                max_images: get_limit("camera_max_images"),
+            },
+            user: Limits {
+                max_bytes: get_limit("user_max_mb").map(|mb| mb * 1024 * 1024),
+                max_images: get_limit("user_max_images"),
+            },
+        }
+    }
+}
+
+/// How much a camera's images take up. Images in the trash don't count.
+#[derive(Queryable, Insertable, AsChangeset, Deserialize, Serialize)]
+#[table_name = "camera_storage_usage"]
+pub struct CameraStorageUsage {
+    pub camera_id: uuid::Uuid,
+    pub image_count: i64,
+    pub total_bytes: i64,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<CameraStorageUsage>> {
+    camera_storage_usage::table.load::<CameraStorageUsage>(&*connection)
+}
+
+pub fn get(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<CameraStorageUsage> {
+    camera_storage_usage::table
+        .find(camera_id)
+        .get_result::<CameraStorageUsage>(connection)
+}
+
+pub fn insert(usage: CameraStorageUsage, connection: &PgConnection) -> QueryResult<usize> {
+    // Two requests can both find there's no row and work it out at the same time
+    diesel::insert_into(camera_storage_usage::table)
+        .values(usage)
+        .on_conflict_do_nothing()
+        .execute(connection)
+}
+
+pub fn update(
+    camera_id: uuid::Uuid,
+    usage: CameraStorageUsage,
+    connection: &PgConnection,
+) -> QueryResult<CameraStorageUsage> {
+    diesel::update(camera_storage_usage::table.find(camera_id))
+        .set(&usage)
+        .get_result(connection)
+}
+
+pub fn delete(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(camera_storage_usage::table.find(camera_id)).execute(connection)
+}
+
+/// Adds to a camera's usage in place, so that concurrent uploads don't lose each other's changes. Use negative numbers to subtract.
+pub fn add_to_usage(
+    camera_id: uuid::Uuid,
+    images: i64,
+    bytes: i64,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::update(camera_storage_usage::table.find(camera_id))
+        .set((
+            camera_storage_usage::image_count.eq(camera_storage_usage::image_count + images),
+            camera_storage_usage::total_bytes.eq(camera_storage_usage::total_bytes + bytes),
+        ))
+        .execute(connection)
+}
+
+/// Records an image being stored or deleted. The usage is already stored by then, so failing to update it is only logged.
+pub fn record_change(camera_id: uuid::Uuid, images: i64, bytes: i64, connection: &PgConnection) {
+    if let Err(error) = add_to_usage(camera_id, images, bytes, connection) {
+        warn!(
+            "Failed to update storage usage for camera {}: {}",
+            camera_id, error
+        );
+    }
+}
+
+fn usage_error(camera_id: uuid::Uuid, error: &dyn std::fmt::Display) -> ApiError {
+    error!(
+        "Failed to get storage usage for camera {}: {}",
+        camera_id, error
+    );
+    ApiError {
+        error: "Failed to get storage usage",
+        status: Status::InternalServerError,
+    }
+}
+
+/// Returns a camera's usage, working it out from its directory if it hasn't been stored yet.
+/// This has to be called before adding or removing images, so that the change isn't counted twice.
+pub fn get_or_compute_usage(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> Result<CameraStorageUsage, ApiError> {
+    match get(camera_id, connection) {
+        Ok(usage) => return Ok(usage),
+        Err(diesel::NotFound) => {}
+        Err(error) => return Err(usage_error(camera_id, &error)),
+    }
+
+    let camera_directory = camera_directory(&images_directory(), &camera_id.to_string());
+    let mut usage = CameraStorageUsage {
+        camera_id,
+        image_count: 0,
+        total_bytes: 0,
+    };
+    match read_dir(&camera_directory) {
+        Ok(entries) => {
+            for metadata in entries
+                .filter_map(Result::ok)
+                .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+                .filter_map(|entry| entry.metadata().ok())
+                .filter(|metadata| metadata.is_file())
+            {
+                usage.image_count += 1;
+                usage.total_bytes += metadata.len() as i64;
+            }
+        }
+        // Cameras that haven't uploaded anything don't have a directory yet
+        Err(error) if error.kind() == ErrorKind::NotFound => {}
+        Err(error) => return Err(usage_error(camera_id, &error)),
+    }
+
+    insert(usage, connection).map_err(|error| usage_error(camera_id, &error))?;
+    get(camera_id, connection).map_err(|error| usage_error(camera_id, &error))
+}
+
+/// Returns the usage of each camera the user owns.
+fn get_users_usage(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> Result<Vec<CameraStorageUsage>, ApiError> {
+    let camera_ids =
+        users_cameras::get_users_owned_camera_ids(user_id, connection).map_err(|error| {
+            error!("Failed to get cameras owned by user {}: {}", user_id, error);
+            ApiError {
+                error: "Failed to get storage usage",
+                status: Status::InternalServerError,
+            }
+        })?;
+
+    camera_ids
+        .into_iter()
+        .map(|camera_id| get_or_compute_usage(camera_id, connection))
+        .collect()
+}
+
+/// Deletes a camera's oldest image for good. Returns its size, or None if the camera has no images left.
+/// The camera's directory is only listed the first time an upload needs to evict an image, and later evictions for
+/// the same upload carry on down that list.
+fn evict_oldest_image(
+    camera_id: uuid::Uuid,
+    oldest_images: &mut Option<vec::IntoIter<DirEntry>>,
+    connection: &PgConnection,
+) -> Result<Option<i64>, ApiError> {
+    if oldest_images.is_none() {
+        let camera_directory = camera_directory(&images_directory(), &camera_id.to_string());
+        let image_list = match list_camera_directory(&camera_directory, true) {
+            Ok(image_list) => image_list,
+            Err(error) if error.status == Status::NotFound => Vec::new(),
+            Err(error) => return Err(error),
+        };
+        *oldest_images = Some(image_list.into_iter());
+    }
+
+    let (oldest_image, bytes) = loop {
+        let oldest_image = match oldest_images.as_mut().and_then(Iterator::next) {
+            Some(oldest_image) => oldest_image,
+            None => return Ok(None),
+        };
+
+        let path = oldest_image.path();
+        let bytes = oldest_image
+            .metadata()
+            .map(|metadata| metadata.len() as i64)
+            .unwrap_or(0);
+        match remove_file(&path) {
+            Ok(()) => break (oldest_image, bytes),
+            // Deleted since the directory was listed, most likely by another upload making room
+            Err(error) if error.kind() == ErrorKind::NotFound => continue,
+            Err(error) => {
+                error!("Failed to evict image {}: {}", path.display(), error);
+                return Err(ApiError {
+                    error: "Failed to make room for image",
+                    status: Status::InternalServerError,
+                });
+            }
+        }
+    };
+
+    remove_original(
+        &camera_id.to_string(),
+        &oldest_image.file_name().to_string_lossy(),
+    );
+    let image_id = image_id(&oldest_image);
+    if let Err(error) = image_metadata::delete(camera_id, &image_id, connection) {
+        warn!(
+            "Failed to delete metadata for evicted image {}: {}",
+            image_id, error
+        );
+    }
+    record_change(camera_id, -1, -bytes, connection);
+    debug!(
+        "Evicted image {} from camera {} to stay under quota",
+        image_id, camera_id
+    );
+
+    Ok(Some(bytes))
+}
+
+/// Checks that a new image of the given size fits within the camera's quota and its owners' quotas.
+/// With the evict_oldest policy, the camera's oldest images are deleted to make room if they need to be.
+/// Quotas are checked before the image is stored, so uploads at the same time can go slightly over them.
+pub fn make_room(
+    camera_id: uuid::Uuid,
+    bytes: u64,
+    quota_config: &QuotaConfig,
+    connection: &PgConnection,
+) -> Result<(), ApiError> {
+    let bytes = bytes as i64;
+    let camera_quota_error = || ApiError {
+        error: "Camera is over its storage quota",
+        status: Status::InsufficientStorage,
+    };
+    let user_quota_error = || ApiError {
+        error: "Camera's owner is over their storage quota",
+        status: Status::InsufficientStorage,
+    };
+
+    let mut camera_usage = get_or_compute_usage(camera_id, connection)?;
+
+    let owner_ids = users_cameras::get_camera_owner_ids(camera_id, connection)
+        .map_err(|error| usage_error(camera_id, &error))?;
+    // Totals across all of each owner's cameras, as (bytes, images)
+    let mut owner_usage = Vec::with_capacity(owner_ids.len());
+    for owner_id in owner_ids {
+        let usage = get_users_usage(owner_id, connection)?;
+        owner_usage.push((
+            usage.iter().map(|usage| usage.total_bytes).sum::<i64>(),
+            usage.iter().map(|usage| usage.image_count).sum::<i64>(),
+        ));
+    }
+
+    // Evicting from this camera can't help if the image is too big on its own, or the owner's other cameras are already over
+    if !quota_config.camera.allows(bytes, 1) {
+        return Err(camera_quota_error());
+    }
+    let over_without_this_camera = owner_usage.iter().any(|(total_bytes, image_count)| {
+        !quota_config.user.allows(
+            total_bytes - camera_usage.total_bytes + bytes,
+            image_count - camera_usage.image_count + 1,
+        )
+    });
+    if over_without_this_camera {
+        return Err(user_quota_error());
+    }
+
+    let mut oldest_images = None;
+    loop {
+        let camera_fits = quota_config.camera.allows(
+            camera_usage.total_bytes + bytes,
+            camera_usage.image_count + 1,
+        );
+        let owners_fit = owner_usage.iter().all(|(total_bytes, image_count)| {
+            quota_config
+                .user
+                .allows(total_bytes + bytes, image_count + 1)
+        });
+        if camera_fits && owners_fit {
+            return Ok(());
+        }
+
+        let quota_error = if camera_fits {
+            user_quota_error
+        } else {
+            camera_quota_error
+        };
+        if quota_config.policy == QuotaPolicy::Reject {
+            return Err(quota_error());
+        }
+
+        let evicted_bytes = evict_oldest_image(camera_id, &mut oldest_images, connection)?
+            .ok_or_else(quota_error)?;
+        camera_usage.total_bytes -= evicted_bytes;
+        camera_usage.image_count -= 1;
+        for (total_bytes, image_count) in owner_usage.iter_mut() {
+            *total_bytes -= evicted_bytes;
+            *image_count -= 1;
+        }
+    }
+}
+
+/// A camera's storage usage along with the quota it's under.
+#[derive(Serialize)]
+pub struct CameraStorageReport {
+    #[serde(flatten)]
+    pub usage: CameraStorageUsage,
+    pub quota: Limits,
+    pub policy: QuotaPolicy,
+}
+
+/// The total storage usage of a user's cameras along with the quota it's under.
+#[derive(Serialize)]
+pub struct UserStorageReport {
+    pub image_count: i64,
+    pub total_bytes: i64,
+    pub quota: Limits,
+    pub policy: QuotaPolicy,
+    /// Each camera the user owns
+    pub cameras: Vec<CameraStorageReport>,
+}
+
+fn camera_storage_report(
+    usage: CameraStorageUsage,
+    quota_config: &QuotaConfig,
+) -> CameraStorageReport {
+    CameraStorageReport {
+        usage,
+        quota: quota_config.camera,
+        policy: quota_config.policy,
+    }
+}
+
+/// Returns how much storage a camera is using and its quota. Only owners can see this.
+#[get("/cameras/<camera_id_string>/storage")]
+pub fn get_camera_storage(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    upload_config: State<UploadConfig>,
+    camera_id_string: String,
+) -> Result<Json<CameraStorageReport>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::CamerasRead)?;
+    check_if_user_has_camera_role(&conn, &user_auth, &camera_id_string, CameraRole::Owner)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    let usage = get_or_compute_usage(camera_id, &conn)?;
+    Ok(Json(camera_storage_report(usage, &upload_config.quotas)))
+}
+
+/// Returns how much storage the user's cameras are using in total and the user's quota, along with each camera's usage.
+#[get("/users/me/storage")]
+pub fn get_user_storage(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    upload_config: State<UploadConfig>,
+) -> Result<Json<UserStorageReport>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::CamerasRead)?;
+
+    let usage: Vec<CameraStorageUsage> = get_users_usage(user_auth.user_id(), &conn)?
+        .into_iter()
+        // API keys restricted to certain cameras only get to see those cameras
+        .filter(|usage| user_auth.allows_camera(&usage.camera_id))
+        .collect();
+
+    Ok(Json(UserStorageReport {
+        image_count: usage.iter().map(|usage| usage.image_count).sum(),
+        total_bytes: usage.iter().map(|usage| usage.total_bytes).sum(),
+        quota: upload_config.quotas.user,
+        policy: upload_config.quotas.policy,
+        cameras: usage
+            .into_iter()
+            .map(|usage| camera_storage_report(usage, &upload_config.quotas))
+            .collect(),
+    }))
+}
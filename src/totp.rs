@@ -0,0 +1,447 @@
+use crate::{
+    api_error::ApiError,
+    audit_log::{self, AuditAction, ClientIp, InsertableAuditEvent},
+    totp_recovery_codes,
+    user::{self, is_login_valid},
+    user_tokens::UserToken,
+    CameraServerDbConn,
+};
+
+use super::schema::totp_secrets;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{self};
+use hmac::{Hmac, Mac, NewMac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rocket::http::Status;
+use rocket::post;
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::time::SystemTime;
+
+/// Shown as the account's issuer in authenticator apps
+const TOTP_ISSUER: &str = "CameraServer";
+/// Length of a TOTP time step in seconds
+const TOTP_PERIOD: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// How many time steps either side of the current one are accepted, to allow for clock drift
+const TOTP_ALLOWED_DRIFT: i64 = 1;
+const TOTP_SECRET_LENGTH: usize = 20;
+
+const BASE32_ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+#[derive(Queryable, AsChangeset, Insertable, Deserialize, Serialize)]
+#[table_name = "totp_secrets"]
+pub struct TotpSecret {
+    pub user_id: uuid::Uuid,
+    /// Base32 encoded shared secret
+    pub secret: String,
+    /// false until the user has proven their authenticator works by verifying a code
+    pub enabled: bool,
+    /// The last time step a code was accepted for, so that codes can't be replayed
+    pub last_used_step: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrolment {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpDisable {
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<TotpSecret>> {
+    totp_secrets::table.load::<TotpSecret>(&*connection)
+}
+
+pub fn get(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<TotpSecret> {
+    totp_secrets::table
+        .find(user_id)
+        .get_result::<TotpSecret>(connection)
+}
+
+pub fn insert(totp_secret: TotpSecret, connection: &PgConnection) -> QueryResult<TotpSecret> {
+    diesel::insert_into(totp_secrets::table)
+        .values(totp_secret)
+        .get_result(connection)
+}
+
+pub fn update(
+    user_id: uuid::Uuid,
+    totp_secret: TotpSecret,
+    connection: &PgConnection,
+) -> QueryResult<TotpSecret> {
+    diesel::update(totp_secrets::table.find(user_id))
+        .set(&totp_secret)
+        .get_result(connection)
+}
+
+pub fn delete(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(totp_secrets::table.find(user_id)).execute(connection)
+}
+
+/// Returns true if the user has finished enrolling in TOTP, meaning logins need a second step.
+pub fn is_enabled(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<bool> {
+    totp_secrets::table
+        .find(user_id)
+        .select(totp_secrets::enabled)
+        .get_result::<bool>(connection)
+        .optional()
+        .map(|enabled| enabled.unwrap_or(false))
+}
+
+/// is_enabled() for route handlers, turning database errors into a 500 so they aren't mistaken for TOTP being off.
+fn check_enabled(user_id: uuid::Uuid, conn: &CameraServerDbConn) -> Result<bool, ApiError> {
+    is_enabled(user_id, conn).map_err(|error| {
+        error!(
+            "Failed to check whether user {} has TOTP enabled: {}",
+            user_id, error
+        );
+        ApiError {
+            error: "Failed to get TOTP settings",
+            status: Status::InternalServerError,
+        }
+    })
+}
+
+fn current_step() -> i64 {
+    let seconds = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Failed to get current time somehow?")
+        .as_secs();
+    (seconds / TOTP_PERIOD) as i64
+}
+
+/// Generates the code for a time step, as described in RFC 4226 and RFC 6238.
+fn code_for_step(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(secret).expect("HMAC can take a key of any size");
+    mac.update(&(step as u64).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = ((hash[offset] as u32 & 0x7f) << 24)
+        | ((hash[offset + 1] as u32) << 16)
+        | ((hash[offset + 2] as u32) << 8)
+        | (hash[offset + 3] as u32);
+
+    truncated % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Checks a code against the secret. Returns the matching time step, which must be newer than last_used_step.
+fn verify_code(totp_secret: &TotpSecret, code: &str) -> Option<i64> {
+    let secret = base32::decode(BASE32_ALPHABET, &totp_secret.secret)?;
+    let code = code.trim().parse::<u32>().ok()?;
+    let step = current_step();
+
+    (step - TOTP_ALLOWED_DRIFT..=step + TOTP_ALLOWED_DRIFT)
+        .filter(|candidate| {
+            totp_secret
+                .last_used_step
+                .map_or(true, |last| *candidate > last)
+        })
+        .find(|candidate| code_for_step(&secret, *candidate) == code)
+}
+
+/// Checks a TOTP code for the user and marks it as used. Returns false if the code is wrong, reused or TOTP isn't set up.
+pub fn check_code(user_id: uuid::Uuid, code: &str, connection: &PgConnection) -> QueryResult<bool> {
+    let totp_secret = match get(user_id, connection).optional()? {
+        Some(totp_secret) => totp_secret,
+        None => return Ok(false),
+    };
+
+    match verify_code(&totp_secret, code) {
+        Some(step) => claim_step(user_id, step, connection),
+        None => Ok(false),
+    }
+}
+
+/// Marks a time step as used unless it, or a later one, already is. Returns false if a concurrent login
+/// claimed it first, so the same code can't be accepted twice.
+fn claim_step(user_id: uuid::Uuid, step: i64, connection: &PgConnection) -> QueryResult<bool> {
+    let updated = diesel::update(
+        totp_secrets::table.find(user_id).filter(
+            totp_secrets::last_used_step
+                .is_null()
+                .or(totp_secrets::last_used_step.lt(step)),
+        ),
+    )
+    .set(totp_secrets::last_used_step.eq(step))
+    .execute(connection)?;
+
+    Ok(updated > 0)
+}
+
+/// Starts TOTP enrolment by generating a new secret. TOTP isn't enabled until a code is verified with /TOTP/Verify.
+#[post("/TOTP/Enroll")]
+pub fn enroll(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<TotpEnrolment>, ApiError> {
+    let user = user::get(user_token.user_id, &conn).map_err(|error| {
+        error!(
+            "Failed to get user {} while enrolling TOTP: {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to get user",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    if check_enabled(user.user_id, &conn)? {
+        return Err(ApiError {
+            error: "TOTP is already enabled",
+            status: Status::Conflict,
+        });
+    }
+
+    let secret = base32::encode(BASE32_ALPHABET, &rand::random::<[u8; TOTP_SECRET_LENGTH]>());
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        delete(user.user_id, &conn)?;
+        insert(
+            TotpSecret {
+                user_id: user.user_id,
+                secret: secret.clone(),
+                enabled: false,
+                last_used_step: None,
+            },
+            &conn,
+        )
+    })
+    .map_err(|error| {
+        error!(
+            "Failed to store TOTP secret for user {}: {}",
+            user.user_id, error
+        );
+        ApiError {
+            error: "Failed to store TOTP secret",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    let provisioning_uri = format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = utf8_percent_encode(TOTP_ISSUER, NON_ALPHANUMERIC),
+        username = utf8_percent_encode(&user.username, NON_ALPHANUMERIC),
+        secret = secret,
+        digits = TOTP_DIGITS,
+        period = TOTP_PERIOD,
+    );
+
+    Ok(Json(TotpEnrolment {
+        secret,
+        provisioning_uri,
+    }))
+}
+
+/// Finishes TOTP enrolment. If the code matches the pending secret, TOTP is enabled and a set of recovery codes is returned.
+#[post("/TOTP/Verify", format = "json", data = "<totp_code>")]
+pub fn verify(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    totp_code: Json<TotpCode>,
+) -> Result<Json<RecoveryCodes>, ApiError> {
+    let totp_secret = get(user_token.user_id, &conn).map_err(|_| ApiError {
+        error: "TOTP enrolment hasn't been started",
+        status: Status::NotFound,
+    })?;
+
+    if totp_secret.enabled {
+        return Err(ApiError {
+            error: "TOTP is already enabled",
+            status: Status::Conflict,
+        });
+    }
+
+    let step = verify_code(&totp_secret, &totp_code.code).ok_or(ApiError {
+        error: "Invalid TOTP code",
+        status: Status::Unauthorized,
+    })?;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        update(
+            user_token.user_id,
+            TotpSecret {
+                enabled: true,
+                last_used_step: Some(step),
+                ..totp_secret
+            },
+            &conn,
+        )?;
+        totp_recovery_codes::regenerate(user_token.user_id, &conn)
+    })
+    .map(|recovery_codes| {
+        audit_log::record(
+            InsertableAuditEvent {
+                actor_user_id: Some(user_token.user_id),
+                target_user_id: Some(user_token.user_id),
+                ..InsertableAuditEvent::new(AuditAction::TotpEnabled, &client_ip)
+            },
+            &conn,
+        );
+        Json(RecoveryCodes { recovery_codes })
+    })
+    .map_err(|error| {
+        error!(
+            "Failed to enable TOTP for user {}: {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to enable TOTP",
+            status: Status::InternalServerError,
+        }
+    })
+}
+
+/// Replaces the user's recovery codes. Needs a current TOTP code.
+#[post("/TOTP/RecoveryCodes", format = "json", data = "<totp_code>")]
+pub fn regenerate_recovery_codes(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    totp_code: Json<TotpCode>,
+) -> Result<Json<RecoveryCodes>, ApiError> {
+    if !check_enabled(user_token.user_id, &conn)? {
+        return Err(ApiError {
+            error: "TOTP isn't enabled",
+            status: Status::NotFound,
+        });
+    }
+
+    if !check_code(user_token.user_id, &totp_code.code, &conn).unwrap_or(false) {
+        return Err(ApiError {
+            error: "Invalid TOTP code",
+            status: Status::Unauthorized,
+        });
+    }
+
+    totp_recovery_codes::regenerate(user_token.user_id, &conn)
+        .map(|recovery_codes| {
+            audit_log::record(
+                InsertableAuditEvent {
+                    actor_user_id: Some(user_token.user_id),
+                    target_user_id: Some(user_token.user_id),
+                    ..InsertableAuditEvent::new(
+                        AuditAction::TotpRecoveryCodesRegenerated,
+                        &client_ip,
+                    )
+                },
+                &conn,
+            );
+            Json(RecoveryCodes { recovery_codes })
+        })
+        .map_err(|error| {
+            error!(
+                "Failed to regenerate recovery codes for user {}: {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to regenerate recovery codes",
+                status: Status::InternalServerError,
+            }
+        })
+}
+
+/// Turns TOTP off. Needs both the user's password and a TOTP (or recovery) code.
+#[post("/TOTP/Disable", format = "json", data = "<totp_disable>")]
+pub fn disable(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    totp_disable: Json<TotpDisable>,
+) -> Result<(), ApiError> {
+    let user = user::get(user_token.user_id, &conn).map_err(|error| {
+        error!(
+            "Failed to get user {} while disabling TOTP: {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to get user",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    if !is_login_valid(user.username.clone(), totp_disable.password.clone(), &conn) {
+        return Err(ApiError {
+            error: "Password is incorrect",
+            status: Status::Unauthorized,
+        });
+    }
+
+    let code_valid = check_code(user.user_id, &totp_disable.code, &conn).unwrap_or(false)
+        || totp_recovery_codes::redeem(user.user_id, &totp_disable.code, &conn).unwrap_or(false);
+
+    if !code_valid {
+        return Err(ApiError {
+            error: "Invalid TOTP code",
+            status: Status::Unauthorized,
+        });
+    }
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        totp_recovery_codes::delete_all_for_user(user.user_id, &conn)?;
+        delete(user.user_id, &conn)?;
+        Ok(())
+    })
+    .map_err(|error| {
+        error!(
+            "Failed to disable TOTP for user {}: {}",
+            user.user_id, error
+        );
+        ApiError {
+            error: "Failed to disable TOTP",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    audit_log::record(
+        InsertableAuditEvent {
+            actor_user_id: Some(user.user_id),
+            target_user_id: Some(user.user_id),
+            ..InsertableAuditEvent::new(AuditAction::TotpDisabled, &client_ip)
+        },
+        &conn,
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::code_for_step;
+
+    /// The SHA-1 test vectors from RFC 6238 appendix B, cut down to six digits
+    #[test]
+    fn code_for_step_matches_rfc_6238() {
+        let secret = b"12345678901234567890";
+        let vectors = [
+            (59, 287_082),
+            (1_111_111_109, 81_804),
+            (1_111_111_111, 50_471),
+            (1_234_567_890, 5_924),
+            (2_000_000_000, 279_037),
+            (20_000_000_000, 353_130),
+        ];
+
+        for (time, code) in vectors.iter() {
+            assert_eq!(code_for_step(secret, time / 30), *code, "time {}", time);
+        }
+    }
+}
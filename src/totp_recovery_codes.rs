@@ -0,0 +1,118 @@
+use super::schema::totp_recovery_codes;
+
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{self};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// How many recovery codes a user is given when enabling TOTP
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+const RECOVERY_CODE_CHARACTERS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+#[derive(Queryable, AsChangeset, Deserialize, Serialize)]
+#[table_name = "totp_recovery_codes"]
+pub struct TotpRecoveryCode {
+    pub recovery_code_id: i32,
+    pub user_id: uuid::Uuid,
+    pub code_hash: String,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "totp_recovery_codes"]
+pub struct InsertableTotpRecoveryCode {
+    pub user_id: uuid::Uuid,
+    pub code_hash: String,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<TotpRecoveryCode>> {
+    totp_recovery_codes::table.load::<TotpRecoveryCode>(&*connection)
+}
+
+pub fn get(recovery_code_id: i32, connection: &PgConnection) -> QueryResult<TotpRecoveryCode> {
+    totp_recovery_codes::table
+        .find(recovery_code_id)
+        .get_result::<TotpRecoveryCode>(connection)
+}
+
+pub fn insert(
+    recovery_code: InsertableTotpRecoveryCode,
+    connection: &PgConnection,
+) -> QueryResult<TotpRecoveryCode> {
+    diesel::insert_into(totp_recovery_codes::table)
+        .values(recovery_code)
+        .get_result(connection)
+}
+
+pub fn update(
+    recovery_code_id: i32,
+    recovery_code: TotpRecoveryCode,
+    connection: &PgConnection,
+) -> QueryResult<TotpRecoveryCode> {
+    diesel::update(totp_recovery_codes::table.find(recovery_code_id))
+        .set(&recovery_code)
+        .get_result(connection)
+}
+
+pub fn delete(recovery_code_id: i32, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(totp_recovery_codes::table.find(recovery_code_id)).execute(connection)
+}
+
+pub fn delete_all_for_user(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(totp_recovery_codes::table.filter(totp_recovery_codes::user_id.eq(user_id)))
+        .execute(connection)
+}
+
+/// Recovery codes are random enough that a plain SHA-256 is fine, which keeps checking ten of them cheap compared to bcrypt.
+fn hash_code(code: &str) -> String {
+    let normalised_code: String = code
+        .chars()
+        .filter(|character| character.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+    hex::encode(Sha256::digest(normalised_code.as_bytes()))
+}
+
+/// Replaces a user's recovery codes with a new set. Returns the new codes in plain text, this is the only time they're available.
+pub fn regenerate(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<Vec<String>> {
+    let mut rng = rand::thread_rng();
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = (0..10)
+                .map(|_| {
+                    RECOVERY_CODE_CHARACTERS[rng.gen_range(0..RECOVERY_CODE_CHARACTERS.len())]
+                        as char
+                })
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+
+    connection.transaction(|| {
+        delete_all_for_user(user_id, connection)?;
+        for code in &codes {
+            insert(
+                InsertableTotpRecoveryCode {
+                    user_id,
+                    code_hash: hash_code(code),
+                },
+                connection,
+            )?;
+        }
+        Ok(codes)
+    })
+}
+
+/// Checks a recovery code for the given user. Recovery codes are single use, so a matching code is deleted.
+pub fn redeem(user_id: uuid::Uuid, code: &str, connection: &PgConnection) -> QueryResult<bool> {
+    let deleted = diesel::delete(
+        totp_recovery_codes::table
+            .filter(totp_recovery_codes::user_id.eq(user_id))
+            .filter(totp_recovery_codes::code_hash.eq(hash_code(code))),
+    )
+    .execute(connection)?;
+
+    Ok(deleted > 0)
+}
@@ -1,19 +1,34 @@
 use crate::{
+    admin_key::AdminKey,
     api_error::ApiError,
+    audit_log::{self, AuditAction, ClientIp, InsertableAuditEvent},
+    auth_token::{remove_session_cookie, set_session_cookie},
+    camera::{self, camera_directory, images_directory},
+    image_store::originals_directory,
+    image_trash::trash_directory,
+    login_challenges::{self, InsertableLoginChallenge},
+    password_reset_tokens::{self, InsertablePasswordResetToken, PasswordResetToken},
+    rate_limiter::{Login, RateLimit, Registration},
+    totp, totp_recovery_codes,
     user_tokens::{self, UserToken},
+    users_cameras,
 };
 
 use super::schema::users;
 use super::CameraServerDbConn;
 use bcrypt;
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::{self};
-use rocket::http::Status;
+use rocket::http::{Cookies, Status};
 use rocket::post;
 
 use rocket_contrib::json::Json;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs::remove_dir_all;
+use std::io::ErrorKind;
 use user_tokens::InsertableUserToken;
 
 #[derive(Queryable, AsChangeset, Deserialize, Serialize)]
@@ -60,6 +75,53 @@ pub struct AuthentiationResult {
     pub user_token: uuid::Uuid,
 }
 
+/// Returned by /Login when the user has TOTP enabled
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpChallenge {
+    pub totp_required: bool,
+    pub challenge: uuid::Uuid,
+    pub expires_at: NaiveDateTime,
+}
+
+/// Untagged so that logins without TOTP look exactly the same as they always have
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LoginResult {
+    Authenticated(AuthentiationResult),
+    TotpRequired(TotpChallenge),
+}
+
+#[derive(Deserialize)]
+pub struct TotpLogin {
+    pub challenge: uuid::Uuid,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordReset {
+    pub reset_token: uuid::Uuid,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct AccountDeletion {
+    pub password: String,
+}
+
+/// How long a password reset token can be used for after being generated
+const PASSWORD_RESET_TOKEN_LIFETIME_MINUTES: i64 = 60;
+/// How long a user has to enter their TOTP code after entering their password
+const LOGIN_CHALLENGE_LIFETIME_MINUTES: i64 = 5;
+/// How many wrong TOTP codes can be entered for a login challenge before it's thrown away
+const LOGIN_CHALLENGE_MAX_FAILED_ATTEMPTS: i16 = 5;
+
 pub fn all(connection: &PgConnection) -> QueryResult<Vec<User>> {
     users::table.load::<User>(&*connection)
 }
@@ -103,18 +165,63 @@ pub fn is_login_valid(username: String, password: String, connection: &PgConnect
     }
 }
 
-#[post("/AddUser", format = "json", data = "<new_user>")]
-pub fn add_user(
-    conn: CameraServerDbConn,
-    new_user: Json<InsertableUser>,
-) -> Result<Json<AuthentiationResult>, ApiError> {
-    if new_user.password.chars().count() < 8 {
+/// Checks that a new password meets our requirements. Returns an ApiError explaining what's wrong if it doesn't.
+pub fn validate_new_password(password: &String) -> Result<(), ApiError> {
+    if password.chars().count() < 8 {
         return Err(ApiError {
             error: "Password must be at least 8 characters long",
             status: Status::UnprocessableEntity,
         });
     }
 
+    Ok(())
+}
+
+/// Hashes a password with bcrypt, without storing it.
+fn hash_password(password: &String) -> Result<String, ApiError> {
+    bcrypt::hash(password.clone(), bcrypt::DEFAULT_COST).map_err(|error| {
+        error!("Failed to hash password: {}", error);
+        ApiError {
+            error: "Failed to hash password",
+            status: Status::InternalServerError,
+        }
+    })
+}
+
+/// Hashes a password and stores it for the given user.
+fn set_password(
+    user: User,
+    password: &String,
+    connection: &PgConnection,
+) -> Result<User, ApiError> {
+    let hashed_password = hash_password(password)?;
+
+    update(
+        user.user_id,
+        User {
+            password: hashed_password,
+            ..user
+        },
+        connection,
+    )
+    .map_err(|error| {
+        error!("Failed to update password: {}", error);
+        ApiError {
+            error: "Failed to update password",
+            status: Status::InternalServerError,
+        }
+    })
+}
+
+#[post("/AddUser", format = "json", data = "<new_user>")]
+pub fn add_user(
+    conn: CameraServerDbConn,
+    _rate_limit: RateLimit<Registration>,
+    client_ip: ClientIp,
+    new_user: Json<InsertableUser>,
+) -> Result<Json<AuthentiationResult>, ApiError> {
+    validate_new_password(&new_user.password)?;
+
     // Tries a DB request with the new username. If something comes back, return an error saying the username already exists
     match get_by_username(new_user.username.clone(), &conn) {
         Ok(_) => {
@@ -133,7 +240,7 @@ pub fn add_user(
 
     // Inserts the new username/pass into the db. Returns a User object, which included the new UUID.
     let new_user_inserted = insert(new_user_insertable, &conn).map_err(|error| {
-        println!("Failed to insert user into table! The error was: {}", error);
+        error!("Failed to insert user into table: {}", error);
         ApiError {
             error: "Failed to insert user into table",
             status: Status::InternalServerError,
@@ -148,8 +255,8 @@ pub fn add_user(
         &conn,
     )
     .map_err(|error| {
-        println!(
-            "Failed to get new token for user {} (id: {}). The error was {}",
+        error!(
+            "Failed to create token for new user {} ({}): {}",
             new_user.username, new_user_inserted.user_id, error
         );
         delete(new_user_inserted.user_id, &conn)
@@ -160,6 +267,15 @@ pub fn add_user(
         }
     })?;
 
+    audit_log::record(
+        InsertableAuditEvent {
+            actor_user_id: Some(new_user_inserted.user_id),
+            target_user_id: Some(new_user_inserted.user_id),
+            ..InsertableAuditEvent::new(AuditAction::UserTokenCreated, &client_ip)
+        },
+        &conn,
+    );
+
     Ok(Json(AuthentiationResult {
         user_info: UserInfo {
             user_id: new_user_inserted.user_id,
@@ -169,56 +285,560 @@ pub fn add_user(
     }))
 }
 
+/// Creates a new token for the user, completing a login.
+fn create_session(
+    user: User,
+    client_ip: &ClientIp,
+    connection: &PgConnection,
+) -> Result<AuthentiationResult, ApiError> {
+    let token = user_tokens::insert(
+        user_tokens::InsertableUserToken {
+            user_id: user.user_id,
+        },
+        connection,
+    )
+    .map_err(|error| {
+        error!(
+            "Failed to create token for user {}: {}",
+            user.username, error
+        );
+        ApiError {
+            error: "Failed to create token",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    for action in &[AuditAction::LoginSucceeded, AuditAction::UserTokenCreated] {
+        audit_log::record(
+            InsertableAuditEvent {
+                actor_user_id: Some(user.user_id),
+                target_user_id: Some(user.user_id),
+                ..InsertableAuditEvent::new(*action, client_ip)
+            },
+            connection,
+        );
+    }
+
+    Ok(AuthentiationResult {
+        user_info: UserInfo::from_user(user),
+        user_token: token.user_token,
+    })
+}
+
 /// Generates a new token for the given user. Actual login checking is handled in the UserLogin request guard.
-#[post("/Login", format = "json", data = "<user_login>")]
+/// If the user has TOTP enabled, a login challenge is returned instead, which is completed with /Login/TOTP.
+/// Browsers can pass ?session_cookie=true to also get the token as a private cookie.
+#[post("/Login?<session_cookie>", format = "json", data = "<user_login>")]
 pub fn login(
     conn: CameraServerDbConn,
+    rate_limit: RateLimit<Login>,
+    client_ip: ClientIp,
+    mut cookies: Cookies,
+    session_cookie: Option<bool>,
     user_login: Json<InsertableUser>,
-) -> Result<Json<AuthentiationResult>, ApiError> {
+) -> Result<Json<LoginResult>, ApiError> {
+    rate_limit.check_username(&user_login.username)?;
+
     if !is_login_valid(
         user_login.username.clone(),
         user_login.password.clone(),
         &conn,
     ) {
+        rate_limit.record_failed_login(&user_login.username);
+        audit_log::record(
+            InsertableAuditEvent {
+                target_user_id: get_by_username(user_login.username.clone(), &conn)
+                    .ok()
+                    .map(|user| user.user_id),
+                details: Some(json!({
+                    "username": user_login.username,
+                    "reason": "invalid_username_or_password",
+                })),
+                ..InsertableAuditEvent::new(AuditAction::LoginFailed, &client_ip)
+            },
+            &conn,
+        );
         return Err(ApiError {
             error: "Invalid username or password",
             status: Status::Unauthorized,
         });
     }
 
+    rate_limit.reset_username(&user_login.username);
+
     let user = get_by_username(user_login.username.clone(), &conn).map_err(|error| {
-        println!(
-            "Failed to get user id from username {}. The error was: {}",
-            user_login.username, error
-        );
+        error!("Failed to get user {}: {}", user_login.username, error);
         ApiError {
             error: "Failed to get user id from username",
             status: Status::InternalServerError,
         }
     })?;
 
-    let token = user_tokens::insert(
-        user_tokens::InsertableUserToken {
+    let totp_enabled = totp::is_enabled(user.user_id, &conn).map_err(|error| {
+        error!(
+            "Failed to check TOTP status for user {}: {}",
+            user.user_id, error
+        );
+        ApiError {
+            error: "Failed to check TOTP status",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    if totp_enabled {
+        let login_challenge = login_challenges::insert(
+            InsertableLoginChallenge {
+                user_id: user.user_id,
+                expires_at: Utc::now().naive_utc()
+                    + Duration::minutes(LOGIN_CHALLENGE_LIFETIME_MINUTES),
+            },
+            &conn,
+        )
+        .map_err(|error| {
+            error!(
+                "Failed to create login challenge for user {}: {}",
+                user.user_id, error
+            );
+            ApiError {
+                error: "Failed to create login challenge",
+                status: Status::InternalServerError,
+            }
+        })?;
+
+        return Ok(Json(LoginResult::TotpRequired(TotpChallenge {
+            totp_required: true,
+            challenge: login_challenge.challenge,
+            expires_at: login_challenge.expires_at,
+        })));
+    }
+
+    let result = create_session(user, &client_ip, &conn)?;
+    if session_cookie.unwrap_or(false) {
+        set_session_cookie(&mut cookies, result.user_token);
+    }
+
+    Ok(Json(LoginResult::Authenticated(result)))
+}
+
+/// Completes a login for a user with TOTP enabled, using the challenge returned by /Login and either a TOTP code or a recovery code.
+#[post("/Login/TOTP?<session_cookie>", format = "json", data = "<totp_login>")]
+pub fn login_totp(
+    conn: CameraServerDbConn,
+    _rate_limit: RateLimit<Login>,
+    client_ip: ClientIp,
+    mut cookies: Cookies,
+    session_cookie: Option<bool>,
+    totp_login: Json<TotpLogin>,
+) -> Result<Json<AuthentiationResult>, ApiError> {
+    let login_challenge = login_challenges::get(totp_login.challenge, &conn)
+        .ok()
+        .filter(|login_challenge| login_challenge.expires_at > Utc::now().naive_utc())
+        .ok_or(ApiError {
+            error: "Invalid or expired login challenge",
+            status: Status::Unauthorized,
+        })?;
+
+    let (code, is_recovery_code) = match (&totp_login.code, &totp_login.recovery_code) {
+        (Some(code), _) => (code, false),
+        (None, Some(recovery_code)) => (recovery_code, true),
+        (None, None) => {
+            return Err(ApiError {
+                error: "Either a TOTP code or a recovery code is required",
+                status: Status::UnprocessableEntity,
+            })
+        }
+    };
+
+    // Challenges only allow a few guesses, after that the user has to log in with their password again.
+    // The attempt is counted before the code is checked, so guesses made at the same time can't get more.
+    let attempts = login_challenges::count_attempt(
+        login_challenge.challenge,
+        LOGIN_CHALLENGE_MAX_FAILED_ATTEMPTS,
+        &conn,
+    )
+    .map_err(|error| {
+        error!("Failed to count TOTP attempt: {}", error);
+        ApiError {
+            error: "Failed to check TOTP code",
+            status: Status::InternalServerError,
+        }
+    })?
+    .ok_or(ApiError {
+        error: "Invalid or expired login challenge",
+        status: Status::Unauthorized,
+    })?;
+
+    let code_valid = if is_recovery_code {
+        totp_recovery_codes::redeem(login_challenge.user_id, code, &conn)
+    } else {
+        totp::check_code(login_challenge.user_id, code, &conn)
+    }
+    .map_err(|error| {
+        error!(
+            "Failed to check TOTP code for user {}: {}",
+            login_challenge.user_id, error
+        );
+        ApiError {
+            error: "Failed to check TOTP code",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    if !code_valid {
+        if attempts >= LOGIN_CHALLENGE_MAX_FAILED_ATTEMPTS {
+            // It can't be used again anyway, since count_attempt() won't count any more attempts
+            if let Err(error) = login_challenges::delete(login_challenge.challenge, &conn) {
+                warn!("Failed to delete used up login challenge: {}", error);
+            }
+        }
+
+        audit_log::record(
+            InsertableAuditEvent {
+                target_user_id: Some(login_challenge.user_id),
+                details: Some(json!({ "reason": "invalid_totp_code" })),
+                ..InsertableAuditEvent::new(AuditAction::LoginFailed, &client_ip)
+            },
+            &conn,
+        );
+
+        return Err(ApiError {
+            error: "Invalid TOTP code",
+            status: Status::Unauthorized,
+        });
+    }
+
+    login_challenges::delete(login_challenge.challenge, &conn).map_err(|error| {
+        error!("Failed to delete login challenge: {}", error);
+        ApiError {
+            error: "Failed to complete login challenge",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    let user = get(login_challenge.user_id, &conn).map_err(|error| {
+        error!(
+            "Failed to get user {} while completing login: {}",
+            login_challenge.user_id, error
+        );
+        ApiError {
+            error: "Failed to get user",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    let result = create_session(user, &client_ip, &conn)?;
+    if session_cookie.unwrap_or(false) {
+        set_session_cookie(&mut cookies, result.user_token);
+    }
+
+    Ok(Json(result))
+}
+
+/// Revokes the token used for this request and clears the session cookie.
+#[post("/Logout")]
+pub fn logout(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    mut cookies: Cookies,
+) -> Result<(), ApiError> {
+    remove_session_cookie(&mut cookies);
+
+    user_tokens::delete(user_token.user_token, &conn).map_err(|error| {
+        error!(
+            "Failed to delete token for user {}: {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to log out",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    audit_log::record(
+        InsertableAuditEvent {
+            actor_user_id: Some(user_token.user_id),
+            target_user_id: Some(user_token.user_id),
+            details: Some(json!({ "revoked_tokens": 1 })),
+            ..InsertableAuditEvent::new(AuditAction::UserTokenRevoked, &client_ip)
+        },
+        &conn,
+    );
+
+    Ok(())
+}
+
+/// Changes the password of the logged in user. Every other token for the user is revoked, so other sessions have to log in again.
+#[post("/ChangePassword", format = "json", data = "<password_change>")]
+pub fn change_password(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    password_change: Json<PasswordChange>,
+) -> Result<(), ApiError> {
+    let user = get(user_token.user_id, &conn).map_err(|error| {
+        error!(
+            "Failed to get user {} while changing password: {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to get user",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    if !is_login_valid(
+        user.username.clone(),
+        password_change.current_password.clone(),
+        &conn,
+    ) {
+        return Err(ApiError {
+            error: "Current password is incorrect",
+            status: Status::Unauthorized,
+        });
+    }
+
+    validate_new_password(&password_change.new_password)?;
+
+    set_password(user, &password_change.new_password, &conn)?;
+
+    audit_log::record(
+        InsertableAuditEvent {
+            actor_user_id: Some(user_token.user_id),
+            target_user_id: Some(user_token.user_id),
+            ..InsertableAuditEvent::new(AuditAction::PasswordChanged, &client_ip)
+        },
+        &conn,
+    );
+
+    let revoked_tokens =
+        user_tokens::delete_all_for_user_except(user_token.user_id, user_token.user_token, &conn)
+            .map_err(|error| {
+            error!(
+                "Failed to revoke old tokens for user {}: {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Password changed, but failed to log out other sessions",
+                status: Status::InternalServerError,
+            }
+        })?;
+
+    audit_log::record(
+        InsertableAuditEvent {
+            actor_user_id: Some(user_token.user_id),
+            target_user_id: Some(user_token.user_id),
+            details: Some(json!({ "revoked_tokens": revoked_tokens })),
+            ..InsertableAuditEvent::new(AuditAction::UserTokenRevoked, &client_ip)
+        },
+        &conn,
+    );
+
+    Ok(())
+}
+
+/// Generates a password reset token for a user. Only usable with the server's admin key, who is expected to pass the token on to the user.
+#[post("/Users/<username>/PasswordResetToken")]
+pub fn create_password_reset_token(
+    conn: CameraServerDbConn,
+    _admin_key: AdminKey,
+    client_ip: ClientIp,
+    username: String,
+) -> Result<Json<PasswordResetToken>, ApiError> {
+    let user = get_by_username(username, &conn).map_err(|_| ApiError {
+        error: "User not found",
+        status: Status::NotFound,
+    })?;
+
+    password_reset_tokens::insert(
+        InsertablePasswordResetToken {
             user_id: user.user_id,
+            expires_at: Utc::now().naive_utc()
+                + Duration::minutes(PASSWORD_RESET_TOKEN_LIFETIME_MINUTES),
         },
         &conn,
     )
+    .map(|password_reset_token| {
+        // There's no user behind the admin key, so this event has no actor
+        audit_log::record(
+            InsertableAuditEvent {
+                target_user_id: Some(user.user_id),
+                details: Some(json!({ "admin_key": true })),
+                ..InsertableAuditEvent::new(AuditAction::PasswordResetTokenCreated, &client_ip)
+            },
+            &conn,
+        );
+        Json(password_reset_token)
+    })
     .map_err(|error| {
-        println!(
-            "Failed to create token for user {}. The error was {}",
-            user_login.username, error
+        error!(
+            "Failed to create password reset token for user {}: {}",
+            user.user_id, error
         );
         ApiError {
-            error: "Failed to create token",
+            error: "Failed to create password reset token",
+            status: Status::InternalServerError,
+        }
+    })
+}
+
+/// Sets a new password using a password reset token. Every existing token for the user is revoked.
+#[post("/ResetPassword", format = "json", data = "<password_reset>")]
+pub fn reset_password(
+    conn: CameraServerDbConn,
+    client_ip: ClientIp,
+    password_reset: Json<PasswordReset>,
+) -> Result<(), ApiError> {
+    let reset_token = password_reset_tokens::get(password_reset.reset_token, &conn)
+        .ok()
+        .filter(|reset_token| reset_token.expires_at > Utc::now().naive_utc())
+        .ok_or(ApiError {
+            error: "Invalid or expired password reset token",
+            status: Status::Unauthorized,
+        })?;
+
+    validate_new_password(&password_reset.new_password)?;
+    let hashed_password = hash_password(&password_reset.new_password)?;
+
+    // Redeeming the token and changing the password happen together, so a token can only be used once
+    // and is never left usable after the password it was for has been replaced
+    let revoked_tokens = conn
+        .transaction::<_, diesel::result::Error, _>(|| {
+            if !password_reset_tokens::redeem(reset_token.reset_token, &conn)? {
+                return Ok(None);
+            }
+            diesel::update(users::table.find(reset_token.user_id))
+                .set(users::password.eq(&hashed_password))
+                .execute(&*conn)?;
+            password_reset_tokens::delete_all_for_user(reset_token.user_id, &conn)?;
+            user_tokens::delete_all_for_user(reset_token.user_id, &conn).map(Some)
+        })
+        .map_err(|error| {
+            error!(
+                "Failed to reset password for user {}: {}",
+                reset_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to reset password",
+                status: Status::InternalServerError,
+            }
+        })?
+        .ok_or(ApiError {
+            error: "Invalid or expired password reset token",
+            status: Status::Unauthorized,
+        })?;
+
+    audit_log::record(
+        InsertableAuditEvent {
+            target_user_id: Some(reset_token.user_id),
+            ..InsertableAuditEvent::new(AuditAction::PasswordReset, &client_ip)
+        },
+        &conn,
+    );
+
+    audit_log::record(
+        InsertableAuditEvent {
+            target_user_id: Some(reset_token.user_id),
+            details: Some(json!({ "revoked_tokens": revoked_tokens })),
+            ..InsertableAuditEvent::new(AuditAction::UserTokenRevoked, &client_ip)
+        },
+        &conn,
+    );
+
+    Ok(())
+}
+
+/// Deletes the logged in user's account. Cameras that only this user has access to are deleted too, along with their images.
+/// Everything else tied to the user (tokens, camera pairings) is removed by the database's cascading deletes.
+#[post("/DeleteAccount", format = "json", data = "<account_deletion>")]
+pub fn delete_account(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client_ip: ClientIp,
+    account_deletion: Json<AccountDeletion>,
+) -> Result<(), ApiError> {
+    let user = get(user_token.user_id, &conn).map_err(|error| {
+        error!(
+            "Failed to get user {} while deleting account: {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to get user",
             status: Status::InternalServerError,
         }
     })?;
 
-    Ok(Json(AuthentiationResult {
-        user_info: UserInfo {
-            user_id: user.user_id,
-            username: user.username,
+    if !is_login_valid(
+        user.username.clone(),
+        account_deletion.password.clone(),
+        &conn,
+    ) {
+        return Err(ApiError {
+            error: "Password is incorrect",
+            status: Status::Unauthorized,
+        });
+    }
+
+    let solely_owned_camera_ids = conn
+        .transaction::<_, diesel::result::Error, _>(|| {
+            let solely_owned_camera_ids =
+                users_cameras::get_solely_owned_camera_ids(user.user_id, &conn)?;
+
+            for camera_id in &solely_owned_camera_ids {
+                camera::delete(*camera_id, &conn)?;
+            }
+
+            delete(user.user_id, &conn)?;
+
+            Ok(solely_owned_camera_ids)
+        })
+        .map_err(|error| {
+            error!(
+                "Failed to delete account for user {}: {}",
+                user.user_id, error
+            );
+            ApiError {
+                error: "Failed to delete account",
+                status: Status::InternalServerError,
+            }
+        })?;
+
+    audit_log::record(
+        InsertableAuditEvent {
+            actor_user_id: Some(user.user_id),
+            target_user_id: Some(user.user_id),
+            details: Some(json!({ "username": user.username })),
+            ..InsertableAuditEvent::new(AuditAction::UserDeleted, &client_ip)
         },
-        user_token: token.user_token,
-    }))
+        &conn,
+    );
+    for camera_id in &solely_owned_camera_ids {
+        audit_log::record(
+            InsertableAuditEvent {
+                actor_user_id: Some(user.user_id),
+                target_camera_id: Some(*camera_id),
+                ..InsertableAuditEvent::new(AuditAction::CameraDeleted, &client_ip)
+            },
+            &conn,
+        );
+    }
+
+    // The account is gone at this point, so failing to remove an image directory is only logged.
+    let images_directory_path = images_directory();
+    for camera_id in solely_owned_camera_ids {
+        let camera_id_string = camera_id.to_string();
+        for directory in &[
+            camera_directory(&images_directory_path, &camera_id_string),
+            trash_directory(&images_directory_path, &camera_id_string),
+            originals_directory(&images_directory_path, &camera_id_string),
+        ] {
+            match remove_dir_all(directory) {
+                Ok(_) => {}
+                Err(error) if error.kind() == ErrorKind::NotFound => {}
+                Err(error) => warn!("Failed to delete image directory {}: {}", directory, error),
+            }
+        }
+    }
+
+    Ok(())
 }
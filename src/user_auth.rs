@@ -0,0 +1,76 @@
+use crate::{
+    api_error::ApiError,
+    api_keys::{ApiKey, ApiKeyScope},
+    auth_token::bearer_token,
+    enums::token_error::TokenError,
+    user_tokens::UserToken,
+};
+
+use rocket::{
+    http::Status,
+    request::{self, FromRequest},
+    Outcome, Request,
+};
+
+/// Request guard for routes that integrations are allowed to use. Accepts either a user token or a scoped API key.
+/// An "Authorization: Bearer <token>" header takes precedence and can hold either kind of token (user tokens are checked first).
+/// Without one, an api_key header is used if sent, otherwise the user_token header or session cookie is required.
+pub enum UserAuth {
+    UserToken(UserToken),
+    ApiKey(ApiKey),
+}
+
+impl UserAuth {
+    pub fn user_id(&self) -> uuid::Uuid {
+        match self {
+            UserAuth::UserToken(user_token) => user_token.user_id,
+            UserAuth::ApiKey(api_key) => api_key.user_id,
+        }
+    }
+
+    /// Returns the ID of the API key used, or None for user tokens.
+    pub fn api_key_id(&self) -> Option<uuid::Uuid> {
+        match self {
+            UserAuth::ApiKey(api_key) => Some(api_key.api_key_id),
+            UserAuth::UserToken(_) => None,
+        }
+    }
+
+    /// Returns an ApiError if this is an API key without the given scope. User tokens can do anything.
+    pub fn require_scope(&self, scope: ApiKeyScope) -> Result<(), ApiError> {
+        match self {
+            UserAuth::ApiKey(api_key) if !api_key.has_scope(scope) => Err(ApiError {
+                error: "API key does not have the required scope",
+                status: Status::Forbidden,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns false if this is an API key that's restricted to other cameras.
+    pub fn allows_camera(&self, camera_id: &uuid::Uuid) -> bool {
+        match self {
+            UserAuth::ApiKey(api_key) => api_key.allows_camera(camera_id),
+            UserAuth::UserToken(_) => true,
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for UserAuth {
+    type Error = TokenError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        if bearer_token(request).is_some() {
+            match UserToken::from_request(request) {
+                Outcome::Failure((_, TokenError::NotFound)) => {
+                    ApiKey::from_request(request).map(UserAuth::ApiKey)
+                }
+                outcome => outcome.map(UserAuth::UserToken),
+            }
+        } else if request.headers().contains("api_key") {
+            ApiKey::from_request(request).map(UserAuth::ApiKey)
+        } else {
+            UserToken::from_request(request).map(UserAuth::UserToken)
+        }
+    }
+}
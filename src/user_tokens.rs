@@ -1,4 +1,8 @@
-use crate::{enums::token_error::TokenError, CameraServerDbConn};
+use crate::{
+    auth_token::{fail, find_token},
+    enums::token_error::TokenError,
+    logging, CameraServerDbConn,
+};
 
 use super::schema::user_tokens;
 
@@ -23,27 +27,26 @@ impl<'a, 'r> FromRequest<'a, 'r> for UserToken {
     type Error = TokenError;
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-        let token = request.headers().get_one("user_token");
+        let token = find_token(request, "user_token", true);
         match token {
             Some(token) => {
-                let parsed_token = match uuid::Uuid::parse_str(token) {
+                let parsed_token = match uuid::Uuid::parse_str(&token) {
                     Ok(parsed_token_ok) => parsed_token_ok,
                     // Token cannot be parsed into a UUID
-                    Err(_) => {
-                        return Outcome::Failure((Status::BadRequest, TokenError::ParseError))
-                    }
+                    Err(_) => return fail(request, Status::BadRequest, TokenError::ParseError),
                 };
                 let connection = CameraServerDbConn::from_request(&request).unwrap();
                 match get(parsed_token, &connection) {
-                    Ok(user_token) => return Outcome::Success(user_token),
-
-                    Err(_) => {
-                        return Outcome::Failure((Status::Unauthorized, TokenError::NotFound))
+                    Ok(user_token) => {
+                        logging::set_user_id(user_token.user_id);
+                        return Outcome::Success(user_token);
                     }
+
+                    Err(_) => return fail(request, Status::Unauthorized, TokenError::NotFound),
                 }
             }
             // Token does not exist
-            None => Outcome::Failure((Status::Unauthorized, TokenError::NoTokenProvided)),
+            None => fail(request, Status::Unauthorized, TokenError::NoTokenProvided),
         }
     }
 }
@@ -94,3 +97,22 @@ pub fn update(
 pub fn delete(user_token: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
     diesel::delete(user_tokens::table.find(user_token)).execute(connection)
 }
+
+/// Deletes every token belonging to a user, logging them out everywhere.
+pub fn delete_all_for_user(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(user_tokens::table.filter(user_tokens::user_id.eq(user_id))).execute(connection)
+}
+
+/// Deletes every token belonging to a user except for kept_token, logging them out everywhere else.
+pub fn delete_all_for_user_except(
+    user_id: uuid::Uuid,
+    kept_token: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::delete(
+        user_tokens::table
+            .filter(user_tokens::user_id.eq(user_id))
+            .filter(user_tokens::user_token.ne(kept_token)),
+    )
+    .execute(connection)
+}
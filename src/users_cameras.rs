@@ -1,12 +1,53 @@
 use super::schema::{cameras, users_cameras};
 use super::CameraServerDbConn;
-use crate::{api_error::ApiError, camera::Camera, user_tokens};
+use crate::{
+    api_error::ApiError,
+    api_keys::ApiKeyScope,
+    audit_log::{self, AuditAction, ClientIp, InsertableAuditEvent},
+    camera::{parse_camera_id, Camera},
+    user,
+    user_auth::UserAuth,
+};
 use diesel::prelude::*;
+use diesel::result::Error::NotFound;
 use diesel::{self};
-use rocket::get;
 use rocket::http::Status;
+use rocket::{get, put};
 use rocket_contrib::json::Json;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// What a user is allowed to do with a camera. Stored in the role column as the string from as_str().
+/// Each role can do everything the ones before it can.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CameraRole {
+    /// Can see the camera's images and config
+    Viewer,
+    /// Can also delete and restore images, and change the config
+    Editor,
+    /// Can also change privacy masks, watermarks and who has access
+    Owner,
+}
+
+impl CameraRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CameraRole::Viewer => "viewer",
+            CameraRole::Editor => "editor",
+            CameraRole::Owner => "owner",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<CameraRole> {
+        match role {
+            "viewer" => Some(CameraRole::Viewer),
+            "editor" => Some(CameraRole::Editor),
+            "owner" => Some(CameraRole::Owner),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Queryable, AsChangeset, Deserialize, Serialize)]
 #[table_name = "users_cameras"]
@@ -14,6 +55,7 @@ pub struct UsersCamera {
     pub users_cameras_id: i32,
     pub camera_id: uuid::Uuid,
     pub user_id: uuid::Uuid,
+    pub role: String,
 }
 
 #[derive(Insertable, Deserialize, Serialize)]
@@ -21,6 +63,21 @@ pub struct UsersCamera {
 pub struct InsertableUsersCamera {
     pub camera_id: uuid::Uuid,
     pub user_id: uuid::Uuid,
+    pub role: String,
+}
+
+#[derive(Deserialize)]
+pub struct CameraRoleUpdate {
+    pub role: CameraRole,
+}
+
+/// A user's access to a camera.
+#[derive(Serialize)]
+pub struct CameraAccess {
+    pub camera_id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub username: String,
+    pub role: CameraRole,
 }
 
 pub fn all(connection: &PgConnection) -> QueryResult<Vec<UsersCamera>> {
@@ -67,28 +124,110 @@ pub fn get_users_cameras(
         .load(connection)
 }
 
-/// Checks if the user in user_token has access to the camera with an ID of camera_id_string.
+/// Returns the pairing between the user and the camera, or None if they aren't paired.
+pub fn get_users_camera(
+    user_id: uuid::Uuid,
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Option<UsersCamera>> {
+    users_cameras::table
+        .filter(users_cameras::user_id.eq(user_id))
+        .filter(users_cameras::camera_id.eq(camera_id))
+        .first::<UsersCamera>(connection)
+        .optional()
+}
+
+/// Returns the user's role on the camera, or None if they aren't paired with it.
+pub fn get_users_camera_role(
+    user_id: uuid::Uuid,
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Option<String>> {
+    users_cameras::table
+        .filter(users_cameras::user_id.eq(user_id))
+        .filter(users_cameras::camera_id.eq(camera_id))
+        .select(users_cameras::role)
+        .first::<String>(connection)
+        .optional()
+}
+
+/// Returns the IDs of the cameras the user has the owner role on.
+pub fn get_users_owned_camera_ids(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<uuid::Uuid>> {
+    users_cameras::table
+        .filter(users_cameras::user_id.eq(user_id))
+        .filter(users_cameras::role.eq(CameraRole::Owner.as_str()))
+        .select(users_cameras::camera_id)
+        .load(connection)
+}
+
+/// Returns the IDs of the users with the owner role on the camera.
+pub fn get_camera_owner_ids(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<uuid::Uuid>> {
+    users_cameras::table
+        .filter(users_cameras::camera_id.eq(camera_id))
+        .filter(users_cameras::role.eq(CameraRole::Owner.as_str()))
+        .select(users_cameras::user_id)
+        .load(connection)
+}
+
+/// Returns the IDs of cameras that the given user is the only user paired with.
+/// Used when deleting an account, since cameras with no users left would be unreachable.
+pub fn get_solely_owned_camera_ids(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<uuid::Uuid>> {
+    let users_camera_ids = users_cameras::table
+        .filter(users_cameras::user_id.eq(user_id))
+        .select(users_cameras::camera_id)
+        .load::<uuid::Uuid>(connection)?;
+
+    let all_pairs = users_cameras::table
+        .filter(users_cameras::camera_id.eq_any(&users_camera_ids))
+        .select(users_cameras::camera_id)
+        .load::<uuid::Uuid>(connection)?;
+
+    Ok(users_camera_ids
+        .into_iter()
+        .filter(|camera_id| all_pairs.iter().filter(|x| *x == camera_id).count() == 1)
+        .collect())
+}
+
+/// Checks if the user in user_auth has access to the camera with an ID of camera_id_string.
+/// API keys restricted to certain cameras are also checked here, scopes are checked with UserAuth::require_scope().
 /// Returns an empty Ok() if access is allowed, returns ApiError if the user isn't allowed or if something else goes wrong.
 pub fn check_if_user_has_access_to_camera(
     conn: &CameraServerDbConn,
-    user_token: &user_tokens::UserToken,
+    user_auth: &UserAuth,
     camera_id_string: &String,
 ) -> Result<(), ApiError> {
-    let camera_id = uuid::Uuid::parse_str(camera_id_string).map_err(|error| {
-        println!(
-            "Failed to parse camera id into UUID: Input was {}, error was {}",
-            camera_id_string, error
-        );
-        ApiError {
-            error: "Failed to parse camera ID string",
-            status: Status::UnprocessableEntity,
-        }
-    })?;
+    check_if_user_has_camera_role(conn, user_auth, camera_id_string, CameraRole::Viewer)
+}
 
-    let users_cameras_list = get_users_cameras(user_token.user_id, conn).map_err(|error| {
-        println!(
-            "Failed to get list of user's cameras! The error was {}",
-            error
+/// Like check_if_user_has_access_to_camera(), but the user also needs at least the given role on the camera.
+pub fn check_if_user_has_camera_role(
+    conn: &CameraServerDbConn,
+    user_auth: &UserAuth,
+    camera_id_string: &String,
+    minimum_role: CameraRole,
+) -> Result<(), ApiError> {
+    let camera_id = parse_camera_id(camera_id_string)?;
+
+    if !user_auth.allows_camera(&camera_id) {
+        return Err(ApiError {
+            error: "API key does not have access to camera",
+            status: Status::Forbidden,
+        });
+    }
+
+    let role = get_users_camera_role(user_auth.user_id(), camera_id, conn).map_err(|error| {
+        error!(
+            "Failed to get user's role on camera {}: {}",
+            camera_id, error
         );
         ApiError {
             error: "Failed to get list of owned cameras",
@@ -96,30 +235,143 @@ pub fn check_if_user_has_access_to_camera(
         }
     })?;
 
-    // If the user doesn't have access to the camera (camera id is not returned by users_cameras), return an error
-    if !users_cameras_list
-        .iter()
-        .any(|users_camera| users_camera.camera_id == camera_id)
-    {
+    // If the user doesn't have access to the camera (there's no users_cameras row for it), return an error
+    let role = match role {
+        Some(role) => role,
+        None => {
+            return Err(ApiError {
+                error: "User does not have access to camera",
+                status: Status::Unauthorized,
+            })
+        }
+    };
+
+    // Unknown roles get the least access
+    if CameraRole::parse(&role).unwrap_or(CameraRole::Viewer) < minimum_role {
         return Err(ApiError {
-            error: "User does not have access to camera",
-            status: Status::Unauthorized,
+            error: "User's role on the camera doesn't allow this",
+            status: Status::Forbidden,
         });
     }
 
     Ok(())
 }
 
+/// Gives a user a role on the camera, pairing them with it if they aren't already. Only owners can change who has access,
+/// and a camera's last owner can't be given a lesser role.
+#[put(
+    "/cameras/<camera_id_string>/users/<username>",
+    format = "json",
+    data = "<role_update>"
+)]
+pub fn set_camera_role(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    client_ip: ClientIp,
+    camera_id_string: String,
+    username: String,
+    role_update: Json<CameraRoleUpdate>,
+) -> Result<Json<CameraAccess>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ConfigWrite)?;
+    check_if_user_has_camera_role(&conn, &user_auth, &camera_id_string, CameraRole::Owner)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+    let role = role_update.into_inner().role;
+
+    let role_error = |error| {
+        error!(
+            "Failed to set the role of {} on camera {}: {}",
+            username, camera_id, error
+        );
+        ApiError {
+            error: "Failed to set camera role",
+            status: Status::InternalServerError,
+        }
+    };
+
+    let target_user = match user::get_by_username(username.clone(), &conn) {
+        Ok(target_user) => target_user,
+        Err(NotFound) => {
+            return Err(ApiError {
+                error: "User not found",
+                status: Status::NotFound,
+            })
+        }
+        Err(error) => return Err(role_error(error)),
+    };
+
+    let existing = get_users_camera(target_user.user_id, camera_id, &conn).map_err(role_error)?;
+    let old_role = existing
+        .as_ref()
+        .map(|existing| CameraRole::parse(&existing.role).unwrap_or(CameraRole::Viewer));
+
+    if old_role == Some(CameraRole::Owner) && role != CameraRole::Owner {
+        let owner_ids = get_camera_owner_ids(camera_id, &conn).map_err(role_error)?;
+        if owner_ids.len() <= 1 {
+            return Err(ApiError {
+                error: "A camera needs at least one owner",
+                status: Status::Conflict,
+            });
+        }
+    }
+
+    match existing {
+        Some(existing) => update(
+            existing.users_cameras_id,
+            UsersCamera {
+                role: role.as_str().to_string(),
+                ..existing
+            },
+            &conn,
+        ),
+        None => insert(
+            InsertableUsersCamera {
+                camera_id,
+                user_id: target_user.user_id,
+                role: role.as_str().to_string(),
+            },
+            &conn,
+        ),
+    }
+    .map_err(role_error)?;
+
+    audit_log::record(
+        InsertableAuditEvent {
+            target_user_id: Some(target_user.user_id),
+            target_camera_id: Some(camera_id),
+            details: Some(json!({
+                "old_role": old_role.map(|old_role| old_role.as_str()),
+                "role": role.as_str(),
+            })),
+            ..InsertableAuditEvent::by_user_auth(
+                AuditAction::CameraAccessGranted,
+                &user_auth,
+                &client_ip,
+            )
+        },
+        &conn,
+    );
+
+    Ok(Json(CameraAccess {
+        camera_id,
+        user_id: target_user.user_id,
+        username: target_user.username,
+        role,
+    }))
+}
+
 /// Returns a list of camera IDs for a user's cameras
 #[get("/ListCameras")]
 pub fn list_cameras(
     conn: CameraServerDbConn,
-    user_token: user_tokens::UserToken,
+    user_auth: UserAuth,
 ) -> Result<Json<Vec<Camera>>, ApiError> {
-    let camera_list = get_users_cameras(user_token.user_id, &conn).map_err(|error| {
-        println!(
-            "Failed to get user's cameras for user ID {}. The error was {}",
-            user_token.user_id, error
+    user_auth.require_scope(ApiKeyScope::CamerasRead)?;
+
+    let camera_list = get_users_cameras(user_auth.user_id(), &conn).map_err(|error| {
+        error!(
+            "Failed to get cameras for user {}: {}",
+            user_auth.user_id(),
+            error
         );
         ApiError {
             error: "Database failed to get list of cameras",
@@ -127,5 +379,11 @@ pub fn list_cameras(
         }
     })?;
 
-    Ok(Json(camera_list))
+    // API keys restricted to certain cameras only get to see those cameras
+    Ok(Json(
+        camera_list
+            .into_iter()
+            .filter(|camera| user_auth.allows_camera(&camera.camera_id))
+            .collect(),
+    ))
 }
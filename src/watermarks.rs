@@ -0,0 +1,680 @@
+use crate::{
+    api_error::ApiError,
+    api_keys::ApiKeyScope,
+    audit_log::{self, AuditAction, ClientIp, InsertableAuditEvent},
+    camera::{self, parse_camera_id},
+    image_metadata,
+    image_store::image_timestamp_ms,
+    user_auth::UserAuth,
+    users_cameras::{
+        check_if_user_has_access_to_camera, check_if_user_has_camera_role, CameraRole,
+    },
+    CameraServerDbConn,
+};
+
+use super::schema::watermarks;
+use chrono::{
+    format::{Item, StrftimeItems},
+    NaiveDateTime, TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use diesel::prelude::*;
+use diesel::{self};
+use image::{DynamicImage, Rgba, RgbaImage};
+use rocket::{delete, get, http::Status, put};
+use rocket_contrib::json::Json;
+use rusttype::{point, Font, Point, Scale};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// DejaVu Sans Mono Bold. See static/fonts/LICENSE-DejaVu.txt.
+const FONT: &[u8] = include_bytes!("../static/fonts/DejaVuSansMono-Bold.ttf");
+const MAX_TIMESTAMP_FORMAT_LENGTH: usize = 64;
+/// Text is this fraction of the image's height tall, but never less than MIN_TEXT_HEIGHT pixels.
+const TEXT_HEIGHT_RATIO: f32 = 0.03;
+const MIN_TEXT_HEIGHT: f32 = 12.0;
+/// The box behind the text is this much as opaque as the text, so that the image still shows through it a little.
+const BACKGROUND_OPACITY: f64 = 0.6;
+
+/// When a camera's watermark is added. Stored in the mode column as the string from as_str().
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatermarkMode {
+    /// Added to images as they're uploaded. The image is stored with the watermark, and a copy without it is kept for
+    /// owners in image_store::ORIGINALS_DIRECTORY.
+    BurnIn,
+    /// Added each time an image is sent. The image is stored as the camera sent it.
+    OnTheFly,
+}
+
+impl WatermarkMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WatermarkMode::BurnIn => "burn_in",
+            WatermarkMode::OnTheFly => "on_the_fly",
+        }
+    }
+
+    pub fn parse(mode: &str) -> Option<WatermarkMode> {
+        match mode {
+            "burn_in" => Some(WatermarkMode::BurnIn),
+            "on_the_fly" => Some(WatermarkMode::OnTheFly),
+            _ => None,
+        }
+    }
+}
+
+/// Which corner of the image the watermark goes in. Stored in the position column as the string from as_str().
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Default for WatermarkPosition {
+    fn default() -> WatermarkPosition {
+        WatermarkPosition::BottomRight
+    }
+}
+
+impl WatermarkPosition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WatermarkPosition::TopLeft => "top_left",
+            WatermarkPosition::TopRight => "top_right",
+            WatermarkPosition::BottomLeft => "bottom_left",
+            WatermarkPosition::BottomRight => "bottom_right",
+        }
+    }
+
+    pub fn parse(position: &str) -> Option<WatermarkPosition> {
+        match position {
+            "top_left" => Some(WatermarkPosition::TopLeft),
+            "top_right" => Some(WatermarkPosition::TopRight),
+            "bottom_left" => Some(WatermarkPosition::BottomLeft),
+            "bottom_right" => Some(WatermarkPosition::BottomRight),
+            _ => None,
+        }
+    }
+
+    fn is_left(self) -> bool {
+        self == WatermarkPosition::TopLeft || self == WatermarkPosition::BottomLeft
+    }
+
+    fn is_top(self) -> bool {
+        self == WatermarkPosition::TopLeft || self == WatermarkPosition::TopRight
+    }
+}
+
+#[derive(Queryable, Insertable, AsChangeset, Serialize, Clone)]
+#[table_name = "watermarks"]
+pub struct Watermark {
+    pub camera_id: uuid::Uuid,
+    /// "burn_in" or "on_the_fly"
+    pub mode: String,
+    /// An IANA time zone, like "Europe/London"
+    pub timezone: String,
+    /// A strftime format string for the capture time. Empty leaves the time out.
+    pub timestamp_format: String,
+    pub show_camera_name: bool,
+    /// "top_left", "top_right", "bottom_left" or "bottom_right"
+    pub position: String,
+    /// 0 is invisible and 1 is solid
+    pub opacity: f64,
+    pub updated_at: NaiveDateTime,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<Watermark>> {
+    watermarks::table.load::<Watermark>(&*connection)
+}
+
+pub fn get(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<Watermark> {
+    watermarks::table
+        .find(camera_id)
+        .get_result::<Watermark>(connection)
+}
+
+pub fn insert(watermark: Watermark, connection: &PgConnection) -> QueryResult<Watermark> {
+    diesel::insert_into(watermarks::table)
+        .values(watermark)
+        .get_result(connection)
+}
+
+pub fn update(
+    camera_id: uuid::Uuid,
+    watermark: Watermark,
+    connection: &PgConnection,
+) -> QueryResult<Watermark> {
+    diesel::update(watermarks::table.find(camera_id))
+        .set(&watermark)
+        .get_result(connection)
+}
+
+pub fn delete(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(watermarks::table.find(camera_id)).execute(connection)
+}
+
+/// Sets the camera's watermark, replacing any it already has.
+pub fn upsert(watermark: Watermark, connection: &PgConnection) -> QueryResult<Watermark> {
+    diesel::insert_into(watermarks::table)
+        .values(&watermark)
+        .on_conflict(watermarks::camera_id)
+        .do_update()
+        .set(&watermark)
+        .get_result(connection)
+}
+
+/// A camera's watermark, ready to be drawn on its images.
+pub struct ActiveWatermark {
+    pub mode: WatermarkMode,
+    position: WatermarkPosition,
+    opacity: f64,
+    timezone: Tz,
+    timestamp_format: String,
+    /// None if the name isn't shown
+    camera_name: Option<String>,
+}
+
+impl ActiveWatermark {
+    /// Returns what to draw on an image taken at the given time. The time is left out if it isn't known.
+    pub fn text(&self, captured_at_ms: Option<u64>) -> WatermarkText {
+        let mut lines = Vec::new();
+        if let Some(camera_name) = &self.camera_name {
+            lines.push(camera_name.clone());
+        }
+        if let (Some(captured_at_ms), false) = (captured_at_ms, self.timestamp_format.is_empty()) {
+            let captured_at = self.timezone.timestamp_millis(captured_at_ms as i64);
+            let mut timestamp = String::new();
+            // Formats are checked when they're set, but one that's still invalid shouldn't stop the image being sent
+            if write!(timestamp, "{}", captured_at.format(&self.timestamp_format)).is_ok() {
+                lines.push(timestamp);
+            } else {
+                warn!(
+                    "Invalid watermark timestamp format {}",
+                    self.timestamp_format
+                );
+            }
+        }
+
+        WatermarkText {
+            lines,
+            position: self.position,
+            opacity: self.opacity,
+        }
+    }
+}
+
+/// Returns the camera's watermark, or None if it doesn't have one.
+pub fn get_active_watermark(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Option<ActiveWatermark>> {
+    let watermark = match get(camera_id, connection).optional()? {
+        Some(watermark) => watermark,
+        None => return Ok(None),
+    };
+    let camera_name = if watermark.show_camera_name {
+        Some(camera::get(camera_id, connection)?.name)
+    } else {
+        None
+    };
+
+    // Anything unrecognised in the database gets the setting that doesn't change the stored image
+    Ok(Some(ActiveWatermark {
+        mode: WatermarkMode::parse(&watermark.mode).unwrap_or(WatermarkMode::OnTheFly),
+        position: WatermarkPosition::parse(&watermark.position).unwrap_or_default(),
+        opacity: watermark.opacity.max(0.0).min(1.0),
+        timezone: watermark.timezone.parse().unwrap_or(Tz::UTC),
+        timestamp_format: watermark.timestamp_format,
+        camera_name,
+    }))
+}
+
+/// Returns the watermark to burn into the camera's uploads, or None if it doesn't have one or it's added on the fly.
+/// Errors are only logged, since the upload shouldn't be lost because it couldn't be watermarked.
+pub fn get_burn_in_watermark(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> Option<ActiveWatermark> {
+    match get_active_watermark(camera_id, connection) {
+        Ok(watermark) => watermark.filter(|watermark| watermark.mode == WatermarkMode::BurnIn),
+        Err(error) => {
+            error!(
+                "Failed to get watermark for camera {}, storing upload without it: {}",
+                camera_id, error
+            );
+            None
+        }
+    }
+}
+
+/// Returns the watermark to add to the camera's images as they're sent, or None if they shouldn't have one. That's
+/// when the camera doesn't have a watermark or burns it in, or when an owner asks for the originals. Only owners can.
+pub fn get_on_the_fly_watermark(
+    conn: &CameraServerDbConn,
+    user_auth: &UserAuth,
+    camera_id_string: &str,
+    original: bool,
+) -> Result<Option<ActiveWatermark>, ApiError> {
+    if original {
+        check_if_user_has_camera_role(
+            conn,
+            user_auth,
+            &camera_id_string.to_string(),
+            CameraRole::Owner,
+        )?;
+        return Ok(None);
+    }
+
+    let camera_id = parse_camera_id(camera_id_string)?;
+    Ok(get_active_watermark(camera_id, conn)
+        .map_err(|error| read_error(camera_id, error))?
+        .filter(|watermark| watermark.mode == WatermarkMode::OnTheFly))
+}
+
+/// Returns the watermark to add to an image as it's sent, or None if it shouldn't have one. As well as when
+/// get_on_the_fly_watermark() returns None, that's when the image already had it burned in.
+pub fn get_on_the_fly_text(
+    conn: &CameraServerDbConn,
+    user_auth: &UserAuth,
+    camera_id_string: &str,
+    image_id: &str,
+    original: bool,
+) -> Result<Option<WatermarkText>, ApiError> {
+    let watermark = match get_on_the_fly_watermark(conn, user_auth, camera_id_string, original)? {
+        Some(watermark) => watermark,
+        None => return Ok(None),
+    };
+    let camera_id = parse_camera_id(camera_id_string)?;
+
+    let burned_in = match image_metadata::get(camera_id, image_id, conn) {
+        Ok(metadata) => metadata.watermarked,
+        // Images from before metadata was stored were never watermarked when uploaded
+        Err(diesel::NotFound) => false,
+        Err(error) => {
+            error!("Failed to get metadata for image {}: {}", image_id, error);
+            return Err(ApiError {
+                error: "Failed to get image metadata",
+                status: Status::InternalServerError,
+            });
+        }
+    };
+    if burned_in {
+        return Ok(None);
+    }
+
+    Ok(Some(watermark.text(image_timestamp_ms(image_id))))
+}
+
+/// The text of a watermark for one image, and where and how to draw it.
+pub struct WatermarkText {
+    lines: Vec<String>,
+    position: WatermarkPosition,
+    opacity: f64,
+}
+
+impl WatermarkText {
+    /// Changes whenever anything about the drawn watermark does, so that it can go in an ETag.
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        for line in &self.lines {
+            hasher.update(line.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.update(self.position.as_str().as_bytes());
+        hasher.update(self.opacity.to_be_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .take(8)
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Draws the text in a box in the watermark's corner, scaled to the image's height.
+    pub fn draw(&self, image: &DynamicImage) -> DynamicImage {
+        let mut image = image.to_rgba8();
+        if self.lines.is_empty() {
+            return DynamicImage::ImageRgba8(image);
+        }
+
+        let font = load_font();
+        let (width, height) = image.dimensions();
+        let text_height = (height as f32 * TEXT_HEIGHT_RATIO).max(MIN_TEXT_HEIGHT);
+        let scale = Scale::uniform(text_height);
+        let v_metrics = font.v_metrics(scale);
+        let line_height = (v_metrics.ascent - v_metrics.descent + v_metrics.line_gap).ceil();
+        let padding = (text_height / 4.0).ceil();
+        let margin = (text_height / 2.0).ceil();
+
+        let line_widths: Vec<f32> = self
+            .lines
+            .iter()
+            .map(|line| text_width(&font, scale, line))
+            .collect();
+        let box_width = line_widths.iter().cloned().fold(0.0, f32::max).ceil() + 2.0 * padding;
+        let box_height = line_height * self.lines.len() as f32 + 2.0 * padding;
+        let left = if self.position.is_left() {
+            margin
+        } else {
+            width as f32 - margin - box_width
+        }
+        .max(0.0);
+        let top = if self.position.is_top() {
+            margin
+        } else {
+            height as f32 - margin - box_height
+        }
+        .max(0.0);
+
+        fill_rect(
+            &mut image,
+            (left as u32, top as u32),
+            (box_width as u32, box_height as u32),
+            [0, 0, 0],
+            self.opacity * BACKGROUND_OPACITY,
+        );
+
+        for (index, (line, line_width)) in self.lines.iter().zip(&line_widths).enumerate() {
+            // Lines line up with the edge of the image they're nearest
+            let x = if self.position.is_left() {
+                left + padding
+            } else {
+                left + box_width - padding - line_width
+            };
+            let baseline = top + padding + line_height * index as f32 + v_metrics.ascent;
+            draw_text(
+                &mut image,
+                &font,
+                scale,
+                point(x, baseline),
+                line,
+                [255, 255, 255],
+                self.opacity,
+            );
+        }
+
+        DynamicImage::ImageRgba8(image)
+    }
+}
+
+/// The font watermarks and other labels are drawn in.
+pub fn load_font() -> Font<'static> {
+    Font::try_from_bytes(FONT).expect("Failed to load the watermark font somehow?")
+}
+
+/// How far the pen moves to write the text, in pixels.
+pub fn text_width(font: &Font, scale: Scale, text: &str) -> f32 {
+    font.layout(text, scale, point(0.0, 0.0))
+        .last()
+        .map(|glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.0)
+}
+
+/// Draws a line of text with its baseline starting at start. Anything outside the image is cut off.
+pub fn draw_text(
+    image: &mut RgbaImage,
+    font: &Font,
+    scale: Scale,
+    start: Point<f32>,
+    text: &str,
+    colour: [u8; 3],
+    opacity: f64,
+) {
+    let (width, height) = image.dimensions();
+    for glyph in font.layout(text, scale, start) {
+        let bounds = match glyph.pixel_bounding_box() {
+            Some(bounds) => bounds,
+            // Spaces
+            None => continue,
+        };
+        glyph.draw(|glyph_x, glyph_y, coverage| {
+            let x = bounds.min.x + glyph_x as i32;
+            let y = bounds.min.y + glyph_y as i32;
+            if x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height {
+                blend(
+                    image.get_pixel_mut(x as u32, y as u32),
+                    colour,
+                    opacity * coverage as f64,
+                );
+            }
+        });
+    }
+}
+
+/// Mixes the colour into a rectangle of the image, given by its top left corner and size. Anything outside the image
+/// is cut off.
+pub fn fill_rect(
+    image: &mut RgbaImage,
+    (left, top): (u32, u32),
+    (width, height): (u32, u32),
+    colour: [u8; 3],
+    opacity: f64,
+) {
+    let right = left.saturating_add(width).min(image.width());
+    let bottom = top.saturating_add(height).min(image.height());
+    for y in top..bottom {
+        for x in left..right {
+            blend(image.get_pixel_mut(x, y), colour, opacity);
+        }
+    }
+}
+
+/// Mixes the colour into the pixel. The pixel's own alpha is left alone.
+fn blend(pixel: &mut Rgba<u8>, colour: [u8; 3], opacity: f64) {
+    for channel in 0..3 {
+        pixel[channel] = (pixel[channel] as f64 * (1.0 - opacity)
+            + colour[channel] as f64 * opacity)
+            .round() as u8;
+    }
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+fn default_timestamp_format() -> String {
+    "%Y-%m-%d %H:%M:%S".to_string()
+}
+
+fn default_show_camera_name() -> bool {
+    true
+}
+
+fn default_opacity() -> f64 {
+    0.8
+}
+
+/// Sent by an owner to set a camera's watermark. Everything but the mode can be left out to get the defaults.
+#[derive(Deserialize)]
+pub struct WatermarkUpdate {
+    pub mode: WatermarkMode,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    #[serde(default = "default_timestamp_format")]
+    pub timestamp_format: String,
+    #[serde(default = "default_show_camera_name")]
+    pub show_camera_name: bool,
+    #[serde(default)]
+    pub position: WatermarkPosition,
+    #[serde(default = "default_opacity")]
+    pub opacity: f64,
+}
+
+fn validate_update(update: &WatermarkUpdate) -> Result<(), ApiError> {
+    let invalid = |error: &'static str| {
+        Err(ApiError {
+            error,
+            status: Status::UnprocessableEntity,
+        })
+    };
+
+    if update.timezone.parse::<Tz>().is_err() {
+        return invalid("Unknown time zone");
+    }
+    let format_is_valid = update.timestamp_format.len() <= MAX_TIMESTAMP_FORMAT_LENGTH
+        && !StrftimeItems::new(&update.timestamp_format).any(|item| item == Item::Error);
+    if !format_is_valid {
+        return invalid("Invalid timestamp format");
+    }
+    // Also rules out NaN
+    if !(0.0..=1.0).contains(&update.opacity) {
+        return invalid("Watermark opacity has to be between 0 and 1");
+    }
+    if update.timestamp_format.is_empty() && !update.show_camera_name {
+        return invalid("Watermark has to show the timestamp or the camera name");
+    }
+    Ok(())
+}
+
+fn read_error(camera_id: uuid::Uuid, error: diesel::result::Error) -> ApiError {
+    error!(
+        "Failed to get watermark for camera {}: {}",
+        camera_id, error
+    );
+    ApiError {
+        error: "Failed to get watermark",
+        status: Status::InternalServerError,
+    }
+}
+
+/// Returns the camera's watermark settings.
+#[get("/cameras/<camera_id_string>/watermark")]
+pub fn get_watermark(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    camera_id_string: String,
+) -> Result<Json<Watermark>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ConfigRead)?;
+    check_if_user_has_access_to_camera(&conn, &user_auth, &camera_id_string)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    match get(camera_id, &conn) {
+        Ok(watermark) => Ok(Json(watermark)),
+        Err(diesel::NotFound) => Err(ApiError {
+            error: "Camera has no watermark",
+            status: Status::NotFound,
+        }),
+        Err(error) => Err(read_error(camera_id, error)),
+    }
+}
+
+/// Sets the camera's watermark. Burned in watermarks only go on images uploaded after the change, while ones added
+/// on the fly go on every image that doesn't already have one burned in. Only owners can change it.
+#[put(
+    "/cameras/<camera_id_string>/watermark",
+    format = "json",
+    data = "<update>"
+)]
+pub fn update_watermark(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    client_ip: ClientIp,
+    camera_id_string: String,
+    update: Json<WatermarkUpdate>,
+) -> Result<Json<Watermark>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ConfigWrite)?;
+    check_if_user_has_camera_role(&conn, &user_auth, &camera_id_string, CameraRole::Owner)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+    let update = update.into_inner();
+    validate_update(&update)?;
+
+    // Kept so that the audit log can show what changed
+    let old_watermark = get(camera_id, &conn).ok();
+
+    let new_watermark = upsert(
+        Watermark {
+            camera_id,
+            mode: update.mode.as_str().to_string(),
+            timezone: update.timezone,
+            timestamp_format: update.timestamp_format,
+            show_camera_name: update.show_camera_name,
+            position: update.position.as_str().to_string(),
+            opacity: update.opacity,
+            updated_at: Utc::now().naive_utc(),
+        },
+        &conn,
+    )
+    .map_err(|error| {
+        error!(
+            "Failed to update watermark for camera {}: {}",
+            camera_id, error
+        );
+        ApiError {
+            error: "Failed to update watermark",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    audit_log::record(
+        InsertableAuditEvent {
+            target_camera_id: Some(camera_id),
+            details: Some(json!({
+                "old": old_watermark,
+                "new": new_watermark,
+            })),
+            ..InsertableAuditEvent::by_user_auth(
+                AuditAction::WatermarkUpdated,
+                &user_auth,
+                &client_ip,
+            )
+        },
+        &conn,
+    );
+
+    Ok(Json(new_watermark))
+}
+
+/// Turns the camera's watermark off. Images it was burned into keep it. Only owners can remove it.
+#[delete("/cameras/<camera_id_string>/watermark")]
+pub fn delete_watermark(
+    conn: CameraServerDbConn,
+    user_auth: UserAuth,
+    client_ip: ClientIp,
+    camera_id_string: String,
+) -> Result<Json<Watermark>, ApiError> {
+    user_auth.require_scope(ApiKeyScope::ConfigWrite)?;
+    check_if_user_has_camera_role(&conn, &user_auth, &camera_id_string, CameraRole::Owner)?;
+    let camera_id = parse_camera_id(&camera_id_string)?;
+
+    let old_watermark = match get(camera_id, &conn) {
+        Ok(watermark) => watermark,
+        Err(diesel::NotFound) => {
+            return Err(ApiError {
+                error: "Camera has no watermark",
+                status: Status::NotFound,
+            })
+        }
+        Err(error) => return Err(read_error(camera_id, error)),
+    };
+    delete(camera_id, &conn).map_err(|error| {
+        error!(
+            "Failed to delete watermark for camera {}: {}",
+            camera_id, error
+        );
+        ApiError {
+            error: "Failed to delete watermark",
+            status: Status::InternalServerError,
+        }
+    })?;
+
+    audit_log::record(
+        InsertableAuditEvent {
+            target_camera_id: Some(camera_id),
+            details: Some(json!({ "old": old_watermark })),
+            ..InsertableAuditEvent::by_user_auth(
+                AuditAction::WatermarkDeleted,
+                &user_auth,
+                &client_ip,
+            )
+        },
+        &conn,
+    );
+
+    Ok(Json(old_watermark))
+}